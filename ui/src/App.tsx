@@ -1,11 +1,11 @@
-import { useEffect, useState } from "react"
+import { useEffect, useRef, useState } from "react"
 import HyperwareClientApi from "@hyperware-ai/client-api"
 import "./App.css"
 import useTlDrawStore from "./store/tldraw"
 import FolderView from "./components/FolderView"
 import TldrawView from "./components/TldrawView"
 import MarkdownView from "./components/MarkdownView"
-import { StructureResponse, ApiFolder, ApiNote, TlDrawFolder, TlDrawNote } from "./types/TlDraw"
+import { StructureResponse, StructureDelta, ApiFolder, ApiNote, TlDrawFolder, TlDrawNote } from "./types/TlDraw"
 
 const BASE_URL = import.meta.env.BASE_URL
 if (window.our) window.our.process = BASE_URL?.replace("/", "")
@@ -16,11 +16,16 @@ const WEBSOCKET_URL = import.meta.env.DEV
   ? `${PROXY_TARGET.replace('http', 'ws')}`
   : undefined
 
+const gunzip = (data: Blob | ArrayBuffer) =>
+  new Response(new Blob([data]).stream().pipeThrough(new DecompressionStream('gzip'))).text()
+
 function App() {
   const [isPublicView, setIsPublicView] = useState(false);
-  const { view, currentNote, setStructure, setCurrentNote } = useTlDrawStore()
+  const { view, currentNote, setStructure, setCurrentNote, updateNote } = useTlDrawStore()
   const [nodeConnected, setNodeConnected] = useState(true)
   const [initializing, setInitializing] = useState(true)
+  const apiRef = useRef<HyperwareClientApi | null>(null)
+  const structureCursorRef = useRef<number | null>(null) // Last structure change received
 
   // Handle view type determination and public note loading
   useEffect(() => {
@@ -121,50 +126,97 @@ function App() {
 
     // Connect to the Kinode via websocket
     if (window.our?.node && window.our?.process) {
+      let received = Promise.resolve()
       new HyperwareClientApi({
         uri: WEBSOCKET_URL,
         nodeId: window.our.node,
         processId: window.our.process,
-        onOpen: (_event, _api) => {
+        onOpen: (_event, api) => {
           console.log("Connected to Kinode")
+          apiRef.current = api
+          useTlDrawStore.getState().set({ wsSend: (data) => api.send({ data }) })
+          // Catch up on changes missed while disconnected
+          api.send({ data: { Resync: structureCursorRef.current } })
+          // Ask again for the open note's content, which a new channel isn't subscribed to
+          const openNoteId = useTlDrawStore.getState().currentNote?.id
+          if (openNoteId) api.send({ data: { Subscribe: [openNoteId] } })
         },
+        // Messages are handled in order, as decompressing one may take a moment
         onMessage: (message, _api) => {
-          try {
-            // Parse the message if it's a string
-            const data = typeof message === 'string' ? JSON.parse(message) : message;
-            console.log("WebSocket received message", data);
+          received = received.then(async () => {
+            try {
+              // Large pushes arrive gzipped in binary frames
+              const text = typeof message === 'string'
+                ? message
+                : message instanceof Blob || message instanceof ArrayBuffer
+                  ? await gunzip(message)
+                  : null;
+              const data = text !== null ? JSON.parse(text) : message;
+              console.log("WebSocket received message", data);
 
-            // Handle real-time updates
-            if (data && typeof data === 'object' && 'GetStructure' in data) {
-              const structure = data.GetStructure;
-              if ('Ok' in structure) {
-                const [folders, notes] = structure.Ok;
+              // Handle real-time updates
+              if (data && typeof data === 'object' && 'UpToDate' in data) {
+                structureCursorRef.current = data.UpToDate;
+              } else if (data && typeof data === 'object' && 'Delta' in data) {
+                const delta: StructureDelta = data.Delta;
+                structureCursorRef.current = delta.cursor;
+                const state = useTlDrawStore.getState();
+                const dropped = new Set(delta.removed);
 
                 // Transform the data to match expected format
-                const transformedFolders = (folders as ApiFolder[]).map((f: ApiFolder): TlDrawFolder => ({
+                const changedFolders = new Map(delta.folders.map((f: ApiFolder): [string, TlDrawFolder] => [f.id, {
                   id: f.id,
                   name: f.name,
                   'parent-id': f.parent_id // Convert snake_case to kebab-case
-                }));
-
-                  const transformedNotes = (notes as ApiNote[]).map((n: ApiNote): TlDrawNote => ({
-                    id: n.id,
-                    name: n.name,
-                    'folder-id': n.folder_id, // Convert snake_case to kebab-case
-                    content: n.content,
-                    type: n.note_type,
-                    isPublic: n.is_public,
-                    collaborators: n.collaborators,
-                  }));
-
-                // Re-apply the transform and update state
+                }]));
+                const folders = delta.full ? [] : state.folders.filter((f) => !changedFolders.has(f.id) && !dropped.has(f.id));
+                const transformedFolders = [...folders, ...changedFolders.values()];
+
+                // Deltas leave out content, which comes only for subscribed notes
+                const known = new Map(state.notes.map((n) => [n.id, n.content]));
+                const changedNotes = new Map(delta.notes.map((n: ApiNote): [string, TlDrawNote] => [n.id, {
+                  id: n.id,
+                  name: n.name,
+                  'folder-id': n.folder_id, // Convert snake_case to kebab-case
+                  content: known.get(n.id) ?? n.content,
+                  type: n.note_type,
+                  isPublic: n.is_public,
+                  collaborators: n.collaborators,
+                }]));
+                const notes = delta.full ? [] : state.notes.filter((n) => !changedNotes.has(n.id) && !dropped.has(n.id));
+                const transformedNotes = [...notes, ...changedNotes.values()];
+
                 setStructure(transformedFolders, transformedNotes);
-                console.log('Set structure with:', { transformedFolders, transformedNotes });
+                console.log('Applied structure delta:', delta);
+              } else if (data && typeof data === 'object' && 'Response' in data) {
+                // Answer to a request sent over the WebSocket, such as an autosave
+                const [id, response] = data.Response;
+                const result = Object.values(response)[0];
+                if ('QuotaExceeded' in response) {
+                  console.error(`WebSocket request ${id} went over the storage limits:`, response.QuotaExceeded);
+                  useTlDrawStore.getState().setError('Not saved: this note is over the storage limits in settings');
+                } else if (result && typeof result === 'object' && 'Err' in result) {
+                  console.error(`WebSocket request ${id} failed:`, result.Err);
+                }
+              } else if (data && typeof data === 'object' && 'GetPresence' in data && 'Ok' in data.GetPresence) {
+                useTlDrawStore.getState().set({ presence: data.GetPresence.Ok })
+              } else if (data && typeof data === 'object' && 'GetNote' in data && 'Ok' in data.GetNote) {
+                // New content of a note we're subscribed to
+                const n: ApiNote = data.GetNote.Ok;
+                updateNote({
+                  id: n.id,
+                  name: n.name,
+                  'folder-id': n.folder_id,
+                  content: n.content,
+                  type: n.note_type,
+                  isPublic: n.is_public,
+                  collaborators: n.collaborators,
+                });
               }
+            } catch (error) {
+              console.error("Error handling WebSocket message", error)
             }
-          } catch (error) {
-            console.error("Error handling WebSocket message", error)
-          }
+          })
         },
       })
     } else {
@@ -172,6 +224,17 @@ function App() {
     }
   }, [])
 
+  // Follow the open note's content over the WebSocket
+  useEffect(() => {
+    const noteId = currentNote?.id
+    if (!noteId || isPublicView) return
+    apiRef.current?.send({ data: { Subscribe: [noteId] } })
+    return () => {
+      apiRef.current?.send({ data: { Unsubscribe: [noteId] } })
+      useTlDrawStore.getState().set({ presence: [] })
+    }
+  }, [currentNote?.id, isPublicView])
+
   if (initializing) {
     return <div>Loading...</div>
   }
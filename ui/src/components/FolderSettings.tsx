@@ -1,6 +1,6 @@
 import React, { useState, useEffect, useRef } from 'react';
 import { X } from 'lucide-react';
-import { TlDrawNote, ImportRequest } from '../types/TlDraw';
+import { TlDrawNote, ImportRequest, ApiSettings, UpdateSettingsRequest } from '../types/TlDraw';
 import './SettingsPane.css';
 
 const BASE_URL = import.meta.env.BASE_URL;
@@ -19,6 +19,7 @@ interface FolderSettingsProps {
 const FolderSettings: React.FC<FolderSettingsProps> = ({ onClose, onNoteUpdated }) => {
   const [pendingInvites, setPendingInvites] = useState<Invite[]>([]);
   const [error, setError] = useState<string | null>(null);
+  const [settings, setSettings] = useState<ApiSettings | null>(null);
   const fileInputRef = useRef<HTMLInputElement>(null);
   const darkModeMediaQuery = window.matchMedia('(prefers-color-scheme: dark)');
   const [darkMode, setDarkMode] = useState(() => {
@@ -37,9 +38,10 @@ const FolderSettings: React.FC<FolderSettingsProps> = ({ onClose, onNoteUpdated
     return () => darkModeMediaQuery.removeEventListener('change', handleThemeChange);
   }, []);
 
-  // Fetch pending invites on mount
+  // Fetch pending invites and settings on mount
   useEffect(() => {
     fetchInvites();
+    fetchSettings();
   }, []);
 
   // Apply dark mode
@@ -67,23 +69,66 @@ const FolderSettings: React.FC<FolderSettingsProps> = ({ onClose, onNoteUpdated
     }
   };
 
-  const handleExport = async () => {
+  // Settings live on the node; localStorage only caches the theme for the next page load
+  const fetchSettings = async () => {
     try {
       const response = await fetch(`${BASE_URL}/api`, {
         method: 'POST',
-        body: JSON.stringify({ ExportAll: null }),
+        body: JSON.stringify('GetSettings'),
       });
+      const data = await response.json();
+      const fetched: ApiSettings | undefined = data.GetSettings?.Ok;
+      if (fetched) {
+        setSettings(fetched);
+        if (fetched.dark_mode !== null) {
+          setDarkMode(fetched.dark_mode);
+          localStorage.setItem('darkMode', fetched.dark_mode.toString());
+        }
+      }
+    } catch (error) {
+      console.error('Failed to fetch settings:', error);
+    }
+  };
 
-      if (!response.ok) throw new Error('Failed to export');
-
+  const handleDarkModeChange = async (checked: boolean) => {
+    setDarkMode(checked);
+    localStorage.setItem('darkMode', checked.toString());
+    if (!settings) return;
+    try {
+      const request: UpdateSettingsRequest = { UpdateSettings: { ...settings, dark_mode: checked } };
+      const response = await fetch(`${BASE_URL}/api`, {
+        method: 'POST',
+        body: JSON.stringify(request),
+      });
       const data = await response.json();
-      if (!data.ExportAll?.Ok) {
-        throw new Error('Export failed: ' + (data.ExportAll?.Err || 'Unknown error'));
+      if (data.UpdateSettings?.Ok) {
+        setSettings(data.UpdateSettings.Ok);
+      } else {
+        setError(data.UpdateSettings?.Err || 'Failed to save settings');
       }
+    } catch (error) {
+      console.error('Failed to save settings:', error);
+      setError('Failed to save settings');
+    }
+  };
 
-      // Create a Uint8Array from the compressed data
-      const compressedData = new Uint8Array(data.ExportAll.Ok);
-      const blob = new Blob([compressedData], { type: 'application/octet-stream' });
+  const handleExport = async () => {
+    try {
+      // The first request builds the archive on the node; it then comes a chunk at a time
+      const parts: Blob[] = [];
+      let offset = 0;
+      let size = 0;
+      do {
+        const response = await fetch(`${BASE_URL}/api/export?offset=${offset}`);
+        if (!response.ok) throw new Error('Failed to export');
+        size = Number(response.headers.get('X-Export-Size') ?? 0);
+        const part = await response.blob();
+        if (part.size === 0) break;
+        parts.push(part);
+        offset += part.size;
+      } while (offset < size);
+
+      const blob = new Blob(parts, { type: 'application/gzip' });
       const url = URL.createObjectURL(blob);
 
       const a = document.createElement('a');
@@ -107,14 +152,24 @@ const FolderSettings: React.FC<FolderSettingsProps> = ({ onClose, onNoteUpdated
       const compressedData = await file.arrayBuffer();
       const bytes = Array.from(new Uint8Array(compressedData));
 
-      const request: ImportRequest = { ImportAll: bytes };
+      const sendImport = async (passphrase: string | null) => {
+        const request: ImportRequest = { ImportAll: [bytes, passphrase, false, null] };
+        const response = await fetch(`${BASE_URL}/api`, {
+          method: 'POST',
+          body: JSON.stringify(request),
+        });
+        return { response, result: await response.json() };
+      };
 
-      const response = await fetch(`${BASE_URL}/api`, {
-        method: 'POST',
-        body: JSON.stringify(request),
-      });
-
-      const result = await response.json();
+      let { response, result } = await sendImport(null);
+      if (result.ImportAll?.Err?.includes('passphrase is required')) {
+        const passphrase = window.prompt('This archive is encrypted. Enter its passphrase:');
+        if (passphrase === null) {
+          event.target.value = '';
+          return;
+        }
+        ({ response, result } = await sendImport(passphrase));
+      }
       if (result.ImportAll?.Err) {
         throw new Error('Import failed: ' + result.ImportAll.Err);
       }
@@ -175,10 +230,7 @@ const FolderSettings: React.FC<FolderSettingsProps> = ({ onClose, onNoteUpdated
           <input
             type="checkbox"
             checked={darkMode}
-            onChange={(e) => {
-              setDarkMode(e.target.checked);
-              localStorage.setItem('darkMode', e.target.checked.toString());
-            }}
+            onChange={(e) => handleDarkModeChange(e.target.checked)}
           />
           Dark Mode
         </label>
@@ -15,7 +15,7 @@ interface MarkdownViewProps {
 }
 
 const MarkdownView: React.FC<MarkdownViewProps> = ({ note, readOnly = false, onEdit }) => {
-  const { currentNote, setView, updateNote } = useTlDrawStore();
+  const { currentNote, setView, updateNote, presence, wsSend, saveNoteContent } = useTlDrawStore();
   const currentNoteToUse = note || currentNote;
   const [content, setContent] = useState('');
   const [preview, setPreview] = useState(false);
@@ -67,17 +67,9 @@ const MarkdownView: React.FC<MarkdownViewProps> = ({ note, readOnly = false, onE
     if (!currentNoteToUse) return;
 
     const contentBytes = Array.from(new TextEncoder().encode(newContent));
-    const request = {
-      UpdateNoteContent: [currentNoteToUse.id, contentBytes]
-    };
 
     try {
-      const response = await fetch(`${BASE_URL}/api`, {
-        method: 'POST',
-        body: JSON.stringify(request),
-      });
-      const result = await response.json();
-      console.log('Save result:', result);
+      await saveNoteContent(currentNoteToUse.id, contentBytes);
     } catch (error) {
       console.error('Save failed:', error);
     }
@@ -96,6 +88,14 @@ const MarkdownView: React.FC<MarkdownViewProps> = ({ note, readOnly = false, onE
     <div className="markdown-view">
       <div className="toolbar">
         <span className="note-name">{currentNoteToUse?.name}</span>
+        {!readOnly && presence.some((p) => p.channel === null) && (
+          <span
+            className="note-presence"
+            title={presence.filter((p) => p.channel === null).map((p) => `${p.node_id}: ${p.cursor ?? 'no cursor'}`).join('\n')}
+          >
+            Also here: {presence.filter((p) => p.channel === null).map((p) => p.node_id).join(', ')}
+          </span>
+        )}
         {!readOnly && (
           <>
             <button onClick={() => setPreview(!preview)}>
@@ -128,6 +128,11 @@ const MarkdownView: React.FC<MarkdownViewProps> = ({ note, readOnly = false, onE
           <textarea
             value={content}
             onChange={(e) => setContent(e.target.value)}
+            onSelect={(e) => {
+              if (!currentNoteToUse || readOnly) return;
+              const { selectionStart, selectionEnd } = e.currentTarget;
+              wsSend?.({ Cursor: [currentNoteToUse.id, JSON.stringify({ start: selectionStart, end: selectionEnd })] });
+            }}
             placeholder="Type your markdown here..."
             spellCheck={false}
             autoFocus={!readOnly}
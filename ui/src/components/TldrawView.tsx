@@ -11,7 +11,6 @@ import {
   StoreListener,
 } from 'tldraw';
 import useTlDrawStore from '../store/tldraw';
-import { UpdateNoteContentRequest } from '../types/TlDraw';
 import { Settings } from 'lucide-react';
 import SettingsPane from './SettingsPane';
 
@@ -24,7 +23,7 @@ interface TldrawViewProps {
 }
 
 const TldrawView: React.FC<TldrawViewProps> = ({ note, readOnly = false, onEdit }) => {
-  const { currentNote, setView, updateNote } = useTlDrawStore();
+  const { currentNote, setView, updateNote, saveNoteContent } = useTlDrawStore();
   const currentNoteToUse = note || currentNote;
   const [editor, setEditor] = useState<Editor | null>(null);
   const [showSettings, setShowSettings] = useState(false);
@@ -38,18 +37,10 @@ const TldrawView: React.FC<TldrawViewProps> = ({ note, readOnly = false, onEdit
     console.log('Saving snapshot immediately:', snapshot);
 
     const contentBytes = Array.from(new TextEncoder().encode(JSON.stringify(snapshot)));
-    const request: UpdateNoteContentRequest = {
-      UpdateNoteContent: [currentNoteToUse.id, contentBytes]
-    };
 
-    fetch(`${BASE_URL}/api`, {
-      method: 'POST',
-      body: JSON.stringify(request),
-    })
-    .then(response => response.json())
-    .then(result => console.log('Save result:', result))
-    .catch(error => console.error('Save failed:', error));
-  }, [editor, currentNoteToUse, readOnly]);
+    saveNoteContent(currentNoteToUse.id, contentBytes)
+      .catch(error => console.error('Save failed:', error));
+  }, [editor, currentNoteToUse, readOnly, saveNoteContent]);
 
   // Handle back button click
   const handleBackClick = useCallback(() => {
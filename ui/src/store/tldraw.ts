@@ -1,5 +1,5 @@
 import { create } from 'zustand';
-import { TlDrawFolder, TlDrawNote, Invite } from '../types/TlDraw';
+import { TlDrawFolder, TlDrawNote, Invite, Presence } from '../types/TlDraw';
 
 const BASE_URL = import.meta.env.BASE_URL;
 
@@ -25,6 +25,8 @@ interface TlDrawStore {
   isLoading: boolean;
   error: string | null;
   collaborationInvites: Invite[];
+  presence: Presence[]; // Who else has the current note open
+  wsSend?: (data: unknown) => void; // Set once the WebSocket is connected
 
   // Basic setters
   set: (updates: Partial<TlDrawStore>) => void;
@@ -37,6 +39,7 @@ interface TlDrawStore {
   setCollaborationInvites: (invites: Invite[]) => void;
 
   // Note operations
+  saveNoteContent: (id: string, content: number[]) => Promise<void>;
   addNote: (note: TlDrawNote) => void;
   updateNote: (note: TlDrawNote) => void;
   deleteNote: (id: string) => Promise<void>;
@@ -72,6 +75,8 @@ const useTlDrawStore = create<TlDrawStore>((set, get) => ({
   isLoading: false,
   error: null,
   collaborationInvites: [],
+  presence: [],
+  wsSend: undefined,
 
   // Basic setters
   set: (updates) => set(updates),
@@ -91,6 +96,18 @@ const useTlDrawStore = create<TlDrawStore>((set, get) => ({
   setCollaborationInvites: (invites) => set({ collaborationInvites: invites }),
 
   // Note operations
+  saveNoteContent: async (id, content) => {
+    const request = { UpdateNoteContent: [id, content] };
+    // Autosaves go over the WebSocket once it's connected; the response arrives there
+    const { wsSend } = get();
+    if (wsSend) {
+      wsSend({ Request: [Date.now(), request] });
+      return;
+    }
+    const result = await apiCall(request);
+    console.log('Save result:', result);
+  },
+
   addNote: (note) => {
     set((state) => ({
       notes: [...state.notes, note],
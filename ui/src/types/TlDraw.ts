@@ -4,19 +4,44 @@ export interface ApiNote {
   name: string;
   folder_id: string | null;
   content: number[];
-  note_type: 'Tldraw' | 'Markdown';
+  note_type: 'Tldraw' | 'Markdown' | 'Table' | 'PlainText' | 'Audio' | 'Pdf';
   is_public: boolean;
   collaborators: string[];
+  tags: string[];
+}
+
+export interface ApiSettings {
+  default_note_type: 'Tldraw' | 'Markdown' | 'Table' | 'PlainText' | 'Audio' | 'Pdf';
+  default_folder: string | null;
+  autosave_interval_ms: number;
+  journal_folder: string | null;
+  dark_mode: boolean | null; // null follows the system theme
+  editor_font_size: number;
+  spell_check: boolean;
+  title_from_heading: boolean; // Markdown notes are renamed after their first "# heading" on save
+  cors_origins: string[]; // Origins whose pages may fetch /public, or "*" for any
+  cors_methods: string[]; // Of GET, HEAD and POST
+  max_note_bytes: number | null; // Larger content and uploads are answered with { QuotaExceeded: QuotaError }
+  drive_quota_bytes: number | null;
+  cold_storage_months: number | null; // Notes untouched this long are archived daily, and restored when opened
+  duplicate_note_names: 'Allow' | 'Reject' | 'Suffix'; // When a note would share a name in its folder; Suffix names it "Name (2)"
 }
 
 export interface ApiFolder {
   id: string;
   name: string;
   parent_id: string | null;
+  mirror_of: string | null; // "ownerNodeId/folderId" on a folder mirroring another node's folder, read-only
 }
 
 // UI types (kebab-case)
-export type TlDrawNoteType = 'Tldraw' | 'Markdown';
+// Why content was refused for the size limits in settings, sent as { QuotaExceeded: QuotaError }
+// in place of the request's own response
+export type QuotaError =
+  | { NoteTooLarge: [number, number] } // [bytes, limit]
+  | { DriveFull: [number, number, number] }; // [bytes used, bytes to add, quota]
+
+export type TlDrawNoteType = 'Tldraw' | 'Markdown' | 'Table' | 'PlainText' | 'Audio' | 'Pdf'; // Table notes hold CSV
 
 export interface TlDrawFolder {
   id: string;
@@ -39,6 +64,59 @@ export interface Invite {
   note_id: string;
   inviter_node_id: string;
   note_name: string;
+  expires: number; // Seconds since the Unix epoch
+}
+
+// A node invited to, collaborating on, or removed from one of our notes
+export interface Collaborator {
+  node_id: string;
+  status: 'Pending' | 'Accepted' | 'Revoked';
+  last_edit: number | null; // Seconds since the Unix epoch
+  can_edit: boolean;
+}
+
+// Answer to { GetSharedActivity: days }, newest first
+export interface SharedEvent {
+  kind: 'Edit' | 'Comment' | 'Joined' | 'Left' | 'Invite' | 'Mention';
+  note_id: string; // ours, or "ownerNodeId/noteId" for another node's note
+  note_name: string;
+  actor: string; // node id, or the name a commenter gave
+  detail: string | null; // a comment's text
+  at: number; // Seconds since the Unix epoch
+}
+
+// Answer to { GetNoteReadState: noteId }, one per collaborator
+export interface ReadState {
+  node_id: string;
+  last_read: number | null; // Seconds since the Unix epoch
+  seen_latest: boolean; // Fetched or edited the note since its content last changed
+}
+
+// Someone with a note open; cursor is whatever the editor sent, e.g. a textarea selection
+export interface Presence {
+  note_id: string;
+  node_id: string;
+  channel: number | null; // WebSocket channel of one of our own clients; null for other nodes
+  cursor: string | null;
+  seen: number;
+}
+
+// Pushed over the WebSocket when folders or notes change, and in answer to { Resync: cursor }
+export interface StructureDelta {
+  cursor: number; // last change included; send it in Resync after reconnecting
+  full: boolean; // replaces everything the client had
+  folders: ApiFolder[];
+  notes: ApiNote[]; // without content
+  removed: string[]; // folder and note ids
+}
+
+// Fetched through the node for a pasted link; image and favicon are absolute URLs
+export interface LinkPreview {
+  url: string;
+  title: string | null;
+  description: string | null;
+  image: string | null;
+  favicon: string | null;
 }
 
 // Request types
@@ -51,17 +129,73 @@ export type CreateNoteRequest = { CreateNote: [string, string | null, TlDrawNote
 export type RenameNoteRequest = { RenameNote: [string, string] }; // [id, newName]
 export type DeleteNoteRequest = { DeleteNote: string }; // noteId
 export type MoveNoteRequest = { MoveNote: [string, string | null] }; // [id, newFolderId]
+// The note is also listed in the folder, as an entry with id "noteId@folderId"
+export type AddNoteAliasRequest = { AddNoteAlias: [string, string] }; // [noteId, folderId]
+export type RemoveNoteAliasRequest = { RemoveNoteAlias: [string, string] }; // [noteId, folderId]
 export type GetNoteRequest = { GetNote: string }; // noteId
 export type UpdateNoteContentRequest = { UpdateNoteContent: [string, number[]] }; // [id, content]
+export type ResolvePathRequest = { ResolvePath: string }; // "Work/Projects/Alpha/Spec"; answered with { ResolvePath: { Ok: { Folder: id } | { Note: id } } }
+export type GetNotePathRequest = { GetNotePath: string }; // noteId; answered with { GetNotePath: { Ok: { id, name }[] } }, root folder first
+export type MergeContentRequest = { MergeContent: [string, string, string] }; // [base, mine, theirs]; answered with { MergeContent: { Ok: { content, conflicts } } }
+export type SetNoteReadOnlyRequest = { SetNoteReadOnly: [string, boolean] }; // [id, readOnly]
+export type UnfurlUrlRequest = { UnfurlUrl: string }; // url; answered with { UnfurlUrl: { Ok: LinkPreview } }
 
 export type SetNotePublicRequest = { SetNotePublic: [string, boolean] }; // [noteId, isPublic]
+export type PublishNoteRequest = { PublishNote: string }; // noteId; /public serves the content as of now, and later edits stay drafts until published again
+export type UnpublishNoteRequest = { UnpublishNote: string }; // noteId; private again, with its share links suspended until it is published
+export type GetPublishHistoryRequest = { GetPublishHistory: string }; // noteId; answered with { GetPublishHistory: { Ok: { action: 'Published' | 'Unpublished', at }[] } }
+export type SetNoteNoindexRequest = { SetNoteNoindex: [string, boolean] }; // [noteId, noindex]
+export type CreateShareLinkRequest = { CreateShareLink: [string, number | null] }; // [noteId, expires]
+export type CreateSnapshotLinkRequest = { CreateSnapshotLink: [string, number | null] }; // [noteId, expires]
+export type ListShareLinksRequest = { ListShareLinks: string }; // noteId
+export type RevokeShareLinkRequest = { RevokeShareLink: string }; // token
 export type InviteCollaboratorRequest = { InviteCollaborator: [string, string] }; // [noteId, nodeId]
 export type RemoveCollaboratorRequest = { RemoveCollaborator: [string, string] }; // [noteId, nodeId]
 export type AcceptInviteRequest = { AcceptInvite: [string, string] }; // [noteId, inviterNodeId]
 export type RejectInviteRequest = { RejectInvite: [string, string] }; // [noteId, inviterNodeId]
 export type GetInvitesRequest = { GetInvites: null };
+export type GetCollaboratorsRequest = { GetCollaborators: string }; // noteId
+export type LeaveSharedNoteRequest = { LeaveSharedNote: string }; // "ownerNodeId/noteId"
+export type BlockNodeRequest = { BlockNode: string }; // nodeId
+export type ListBlockedNodesRequest = { ListBlockedNodes: null };
+export type UnblockNodeRequest = { UnblockNode: string }; // nodeId
+export type ShareFolderRequest = { ShareFolder: [string, string[]] }; // [folderId, nodeIds]
+export type ShareFolderWithNodesRequest = { ShareFolderWithNodes: [string, string[], boolean] }; // [folderId, nodeIds, readOnly]; unless readOnly, the nodes are invited to every note in it
+export type MirrorFolderRequest = { MirrorFolder: [string, string] }; // [ownerNodeId, ownerFolderId]
+export type UnmirrorFolderRequest = { UnmirrorFolder: string }; // folderId
+export type TransferNoteRequest = { TransferNote: [string, string, boolean] }; // [noteId, targetNodeId, keepCopy]
+export type PingSharedPresenceRequest = { PingSharedPresence: [string, string, string | null] }; // [ownerNodeId, noteId, cursor]
+export type GetPresenceRequest = { GetPresence: string }; // noteId
+
+// Ties, and folders under created, modified or size, are ordered by name
+export interface ListingSort {
+  key: 'Name' | 'Created' | 'Modified' | 'Size' | 'Manual';
+  descending: boolean;
+}
+export type GetStructureSortedRequest = { GetStructureSorted: ListingSort }; // answered like GetStructure
+export type GetFolderContentsRequest = { GetFolderContents: [string | null, ListingSort | null] }; // [folderId, sort]; null lists the root, notes come without content
+export type SetManualOrderRequest = { SetManualOrder: string[] }; // folder and note ids in order
+
+export type UpdateSettingsRequest = { UpdateSettings: ApiSettings };
+
+// Two of our nodes naming each other as replicas keep the same notes
+export type SetReplicaRequest = { SetReplica: string | null }; // nodeId
+export type GetReplicaStatusRequest = { GetReplicaStatus: null };
+export type ReplicaSyncNowRequest = { ReplicaSyncNow: null };
+
+// Requests run in order, with one save and one structure push; answered with { Batch: responses }
+export type BatchRequest = { Batch: object[] };
+
+// Notes the filter keeps, in the ImportAll format; folders left empty are dropped
+export interface ExportFilter {
+  exclude_private: boolean;
+  tags: string[]; // Also keeps notes with tags nested under these; empty keeps all
+  note_type: TlDrawNoteType | null;
+  exclude_attachments: boolean;
+}
+export type ExportFilteredRequest = { ExportFiltered: ExportFilter };
 
-export type ImportRequest = { ImportAll: number[] };
+export type ImportRequest = { ImportAll: [number[], string | null, boolean, string | null] }; // [data, passphrase, previewOnly, workspace]
 
 // Response type
 export type StructureResponse = {
@@ -1,16 +1,30 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::io::prelude::*;
 
-use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use aes_gcm::aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use flate2::{
+    read::GzDecoder,
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
 use serde::{Deserialize, Serialize};
 
 use crate::hyperware::process::wifenote::{
-    Folder, Invite, Note, NoteType, Request as NoteRequest, Response as NoteResponse,
+    ApiToken, Attachment, BackupStatus, BackupTarget, Breadcrumb, Collaborator, CollaboratorStatus,
+    Comment, CommentAction, DueNote, ExportFilter, Feed, Folder, FolderMirror, FolderMirrorUpdate,
+    GitRemote, GitSyncStatus, ImportPreview, Invite, LatencyHistogram, LinkPreview, ListingSort,
+    LogLevel, Mention, MergedContent, NameClash, Note, NoteSize, NoteType, OrphanNote, PathItem,
+    Presence, ProcessGrant, Progress, PublishAction, PublishEvent, QuotaError, ReadState,
+    RecentNote, ReplaceMatch, ReplaceScope, ReplicaChange, ReplicaItem, ReplicaNote, ReplicaStatus,
+    Request as NoteRequest, RequestMetrics, Response as NoteResponse, S3Target, SearchHit,
+    Settings, ShareLink, SharedEvent, SharedEventKind, SortKey, StorageStats, TableEdit, TagNode,
+    TitleMatch, TokenAccess, TokenScope, TransferOffer, TransferredNote, Workspace,
 };
 use hyperware_process_lib::logging::{error, info, init_logging, Level};
 use hyperware_process_lib::{
-    await_message, call_init, http, http::server::HttpServerRequest, last_blob, our, vfs, Address,
-    LazyLoadBlob, Message, Response,
+    await_message, call_init, http, http::server::HttpServerRequest, last_blob, our, timer, vfs,
+    Address, LazyLoadBlob, Message, Request, Response, SendError,
 };
 
 wit_bindgen::generate!({
@@ -25,6 +39,30 @@ const CURRENT_STATE_VERSION: u32 = 1;
 
 const ICON: &str = include_str!("./icon");
 
+// Maximum number of mutations kept for undo
+const MAX_HISTORY_ENTRIES: usize = 50;
+
+// Maximum number of note opens remembered for the recent notes list
+const MAX_RECENT_NOTES: usize = 100;
+
+// Maximum number of mentions kept in the inbox
+const MAX_MENTIONS: usize = 100;
+
+// Limits on comments visitors leave on public notes
+const MAX_COMMENTS_PER_NOTE: usize = 500;
+const MAX_COMMENT_CHARS: usize = 2000;
+const MAX_COMMENT_AUTHOR_CHARS: usize = 80;
+const COMMENT_RATE_LIMIT: usize = 5; // Comments per client address per window
+const COMMENT_RATE_WINDOW_SECS: u64 = 10 * 60;
+
+// Each password tried over HTTP, for a profile or WebDAV, is hashed with argon2, so clients get
+// only a few wrong guesses per window
+const PASSWORD_ATTEMPT_LIMIT: usize = 10; // Wrong passwords per client address per window
+const PASSWORD_ATTEMPT_WINDOW_SECS: u64 = 10 * 60;
+
+// Autosave sends many edits, so a note's collaborator edits raise a notification at most this often
+const EDIT_NOTIFICATION_INTERVAL_SECS: u64 = 10 * 60;
+
 // Version 0: Original format with full notes inline
 // Version 1: New format with note metadata only, content in separate files
 const STATE_VERSION_WITH_SEPARATE_FILES: u32 = 1;
@@ -41,6 +79,8 @@ struct LegacyNote {
     is_public: bool,
     #[serde(default)]
     collaborators: Vec<String>,
+    #[serde(default)]
+    tags: Vec<String>,
 }
 
 impl From<LegacyNote> for Note {
@@ -53,6 +93,7 @@ impl From<LegacyNote> for Note {
             content: legacy.content,
             is_public: legacy.is_public,
             collaborators: legacy.collaborators,
+            tags: legacy.tags,
         }
     }
 }
@@ -67,6 +108,357 @@ struct ExportData {
     note_metadata: Vec<NoteMetadata>, // For v1+
     #[serde(default)]
     collaboration_invites: HashMap<String, HashMap<String, String>>,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+    #[serde(default)]
+    attachment_contents: HashMap<String, Vec<u8>>, // Only populated in exports
+    #[serde(default)]
+    note_aliases: HashMap<String, Vec<String>>, // Only populated in exports; note id -> other folders it is in
+    #[serde(default)]
+    locked_notes: HashSet<String>, // Only populated in exports; notes whose content is still encrypted
+    #[serde(default)]
+    backup: BackupSettings, // Never included in exports
+    #[serde(default)]
+    git_sync: GitSyncSettings, // Never included in exports
+    #[serde(default)]
+    replica: ReplicaSettings, // Never included in exports
+    #[serde(default)]
+    webdav: Option<WebDavCredentials>, // Never included in exports
+    #[serde(
+        default = "default_settings",
+        deserialize_with = "deserialize_settings"
+    )]
+    settings: Settings, // Never included in exports
+    #[serde(default)]
+    share_keys: HashMap<String, [u8; 32]>, // Never included in exports
+    #[serde(default)]
+    received_share_keys: HashMap<String, HashMap<String, [u8; 32]>>, // Never included in exports
+    #[serde(default)]
+    recent_notes: Vec<(String, u64)>, // Never included in exports
+    #[serde(default)]
+    mentions: Vec<Mention>, // Never included in exports
+    #[serde(default)]
+    comments: HashMap<String, NoteComments>, // Never included in exports
+    #[serde(default)]
+    feeds: Vec<FeedSubscription>, // Never included in exports
+    #[serde(default)]
+    shared_notes: Vec<SharedNote>, // Never included in exports
+    #[serde(default)]
+    pending_shared_edits: Vec<PendingSharedEdit>, // Never included in exports
+    #[serde(default)]
+    received_invites: Vec<Invite>, // Never included in exports
+    #[serde(default)]
+    invite_expiry: HashMap<String, HashMap<String, u64>>, // Never included in exports
+    #[serde(default)]
+    blocked_nodes: Vec<String>, // Never included in exports
+    #[serde(default)]
+    collaborator_activity: HashMap<String, HashMap<String, CollaboratorActivity>>, // Never included in exports
+    #[serde(default)]
+    shared_folders: HashMap<String, Vec<String>>, // Never included in exports
+    #[serde(default)]
+    folder_subscribers: HashMap<String, HashSet<String>>, // Never included in exports
+    #[serde(default)]
+    mirrors: Vec<MirroredFolder>, // Never included in exports
+    #[serde(default)]
+    share_links: Vec<ShareLink>, // Never included in exports
+    #[serde(default)]
+    by_name: HashMap<String, String>, // Never included in exports
+    #[serde(default)]
+    attachment_blobs: HashMap<String, String>, // Never included in exports
+    #[serde(default)]
+    manual_order: HashMap<String, u32>, // Never included in exports
+    #[serde(default)]
+    publish_history: HashMap<String, Vec<PublishEvent>>, // Never included in exports
+    #[serde(default)]
+    transfer_offers: Vec<TransferOffer>, // Never included in exports
+    #[serde(default)]
+    outgoing_transfers: HashMap<String, (String, bool)>, // Never included in exports
+}
+
+// What a node has done with one of our notes, kept after it stops collaborating
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct CollaboratorActivity {
+    last_edit: Option<u64>,
+    revoked: Option<u64>, // When it was removed or left, unless it has since rejoined
+    #[serde(default)]
+    last_read: Option<u64>, // When it last fetched the note
+    #[serde(default)]
+    joined: Option<u64>, // When it last accepted an invite to the note
+}
+
+// A save to a shared note made while its owner was unreachable, sent once the owner is back
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PendingSharedEdit {
+    owner: String,
+    note_id: String,
+    content: Vec<u8>,
+    base_hash: Option<String>, // Hash of the owner's content the edit was made on, if known
+    queued: u64,
+}
+
+// A note on another node that we collaborate on, listed in the "Shared with me" folder
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SharedNote {
+    owner: String,
+    note_id: String, // The owner's id for it
+    name: String,
+    note_type: NoteType,
+}
+
+// Virtual folder holding notes shared with us, which isn't stored with our own folders
+const SHARED_FOLDER_ID: &str = "shared-with-me";
+
+// An RSS or Atom feed whose items are saved as markdown notes in a folder
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FeedSubscription {
+    url: String,
+    folder_id: String,
+    items: HashMap<String, (String, String)>, // Item id -> (note id, hash of the content saved)
+    last_fetched: Option<u64>,
+    last_error: Option<String>,
+}
+
+// Comments on a public note, which only open once the owner enables them
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct NoteComments {
+    enabled: bool,
+    comments: Vec<Comment>, // Oldest first
+}
+
+// Registry of workspaces, kept in the default workspace's drive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WorkspaceList {
+    active: String,
+    names: Vec<String>,
+}
+
+impl Default for WorkspaceList {
+    fn default() -> Self {
+        WorkspaceList {
+            active: DEFAULT_WORKSPACE.to_string(),
+            names: vec![DEFAULT_WORKSPACE.to_string()],
+        }
+    }
+}
+
+// A named profile with its own note tree, for people sharing a node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ProfileAccount {
+    name: String,
+    salt: Vec<u8>,
+    password_hash: Vec<u8>, // Argon2
+}
+
+// An API token as stored; the secret itself is only shown once, when the token is created
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredApiToken {
+    id: String,
+    secret_hash: String, // SHA-256 hex; secrets are random, so no salt is needed
+    scope: TokenScope,
+    created: u64,
+}
+
+fn default_settings() -> Settings {
+    Settings {
+        default_note_type: NoteType::Markdown,
+        default_folder: None,
+        autosave_interval_ms: 1000,
+        journal_folder: None,
+        dark_mode: None,
+        editor_font_size: 16,
+        spell_check: true,
+        title_from_heading: false,
+        cors_origins: Vec::new(),
+        cors_methods: vec!["GET".to_string()],
+        max_note_bytes: None,
+        drive_quota_bytes: None,
+        cold_storage_months: None,
+        duplicate_note_names: NameClash::Allow,
+    }
+}
+
+impl std::fmt::Display for QuotaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            QuotaError::NoteTooLarge((bytes, limit)) => write!(
+                f,
+                "Quota exceeded: {} bytes is over the {} byte limit for a note or attachment",
+                bytes, limit
+            ),
+            QuotaError::DriveFull((used, bytes, quota)) => write!(
+                f,
+                "Quota exceeded: {} more bytes would take the drive past its {} byte quota ({} used)",
+                bytes, quota, used
+            ),
+        }
+    }
+}
+
+// Carried through anyhow by the REST and WebDAV handlers, which answer it with 507
+impl std::error::Error for QuotaError {}
+
+// Settings saved before a setting existed take its default
+fn deserialize_settings<'de, D>(deserializer: D) -> Result<Settings, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    let saved = serde_json::Value::deserialize(deserializer)?;
+    let mut settings = serde_json::to_value(default_settings()).map_err(D::Error::custom)?;
+    if let (Some(settings), serde_json::Value::Object(saved)) = (settings.as_object_mut(), saved) {
+        settings.extend(saved);
+    }
+    serde_json::from_value(settings).map_err(D::Error::custom)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WebDavCredentials {
+    username: String,
+    salt: Vec<u8>,
+    password_hash: Vec<u8>, // Argon2
+}
+
+// A folder or note addressed by a WebDAV path
+#[derive(Debug, Clone, PartialEq)]
+enum DavItem {
+    Root,
+    Folder(String),
+    Note(String),
+}
+
+// The backup target as stored: the key derived from its passphrase, never the passphrase itself
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredBackupTarget {
+    node: String,
+    interval_hours: u32,
+    #[serde(default)]
+    salt: Vec<u8>,
+    #[serde(default)]
+    key: Vec<u8>,
+    // Only read from states saved before keys were stored, and replaced by a key on load
+    #[serde(default, skip_serializing)]
+    passphrase: Option<String>,
+}
+
+impl StoredBackupTarget {
+    fn new(target: BackupTarget) -> Result<Self, String> {
+        let salt = new_archive_salt();
+        let key = archive_key(&target.passphrase, &salt)?;
+        Ok(StoredBackupTarget {
+            node: target.node,
+            interval_hours: target.interval_hours,
+            salt,
+            key: key.to_vec(),
+            passphrase: None,
+        })
+    }
+
+    fn key(&self) -> Result<[u8; 32], String> {
+        self.key
+            .as_slice()
+            .try_into()
+            .map_err(|_| "Backup key is missing; set the backup target again".to_string())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct BackupSettings {
+    target: Option<StoredBackupTarget>,
+    sources: Vec<String>, // Nodes allowed to store their backups here
+    last_backup: Option<u64>,
+    last_error: Option<String>,
+    #[serde(default)]
+    last_restore: Option<u64>,
+    #[serde(default)]
+    s3: Option<S3Target>,
+    #[serde(default)]
+    last_s3_backup: Option<u64>,
+    #[serde(default)]
+    last_s3_error: Option<String>,
+    #[serde(default)]
+    last_s3_skipped: Vec<String>, // Note files the last S3 backup could not read
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct GitSyncSettings {
+    remote: Option<GitRemote>,
+    files: HashMap<String, SyncedFile>, // note_id -> its file as of the last sync
+    last_sync: Option<u64>,
+    last_error: Option<String>,
+}
+
+// A folder another node shares with us, mirrored read-only under one of our folders
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MirroredFolder {
+    owner: String,
+    folder_id: String, // The owner's id for it
+    local_folder_id: String,
+    items: HashMap<String, String>, // Owner's folder or note id -> ours, including the folder itself
+}
+
+// Another of our nodes keeping the same notes as this one
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ReplicaSettings {
+    node: Option<String>,
+    synced: HashMap<String, String>, // Folder, note or attachment id -> its hash as of the last sync
+    last_sync: Option<u64>,
+    last_error: Option<String>,
+}
+
+// A replica sync under way, driven by our replica's answers: its changes first, then the items
+// to pull from it, then which of ours it applied
+#[derive(Debug, Clone)]
+struct ReplicaSyncJob {
+    id: u64, // Carried in the context of each of its requests
+    node: String,
+    expected: HashMap<String, Option<String>>, // Item id -> our hash when the plan was made
+    agreed: Vec<(String, Option<String>)>,     // Hashes both sides will have once it finishes
+    pulls: Vec<String>,
+    conflicts: Vec<String>,
+    pushes: Vec<(String, Option<String>)>, // (id, the replica's hash it replaces)
+    pulled: u32,
+    rerun: bool, // Something asked for a sync while this one ran
+}
+
+// A git sync under way, driven by http-client responses: the tree first, then every blob to pull
+// at once, then one commit at a time, since the remote rejects concurrent commits to a branch
+#[derive(Debug, Clone)]
+struct GitSyncJob {
+    id: u64, // Carried in the context of each of its requests
+    remote: GitRemote,
+    tree: HashMap<String, String>,
+    blobs: HashMap<String, Vec<u8>>, // Blob SHA -> content, for files being pulled
+    awaiting: usize,                 // Blob fetches not answered yet
+    writes: VecDeque<GitWrite>,      // Commits still to make; the front one is in flight
+    pulled: u32,
+    pushed: u32,
+    rerun: bool, // Something asked for a sync while this one ran
+}
+
+#[derive(Debug, Clone)]
+enum GitWrite {
+    Delete {
+        note_id: Option<String>, // Forgotten as synced once the file is gone
+        path: String,
+        sha: String,
+        message: String,
+    },
+    Put {
+        note_id: String,
+        path: String,
+        content: Vec<u8>,
+        existing_sha: Option<String>,
+        local_sha: String,
+        message: String,
+    },
+}
+
+// Git blob SHAs let a sync tell which side changed a file since it was last synced
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SyncedFile {
+    path: String,
+    remote_sha: String,
+    local_sha: String,
 }
 
 // Custom deserializer that can handle both old and new Note formats
@@ -82,11 +474,254 @@ where
 #[serde(untagged)]
 enum Msg {
     NoteRequest(NoteRequest),
+    Batch(BatchRequest),
     HttpRequest(HttpServerRequest),
 }
 
+// Several requests processed in order, with a single state save and WebSocket push at the end.
+// Kept out of the WIT request variant, which cannot contain itself.
+#[derive(Debug, serde::Deserialize, serde::Serialize, process_macros::SerdeJsonInto)]
+enum BatchRequest {
+    Batch(Vec<NoteRequest>),
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, process_macros::SerdeJsonInto)]
+enum BatchResponse {
+    Batch(Vec<NoteResponse>), // One response per request, in order
+}
+
+// Messages WebSocket clients send on "/": which notes' content is pushed to them, and requests
+// answered over the same connection, so autosaves don't need an HTTP request each
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+enum WsRequest {
+    Subscribe(Vec<String>), // note ids
+    Unsubscribe(Vec<String>),
+    Cursor(String, Option<String>), // (note id, cursor); for a note the channel is subscribed to
+    Request(u64, NoteRequest),      // (client's request id, request)
+    Batch(u64, Vec<NoteRequest>),
+    Resync(Option<u64>), // cursor of the last structure change seen, after reconnecting
+    LogIn(String), // profile session; the channel acts on that profile's notebook from then on
+}
+
+// Answers to WsRequest::Request and WsRequest::Batch, carrying the client's request id
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+enum WsResponse {
+    Response(u64, NoteResponse),
+    Batch(u64, BatchResponse),
+    UpToDate(u64), // cursor; nothing changed since the one given to Resync
+    Delta(StructureDelta),
+    Profile(Result<String, String>), // answer to LogIn: the profile's name
+}
+
+// Folders and notes changed since a cursor, without content
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct StructureDelta {
+    cursor: u64, // pass to Resync to continue from here
+    full: bool,  // the cursor was too old or unknown: these are all the folders and notes
+    folders: Vec<Folder>,
+    notes: Vec<Note>,
+    removed: Vec<String>, // folder and note ids
+}
+
+// Oldest removals are forgotten past this; cursors from before them get the full structure
+const MAX_STRUCTURE_REMOVALS: usize = 1000;
+
+// Notion nests at most one level of zips; anything deeper is skipped rather than expanded
+const MAX_NESTED_ZIP_DEPTH: u32 = 1;
+
+// A spooled export is written and handed out in pieces of at most this size
+const EXPORT_CHUNK_BYTES: usize = 4 * 1024 * 1024;
+const EXPORT_SPOOL_FILE: &str = "export.json.gz";
+
+// Upper bounds of the latency histogram buckets, in microseconds; slower timings go in one more
+const LATENCY_BUCKETS_US: [u64; 12] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000,
+];
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    counts: [u64; LATENCY_BUCKETS_US.len() + 1],
+    total_us: u64,
+    max_us: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, us: u64) {
+        let bucket = LATENCY_BUCKETS_US
+            .iter()
+            .position(|bound| us <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.counts[bucket] += 1;
+        self.total_us += us;
+        self.max_us = self.max_us.max(us);
+    }
+
+    fn to_wit(&self, name: &str) -> LatencyHistogram {
+        LatencyHistogram {
+            name: name.to_string(),
+            count: self.counts.iter().sum(),
+            total_us: self.total_us,
+            max_us: self.max_us,
+            buckets: LATENCY_BUCKETS_US
+                .iter()
+                .copied()
+                .chain([u64::MAX])
+                .zip(self.counts)
+                .collect(),
+        }
+    }
+}
+
+// Timings since startup, kept in memory only: request handling and the file time within it by
+// request name, and the work after each message that keeps clients up to date, by kind
+#[derive(Debug, Clone, Default)]
+struct Metrics {
+    handling: HashMap<String, Histogram>,
+    vfs: HashMap<String, Histogram>,
+    push: HashMap<String, Histogram>,
+}
+
+impl Metrics {
+    fn to_wit(&self) -> RequestMetrics {
+        let list = |histograms: &HashMap<String, Histogram>| {
+            let mut list: Vec<LatencyHistogram> = histograms
+                .iter()
+                .map(|(name, histogram)| histogram.to_wit(name))
+                .collect();
+            list.sort_by(|a, b| b.total_us.cmp(&a.total_us).then(a.name.cmp(&b.name)));
+            list
+        };
+        RequestMetrics {
+            handling: list(&self.handling),
+            vfs: list(&self.vfs),
+            push: list(&self.push),
+        }
+    }
+}
+
+// Numbered changes to the folder and note structure, so WebSocket clients are only sent what
+// changed. Kept in memory, with cursors starting from the time it was created in milliseconds,
+// so that cursors from before a restart are never mistaken for current ones.
+#[derive(Debug, Clone)]
+struct StructureLog {
+    seq: u64,
+    first_seq: u64,                          // oldest cursor a delta can start from
+    folders: HashMap<String, (u64, Folder)>, // id -> (seq it last changed at, folder)
+    notes: HashMap<String, (u64, Note)>,
+    removed: Vec<(u64, String)>, // oldest first
+}
+
+impl StructureLog {
+    fn new() -> Self {
+        StructureLog {
+            seq: now_secs() * 1000,
+            first_seq: u64::MAX, // Nothing recorded yet
+            folders: HashMap::new(),
+            notes: HashMap::new(),
+            removed: Vec::new(),
+        }
+    }
+
+    // Record what changed since the last update, given every folder and note without content;
+    // returns whether anything did
+    fn update(&mut self, folders: Vec<Folder>, notes: Vec<Note>) -> bool {
+        let seq = self.seq + 1;
+        let mut changed = false;
+        let folders: HashMap<String, Folder> = folders
+            .into_iter()
+            .map(|folder| (folder.id.clone(), folder))
+            .collect();
+        let notes: HashMap<String, Note> = notes
+            .into_iter()
+            .map(|note| (note.id.clone(), note))
+            .collect();
+        for (id, folder) in &folders {
+            let same = self.folders.get(id).is_some_and(|(_, logged)| {
+                serde_json::to_value(logged).ok() == serde_json::to_value(folder).ok()
+            });
+            if !same {
+                self.folders.insert(id.clone(), (seq, folder.clone()));
+                changed = true;
+            }
+        }
+        for (id, note) in &notes {
+            let same = self.notes.get(id).is_some_and(|(_, logged)| {
+                serde_json::to_value(logged).ok() == serde_json::to_value(note).ok()
+            });
+            if !same {
+                self.notes.insert(id.clone(), (seq, note.clone()));
+                changed = true;
+            }
+        }
+        let removed_folders: Vec<String> = self
+            .folders
+            .keys()
+            .filter(|id| !folders.contains_key(*id))
+            .cloned()
+            .collect();
+        let removed_notes: Vec<String> = self
+            .notes
+            .keys()
+            .filter(|id| !notes.contains_key(*id))
+            .cloned()
+            .collect();
+        for id in removed_folders {
+            self.folders.remove(&id);
+            self.removed.push((seq, id));
+            changed = true;
+        }
+        for id in removed_notes {
+            self.notes.remove(&id);
+            self.removed.push((seq, id));
+            changed = true;
+        }
+        if self.removed.len() > MAX_STRUCTURE_REMOVALS {
+            let excess = self.removed.len() - MAX_STRUCTURE_REMOVALS;
+            self.first_seq = self.removed[excess - 1].0;
+            self.removed.drain(..excess);
+        }
+        if changed {
+            self.seq = seq;
+        }
+        if self.first_seq == u64::MAX {
+            self.first_seq = self.seq;
+        }
+        changed
+    }
+
+    // What a client that has seen everything up to `since` is missing
+    fn delta(&self, since: Option<u64>) -> StructureDelta {
+        let since = since.filter(|since| (self.first_seq..=self.seq).contains(since));
+        StructureDelta {
+            cursor: self.seq,
+            full: since.is_none(),
+            folders: self
+                .folders
+                .values()
+                .filter(|(seq, _)| since.is_none_or(|since| *seq > since))
+                .map(|(_, folder)| folder.clone())
+                .collect(),
+            notes: self
+                .notes
+                .values()
+                .filter(|(seq, _)| since.is_none_or(|since| *seq > since))
+                .map(|(_, note)| note.clone())
+                .collect(),
+            removed: match since {
+                Some(since) => self
+                    .removed
+                    .iter()
+                    .filter(|(seq, _)| *seq > since)
+                    .map(|(_, id)| id.clone())
+                    .collect(),
+                None => Vec::new(),
+            },
+        }
+    }
+}
+
 // Note metadata stored in state.json (without content)
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct NoteMetadata {
     id: String,
     name: String,
@@ -96,6 +731,30 @@ struct NoteMetadata {
     is_public: bool,
     #[serde(default)]
     collaborators: Vec<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    is_locked: bool, // Content is encrypted with the note's password
+    #[serde(default)]
+    read_only: bool, // Content and name can't change until it is made editable again
+    #[serde(default)]
+    noindex: bool, // A public note only for those given its link: unlisted, and search engines told to skip it
+    #[serde(default)]
+    compressed: bool, // The content file is gzipped
+    #[serde(default)]
+    archive: Option<String>, // Cold storage archive holding the content, in place of its own file
+    #[serde(default)]
+    published: Option<u64>, // When the content served publicly was published; None serves the live content
+    #[serde(default)]
+    updated: u64, // When the content last changed, in seconds since the Unix epoch; 0 if unknown
+    #[serde(default)]
+    created: u64, // When the note was created, in seconds since the Unix epoch; 0 if unknown
+    #[serde(default)]
+    due: Option<u64>, // When the note is due, in seconds since the Unix epoch
+    #[serde(default)]
+    aliases: Vec<String>, // Other names for the note, from its frontmatter
+    #[serde(default)]
+    alias_folders: Vec<String>, // Other folders the note is listed in
 }
 
 impl From<Note> for NoteMetadata {
@@ -107,10 +766,105 @@ impl From<Note> for NoteMetadata {
             note_type: note.note_type,
             is_public: note.is_public,
             collaborators: note.collaborators,
+            tags: note.tags,
+            is_locked: false, // Carried beside the note, in ExportData::locked_notes
+            read_only: false,
+            noindex: false,
+            compressed: false,
+            archive: None,
+            published: None,
+            updated: 0,
+            created: 0,
+            due: None,
+            aliases: Vec::new(),
+            alias_folders: Vec::new(),
+        }
+    }
+}
+
+// Prior state of a single folder or note, used to reverse a mutation
+#[derive(Debug, Clone)]
+enum ItemSnapshot {
+    Folder(String, Option<Folder>),
+    Note(String, Option<NoteMetadata>, Option<Vec<u8>>), // (id, metadata, content if it changed)
+    Attachment(String, Option<Attachment>, Option<Vec<u8>>),
+}
+
+#[derive(Debug, Clone)]
+struct HistoryEntry {
+    description: String,
+    snapshots: Vec<ItemSnapshot>,
+}
+
+// Bytes in the files of a drive, listed when a quota is first checked. Files written
+// and deleted through it keep the count current from then on.
+#[derive(Debug, Clone, Default)]
+struct DriveUsage(std::cell::Cell<Option<u64>>);
+
+impl DriveUsage {
+    fn write(&self, path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        let replaced = self.file_bytes(path);
+        vfs::create_file(path, None)?.write(bytes)?;
+        self.count(replaced, bytes.len() as u64);
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> Result<(), vfs::VfsError> {
+        let removed = self.file_bytes(path);
+        vfs::remove_file(path, None)?;
+        self.count(removed, 0);
+        Ok(())
+    }
+
+    // Size of a file about to be replaced or deleted, only looked up while there is a count
+    fn file_bytes(&self, path: &str) -> u64 {
+        if self.0.get().is_none() {
+            return 0;
+        }
+        vfs::metadata(path, None).map(|m| m.len).unwrap_or_default()
+    }
+
+    fn count(&self, old: u64, new: u64) {
+        if let Some(used) = self.0.get() {
+            self.0.set(Some((used + new).saturating_sub(old)));
+        }
+    }
+}
+
+// Appends what is written to a drive file a block at a time, so output larger than memory
+// can be built there
+struct VfsAppender {
+    file: vfs::File,
+    buffer: Vec<u8>,
+}
+
+impl Write for VfsAppender {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(bytes);
+        if self.buffer.len() >= EXPORT_CHUNK_BYTES {
+            self.flush()?;
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.file
+                .append(&self.buffer)
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            self.buffer.clear();
         }
+        Ok(())
     }
 }
 
+// Archive paths of exported items
+#[derive(Debug, Default)]
+struct ExportPaths {
+    folders: Vec<(String, String)>, // (folder id, directory path ending in '/')
+    notes: Vec<(String, String)>,   // (note id, file path)
+}
+
 #[derive(Debug, Clone)]
 struct State {
     drive: String,
@@ -118,6 +872,75 @@ struct State {
     notes: HashMap<String, NoteMetadata>, // Now stores metadata only
     root_items: HashSet<String>,          // IDs of folders/notes at root
     collaboration_invites: HashMap<String, HashMap<String, String>>, // note_id -> {invitee_id -> inviter_id}
+    invite_expiry: HashMap<String, HashMap<String, u64>>, // note_id -> {invitee_id -> expires at}
+    attachments: HashMap<String, Attachment>,
+    backup: BackupSettings,
+    backup_timer: u64, // Generation of the live backup timer; older timers are ignored
+    s3_backup_timer: u64,
+    git_sync: GitSyncSettings,
+    git_sync_timer: u64, // Generation of the live git sync timer
+    git_job: Option<GitSyncJob>,
+    replica: ReplicaSettings,
+    replica_timer: u64, // Generation of the live replica sync timer
+    replica_job: Option<ReplicaSyncJob>,
+    note_hashes: HashMap<String, String>, // Note id -> hash of its content, kept by save_note_content
+    webdav: Option<WebDavCredentials>,
+    settings: Settings,
+    share_keys: HashMap<String, [u8; 32]>, // Note id -> key its content is shared under
+    received_share_keys: HashMap<String, HashMap<String, [u8; 32]>>, // Owner node -> note id -> key
+    recent_notes: Vec<(String, u64)>,      // (note id, opened at), most recent first
+    mentions: Vec<Mention>,                // Mentions of us in shared notes, newest first
+    comments: HashMap<String, NoteComments>, // Note id -> its comments
+    feeds: Vec<FeedSubscription>,
+    feed_timer: u64,         // Generation of the live feed poll timer
+    cold_storage_timer: u64, // Generation of the live cold storage compaction timer
+    comment_posts: HashMap<String, Vec<u64>>, // Client address -> times it recently commented
+    password_failures: HashMap<String, Vec<u64>>, // Client address -> times it recently gave a wrong password
+    edit_notified: HashMap<String, u64>, // Note id -> when a collaborator's edit was last notified
+    webdav_authorized: Option<String>,   // Authorization header last verified, to skip rehashing
+    note_keys: HashMap<String, ([u8; 32], Vec<u8>)>, // Locked note id -> (key, salt), once unlocked
+    undo_stack: Vec<HistoryEntry>,       // Kept in memory only
+    redo_stack: Vec<HistoryEntry>,
+    dry_run: bool, // Skip content writes, for previewing imports on a clone
+    request_counts: HashMap<String, u64>, // Request name -> requests handled since startup
+    log_level: LogLevel, // Request tracing asked for with set-log-level, until restart
+    metrics: Metrics,
+    vfs_us: std::cell::Cell<u64>, // Time spent reading and writing files, in microseconds
+    drive_usage: DriveUsage,
+    message_id: u64,    // Correlation ID of the message being handled, for tracing
+    defer_saves: bool,  // Set while a batch runs, which saves once at the end
+    save_pending: bool, // Set by save_later until the save timer fires
+    profile: Option<String>, // Set when this is a profile's notebook rather than the node's
+    profile_sessions: HashMap<String, String>, // Session token -> profile name
+    profile_states: HashMap<String, State>, // Notebooks of profiles used since startup
+    profile_channels: HashMap<u32, String>, // WebSocket channel -> profile session it logged in with
+    ws_subscriptions: HashMap<u32, HashSet<String>>, // WebSocket channel -> note ids it follows
+    ws_private_channels: HashSet<u32>,      // Channels opened on the authenticated WebSocket path
+    presence: HashMap<String, Vec<Presence>>, // Note id -> who has it open
+    shared_presence: HashMap<String, Vec<Presence>>, // Shared note id -> who its owner last said has it open
+    changed_notes: HashSet<String>, // Notes whose content changed since the last WebSocket push
+    structure_log: StructureLog,
+    shared_note_cache: HashMap<String, Note>, // Shared note id -> last copy fetched from its owner
+    shared_notes: Vec<SharedNote>,
+    pending_shared_edits: Vec<PendingSharedEdit>, // Saves to shared notes whose owner was unreachable
+    received_invites: Vec<Invite>,                // Invites to notes on other nodes
+    blocked_nodes: Vec<String>,                   // Nodes whose invites are rejected unseen
+    collaborator_activity: HashMap<String, HashMap<String, CollaboratorActivity>>, // Note id -> node -> activity
+    shared_folders: HashMap<String, Vec<String>>, // Folder id -> nodes allowed to mirror it
+    folder_subscribers: HashMap<String, HashSet<String>>, // Folder id -> nodes mirroring it
+    mirror_sent: HashMap<String, HashMap<String, String>>, // Folder id -> item id -> hash last pushed
+    mirrors: Vec<MirroredFolder>,
+    share_links: Vec<ShareLink>,
+    by_name: HashMap<String, String>, // Folder or note id -> its path under by-name/, as last written
+    attachment_blobs: HashMap<String, String>, // Attachment id -> hash of the blob holding its content
+    manual_order: HashMap<String, u32>, // Folder or note id -> position under the manual sort key
+    publish_history: HashMap<String, Vec<PublishEvent>>, // Note id -> publishes and unpublishes, oldest first
+    transfer_offers: Vec<TransferOffer>, // Notes other nodes offered us, awaiting accept-transfer
+    outgoing_transfers: HashMap<String, (String, bool)>, // Note id -> (target node, keep a copy)
+    shared_edit_timer: u64, // Generation of the live timer replaying pending_shared_edits
+    pending_replies: HashMap<u64, PendingReply>, // Reply context id -> request awaiting its answer
+    next_reply_id: u64,
+    accepted_transfers: HashMap<(String, String), String>, // (sender node, its note id) -> our note id
 }
 
 impl State {
@@ -128,6 +951,75 @@ impl State {
             notes: HashMap::new(),
             root_items: HashSet::new(),
             collaboration_invites: HashMap::new(),
+            invite_expiry: HashMap::new(),
+            attachments: HashMap::new(),
+            backup: BackupSettings::default(),
+            backup_timer: 0,
+            s3_backup_timer: 0,
+            git_sync: GitSyncSettings::default(),
+            git_sync_timer: 0,
+            git_job: None,
+            replica: ReplicaSettings::default(),
+            replica_timer: 0,
+            replica_job: None,
+            note_hashes: HashMap::new(),
+            webdav: None,
+            settings: default_settings(),
+            share_keys: HashMap::new(),
+            received_share_keys: HashMap::new(),
+            recent_notes: Vec::new(),
+            mentions: Vec::new(),
+            comments: HashMap::new(),
+            feeds: Vec::new(),
+            feed_timer: 0,
+            cold_storage_timer: 0,
+            comment_posts: HashMap::new(),
+            password_failures: HashMap::new(),
+            edit_notified: HashMap::new(),
+            webdav_authorized: None,
+            note_keys: HashMap::new(),
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            dry_run: false,
+            request_counts: HashMap::new(),
+            log_level: LogLevel::Info,
+            metrics: Metrics::default(),
+            vfs_us: std::cell::Cell::new(0),
+            drive_usage: DriveUsage::default(),
+            message_id: 0,
+            defer_saves: false,
+            save_pending: false,
+            profile: None,
+            profile_sessions: HashMap::new(),
+            profile_states: HashMap::new(),
+            profile_channels: HashMap::new(),
+            ws_subscriptions: HashMap::new(),
+            ws_private_channels: HashSet::new(),
+            presence: HashMap::new(),
+            shared_presence: HashMap::new(),
+            changed_notes: HashSet::new(),
+            structure_log: StructureLog::new(),
+            shared_note_cache: HashMap::new(),
+            shared_notes: Vec::new(),
+            pending_shared_edits: Vec::new(),
+            received_invites: Vec::new(),
+            blocked_nodes: Vec::new(),
+            collaborator_activity: HashMap::new(),
+            shared_folders: HashMap::new(),
+            folder_subscribers: HashMap::new(),
+            mirror_sent: HashMap::new(),
+            mirrors: Vec::new(),
+            share_links: Vec::new(),
+            by_name: HashMap::new(),
+            attachment_blobs: HashMap::new(),
+            manual_order: HashMap::new(),
+            publish_history: HashMap::new(),
+            transfer_offers: Vec::new(),
+            outgoing_transfers: HashMap::new(),
+            shared_edit_timer: 0,
+            pending_replies: HashMap::new(),
+            next_reply_id: 0,
+            accepted_transfers: HashMap::new(),
         }
     }
 
@@ -136,891 +1028,15118 @@ impl State {
         match note_type {
             NoteType::Markdown => "md",
             NoteType::Tldraw => "json",
+            NoteType::Table => "csv",
+            NoteType::PlainText => "txt",
+            NoteType::Audio => "json",
+            NoteType::Pdf => "pdf",
         }
     }
 
-    // Load note content from individual file
-    fn load_note_content(&self, note_id: &str) -> anyhow::Result<Vec<u8>> {
-        let metadata = self
-            .notes
-            .get(note_id)
-            .ok_or_else(|| anyhow::anyhow!("Note metadata not found"))?;
+    // Content as stored: a note's entry in its cold storage archive, else its own file
+    fn read_note_file(&self, metadata: &NoteMetadata) -> anyhow::Result<Vec<u8>> {
+        if let Some(archive) = &metadata.archive {
+            let read_entry = || -> anyhow::Result<Vec<u8>> {
+                let path = format!("{}/{}", &self.drive, archive);
+                let bytes = vfs::open_file(&path, false, None)?.read()?;
+                let mut zip = zip::ZipArchive::new(std::io::Cursor::new(bytes))?;
+                let mut content = Vec::new();
+                zip.by_name(&metadata.id)?.read_to_end(&mut content)?;
+                Ok(content)
+            };
+            // Metadata restored by undo can name an archive the note has since left
+            if let Ok(content) = read_entry() {
+                return Ok(content);
+            }
+        }
         let ext = Self::get_note_extension(&metadata.note_type);
-        let path = format!("{}/note_{}.{}", &self.drive, note_id, ext);
-        let file = vfs::open_file(&path, false, None)?;
-        Ok(file.read()?)
+        let path = format!("{}/note_{}.{}", &self.drive, metadata.id, ext);
+        self.timed_vfs(|| Ok(vfs::open_file(&path, false, None)?.read()?))
     }
 
-    // Save note content to individual file
-    fn save_note_content(&self, note_id: &str, content: &[u8]) -> anyhow::Result<()> {
-        let metadata = self
-            .notes
-            .get(note_id)
-            .ok_or_else(|| anyhow::anyhow!("Note metadata not found"))?;
+    // Run a file operation, counting its time towards the request being handled
+    fn timed_vfs<T>(&self, op: impl FnOnce() -> T) -> T {
+        let started = std::time::Instant::now();
+        let result = op();
+        self.vfs_us
+            .set(self.vfs_us.get() + started.elapsed().as_micros() as u64);
+        result
+    }
+
+    fn record_push_timing(&mut self, kind: &str, started: std::time::Instant) {
+        let elapsed = started.elapsed().as_micros() as u64;
+        self.metrics
+            .push
+            .entry(kind.to_string())
+            .or_default()
+            .record(elapsed);
+    }
+
+    fn record_request_timing(&mut self, name: &str, started: std::time::Instant, vfs_before: u64) {
+        let elapsed = started.elapsed().as_micros() as u64;
+        let vfs = self.vfs_us.get().saturating_sub(vfs_before);
+        self.metrics
+            .handling
+            .entry(name.to_string())
+            .or_default()
+            .record(elapsed);
+        self.metrics
+            .vfs
+            .entry(name.to_string())
+            .or_default()
+            .record(vfs);
+    }
+
+    // Delete a removed note's content file; archived content goes once its archive is pruned
+    fn remove_note_file(&self, metadata: &NoteMetadata) {
+        if metadata.archive.is_some() {
+            return;
+        }
         let ext = Self::get_note_extension(&metadata.note_type);
-        let path = format!("{}/note_{}.{}", &self.drive, note_id, ext);
-        let file = vfs::create_file(&path, None)?;
+        let path = format!("{}/note_{}.{}", &self.drive, metadata.id, ext);
+        if let Err(e) = self.drive_usage.remove(&path) {
+            error!(
+                "Failed to delete note content file for {}: {}",
+                metadata.id, e
+            );
+        }
+    }
+
+    // Content served publicly for a note, as of when it was last published
+    fn published_path(&self, metadata: &NoteMetadata) -> String {
+        let ext = Self::get_note_extension(&metadata.note_type);
+        format!("{}/published_{}.{}", &self.drive, metadata.id, ext)
+    }
+
+    fn record_publish(&mut self, note_id: &str, action: PublishAction) {
+        self.publish_history
+            .entry(note_id.to_string())
+            .or_default()
+            .push(PublishEvent {
+                action,
+                at: now_secs(),
+            });
+    }
+
+    // Taken down with unpublish-note and not published since, so its share links are suspended
+    fn is_unpublished(&self, note_id: &str) -> bool {
+        self.publish_history
+            .get(note_id)
+            .and_then(|events| events.last())
+            .is_some_and(|event| event.action == PublishAction::Unpublished)
+    }
+
+    // The published copy goes with the note; cold storage leaves it alone
+    fn remove_published_file(&self, metadata: &NoteMetadata) {
+        if metadata.published.is_some() {
+            let _ = self.drive_usage.remove(&self.published_path(metadata));
+        }
+    }
+
+    // A public note as visitors see it: its published content if it has been published, and
+    // without its folder or collaborators
+    fn public_note(&self, metadata: &NoteMetadata) -> anyhow::Result<Note> {
+        let mut note = self.get_full_note(metadata)?;
+        if metadata.published.is_some() {
+            note.content = vfs::open_file(&self.published_path(metadata), false, None)?.read()?;
+        }
+        note.folder_id = None;
+        note.collaborators = Vec::new();
+        Ok(note)
+    }
+
+    // Load note content, decompressing it if it was stored gzipped
+    fn load_note_content(&self, note_id: &str) -> anyhow::Result<Vec<u8>> {
+        let metadata = self
+            .notes
+            .get(note_id)
+            .ok_or_else(|| anyhow::anyhow!("Note metadata not found"))?;
+        let content = self.read_note_file(metadata)?;
+        if !metadata.compressed {
+            return Ok(content);
+        }
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&content[..]).read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    }
+
+    // Note files at least this big are stored gzipped, when that makes them smaller
+    const NOTE_COMPRESS_MIN_BYTES: usize = 512;
+
+    // Save note content to individual file, noting when it changed in the note's metadata
+    fn save_note_content(&mut self, note_id: &str, content: &[u8]) -> anyhow::Result<()> {
+        let metadata = self
+            .notes
+            .get_mut(note_id)
+            .ok_or_else(|| anyhow::anyhow!("Note metadata not found"))?;
+        if self.dry_run {
+            return Ok(());
+        }
+        let ext = Self::get_note_extension(&metadata.note_type);
+        let path = format!("{}/note_{}.{}", &self.drive, note_id, ext);
 
         // For markdown files, ensure they end with a newline
+        let mut content = std::borrow::Cow::Borrowed(content);
         if metadata.note_type == NoteType::Markdown
+            && !metadata.is_locked
             && !content.is_empty()
             && !content.ends_with(b"\n")
         {
-            let mut content_with_newline = content.to_vec();
-            content_with_newline.push(b'\n');
-            file.write(&content_with_newline)?;
-        } else {
-            file.write(content)?;
+            content.to_mut().push(b'\n');
         }
+        // Text and tldraw JSON shrink a lot; PDFs and encrypted content are already dense
+        let compressible = metadata.note_type != NoteType::Pdf && !metadata.is_locked;
+        let compressed = if compressible && content.len() >= Self::NOTE_COMPRESS_MIN_BYTES {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&content)?;
+            Some(encoder.finish()?).filter(|compressed| compressed.len() < content.len())
+        } else {
+            None
+        };
+        // Not timed_vfs, which would borrow all of self while metadata is borrowed
+        let started = std::time::Instant::now();
+        self.drive_usage
+            .write(&path, compressed.as_deref().unwrap_or(&content))?;
+        self.vfs_us
+            .set(self.vfs_us.get() + started.elapsed().as_micros() as u64);
+        metadata.compressed = compressed.is_some();
+        metadata.archive = None;
+        metadata.updated = now_secs();
+        self.changed_notes.insert(note_id.to_string());
+        self.note_hashes
+            .insert(note_id.to_string(), sha256_hex(&content));
 
         Ok(())
     }
 
-    // Get full Note from NoteMetadata by loading content
+    // Every folder and note, with content, in the order asked for or else unordered. The
+    // "Shared with me" section always comes last.
+    fn structure(&self, sort: Option<&ListingSort>) -> (Vec<Folder>, Vec<Note>) {
+        // Convert metadata to full notes for API compatibility
+        let mut notes = Vec::new();
+        for metadata in self.notes.values() {
+            match self.get_full_note(metadata) {
+                Ok(note) => notes.push(note),
+                Err(_) => {
+                    // If we can't load content, create note with empty content
+                    notes.push(Note {
+                        id: metadata.id.clone(),
+                        name: metadata.name.clone(),
+                        folder_id: metadata.folder_id.clone(),
+                        note_type: metadata.note_type,
+                        content: vec![],
+                        is_public: metadata.is_public,
+                        collaborators: metadata.collaborators.clone(),
+                        tags: metadata.tags.clone(),
+                    });
+                }
+            }
+        }
+        notes.extend(self.alias_entries(None));
+        let mut folders: Vec<Folder> = self.folders.values().cloned().collect();
+        if let Some(sort) = sort {
+            self.sort_listing(sort, &mut folders, &mut notes);
+        }
+        if let Some((folder, shared)) = self.shared_section() {
+            folders.push(folder);
+            notes.extend(shared);
+        }
+        (folders, notes)
+    }
+
+    // Order folders and notes by a listing's sort key, then by name. Folders have no times or
+    // size, so under those keys they are ordered by name alone.
+    fn sort_listing(&self, sort: &ListingSort, folders: &mut [Folder], notes: &mut [Note]) {
+        let manual = |id: &str| self.manual_order.get(id).copied().unwrap_or(u32::MAX) as u64;
+        let folder_key = |folder: &Folder| match sort.key {
+            SortKey::Manual => manual(&folder.id),
+            _ => 0,
+        };
+        let note_key = |note: &Note| {
+            let id = note_alias_ref(&note.id).map_or(note.id.as_str(), |(id, _)| id);
+            let Some(metadata) = self.notes.get(id) else {
+                return 0;
+            };
+            match sort.key {
+                SortKey::Name => 0,
+                SortKey::Created => metadata.created,
+                SortKey::Modified => metadata.updated,
+                SortKey::Size => self.note_file_bytes(metadata),
+                SortKey::Manual => manual(&metadata.id),
+            }
+        };
+        folders.sort_by_cached_key(|folder| (folder_key(folder), folder.name.to_lowercase()));
+        notes.sort_by_cached_key(|note| (note_key(note), note.name.to_lowercase()));
+        if sort.descending {
+            folders.reverse();
+            notes.reverse();
+        }
+    }
+
+    // The "Shared with me" folder and the notes in it, without content, if anything is shared
+    fn shared_section(&self) -> Option<(Folder, Vec<Note>)> {
+        if self.shared_notes.is_empty() {
+            return None;
+        }
+        let folder = Folder {
+            id: SHARED_FOLDER_ID.to_string(),
+            name: "Shared with me".to_string(),
+            parent_id: None,
+            mirror_of: None,
+        };
+        let notes = self
+            .shared_notes
+            .iter()
+            .map(|shared| Note {
+                id: format!("{}/{}", shared.owner, shared.note_id),
+                name: shared.name.clone(),
+                folder_id: Some(SHARED_FOLDER_ID.to_string()),
+                note_type: shared.note_type,
+                content: vec![],
+                is_public: false,
+                collaborators: Vec::new(),
+                tags: Vec::new(),
+            })
+            .collect();
+        Some((folder, notes))
+    }
+
+    // Every folder and note without content, as WebSocket clients are sent them
+    fn structure_summary(&self) -> (Vec<Folder>, Vec<Note>) {
+        let mut folders: Vec<Folder> = self.folders.values().cloned().collect();
+        let mut notes: Vec<Note> = self
+            .notes
+            .values()
+            .map(|metadata| Note {
+                id: metadata.id.clone(),
+                name: metadata.name.clone(),
+                folder_id: metadata.folder_id.clone(),
+                note_type: metadata.note_type,
+                content: vec![],
+                is_public: metadata.is_public,
+                collaborators: metadata.collaborators.clone(),
+                tags: metadata.tags.clone(),
+            })
+            .collect();
+        notes.extend(self.alias_entries(None));
+        if let Some((folder, shared)) = self.shared_section() {
+            folders.push(folder);
+            notes.extend(shared);
+        }
+        (folders, notes)
+    }
+
+    // The structure log, first filled with the structure as it is when nothing was recorded
+    // yet, so that neither a resync nor the first push treats every item as new
+    fn structure_log(&mut self) -> &mut StructureLog {
+        if self.structure_log.first_seq == u64::MAX {
+            let (folders, notes) = self.structure_summary();
+            self.structure_log.update(folders, notes);
+        }
+        &mut self.structure_log
+    }
+
+    // Entries without content for notes listed in other folders, in one folder or all of them
+    fn alias_entries(&self, folder_id: Option<&str>) -> Vec<Note> {
+        let mut entries = Vec::new();
+        for metadata in self.notes.values() {
+            for alias_folder in &metadata.alias_folders {
+                if folder_id.is_some_and(|id| id != alias_folder)
+                    || !self.folders.contains_key(alias_folder)
+                {
+                    continue;
+                }
+                entries.push(Note {
+                    id: alias_entry_id(&metadata.id, alias_folder),
+                    name: metadata.name.clone(),
+                    folder_id: Some(alias_folder.clone()),
+                    note_type: metadata.note_type,
+                    content: vec![],
+                    is_public: metadata.is_public,
+                    collaborators: metadata.collaborators.clone(),
+                    tags: metadata.tags.clone(),
+                });
+            }
+        }
+        entries
+    }
+
+    // The folders and notes directly in a folder, or at the root, without content; None if
+    // there is no such folder. "Shared with me" is listed at the root and can be opened too.
+    fn folder_contents(
+        &self,
+        folder_id: Option<&str>,
+        sort: &ListingSort,
+    ) -> Option<(Vec<Folder>, Vec<Note>)> {
+        let shared = self.shared_section();
+        if let Some(id) = folder_id {
+            if id == SHARED_FOLDER_ID {
+                return shared.map(|(_, notes)| (Vec::new(), notes));
+            }
+            if !self.folders.contains_key(id) {
+                return None;
+            }
+        }
+        let mut folders: Vec<Folder> = self
+            .folders
+            .values()
+            .filter(|f| f.parent_id.as_deref() == folder_id)
+            .cloned()
+            .collect();
+        let mut notes: Vec<Note> = self
+            .notes
+            .values()
+            .filter(|n| n.folder_id.as_deref() == folder_id)
+            .map(|metadata| Note {
+                id: metadata.id.clone(),
+                name: metadata.name.clone(),
+                folder_id: metadata.folder_id.clone(),
+                note_type: metadata.note_type,
+                content: vec![],
+                is_public: metadata.is_public,
+                collaborators: metadata.collaborators.clone(),
+                tags: metadata.tags.clone(),
+            })
+            .collect();
+        if folder_id.is_some() {
+            notes.extend(self.alias_entries(folder_id));
+        }
+        self.sort_listing(sort, &mut folders, &mut notes);
+        if let (None, Some((folder, _))) = (folder_id, shared) {
+            folders.push(folder);
+        }
+        Some((folders, notes))
+    }
+
+    // Get full Note from NoteMetadata by loading content; locked notes come without it
     fn get_full_note(&self, metadata: &NoteMetadata) -> anyhow::Result<Note> {
-        let content = self
-            .load_note_content(&metadata.id)
-            .unwrap_or_else(|_| Vec::new());
+        let content = if metadata.is_locked {
+            Vec::new()
+        } else {
+            self.load_note_content(&metadata.id)
+                .unwrap_or_else(|_| Vec::new())
+        };
         Ok(Note {
             id: metadata.id.clone(),
             name: metadata.name.clone(),
             folder_id: metadata.folder_id.clone(),
-            note_type: metadata.note_type.clone(),
+            note_type: metadata.note_type,
             content,
             is_public: metadata.is_public,
             collaborators: metadata.collaborators.clone(),
+            tags: metadata.tags.clone(),
         })
     }
 
-    // Helper to generate a unique ID
-    fn generate_id() -> String {
-        use std::time::{SystemTime, UNIX_EPOCH};
-        let time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
-        format!("{}", time)
+    // Key the content of a shared note is encrypted with between us and its collaborators. It
+    // reaches them sealed under an ECDH agreement of our exchange key with theirs, so only the
+    // two nodes ever hold it.
+    fn share_key(&mut self, note_id: &str) -> anyhow::Result<[u8; 32]> {
+        if let Some(key) = self.share_keys.get(note_id) {
+            return Ok(*key);
+        }
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        self.share_keys.insert(note_id.to_string(), key);
+        self.save_to_disk()?;
+        Ok(key)
     }
 
-    fn save_to_disk(&self) -> anyhow::Result<()> {
-        let data = serde_json::to_vec(&ExportData {
-            version: CURRENT_STATE_VERSION,
-            folders: self.folders.values().cloned().collect(),
-            notes: Vec::new(), // No longer store full notes in v1+
-            note_metadata: self.notes.values().cloned().collect(),
-            collaboration_invites: self.collaboration_invites.clone(),
-        })?;
+    // Take a markdown note's title, tags and aliases from its frontmatter, where it has them
+    fn apply_frontmatter(&mut self, note_id: &str, content: &[u8]) {
+        let Some(frontmatter) = parse_frontmatter(&String::from_utf8_lossy(content)) else {
+            return;
+        };
+        let Some(metadata) = self.notes.get_mut(note_id) else {
+            return;
+        };
+        if let Some(title) = frontmatter.title {
+            metadata.name = title;
+        }
+        if let Some(tags) = frontmatter.tags {
+            metadata.tags = tags;
+        }
+        if let Some(aliases) = frontmatter.aliases {
+            metadata.aliases = aliases;
+        }
+    }
+
+    // Name a markdown note after its first "# heading", unless its frontmatter gives a title
+    fn apply_heading_title(&mut self, note_id: &str, content: &[u8]) {
+        let text = String::from_utf8_lossy(content);
+        if parse_frontmatter(&text).is_some_and(|frontmatter| frontmatter.title.is_some()) {
+            return;
+        }
+        if let (Some(heading), Some(metadata)) = (first_heading(&text), self.notes.get_mut(note_id))
+        {
+            metadata.name = heading;
+        }
+    }
+
+    fn has_frontmatter(&self, note_id: &str) -> bool {
+        self.notes
+            .get(note_id)
+            .is_some_and(|n| n.note_type == NoteType::Markdown && !n.is_locked)
+            && self.load_note_content(note_id).is_ok_and(|content| {
+                split_frontmatter(&String::from_utf8_lossy(&content)).is_some()
+            })
+    }
 
-        let file = vfs::create_file(&format!("{}/state.json", &self.drive), None)?;
-        file.write(&data)?;
+    // Rewrite a note's frontmatter, if it has one, after its title or tags changed
+    fn sync_frontmatter(&mut self, note_id: &str) -> anyhow::Result<()> {
+        if !self.has_frontmatter(note_id) {
+            return Ok(());
+        }
+        let content = String::from_utf8_lossy(&self.load_note_content(note_id)?).into_owned();
+        let synced = write_frontmatter(&content, &frontmatter_fields(&self.notes[note_id]));
+        if synced != content {
+            self.save_note_content(note_id, synced.as_bytes())?;
+        }
         Ok(())
     }
 
-    fn load_from_disk(drive: String) -> anyhow::Result<Self> {
-        let file = match vfs::open_file(&format!("{}/state.json", &drive), false, None) {
-            Ok(file) => file,
-            Err(_) => return Ok(State::new(drive)), // If file doesn't exist, return new state
-        };
+    // Notes with a due date, soonest first
+    fn due_notes(&self) -> Vec<DueNote> {
+        let mut due: Vec<DueNote> = self
+            .notes
+            .values()
+            .filter_map(|note| {
+                Some(DueNote {
+                    note_id: note.id.clone(),
+                    name: note.name.clone(),
+                    due: note.due?,
+                })
+            })
+            .collect();
+        due.sort_by(|a, b| a.due.cmp(&b.due).then_with(|| a.name.cmp(&b.name)));
+        due
+    }
 
-        let data = file.read()?;
-        let export_data: ExportData = serde_json::from_slice(&data)?;
-        let export_data = migrate_export_data(export_data, &drive)?;
-        let mut state = State::new(drive);
+    // Move a note to the front of the recent notes list
+    fn record_open(&mut self, note_id: &str) -> anyhow::Result<()> {
+        self.recent_notes.retain(|(id, _)| id != note_id);
+        self.recent_notes
+            .insert(0, (note_id.to_string(), now_secs()));
+        self.recent_notes.truncate(MAX_RECENT_NOTES);
+        self.save_later()
+    }
 
-        // Reconstruct shared state
-        state.collaboration_invites = export_data.collaboration_invites;
+    // Notes matching a query, ordered by name; locked notes are only searched by name
+    fn search_notes(&self, query: &SearchExpr) -> Vec<SearchHit> {
+        let mut hits: Vec<SearchHit> = self
+            .notes
+            .values()
+            .filter_map(|metadata| {
+                let content = if metadata.is_locked {
+                    String::new()
+                } else {
+                    self.load_note_content(&metadata.id)
+                        .map(|content| searchable_text(&metadata.note_type, &content))
+                        .unwrap_or_default()
+                };
+                let text = format!("{}\n{}", metadata.name, content);
+                let target = SearchTarget {
+                    metadata,
+                    folder_name: metadata
+                        .folder_id
+                        .as_ref()
+                        .and_then(|id| self.folders.get(id))
+                        .map(|folder| folder.name.as_str()),
+                    lowercase_text: text.to_lowercase(),
+                    text,
+                };
+                query.matches(&target).then(|| SearchHit {
+                    note_id: metadata.id.clone(),
+                    name: metadata.name.clone(),
+                    folder_id: metadata.folder_id.clone(),
+                    snippet: query.snippet(&target),
+                })
+            })
+            .collect();
+        hits.sort_by_key(|hit| hit.name.to_lowercase());
+        hits
+    }
 
-        // Reconstruct state from export data
-        for folder in export_data.folders {
-            if folder.parent_id.is_none() {
-                state.root_items.insert(folder.id.clone());
-            }
-            state.folders.insert(folder.id.clone(), folder);
+    // Who has a note open, forgetting other nodes that stopped pinging
+    fn note_presence(&mut self, note_id: &str) -> Vec<Presence> {
+        let Some(entries) = self.presence.get_mut(note_id) else {
+            return Vec::new();
+        };
+        let cutoff = now_secs().saturating_sub(PRESENCE_TIMEOUT_SECS);
+        entries.retain(|entry| entry.channel.is_some() || entry.seen >= cutoff);
+        let entries = entries.clone();
+        if entries.is_empty() {
+            self.presence.remove(note_id);
         }
+        entries
+    }
 
-        // Load note metadata (v1+) or migrate from full notes (v0)
-        if !export_data.note_metadata.is_empty() {
-            // Version 1+: Load from metadata
-            for metadata in export_data.note_metadata {
-                if metadata.folder_id.is_none() {
-                    state.root_items.insert(metadata.id.clone());
-                }
-                state.notes.insert(metadata.id.clone(), metadata);
+    // Record that a node, or one of our WebSocket clients, has a note open
+    fn update_presence(&mut self, entry: Presence) {
+        let entries = self.presence.entry(entry.note_id.clone()).or_default();
+        entries.retain(|e| e.node_id != entry.node_id || e.channel != entry.channel);
+        entries.push(entry);
+    }
+
+    // Forget a WebSocket client on the given notes, or all of them; returns the notes it left
+    fn remove_channel_presence(
+        &mut self,
+        channel_id: u32,
+        note_ids: Option<&[String]>,
+    ) -> Vec<String> {
+        let mut left = Vec::new();
+        for (note_id, entries) in self.presence.iter_mut() {
+            if note_ids.is_some_and(|ids| !ids.contains(note_id)) {
+                continue;
             }
-        } else if !export_data.notes.is_empty() {
-            // Version 0: Migrate from full notes (this shouldn't happen after migration)
-            for note in export_data.notes {
-                if note.folder_id.is_none() {
-                    state.root_items.insert(note.id.clone());
+            let before = entries.len();
+            entries.retain(|entry| entry.channel != Some(channel_id));
+            if entries.len() != before {
+                left.push(note_id.clone());
+            }
+        }
+        self.presence.retain(|_, entries| !entries.is_empty());
+        left
+    }
+
+    // When an invite runs out, starting the clock for invites from before expiry was tracked
+    fn invite_expires(&mut self, note_id: &str, invitee: &str) -> u64 {
+        *self
+            .invite_expiry
+            .entry(note_id.to_string())
+            .or_default()
+            .entry(invitee.to_string())
+            .or_insert_with(|| now_secs() + INVITE_TTL_SECS)
+    }
+
+    // Drop expired invites, sent and received, and invites to notes that are gone. Returns
+    // whether any were dropped.
+    fn prune_invites(&mut self) -> bool {
+        let now = now_secs();
+        let mut pruned = false;
+        for (note_id, invitees) in self.collaboration_invites.clone() {
+            for invitee in invitees.keys() {
+                if !self.notes.contains_key(&note_id)
+                    || self.invite_expires(&note_id, invitee) <= now
+                {
+                    self.remove_invite(&note_id, invitee);
+                    pruned = true;
                 }
-                let metadata = NoteMetadata::from(note);
-                state.notes.insert(metadata.id.clone(), metadata);
             }
         }
+        // Expiry times can outlive their invites in states saved before deletes cleaned them up
+        let expiry_count = self.invite_expiry.len();
+        self.invite_expiry
+            .retain(|note_id, _| self.notes.contains_key(note_id));
+        pruned |= self.invite_expiry.len() != expiry_count;
+        let count = self.received_invites.len();
+        self.received_invites.retain(|invite| invite.expires > now);
+        pruned || self.received_invites.len() != count
+    }
 
-        Ok(state)
+    fn collaborator_activity(&mut self, note_id: &str, node: &str) -> &mut CollaboratorActivity {
+        self.collaborator_activity
+            .entry(note_id.to_string())
+            .or_default()
+            .entry(node.to_string())
+            .or_default()
     }
-}
 
-// Helper function to migrate state data from older versions
-fn migrate_export_data(mut data: ExportData, drive: &str) -> anyhow::Result<ExportData> {
-    // Return error if version is newer than current
-    if data.version > CURRENT_STATE_VERSION {
-        return Err(anyhow::anyhow!(
-            "Cannot import data from newer version {} (current version is {})",
-            data.version,
-            CURRENT_STATE_VERSION
-        ));
+    // Invite a node to collaborate on a note, returning when the invite expires
+    fn add_invite(&mut self, note_id: &str, invitee: &str) -> u64 {
+        self.collaboration_invites
+            .entry(note_id.to_string())
+            .or_default()
+            .insert(invitee.to_string(), our().node);
+        let expires = now_secs() + INVITE_TTL_SECS;
+        self.invite_expiry
+            .entry(note_id.to_string())
+            .or_default()
+            .insert(invitee.to_string(), expires);
+        expires
     }
 
-    // Apply migrations sequentially based on version
-    if data.version < STATE_VERSION_WITH_SEPARATE_FILES {
-        // Migrate from v0 to v1: Extract note contents to separate files
-        info!(
-            "Migrating state from version {} to {}",
-            data.version, STATE_VERSION_WITH_SEPARATE_FILES
-        );
+    // Let nodes mirror or pull a folder, or stop sharing it when there are none
+    fn share_folder(&mut self, folder_id: &str, nodes: Vec<String>) {
+        if nodes.is_empty() {
+            self.shared_folders.remove(folder_id);
+            self.folder_subscribers.remove(folder_id);
+            self.mirror_sent.remove(folder_id);
+        } else {
+            // Nodes no longer allowed stop getting updates
+            if let Some(subscribers) = self.folder_subscribers.get_mut(folder_id) {
+                subscribers.retain(|node| nodes.contains(node));
+            }
+            self.shared_folders.insert(folder_id.to_string(), nodes);
+        }
+    }
 
-        // Convert full notes to metadata and save content separately
-        let mut note_metadata = Vec::new();
-        for note in data.notes.iter() {
-            // Save note content to individual file with appropriate extension
-            let ext = match note.note_type {
-                NoteType::Markdown => "md",
-                NoteType::Tldraw => "json",
-            };
-            let path = format!("{}/note_{}.{}", drive, note.id, ext);
-            if let Ok(file) = vfs::create_file(&path, None) {
-                if let Err(e) = file.write(&note.content) {
-                    error!(
-                        "Failed to write note content during migration for note {}: {}",
-                        note.id, e
-                    );
-                }
+    fn remove_invite(&mut self, note_id: &str, invitee: &str) {
+        if let Some(invites) = self.collaboration_invites.get_mut(note_id) {
+            invites.remove(invitee);
+            if invites.is_empty() {
+                self.collaboration_invites.remove(note_id);
+            }
+        }
+        if let Some(expiry) = self.invite_expiry.get_mut(note_id) {
+            expiry.remove(invitee);
+            if expiry.is_empty() {
+                self.invite_expiry.remove(note_id);
             }
+        }
+    }
 
-            // Create metadata
-            note_metadata.push(NoteMetadata {
-                id: note.id.clone(),
+    // Drop every pending invite to a note, as it is going away
+    fn remove_note_invites(&mut self, note_id: &str) {
+        self.collaboration_invites.remove(note_id);
+        self.invite_expiry.remove(note_id);
+    }
+
+    // Clear away what a note just taken out of `notes` leaves behind: its place in the tree,
+    // what is kept about it elsewhere in state, and its files. Its attachments are removed
+    // separately.
+    fn forget_note(&mut self, metadata: &NoteMetadata) {
+        let id = &metadata.id;
+        self.root_items.remove(id);
+        self.comments.remove(id);
+        self.remove_note_invites(id);
+        self.collaborator_activity.remove(id);
+        self.publish_history.remove(id);
+        // Snapshots are copies, so they outlive the note
+        self.share_links
+            .retain(|link| link.note_id != *id || link.taken.is_some());
+        self.remove_note_file(metadata);
+        self.remove_published_file(metadata);
+    }
+
+    // Whether a client may try another password, or has given too many wrong ones lately.
+    // Clients whose wrong passwords have all left the window are forgotten.
+    fn may_try_password(&mut self, client: &str) -> bool {
+        let now = now_secs();
+        self.password_failures.retain(|_, failures| {
+            failures.retain(|time| now.saturating_sub(*time) < PASSWORD_ATTEMPT_WINDOW_SECS);
+            !failures.is_empty()
+        });
+        self.password_failures
+            .get(client)
+            .is_none_or(|failures| failures.len() < PASSWORD_ATTEMPT_LIMIT)
+    }
+
+    fn record_wrong_password(&mut self, client: String) {
+        self.password_failures
+            .entry(client)
+            .or_default()
+            .push(now_secs());
+    }
+
+    // List a note we could open on another node under "Shared with me", keeping its name current
+    fn remember_shared_note(
+        &mut self,
+        owner: &str,
+        note_id: &str,
+        note: &Note,
+    ) -> anyhow::Result<()> {
+        let existing = self
+            .shared_notes
+            .iter_mut()
+            .find(|shared| shared.owner == owner && shared.note_id == note_id);
+        match existing {
+            Some(shared) if shared.name == note.name && shared.note_type == note.note_type => {
+                return Ok(())
+            }
+            Some(shared) => {
+                shared.name = note.name.clone();
+                shared.note_type = note.note_type;
+            }
+            None => self.shared_notes.push(SharedNote {
+                owner: owner.to_string(),
+                note_id: note_id.to_string(),
                 name: note.name.clone(),
-                folder_id: note.folder_id.clone(),
-                note_type: note.note_type.clone(),
-                is_public: note.is_public,
-                collaborators: note.collaborators.clone(),
-            });
+                note_type: note.note_type,
+            }),
         }
+        self.save_to_disk()
+    }
 
-        data.note_metadata = note_metadata;
-        data.notes = Vec::new(); // Clear old format notes
-        data.version = STATE_VERSION_WITH_SEPARATE_FILES;
+    // Add a mention to the inbox, replacing an earlier one of the same note by the same node
+    fn record_mention(&mut self, mention: Mention) -> anyhow::Result<()> {
+        push_notification(
+            &format!("{} mentioned you", mention.from_node_id),
+            &format!("In \"{}\"", mention.note_name),
+        );
+        self.mentions.retain(|m| {
+            (&m.owner_node_id, &m.note_id, &m.from_node_id)
+                != (
+                    &mention.owner_node_id,
+                    &mention.note_id,
+                    &mention.from_node_id,
+                )
+        });
+        self.mentions.insert(0, mention);
+        self.mentions.truncate(MAX_MENTIONS);
+        self.save_to_disk()
     }
 
-    data.version = CURRENT_STATE_VERSION;
-    Ok(data)
-}
+    // Attachment content is stored once however many attachments share it, in a blob named
+    // after its hash; attachments saved before that keep a file of their own
+    fn attachment_path(&self, id: &str) -> String {
+        match self.attachment_blobs.get(id) {
+            Some(hash) => format!("{}/blob_{}", &self.drive, hash),
+            None => format!("{}/attachment_{}", &self.drive, id),
+        }
+    }
 
-fn handle_http_request(
-    req: HttpServerRequest,
-    state: &mut State,
-    server: &mut http::server::HttpServer,
-) -> anyhow::Result<()> {
-    let is_public = if let HttpServerRequest::Http(ref http_request) = req {
-        http_request.path()?.starts_with("/public")
-    } else {
-        false
-    };
+    fn load_attachment_content(&self, id: &str) -> anyhow::Result<Vec<u8>> {
+        self.timed_vfs(|| {
+            let file = vfs::open_file(&self.attachment_path(id), false, None)?;
+            Ok(file.read()?)
+        })
+    }
 
-    match req {
-        HttpServerRequest::WebSocketOpen {
-            ref path,
-            channel_id,
-        } => server.handle_websocket_open(path, channel_id),
-        HttpServerRequest::WebSocketClose(channel_id) => server.handle_websocket_close(channel_id),
-        HttpServerRequest::Http(http_request) => {
-            info!("http: a");
-            match http_request.method()? {
-                http::Method::GET => {
-                    info!("http: GET");
+    // A note frozen for a snapshot link, kept apart from the note itself
+    fn save_snapshot(&self, token: &str, note: &Note) -> anyhow::Result<()> {
+        let path = format!("{}/snapshot_{}", &self.drive, token);
+        self.drive_usage.write(&path, &serde_json::to_vec(note)?)
+    }
 
-                    // Handle public note access through public server
-                    if is_public {
-                        // For backward compatibility, support both GET and POST
-                        if let Some(note_id) = http_request.path()?.strip_prefix("/public/") {
-                            let mut headers = HashMap::new();
-                            headers
-                                .insert("Content-Type".to_string(), "application/json".to_string());
-
-                            let result = if let Some(metadata) = state.notes.get(note_id) {
-                                if metadata.is_public {
-                                    match state.get_full_note(metadata) {
-                                        Ok(mut note) => {
-                                            note.folder_id = None; // Don't expose folder structure
-                                            note.collaborators = Vec::new(); // Don't expose collaborators
-                                            Ok(note)
-                                        }
-                                        Err(_) => Err("Error loading note content".to_string()),
-                                    }
-                                } else {
-                                    Err("Note is not public".to_string())
-                                }
-                            } else {
-                                Err("Note not found".to_string())
-                            };
+    fn load_snapshot(&self, token: &str) -> anyhow::Result<Note> {
+        let file = vfs::open_file(&format!("{}/snapshot_{}", &self.drive, token), false, None)?;
+        Ok(serde_json::from_slice(&file.read()?)?)
+    }
 
-                            let (status_code, response) = match result {
-                                Ok(note) => {
-                                    (http::StatusCode::OK, serde_json::json!({ "Ok": note }))
-                                }
-                                Err(msg) => (
-                                    http::StatusCode::NOT_FOUND,
-                                    serde_json::json!({ "Err": msg }),
-                                ),
-                            };
+    fn save_attachment_content(&mut self, id: &str, content: &[u8]) -> anyhow::Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let hash = sha256_hex(content);
+        let path = format!("{}/blob_{}", &self.drive, hash);
+        // Content some other attachment already has is stored already
+        if vfs::metadata(&path, None).is_err() {
+            self.timed_vfs(|| self.drive_usage.write(&path, content))?;
+        }
+        match self.attachment_blobs.insert(id.to_string(), hash.clone()) {
+            Some(previous) if previous != hash => self.release_blob(&previous),
+            Some(_) => {}
+            None => {
+                // Moving over from a file of its own, if it had one
+                let _ = self
+                    .drive_usage
+                    .remove(&format!("{}/attachment_{}", &self.drive, id));
+            }
+        }
+        Ok(())
+    }
 
-                            http::server::send_response(
-                                status_code,
-                                Some(headers),
-                                serde_json::to_vec(&response)?,
-                            );
-                            return Ok(());
-                        }
-                    }
+    // Delete a blob once no attachment refers to it
+    fn release_blob(&self, hash: &str) {
+        if self.attachment_blobs.values().any(|h| h == hash) {
+            return;
+        }
+        if let Err(e) = self
+            .drive_usage
+            .remove(&format!("{}/blob_{}", &self.drive, hash))
+        {
+            error!("Failed to delete attachment blob {}: {}", hash, e);
+        }
+    }
 
-                    // Serve static files for all other GET requests
-                    let mut headers = HashMap::new();
-                    headers.insert("Content-Type".to_string(), "text/html".to_string());
-                    http::server::send_response(
-                        http::StatusCode::OK,
-                        Some(headers),
-                        include_bytes!("../../pkg/ui/index.html").to_vec(),
-                    );
+    // Size of the files in the drive, the by-name tree included. Listing them starts
+    // drive_usage counting afresh.
+    fn drive_bytes(&self) -> anyhow::Result<u64> {
+        let mut total_bytes = 0;
+        let mut dirs = vec![self.drive.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in vfs::open_dir(&dir, false, None)?.read()? {
+                // Directory entries come without the leading slash paths are opened with
+                let path = format!("/{}", entry.path.trim_start_matches('/'));
+                match entry.file_type {
+                    vfs::FileType::Directory => dirs.push(path),
+                    vfs::FileType::File => total_bytes += vfs::metadata(&path, None)?.len,
+                    _ => {}
                 }
-                http::Method::POST => {
-                    // Handle public note access via POST
-                    if is_public {
-                        if http_request.path()? == "/public" {
-                            let Some(body) = last_blob() else {
-                                http::server::send_response(
-                                    http::StatusCode::BAD_REQUEST,
-                                    None,
-                                    "Missing request body".as_bytes().to_vec(),
-                                );
-                                return Ok(());
-                            };
-
-                            // Parse request body
-                            let req: serde_json::Value = match serde_json::from_slice(&body.bytes) {
-                                Ok(req) => req,
-                                Err(_) => {
-                                    http::server::send_response(
-                                        http::StatusCode::BAD_REQUEST,
-                                        None,
-                                        "Invalid JSON".as_bytes().to_vec(),
-                                    );
-                                    return Ok(());
-                                }
-                            };
-
-                            let note_id = match req.get("note_id").and_then(|v| v.as_str()) {
-                                Some(id) => id,
-                                None => {
-                                    http::server::send_response(
-                                        http::StatusCode::BAD_REQUEST,
-                                        None,
-                                        "Missing note_id".as_bytes().to_vec(),
-                                    );
-                                    return Ok(());
-                                }
-                            };
+            }
+        }
+        self.drive_usage.0.set(Some(total_bytes));
+        Ok(total_bytes)
+    }
 
-                            let mut headers = HashMap::new();
-                            headers
-                                .insert("Content-Type".to_string(), "application/json".to_string());
-
-                            let result = if let Some(metadata) = state.notes.get(note_id) {
-                                if metadata.is_public {
-                                    match state.get_full_note(metadata) {
-                                        Ok(mut note) => {
-                                            note.folder_id = None; // Don't expose folder structure
-                                            note.collaborators = Vec::new(); // Don't expose collaborators
-                                            Ok(note)
-                                        }
-                                        Err(_) => Err("Error loading note content".to_string()),
-                                    }
-                                } else {
-                                    Err("Note is not public".to_string())
-                                }
-                            } else {
-                                Err("Note not found".to_string())
-                            };
+    // Size of a note's content file, 0 if it has none
+    fn note_file_bytes(&self, note: &NoteMetadata) -> u64 {
+        let ext = Self::get_note_extension(&note.note_type);
+        let path = format!("{}/note_{}.{}", &self.drive, note.id, ext);
+        vfs::metadata(&path, None)
+            .map(|m| m.len)
+            .unwrap_or_default()
+    }
 
-                            let (status_code, response) = match result {
-                                Ok(note) => {
-                                    (http::StatusCode::OK, serde_json::json!({ "Ok": note }))
-                                }
-                                Err(msg) => (
-                                    http::StatusCode::NOT_FOUND,
-                                    serde_json::json!({ "Err": msg }),
-                                ),
-                            };
+    // Whether `bytes` of new content, taking the place of `replaced` bytes, fit the limits in
+    // settings. The drive is only measured when it has a quota.
+    fn check_quota(&self, bytes: u64, replaced: u64) -> anyhow::Result<Result<(), QuotaError>> {
+        if let Err(e) = self.check_note_size(bytes) {
+            return Ok(Err(e));
+        }
+        self.check_drive_quota(bytes, replaced)
+    }
 
-                            http::server::send_response(
-                                status_code,
-                                Some(headers),
-                                serde_json::to_vec(&response)?,
-                            );
-                            return Ok(());
-                        }
+    // As check_quota, for several notes or attachments arriving together, taking the place of
+    // `replaced` bytes between them
+    fn check_quota_all(
+        &self,
+        sizes: &[u64],
+        replaced: u64,
+    ) -> anyhow::Result<Result<(), QuotaError>> {
+        for &bytes in sizes {
+            if let Err(e) = self.check_note_size(bytes) {
+                return Ok(Err(e));
+            }
+        }
+        self.check_drive_quota(sizes.iter().sum(), replaced)
+    }
 
-                        http::server::send_response(
-                            http::StatusCode::NOT_FOUND,
-                            None,
-                            "Invalid path".as_bytes().to_vec(),
-                        );
-                        return Ok(());
+    // As check_quota_all, for items from our replica; each takes the place of our copy, if any
+    fn check_replica_quota<'a>(
+        &self,
+        items: impl Iterator<Item = (&'a str, &'a ReplicaItem)>,
+    ) -> anyhow::Result<Result<(), QuotaError>> {
+        let mut sizes = Vec::new();
+        let mut replaced = 0;
+        // Our copies are only measured when there is a drive quota for them to free room under
+        let has_quota = self.settings.drive_quota_bytes.is_some();
+        for (id, item) in items {
+            match item {
+                ReplicaItem::Folder(_) => {}
+                ReplicaItem::Note(replica) => {
+                    sizes.push(replica.note.content.len() as u64);
+                    if let Some(note) = self.notes.get(id).filter(|_| has_quota) {
+                        replaced += self.note_file_bytes(note);
                     }
-                    info!("http: POST");
-                    let Some(body) = last_blob() else {
-                        return Err(anyhow::anyhow!(
-                            "received a POST HTTP request with no body, skipping"
-                        ));
-                    };
-                    info!(
-                        "http: POST trying to into note {:?}",
-                        String::from_utf8(body.bytes.clone())
-                            .map(|s| s.chars().take(10).collect::<String>())
-                    );
-                    let resp = handle_note_request(body.bytes.try_into()?, Some(&our()), state)?;
-                    http::server::send_response(http::StatusCode::OK, None, resp.into());
                 }
-                _ => {
-                    http::server::send_response(http::StatusCode::METHOD_NOT_ALLOWED, None, vec![]);
+                ReplicaItem::Attachment((_, content)) => {
+                    sizes.push(content.len() as u64);
+                    replaced += self.attachments.get(id).map_or(0, |a| a.size);
                 }
             }
         }
-        HttpServerRequest::WebSocketPush { .. } => {}
+        self.check_quota_all(&sizes, replaced)
     }
-    Ok(())
-}
 
-fn handle_note_request(
-    req: NoteRequest,
-    source: Option<&Address>,
-    state: &mut State,
-) -> anyhow::Result<NoteResponse> {
-    let resp = 'resp: {
-        match req {
-            NoteRequest::CreateFolder((name, parent)) => {
-                let id = State::generate_id();
-                let folder = Folder {
-                    id: id.clone(),
-                    name,
-                    parent_id: parent,
-                };
-                state.folders.insert(id.clone(), folder.clone());
-                state.root_items.insert(id);
-                state.save_to_disk()?;
-                NoteResponse::CreateFolder(Ok(folder))
-            }
+    fn check_note_size(&self, bytes: u64) -> Result<(), QuotaError> {
+        match self.settings.max_note_bytes {
+            Some(limit) if bytes > limit => Err(QuotaError::NoteTooLarge((bytes, limit))),
+            _ => Ok(()),
+        }
+    }
 
-            NoteRequest::RenameFolder((id, new_name)) => {
-                if let Some(mut folder) = state.folders.get(&id).cloned() {
-                    folder.name = new_name;
-                    state.folders.insert(id, folder.clone());
-                    state.save_to_disk()?;
-                    NoteResponse::RenameFolder(Ok(folder))
-                } else {
-                    NoteResponse::RenameFolder(Err("Folder not found".to_string()))
-                }
+    fn check_drive_quota(
+        &self,
+        bytes: u64,
+        replaced: u64,
+    ) -> anyhow::Result<Result<(), QuotaError>> {
+        if let Some(quota) = self.settings.drive_quota_bytes {
+            let used = match self.drive_usage.0.get() {
+                Some(used) => used,
+                None => self.drive_bytes()?,
+            };
+            // Shrinking is always allowed, even on a drive already over its quota
+            if bytes > replaced && used.saturating_sub(replaced) + bytes > quota {
+                return Ok(Err(QuotaError::DriveFull((used, bytes - replaced, quota))));
             }
+        }
+        Ok(Ok(()))
+    }
 
-            NoteRequest::DeleteFolder(id) => {
-                if let Some(folder) = state.folders.remove(&id) {
-                    state.root_items.remove(&id);
-                    // Move child items to root if any
-                    for note in state.notes.values_mut() {
-                        if note.folder_id.as_ref() == Some(&folder.id) {
-                            note.folder_id = None;
-                            state.root_items.insert(note.id.clone());
-                        }
-                    }
-                    for subfolder in state.folders.values_mut() {
-                        if subfolder.parent_id.as_ref() == Some(&folder.id) {
-                            subfolder.parent_id = None;
-                            state.root_items.insert(subfolder.id.clone());
-                        }
-                    }
-                    state.save_to_disk()?;
-                    NoteResponse::DeleteFolder(Ok(()))
-                } else {
-                    NoteResponse::DeleteFolder(Err("Folder not found".to_string()))
+    fn storage_stats(&self) -> anyhow::Result<StorageStats> {
+        let total_bytes = self.drive_bytes()?;
+        let mut notes: Vec<NoteSize> = self
+            .notes
+            .values()
+            .map(|note| {
+                let attachments = self.attachments.values().filter(|a| a.note_id == note.id);
+                NoteSize {
+                    note_id: note.id.clone(),
+                    name: note.name.clone(),
+                    content_bytes: self.note_file_bytes(note),
+                    attachment_bytes: attachments.clone().map(|a| a.size).sum(),
+                    attachment_count: attachments.count() as u32,
                 }
+            })
+            .collect();
+        notes.sort_by_key(|n| std::cmp::Reverse(n.content_bytes + n.attachment_bytes));
+        let mut request_counts: Vec<(String, u64)> = self
+            .request_counts
+            .iter()
+            .map(|(name, count)| (name.clone(), *count))
+            .collect();
+        request_counts.sort();
+        Ok(StorageStats {
+            total_bytes,
+            note_bytes: notes.iter().map(|n| n.content_bytes).sum(),
+            attachment_bytes: self.attachments.values().map(|a| a.size).sum(),
+            attachment_count: self.attachments.len() as u32,
+            notes,
+            request_counts,
+        })
+    }
+
+    fn remove_attachment(&mut self, id: &str) {
+        if self.attachments.remove(id).is_some() {
+            if let Some(hash) = self.attachment_blobs.remove(id) {
+                self.release_blob(&hash);
+                return;
             }
+            let path = format!("{}/attachment_{}", &self.drive, id);
+            if let Err(e) = self.drive_usage.remove(&path) {
+                error!("Failed to delete attachment file for {}: {}", id, e);
+            }
+        }
+    }
 
-            NoteRequest::MoveFolder((id, new_parent_id)) => {
-                if let Some(mut folder) = state.folders.get(&id).cloned() {
-                    // Validate new parent exists if some
-                    if let Some(ref parent_id) = new_parent_id {
-                        if !state.folders.contains_key(parent_id) {
-                            return Ok(NoteResponse::MoveFolder(Err(
-                                "Parent folder not found".to_string()
-                            )));
-                        }
-                    }
+    // Store a file attached to a note, returning the attachment ID
+    fn add_attachment(
+        &mut self,
+        note_id: &str,
+        name: String,
+        mime: Option<String>,
+        content: &[u8],
+    ) -> anyhow::Result<String> {
+        let id = self.generate_unique_id();
+        self.save_attachment_content(&id, content)?;
+        self.attachments.insert(
+            id.clone(),
+            Attachment {
+                id: id.clone(),
+                note_id: note_id.to_string(),
+                name,
+                mime,
+                size: content.len() as u64,
+            },
+        );
+        Ok(id)
+    }
 
-                    // Remove from old parent's children or root
-                    if folder.parent_id.is_some() {
-                        state.root_items.remove(&id);
-                    }
+    // Move the content of notes untouched for the cold storage period out of their own files and
+    // into one new zip archive, returning how many were moved. Shared notes stay where they are.
+    fn compact_cold_notes(&mut self) -> anyhow::Result<u32> {
+        let Some(months) = self.settings.cold_storage_months else {
+            return Ok(0);
+        };
+        let cutoff = now_secs().saturating_sub(months as u64 * 30 * 24 * 60 * 60);
+        let opened: HashMap<&str, u64> = self
+            .recent_notes
+            .iter()
+            .map(|(id, opened)| (id.as_str(), *opened))
+            .collect();
+        let mut ids: Vec<String> = self
+            .notes
+            .values()
+            .filter(|note| note.archive.is_none() && note.collaborators.is_empty())
+            // Notes saved before edit times were kept can't be judged
+            .filter(|note| note.updated != 0 && note.updated < cutoff)
+            .filter(|note| opened.get(note.id.as_str()).is_none_or(|at| *at < cutoff))
+            .map(|note| note.id.clone())
+            .collect();
+        ids.sort();
+        if ids.is_empty() {
+            self.prune_cold_archives()?;
+            return Ok(0);
+        }
 
-                    // Update folder
-                    folder.parent_id = new_parent_id;
-                    state.folders.insert(id.clone(), folder.clone());
+        let archive = format!("{}{}.zip", COLD_ARCHIVE_PREFIX, self.generate_unique_id());
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default();
+        // A note whose file can't be read is left out, so the rest still move
+        let mut archived = Vec::new();
+        for id in ids {
+            let content = match self.read_note_file(&self.notes[&id]) {
+                Ok(content) => content,
+                Err(e) => {
+                    error!(
+                        "Cold storage skipped note {}, whose file can't be read: {}",
+                        id, e
+                    );
+                    continue;
+                }
+            };
+            writer.start_file(id.as_str(), options)?;
+            writer.write_all(&content)?;
+            archived.push(id);
+        }
+        let ids = archived;
+        if ids.is_empty() {
+            self.prune_cold_archives()?;
+            return Ok(0);
+        }
+        let bytes = writer.finish()?.into_inner();
+        self.drive_usage
+            .write(&format!("{}/{}", &self.drive, archive), &bytes)?;
 
-                    // Add to root if needed
-                    if folder.parent_id.is_none() {
-                        state.root_items.insert(id);
-                    }
+        // Only once the archive is safely written do the files go
+        for id in &ids {
+            let metadata = &self.notes[id];
+            self.remove_note_file(metadata);
+            if let Some(metadata) = self.notes.get_mut(id) {
+                metadata.archive = Some(archive.clone());
+            }
+        }
+        self.save_to_disk()?;
+        self.prune_cold_archives()?;
+        Ok(ids.len() as u32)
+    }
 
-                    state.save_to_disk()?;
-                    NoteResponse::MoveFolder(Ok(folder))
-                } else {
-                    NoteResponse::MoveFolder(Err("Folder not found".to_string()))
+    // Give an archived note its own file again, as it is being used
+    fn rehydrate_note(&mut self, id: &str) -> anyhow::Result<()> {
+        let Some(metadata) = self.notes.get(id).filter(|m| m.archive.is_some()) else {
+            return Ok(());
+        };
+        let content = self.read_note_file(metadata)?;
+        let ext = Self::get_note_extension(&metadata.note_type);
+        let path = format!("{}/note_{}.{}", &self.drive, id, ext);
+        self.drive_usage.write(&path, &content)?;
+        if let Some(metadata) = self.notes.get_mut(id) {
+            metadata.archive = None;
+        }
+        self.save_to_disk()?;
+        self.prune_cold_archives()
+    }
+
+    // Delete cold storage archives no note's content is in any more
+    fn prune_cold_archives(&self) -> anyhow::Result<()> {
+        let used: HashSet<&str> = self
+            .notes
+            .values()
+            .filter_map(|note| note.archive.as_deref())
+            .collect();
+        for entry in vfs::open_dir(&self.drive, false, None)?.read()? {
+            let name = entry.path.rsplit('/').next().unwrap_or_default();
+            if entry.file_type == vfs::FileType::File
+                && name.starts_with(COLD_ARCHIVE_PREFIX)
+                && !used.contains(name)
+            {
+                // Directory entries come without the leading slash paths are opened with
+                let path = format!("/{}", entry.path.trim_start_matches('/'));
+                if let Err(e) = self.drive_usage.remove(&path) {
+                    error!("Failed to delete cold storage archive {}: {}", name, e);
                 }
             }
+        }
+        Ok(())
+    }
 
-            NoteRequest::CreateNote((name, folder_id, note_type)) => {
-                // Validate folder exists if some
-                if let Some(ref folder_id) = folder_id {
-                    if !state.folders.contains_key(folder_id) {
-                        return Ok(NoteResponse::CreateNote(Err(
-                            "Parent folder not found".to_string()
-                        )));
+    // Edits, comments and comings and goings on the notes we share, and invites and mentions
+    // from the nodes sharing theirs, in the last `days` days, newest first
+    fn shared_activity(&self, days: u32) -> Vec<SharedEvent> {
+        let cutoff = now_secs().saturating_sub(days as u64 * 24 * 60 * 60);
+        let mut events = Vec::new();
+        for (note_id, nodes) in &self.collaborator_activity {
+            let Some(note) = self.notes.get(note_id) else {
+                continue;
+            };
+            for (node, activity) in nodes {
+                let happened = [
+                    (SharedEventKind::Edit, activity.last_edit),
+                    (SharedEventKind::Joined, activity.joined),
+                    (SharedEventKind::Left, activity.revoked),
+                ];
+                for (kind, at) in happened {
+                    if let Some(at) = at {
+                        events.push(SharedEvent {
+                            kind,
+                            note_id: note_id.clone(),
+                            note_name: note.name.clone(),
+                            actor: node.clone(),
+                            detail: None,
+                            at,
+                        });
                     }
                 }
+            }
+        }
+        for (note_id, comments) in &self.comments {
+            let Some(note) = self.notes.get(note_id) else {
+                continue;
+            };
+            for comment in comments.comments.iter().filter(|c| !c.hidden) {
+                events.push(SharedEvent {
+                    kind: SharedEventKind::Comment,
+                    note_id: note_id.clone(),
+                    note_name: note.name.clone(),
+                    actor: comment.author.clone(),
+                    detail: Some(comment.text.clone()),
+                    at: comment.created,
+                });
+            }
+        }
+        for invite in &self.received_invites {
+            events.push(SharedEvent {
+                kind: SharedEventKind::Invite,
+                note_id: format!("{}/{}", invite.inviter_node_id, invite.note_id),
+                note_name: invite.note_name.clone(),
+                actor: invite.inviter_node_id.clone(),
+                detail: None,
+                // Invites last a fixed time from when they were sent
+                at: invite.expires.saturating_sub(INVITE_TTL_SECS),
+            });
+        }
+        for mention in &self.mentions {
+            events.push(SharedEvent {
+                kind: SharedEventKind::Mention,
+                note_id: format!("{}/{}", mention.owner_node_id, mention.note_id),
+                note_name: mention.note_name.clone(),
+                actor: mention.from_node_id.clone(),
+                detail: None,
+                at: mention.received,
+            });
+        }
+        events.retain(|event| event.at >= cutoff);
+        events.sort_by_key(|event| std::cmp::Reverse(event.at));
+        events
+    }
 
-                let id = State::generate_id();
-                let metadata = NoteMetadata {
-                    id: id.clone(),
-                    name: name.clone(),
-                    folder_id: folder_id.clone(),
-                    note_type: note_type.clone(),
-                    is_public: false,
-                    collaborators: Vec::new(),
-                };
-
-                // Insert metadata first so save_note_content can access it
-                state.notes.insert(id.clone(), metadata);
+    // Notes in no folder, without tags, that no markdown note links to and that haven't been
+    // edited in `days` days, least recently edited first
+    fn orphan_notes(&self, days: u32) -> Vec<OrphanNote> {
+        let cutoff = now_secs().saturating_sub(days as u64 * 24 * 60 * 60);
+        let candidates: Vec<&NoteMetadata> = self
+            .notes
+            .values()
+            .filter(|note| note.folder_id.is_none() && note.tags.is_empty())
+            .filter(|note| note.updated < cutoff)
+            .collect();
+        if candidates.is_empty() {
+            return Vec::new();
+        }
 
-                // Save empty content to file
-                state.save_note_content(&id, &vec![])?;
-                if folder_id.is_none() {
-                    state.root_items.insert(id.clone());
+        // Links between notes end in the note ID, e.g. `[Other](/public/<id>)`; the contents
+        // of locked notes can't be read, so their links don't count
+        let mut linked = HashSet::new();
+        for note in self.notes.values() {
+            if note.note_type != NoteType::Markdown || note.is_locked {
+                continue;
+            }
+            let content = self.load_note_content(&note.id).unwrap_or_default();
+            rewrite_markdown_links(&String::from_utf8_lossy(&content), |_, _, target| {
+                let target = target.rsplit("/public/").next().unwrap_or(target);
+                if target != note.id {
+                    linked.insert(target.to_string());
                 }
+                None
+            });
+        }
 
-                state.save_to_disk()?;
+        let mut orphans: Vec<OrphanNote> = candidates
+            .into_iter()
+            .filter(|note| !linked.contains(&note.id))
+            .map(|note| OrphanNote {
+                note_id: note.id.clone(),
+                name: note.name.clone(),
+                note_type: note.note_type,
+                updated: note.updated,
+            })
+            .collect();
+        orphans.sort_by(|a, b| a.updated.cmp(&b.updated).then(a.name.cmp(&b.name)));
+        orphans
+    }
 
-                // Return full Note for API compatibility
-                let note = Note {
-                    id,
-                    name,
-                    folder_id,
-                    note_type,
-                    content: vec![],
-                    is_public: false,
-                    collaborators: Vec::new(),
-                };
-                NoteResponse::CreateNote(Ok(note))
-            }
+    // Save a web page as a markdown note, returning the note's ID. Images it shows are stored as
+    // attachments, except ones that fail to download, which keep linking to the web.
+    fn clip_page(
+        &mut self,
+        url: &url::Url,
+        folder_id: Option<String>,
+    ) -> anyhow::Result<Result<String, String>> {
+        let (mime, body) = match http_get(url) {
+            Ok(page) => page,
+            Err(e) => return Ok(Err(e)),
+        };
+        let mime = mime.unwrap_or_default();
+        let (title, markdown) = if mime.starts_with("text/html") || mime.is_empty() {
+            let html = String::from_utf8_lossy(&body);
+            let (title, content) = readable_html(&html);
+            (title, html_to_markdown(content))
+        } else if mime.starts_with("text/") {
+            (None, String::from_utf8_lossy(&body).into_owned())
+        } else {
+            return Ok(Err(format!("Cannot clip a page of type {}", mime)));
+        };
+        let title = title
+            .filter(|title| !title.is_empty())
+            .unwrap_or_else(|| url.host_str().unwrap_or("Clipped page").to_string());
+        if let Err(e) = self.check_quota(markdown.len() as u64, 0)? {
+            return Ok(Err(e.to_string()));
+        }
 
-            NoteRequest::RenameNote((id, new_name)) => {
-                if let Some(mut metadata) = state.notes.get(&id).cloned() {
-                    metadata.name = new_name;
-                    state.notes.insert(id.clone(), metadata.clone());
-                    state.save_to_disk()?;
-                    // Return full Note for API compatibility
-                    match state.get_full_note(&metadata) {
-                        Ok(note) => NoteResponse::RenameNote(Ok(note)),
-                        Err(_) => {
-                            NoteResponse::RenameNote(Err("Error loading note content".to_string()))
-                        }
-                    }
-                } else {
-                    NoteResponse::RenameNote(Err("Note not found".to_string()))
+        let note_id = self.add_note(title.clone(), folder_id, NoteType::Markdown, &[])?;
+        let mut snapshots = vec![ItemSnapshot::Note(note_id.clone(), None, None)];
+        let mut images = 0;
+        let started = std::time::Instant::now();
+        let body = rewrite_markdown_links(&markdown, |is_image, text, target| {
+            let image_url = url.join(target).ok()?;
+            let time_left = CLIP_IMAGES_TOTAL_SECS.saturating_sub(started.elapsed().as_secs());
+            if !is_image
+                || images >= MAX_CLIPPED_IMAGES
+                || time_left == 0
+                || !(image_url.scheme() == "http" || image_url.scheme() == "https")
+                // The page chooses its image URLs, so they must not reach into the node's network
+                || !is_public_host(&image_url)
+            {
+                return Some(format!(
+                    "{}[{}]({})",
+                    if is_image { "!" } else { "" },
+                    text,
+                    image_url
+                ));
+            }
+            images += 1;
+            let stored = http_get_with(
+                &image_url,
+                HashMap::new(),
+                time_left.min(CLIP_IMAGE_TIMEOUT_SECS),
+            )
+            .ok()
+            .filter(|(mime, _)| mime.as_deref().is_some_and(|m| m.starts_with("image/")))
+            .filter(|(_, content)| content.len() <= MAX_CLIPPED_IMAGE_BYTES)
+            // Images that don't fit the quotas stay links to the page's copy
+            .filter(|(_, content)| matches!(self.check_quota(content.len() as u64, 0), Ok(Ok(()))))
+            .and_then(|(mime, content)| {
+                let name = image_url
+                    .path_segments()
+                    .and_then(|mut segments| segments.next_back())
+                    .filter(|name| !name.is_empty())
+                    .unwrap_or("image")
+                    .to_string();
+                self.add_attachment(&note_id, name, mime, &content).ok()
+            });
+            match stored {
+                Some(attachment_id) => {
+                    snapshots.push(ItemSnapshot::Attachment(attachment_id.clone(), None, None));
+                    Some(format!("![{}]({})", text, attachment_url(&attachment_id)))
                 }
+                None => Some(format!("![{}]({})", text, image_url)),
+            }
+        });
+        let content = format!("> Clipped from <{}>\n\n{}", url, body);
+        self.save_note_content(&note_id, content.as_bytes())?;
+        self.record_history(format!("Clip \"{}\"", title), snapshots);
+        self.save_to_disk()?;
+        Ok(Ok(note_id))
+    }
+
+    // Send a request for every registered feed; receive_feed saves what each one answers.
+    // Returns how many feeds are being fetched.
+    fn start_feed_poll(&mut self) -> anyhow::Result<u32> {
+        let mut fetching = 0;
+        for feed in &mut self.feeds {
+            if !self.folders.contains_key(&feed.folder_id) {
+                feed.last_error = Some("Folder not found".to_string());
+                continue;
             }
+            let url = match url::Url::parse(&feed.url) {
+                Ok(url) => url,
+                Err(e) => {
+                    feed.last_error = Some(format!("Invalid feed URL: {}", e));
+                    continue;
+                }
+            };
+            let headers = HashMap::from([("User-Agent".to_string(), "wifenote".to_string())]);
+            let context = format!("{}:{}", FEED_HTTP_CONTEXT, feed.url);
+            send_http_request(
+                http::Method::GET,
+                &url,
+                headers,
+                Vec::new(),
+                CLIP_TIMEOUT_SECS,
+                &context,
+            )?;
+            fetching += 1;
+        }
+        self.save_to_disk()?;
+        Ok(fetching)
+    }
 
-            NoteRequest::DeleteNote(id) => {
-                if let Some(metadata) = state.notes.remove(&id) {
-                    state.root_items.remove(&id);
-                    // Delete the note content file with correct extension
-                    let ext = State::get_note_extension(&metadata.note_type);
-                    let path = format!("{}/note_{}.{}", &state.drive, &id, ext);
-                    if let Err(e) = vfs::remove_file(&path, None) {
-                        error!("Failed to delete note content file for {}: {}", &id, e);
-                    }
-                    state.save_to_disk()?;
-                    NoteResponse::DeleteNote(Ok(()))
-                } else {
-                    NoteResponse::DeleteNote(Err("Note not found".to_string()))
+    // Save a feed's new items as notes in its folder and update the notes of items whose content
+    // changed. Items whose notes were deleted stay deleted. Returns how many notes were created
+    // or updated.
+    fn receive_feed(
+        &mut self,
+        url: &str,
+        result: Result<Vec<FeedItem>, String>,
+    ) -> anyhow::Result<u32> {
+        // The feed may have been removed while it was being fetched
+        let Some(index) = self.feeds.iter().position(|feed| feed.url == url) else {
+            return Ok(0);
+        };
+        let feed = self.feeds[index].clone();
+        let items = match result {
+            Ok(items) => items,
+            Err(e) => {
+                self.feeds[index].last_error = Some(e);
+                self.save_to_disk()?;
+                return Ok(0);
+            }
+        };
+        let mut changed = 0;
+        let mut quota_error = None;
+        // Feeds list their newest items first; saving oldest first keeps creation order
+        for item in items.into_iter().rev() {
+            let content = item.to_markdown();
+            let hash = sha256_hex(content.as_bytes());
+            // The note saved for the item, if it was saved and not deleted since
+            let saved = match feed.items.get(&item.id) {
+                Some((_, saved_hash)) if *saved_hash == hash => continue,
+                Some((note_id, _)) => Some(self.notes.get(note_id).cloned()),
+                None if self.folders.contains_key(&feed.folder_id) => None,
+                None => continue,
+            };
+            // Items that don't fit are left for a later fetch, once there is room
+            let replaced = match &saved {
+                Some(Some(note)) => Some(self.note_file_bytes(note)),
+                Some(None) => None,
+                None => Some(0),
+            };
+            if let Some(replaced) = replaced {
+                if let Err(e) = self.check_quota(content.len() as u64, replaced)? {
+                    quota_error = Some(e.to_string());
+                    break;
                 }
             }
-
-            NoteRequest::MoveNote((id, new_folder_id)) => {
-                // Validate new folder exists if some
-                if let Some(ref folder_id) = new_folder_id {
-                    if !state.folders.contains_key(folder_id) {
-                        return Ok(NoteResponse::MoveNote(Err(
-                            "Parent folder not found".to_string()
-                        )));
-                    }
+            let note_id = match saved {
+                Some(Some(note)) => {
+                    self.save_note_content(&note.id, content.as_bytes())?;
+                    changed += 1;
+                    note.id
                 }
+                Some(None) => feed.items[&item.id].0.clone(),
+                None => {
+                    changed += 1;
+                    self.add_note(
+                        item.title,
+                        Some(feed.folder_id.clone()),
+                        NoteType::Markdown,
+                        content.as_bytes(),
+                    )?
+                }
+            };
+            self.feeds[index].items.insert(item.id, (note_id, hash));
+        }
+        self.feeds[index].last_fetched = Some(now_secs());
+        self.feeds[index].last_error = quota_error;
+        self.save_to_disk()?;
+        Ok(changed)
+    }
 
-                if let Some(mut metadata) = state.notes.get(&id).cloned() {
-                    // Update root items tracking
-                    if metadata.folder_id.is_none() {
-                        state.root_items.remove(&id);
+    // Two-way sync of markdown notes with a git remote: external edits are pulled in first, then
+    // every note changed locally is committed. When both sides changed a note the local version
+    // is kept and the remote one is saved alongside it. The sync runs as a GitSyncJob so the
+    // remote's round-trips never hold up other messages; this sends its first request.
+    fn start_git_sync(&mut self) -> anyhow::Result<()> {
+        let Some(remote) = self.git_sync.remote.clone() else {
+            return Err(anyhow::anyhow!("No git remote set"));
+        };
+        if self.git_job.is_some() {
+            return Err(anyhow::anyhow!("A git sync is already running"));
+        }
+        let job = GitSyncJob {
+            id: self.message_id,
+            remote,
+            tree: HashMap::new(),
+            blobs: HashMap::new(),
+            awaiting: 0,
+            writes: VecDeque::new(),
+            pulled: 0,
+            pushed: 0,
+            rerun: false,
+        };
+        let endpoint = format!(
+            "git/trees/{}?recursive=1",
+            percent_encode(&job.remote.branch, b"-._~")
+        );
+        git_send(
+            &job.remote,
+            http::Method::GET,
+            &endpoint,
+            None,
+            &git_context(job.id, "tree"),
+        )?;
+        self.git_job = Some(job);
+        Ok(())
+    }
+
+    // Advance a git sync with the remote's answer to one of its requests. Returns whether the
+    // sync is done. The synced file map only ever changes to match what has taken effect: all at
+    // once when external edits are pulled in, then one file per commit that succeeds.
+    fn git_sync_step(
+        &mut self,
+        job: &mut GitSyncJob,
+        step: &str,
+        response: Result<(http::StatusCode, Vec<u8>), String>,
+    ) -> anyhow::Result<bool> {
+        let (status, body) =
+            response.map_err(|e| anyhow::anyhow!("Git remote request failed: {}", e))?;
+        let json: serde_json::Value =
+            serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null);
+        if step == "tree" {
+            job.tree = git_tree_files(status, &json)?;
+            let shas = self.git_blobs_to_pull(&job.tree);
+            for sha in &shas {
+                git_send(
+                    &job.remote,
+                    http::Method::GET,
+                    &format!("git/blobs/{}", sha),
+                    None,
+                    &git_context(job.id, &format!("blob:{}", sha)),
+                )?;
+            }
+            job.awaiting = shas.len();
+        } else if let Some(sha) = step.strip_prefix("blob:") {
+            job.blobs
+                .insert(sha.to_string(), git_blob_content(status, &json)?);
+            job.awaiting = job.awaiting.saturating_sub(1);
+        } else {
+            // Commits go one at a time, so this answers the one at the front of the queue
+            match job.writes.pop_front() {
+                Some(GitWrite::Delete { note_id, .. }) => {
+                    if !status.is_success() && status != http::StatusCode::NOT_FOUND {
+                        return Err(git_api_error(status, &json));
                     }
-                    if new_folder_id.is_none() {
-                        state.root_items.insert(id.clone());
+                    if let Some(note_id) = note_id {
+                        self.git_sync.files.remove(&note_id);
                     }
-
-                    metadata.folder_id = new_folder_id;
-                    state.notes.insert(id.clone(), metadata.clone());
-                    state.save_to_disk()?;
-                    // Return full Note for API compatibility
-                    match state.get_full_note(&metadata) {
-                        Ok(note) => NoteResponse::MoveNote(Ok(note)),
-                        Err(_) => {
-                            NoteResponse::MoveNote(Err("Error loading note content".to_string()))
-                        }
+                }
+                Some(GitWrite::Put {
+                    note_id,
+                    path,
+                    content,
+                    local_sha,
+                    ..
+                }) => {
+                    if !status.is_success() {
+                        return Err(git_api_error(status, &json));
                     }
-                } else {
-                    NoteResponse::MoveNote(Err("Note not found".to_string()))
+                    let remote_sha = json["content"]["sha"]
+                        .as_str()
+                        .map(str::to_string)
+                        .unwrap_or_else(|| git_blob_sha(&content));
+                    self.git_sync.files.insert(
+                        note_id,
+                        SyncedFile {
+                            path,
+                            remote_sha,
+                            local_sha,
+                        },
+                    );
+                    job.pushed += 1;
                 }
+                None => return Ok(true),
             }
+            return git_send_next_write(job);
+        }
+        if job.awaiting > 0 {
+            return Ok(false);
+        }
 
-            NoteRequest::GetNote(id) => {
-                // Allow access if:
-                // 1. Note is public
-                // 2. Current node is owner (checking against process name should be enough)
-                // 3. Current node is a collaborator
-                let Some(metadata) = state.notes.get(&id) else {
-                    break 'resp NoteResponse::GetNote(Err(
-                        "Not found or not authorized".to_string()
-                    ));
-                };
-                if metadata.is_public {
-                    match state.get_full_note(metadata) {
-                        Ok(note) => break 'resp NoteResponse::GetNote(Ok(note)),
-                        Err(_) => {
-                            break 'resp NoteResponse::GetNote(Err(
-                                "Error loading note content".to_string()
-                            ))
-                        }
-                    }
-                }
-                let Some(source) = source else {
-                    break 'resp NoteResponse::GetNote(Err(
-                        "Not found or not authorized".to_string()
-                    ));
-                };
-                if source == &our() || metadata.collaborators.contains(&source.node) {
-                    match state.get_full_note(metadata) {
-                        Ok(note) => NoteResponse::GetNote(Ok(note)),
-                        Err(_) => {
-                            NoteResponse::GetNote(Err("Error loading note content".to_string()))
-                        }
-                    }
-                } else {
-                    NoteResponse::GetNote(Err("Not found or not authorized".to_string()))
+        // Everything to pull has arrived, so bring it in, then commit what changed here
+        let mut files = self.git_sync.files.clone();
+        job.writes = self.git_pull(job, &mut files)?;
+        self.git_sync.files = files;
+        let pushes = self.git_push_plan(&job.tree);
+        job.writes.extend(pushes);
+        git_send_next_write(job)
+    }
+
+    // Blob SHAs of the remote files a sync against this tree pulls in
+    fn git_blobs_to_pull(&self, tree: &HashMap<String, String>) -> HashSet<String> {
+        let files = &self.git_sync.files;
+        let mut shas = HashSet::new();
+        for (note_id, synced) in files {
+            let Some(metadata) = self.notes.get(note_id) else {
+                continue;
+            };
+            if metadata.is_locked {
+                continue;
+            }
+            if let Some(remote_sha) = tree.get(&synced.path) {
+                if *remote_sha != synced.remote_sha {
+                    shas.insert(remote_sha.clone());
                 }
             }
+        }
+        let synced_paths: HashSet<&String> = files.values().map(|f| &f.path).collect();
+        for (path, remote_sha) in tree {
+            if path.ends_with(".md") && !synced_paths.contains(path) {
+                shas.insert(remote_sha.clone());
+            }
+        }
+        shas
+    }
 
-            NoteRequest::UpdateNoteContent((id, content)) => {
-                let Some(metadata) = state.notes.get(&id).cloned() else {
-                    break 'resp NoteResponse::UpdateNoteContent(Err(
-                        "Not found or not authorized".to_string(),
-                    ));
-                };
-                let Some(source) = source else {
-                    break 'resp NoteResponse::UpdateNoteContent(Err(
-                        "Not found or not authorized".to_string(),
-                    ));
-                };
-                if source == &our() || metadata.collaborators.contains(&source.node) {
-                    // Save content to file with appropriate extension
-                    state.save_note_content(&id, &content)?;
-                    // No need to update metadata or save state since content is stored separately
-                    NoteResponse::UpdateNoteContent(Ok(()))
+    // Bring in external edits to notes synced before and files created remotely, returning the
+    // deletions of files whose notes were deleted here
+    fn git_pull(
+        &mut self,
+        job: &mut GitSyncJob,
+        files: &mut HashMap<String, SyncedFile>,
+    ) -> anyhow::Result<VecDeque<GitWrite>> {
+        let tree = &job.tree;
+        let blob = |sha: &String| {
+            job.blobs
+                .get(sha)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("Git blob {} was not fetched", sha))
+        };
+        let mut deletes = VecDeque::new();
+        let mut pulled = 0;
+
+        // Pull external edits to notes synced before
+        for (note_id, synced) in files.clone() {
+            if self.notes.get(&note_id).is_some_and(|n| n.is_locked) {
+                continue;
+            }
+            let Some(metadata) = self.notes.get(&note_id).cloned() else {
+                // Deleted here, so delete the file too unless it was edited remotely since
+                if tree.get(&synced.path) == Some(&synced.remote_sha) {
+                    deletes.push_back(GitWrite::Delete {
+                        note_id: Some(note_id),
+                        message: format!("Delete {}", synced.path),
+                        path: synced.path,
+                        sha: synced.remote_sha,
+                    });
                 } else {
-                    NoteResponse::UpdateNoteContent(Err("Not found or not authorized".to_string()))
+                    files.remove(&note_id);
                 }
+                continue;
+            };
+            let Some(remote_sha) = tree.get(&synced.path) else {
+                // Deleted remotely; forget the file so the note is pushed again as new
+                files.remove(&note_id);
+                continue;
+            };
+            if *remote_sha == synced.remote_sha {
+                continue;
             }
-
-            NoteRequest::GetStructure => {
-                // Convert metadata to full notes for API compatibility
-                let mut notes = Vec::new();
-                for metadata in state.notes.values() {
-                    match state.get_full_note(metadata) {
-                        Ok(note) => notes.push(note),
-                        Err(_) => {
-                            // If we can't load content, create note with empty content
-                            notes.push(Note {
-                                id: metadata.id.clone(),
-                                name: metadata.name.clone(),
-                                folder_id: metadata.folder_id.clone(),
-                                note_type: metadata.note_type.clone(),
-                                content: vec![],
-                                is_public: metadata.is_public,
-                                collaborators: metadata.collaborators.clone(),
-                            });
-                        }
-                    }
+            let content = blob(remote_sha)?;
+            let local = self.load_note_content(&note_id).unwrap_or_default();
+            if git_blob_sha(&local) == synced.local_sha {
+                self.save_note_content(&note_id, &content)?;
+                let local = self.load_note_content(&note_id).unwrap_or_default();
+                files.insert(
+                    note_id,
+                    SyncedFile {
+                        path: synced.path,
+                        remote_sha: remote_sha.clone(),
+                        local_sha: git_blob_sha(&local),
+                    },
+                );
+            } else {
+                let name = format!("{} (remote)", metadata.name);
+                self.add_note(name, metadata.folder_id, NoteType::Markdown, &content)?;
+                if let Some(synced) = files.get_mut(&note_id) {
+                    synced.remote_sha = remote_sha.clone();
                 }
-                NoteResponse::GetStructure(Ok((state.folders.values().cloned().collect(), notes)))
             }
+            pulled += 1;
+        }
 
-            NoteRequest::ExportAll => {
-                // Load all notes with content for export
-                let mut notes = Vec::new();
-                for metadata in state.notes.values() {
-                    match state.get_full_note(metadata) {
-                        Ok(note) => notes.push(note),
-                        Err(_) => {
-                            // If we can't load content, create note with empty content
-                            notes.push(Note {
-                                id: metadata.id.clone(),
-                                name: metadata.name.clone(),
-                                folder_id: metadata.folder_id.clone(),
-                                note_type: metadata.note_type.clone(),
-                                content: vec![],
-                                is_public: metadata.is_public,
-                                collaborators: metadata.collaborators.clone(),
-                            });
-                        }
+        // Pull files created remotely, reusing existing folders where the paths match
+        let paths = self.export_paths();
+        let mut folder_ids: HashMap<String, String> = paths
+            .folders
+            .iter()
+            .map(|(id, path)| (path.trim_end_matches('/').to_string(), id.clone()))
+            .collect();
+        let synced_paths: HashSet<String> = files.values().map(|f| f.path.clone()).collect();
+        let unsynced_notes: HashMap<&String, &String> = paths
+            .notes
+            .iter()
+            .filter(|(id, _)| !files.contains_key(id))
+            .map(|(id, path)| (path, id))
+            .collect();
+        let mut new_paths: Vec<(&String, &String)> = tree
+            .iter()
+            .filter(|(path, _)| path.ends_with(".md") && !synced_paths.contains(*path))
+            .collect();
+        new_paths.sort();
+        for (path, remote_sha) in new_paths {
+            let content = blob(remote_sha)?;
+            let components: Vec<String> = path.split('/').map(str::to_string).collect();
+            let Some((file_name, dirs)) = components.split_last() else {
+                continue;
+            };
+            let stem = file_name.strip_suffix(".md").unwrap_or(file_name);
+            let folder_id = self.ensure_import_folder(dirs, &mut folder_ids, false);
+            let (note_id, local_sha) = match unsynced_notes.get(path) {
+                // A note here already has this path; link the two, keeping both if they differ
+                Some(note_id) => {
+                    let local = self.load_note_content(note_id).unwrap_or_default();
+                    if local == content {
+                        (note_id.to_string(), git_blob_sha(&local))
+                    } else {
+                        let name = format!("{} (remote)", stem);
+                        self.add_note(name, folder_id, NoteType::Markdown, &content)?;
+                        pulled += 1;
+                        // Never matches, so the local version is pushed over the remote one
+                        (note_id.to_string(), String::new())
                     }
                 }
+                None => {
+                    let note_id =
+                        self.add_note(stem.to_string(), folder_id, NoteType::Markdown, &content)?;
+                    let local = self.load_note_content(&note_id).unwrap_or_default();
+                    pulled += 1;
+                    (note_id, git_blob_sha(&local))
+                }
+            };
+            files.insert(
+                note_id,
+                SyncedFile {
+                    path: path.clone(),
+                    remote_sha: remote_sha.clone(),
+                    local_sha,
+                },
+            );
+        }
+        job.pulled = pulled;
+        Ok(deletes)
+    }
 
-                // Create export data structure with full notes for compatibility
-                let export_data = ExportData {
-                    version: 0, // Export as v0 for compatibility
-                    folders: state.folders.values().cloned().collect(),
-                    notes, // Full notes for export
-                    note_metadata: Vec::new(),
-                    collaboration_invites: state.collaboration_invites.clone(),
-                };
-
-                // Serialize to JSON
-                let json_str = serde_json::to_string(&export_data)?;
+    // Commits for every markdown note changed, created, renamed or moved here
+    fn git_push_plan(&self, tree: &HashMap<String, String>) -> Vec<GitWrite> {
+        let mut writes = Vec::new();
+        for (note_id, path) in self.export_paths().notes {
+            let Some(metadata) = self.notes.get(&note_id) else {
+                continue;
+            };
+            if metadata.note_type != NoteType::Markdown || metadata.is_locked {
+                continue;
+            }
+            let local = self.load_note_content(&note_id).unwrap_or_default();
+            let local_sha = git_blob_sha(&local);
+            let previous = self.git_sync.files.get(&note_id);
+            if previous.is_some_and(|f| f.path == path && f.local_sha == local_sha) {
+                continue;
+            }
+            let message = format!("Update {}", metadata.name);
+            if let Some(previous) = previous.filter(|f| f.path != path) {
+                if tree.get(&previous.path) == Some(&previous.remote_sha) {
+                    writes.push(GitWrite::Delete {
+                        note_id: None,
+                        path: previous.path.clone(),
+                        sha: previous.remote_sha.clone(),
+                        message: message.clone(),
+                    });
+                }
+            }
+            let existing_sha = match previous.filter(|f| f.path == path) {
+                Some(previous) => Some(previous.remote_sha.clone()),
+                None => tree.get(&path).cloned(),
+            };
+            writes.push(GitWrite::Put {
+                note_id,
+                path,
+                content: local,
+                existing_sha,
+                local_sha,
+                message,
+            });
+        }
+        writes
+    }
 
-                // Compress with gzip
-                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-                encoder.write_all(json_str.as_bytes())?;
-                let compressed = encoder.finish()?;
+    // Every folder, note and attachment with a hash of everything replicated about it. Note
+    // content is hashed when it is saved, so only notes not saved since startup are read here.
+    fn replica_hashes(&mut self) -> HashMap<String, String> {
+        let unhashed: Vec<String> = self
+            .notes
+            .keys()
+            .filter(|id| !self.note_hashes.contains_key(*id))
+            .cloned()
+            .collect();
+        for id in unhashed {
+            let content = self.load_note_content(&id).unwrap_or_default();
+            self.note_hashes.insert(id, sha256_hex(&content));
+        }
+        let mut hashes = HashMap::new();
+        for folder in self.folders.values() {
+            let json = serde_json::to_vec(folder).unwrap_or_default();
+            hashes.insert(folder.id.clone(), sha256_hex(&json));
+        }
+        for metadata in self.notes.values() {
+            // When the note was created and its content changed differ between nodes, as does
+            // its published copy, which isn't replicated, so only the content counts
+            let metadata = NoteMetadata {
+                updated: 0,
+                created: 0,
+                published: None,
+                ..metadata.clone()
+            };
+            let mut bytes = serde_json::to_vec(&metadata).unwrap_or_default();
+            bytes.extend(self.note_hashes[&metadata.id].as_bytes());
+            hashes.insert(metadata.id, sha256_hex(&bytes));
+        }
+        // Attachment content never changes once stored
+        for attachment in self.attachments.values() {
+            let json = serde_json::to_vec(attachment).unwrap_or_default();
+            hashes.insert(attachment.id.clone(), sha256_hex(&json));
+        }
+        hashes
+    }
 
-                // Return compressed bytes
-                NoteResponse::ExportAll(Ok(compressed))
+    // Items changed here since the last replica sync, with their hash, or none if deleted
+    fn replica_changes(&mut self) -> Vec<(String, Option<String>)> {
+        let hashes = self.replica_hashes();
+        let mut changes: Vec<(String, Option<String>)> = self
+            .replica
+            .synced
+            .keys()
+            .filter(|id| !hashes.contains_key(*id))
+            .map(|id| (id.clone(), None))
+            .collect();
+        for (id, hash) in hashes {
+            if self.replica.synced.get(&id) != Some(&hash) {
+                changes.push((id, Some(hash)));
             }
+        }
+        changes
+    }
 
-            NoteRequest::SetNotePublic((note_id, is_public)) => {
-                if let Some(mut metadata) = state.notes.get(&note_id).cloned() {
-                    metadata.is_public = is_public;
-                    state.notes.insert(note_id.clone(), metadata.clone());
-                    state.save_to_disk()?;
-                    // Return full Note for API compatibility
-                    match state.get_full_note(&metadata) {
-                        Ok(note) => NoteResponse::SetNotePublic(Ok(note)),
-                        Err(_) => NoteResponse::SetNotePublic(Err(
-                            "Error loading note content".to_string()
-                        )),
-                    }
+    // A folder, note or attachment as sent to our replica; locked notes go as stored, encrypted
+    fn replica_item(&self, id: &str) -> Option<ReplicaItem> {
+        if let Some(folder) = self.folders.get(id) {
+            return Some(ReplicaItem::Folder(folder.clone()));
+        }
+        if let Some(metadata) = self.notes.get(id) {
+            return Some(ReplicaItem::Note(ReplicaNote {
+                note: Note {
+                    id: metadata.id.clone(),
+                    name: metadata.name.clone(),
+                    folder_id: metadata.folder_id.clone(),
+                    note_type: metadata.note_type,
+                    content: self.load_note_content(id).unwrap_or_default(),
+                    is_public: metadata.is_public,
+                    collaborators: metadata.collaborators.clone(),
+                    tags: metadata.tags.clone(),
+                },
+                locked: metadata.is_locked,
+                read_only: metadata.read_only,
+                noindex: metadata.noindex,
+                due: metadata.due,
+                aliases: metadata.aliases.clone(),
+                alias_folders: metadata.alias_folders.clone(),
+            }));
+        }
+        let attachment = self.attachments.get(id)?;
+        let content = self.load_attachment_content(id).ok()?;
+        Some(ReplicaItem::Attachment((attachment.clone(), content)))
+    }
+
+    // Store an item from our replica under `id`, or delete the item with that id
+    fn apply_replica_change(&mut self, id: &str, item: Option<ReplicaItem>) -> anyhow::Result<()> {
+        match item {
+            Some(ReplicaItem::Folder(folder)) => {
+                if folder.parent_id.is_none() {
+                    self.root_items.insert(id.to_string());
                 } else {
-                    NoteResponse::SetNotePublic(Err("Note not found".to_string()))
+                    self.root_items.remove(id);
                 }
+                self.folders.insert(id.to_string(), folder);
             }
-
-            NoteRequest::InviteCollaborator((note_id, node_id)) => {
-                if let Some(metadata) = state.notes.get(&note_id) {
-                    // Create invites map for this note if it doesn't exist
-                    let invites = state
-                        .collaboration_invites
-                        .entry(note_id.clone())
-                        .or_insert_with(HashMap::new);
-
-                    // Add new invite
-                    invites.insert(node_id.clone(), our().node);
-
-                    state.save_to_disk()?;
-                    // Return full Note for API compatibility
-                    match state.get_full_note(metadata) {
-                        Ok(note) => NoteResponse::InviteCollaborator(Ok(note)),
-                        Err(_) => NoteResponse::InviteCollaborator(Err(
-                            "Error loading note content".to_string(),
-                        )),
-                    }
+            Some(ReplicaItem::Note(replica)) => {
+                let ReplicaNote {
+                    note,
+                    locked,
+                    read_only,
+                    noindex,
+                    due,
+                    aliases,
+                    alias_folders,
+                } = replica;
+                if note.folder_id.is_none() {
+                    self.root_items.insert(id.to_string());
                 } else {
-                    NoteResponse::InviteCollaborator(Err("Note not found".to_string()))
+                    self.root_items.remove(id);
+                }
+                let updated = self.notes.get(id).map(|m| m.updated).unwrap_or_default();
+                let created = self.notes.get(id).map_or_else(now_secs, |m| m.created);
+                self.notes.insert(
+                    id.to_string(),
+                    NoteMetadata {
+                        id: id.to_string(),
+                        name: note.name,
+                        folder_id: note.folder_id,
+                        note_type: note.note_type,
+                        is_public: note.is_public,
+                        collaborators: note.collaborators,
+                        tags: note.tags,
+                        is_locked: locked,
+                        read_only,
+                        noindex,
+                        compressed: false,
+                        archive: None,
+                        published: None,
+                        updated,
+                        created,
+                        due,
+                        aliases,
+                        alias_folders,
+                    },
+                );
+                self.save_note_content(id, &note.content)?;
+            }
+            Some(ReplicaItem::Attachment((attachment, content))) => {
+                self.save_attachment_content(id, &content)?;
+                self.attachments.insert(id.to_string(), attachment);
+            }
+            None => {
+                self.root_items.remove(id);
+                if self.folders.remove(id).is_some() {
+                    return Ok(());
                 }
+                if let Some(metadata) = self.notes.remove(id) {
+                    self.forget_note(&metadata);
+                    return Ok(());
+                }
+                self.remove_attachment(id);
             }
+        }
+        Ok(())
+    }
 
-            NoteRequest::RemoveCollaborator((note_id, node_id)) => {
-                if let Some(mut metadata) = state.notes.get(&note_id).cloned() {
-                    // Remove from collaborators if present
-                    metadata.collaborators.retain(|id| id != &node_id);
-                    state.notes.insert(note_id.clone(), metadata.clone());
+    // Start a two-way sync with our replica node: both sides' changes since the last sync are
+    // compared, and whatever changed on one side only is copied to the other. A change beats a
+    // deletion. When both sides changed a note ours is kept and the replica's is saved alongside
+    // it. replica_sync_step carries it on as the replica answers.
+    fn start_replica_sync(&mut self) -> anyhow::Result<()> {
+        let Some(node) = self.replica.node.clone() else {
+            return Err(anyhow::anyhow!("No replica set"));
+        };
+        if self.replica_job.is_some() {
+            return Err(anyhow::anyhow!("A replica sync is already running"));
+        }
+        let job = ReplicaSyncJob {
+            id: self.message_id,
+            node,
+            expected: HashMap::new(),
+            agreed: Vec::new(),
+            pulls: Vec::new(),
+            conflicts: Vec::new(),
+            pushes: Vec::new(),
+            pulled: 0,
+            rerun: false,
+        };
+        replica_send(&job, "changes", NoteRequest::ReplicaChanges)?;
+        self.replica_job = Some(job);
+        Ok(())
+    }
 
-                    // Remove any pending invites
-                    if let Some(invites) = state.collaboration_invites.get_mut(&note_id) {
-                        invites.remove(&node_id);
+    // Advance a replica sync with the replica's answer to one of its requests. Returns whether
+    // the sync is done.
+    fn replica_sync_step(
+        &mut self,
+        job: &mut ReplicaSyncJob,
+        step: &str,
+        response: Result<NoteResponse, String>,
+    ) -> anyhow::Result<bool> {
+        let node = job.node.clone();
+        let unexpected = || anyhow::anyhow!("Unexpected response from {}", node);
+        match step {
+            "changes" => {
+                let theirs: HashMap<String, Option<String>> = match response {
+                    Ok(NoteResponse::ReplicaChanges(Ok(changes))) => changes.into_iter().collect(),
+                    Ok(NoteResponse::ReplicaChanges(Err(e))) | Err(e) => {
+                        return Err(anyhow::anyhow!(e))
                     }
-
-                    state.save_to_disk()?;
-                    // Return full Note for API compatibility
-                    match state.get_full_note(&metadata) {
-                        Ok(note) => NoteResponse::RemoveCollaborator(Ok(note)),
-                        Err(_) => NoteResponse::RemoveCollaborator(Err(
-                            "Error loading note content".to_string(),
-                        )),
+                    Ok(_) => return Err(unexpected()),
+                };
+                self.plan_replica_sync(job, theirs)?;
+                let wanted: Vec<String> = job.pulls.iter().chain(&job.conflicts).cloned().collect();
+                if !wanted.is_empty() {
+                    replica_send(job, "fetch", NoteRequest::ReplicaFetch(wanted))?;
+                    return Ok(false);
+                }
+            }
+            "fetch" => {
+                let items = match response {
+                    Ok(NoteResponse::ReplicaFetch(Ok(items))) => items,
+                    Ok(NoteResponse::ReplicaFetch(Err(e))) | Err(e) => {
+                        return Err(anyhow::anyhow!(e))
+                    }
+                    Ok(_) => return Err(unexpected()),
+                };
+                self.apply_replica_pulls(job, items)?;
+            }
+            "apply" => {
+                let skipped = match response {
+                    Ok(NoteResponse::ReplicaApply(Ok(skipped))) => skipped,
+                    Ok(NoteResponse::ReplicaApply(Err(e))) | Err(e) => {
+                        return Err(anyhow::anyhow!(e))
+                    }
+                    Ok(_) => return Err(unexpected()),
+                };
+                info!(
+                    "Replica sync pulled {} and pushed {} items",
+                    job.pulled,
+                    job.pushes.len() - skipped.len()
+                );
+                self.record_replica_sync(std::mem::take(&mut job.agreed), &skipped);
+                return Ok(true);
+            }
+            _ => return Ok(false),
+        }
+        // Ours are read now rather than when the plan was made, so the replica gets the latest
+        let changes: Vec<ReplicaChange> = job
+            .pushes
+            .iter()
+            .map(|(id, replaces)| ReplicaChange {
+                item: self.replica_item(id),
+                id: id.clone(),
+                replaces: replaces.clone(),
+            })
+            .collect();
+        let request = NoteRequest::ReplicaApply((changes, job.agreed.clone()));
+        replica_send(job, "apply", request)?;
+        Ok(false)
+    }
+
+    // Compare the replica's changes with ours, deciding what to pull and push; deletions on
+    // their side only are applied straight away
+    fn plan_replica_sync(
+        &mut self,
+        job: &mut ReplicaSyncJob,
+        theirs: HashMap<String, Option<String>>,
+    ) -> anyhow::Result<()> {
+        let ours: HashMap<String, Option<String>> = self.replica_changes().into_iter().collect();
+        for (id, our_hash) in &ours {
+            let their_hash = match theirs.get(id) {
+                None => self.replica.synced.get(id).cloned(),
+                Some(their_hash) if their_hash == our_hash => {
+                    job.agreed.push((id.clone(), our_hash.clone()));
+                    continue;
+                }
+                Some(None) => None,
+                // Deleted here but changed there: keep their version
+                Some(their_hash @ Some(_)) if our_hash.is_none() => {
+                    job.pulls.push(id.clone());
+                    job.agreed.push((id.clone(), their_hash.clone()));
+                    continue;
+                }
+                Some(their_hash) => {
+                    if self.notes.contains_key(id) {
+                        job.conflicts.push(id.clone());
                     }
+                    their_hash.clone()
+                }
+            };
+            job.pushes.push((id.clone(), their_hash));
+            job.agreed.push((id.clone(), our_hash.clone()));
+        }
+        for (id, their_hash) in &theirs {
+            if !ours.contains_key(id) {
+                if their_hash.is_some() {
+                    job.pulls.push(id.clone());
                 } else {
-                    NoteResponse::RemoveCollaborator(Err("Note not found".to_string()))
+                    self.apply_replica_change(id, None)?;
                 }
+                job.agreed.push((id.clone(), their_hash.clone()));
             }
+        }
+        job.expected = job
+            .pulls
+            .iter()
+            .map(|id| {
+                let hash = match ours.get(id) {
+                    Some(hash) => hash.clone(),
+                    None => self.replica.synced.get(id).cloned(),
+                };
+                (id.clone(), hash)
+            })
+            .collect();
+        Ok(())
+    }
 
-            NoteRequest::AcceptInvite((note_id, inviter_node_id)) => {
-                // Verify invite exists
-                if let Some(invites) = state.collaboration_invites.get_mut(&note_id) {
-                    if invites.get(&our().node) == Some(&inviter_node_id) {
-                        if let Some(mut metadata) = state.notes.get(&note_id).cloned() {
-                            // Add to collaborators
-                            metadata.collaborators.push(our().node);
-                            state.notes.insert(note_id.clone(), metadata.clone());
+    // Store the items fetched from the replica. Items edited here since the plan was made keep
+    // our edit, and are compared again next sync.
+    fn apply_replica_pulls(
+        &mut self,
+        job: &mut ReplicaSyncJob,
+        items: Vec<ReplicaItem>,
+    ) -> anyhow::Result<()> {
+        let mut fetched: HashMap<String, ReplicaItem> = HashMap::new();
+        for item in items {
+            let id = match &item {
+                ReplicaItem::Folder(folder) => folder.id.clone(),
+                ReplicaItem::Note(replica) => replica.note.id.clone(),
+                ReplicaItem::Attachment((attachment, _)) => attachment.id.clone(),
+            };
+            fetched.insert(id, item);
+        }
+        let incoming = job
+            .pulls
+            .iter()
+            .chain(&job.conflicts)
+            .filter_map(|id| Some((id.as_str(), fetched.get(id)?)));
+        if let Err(e) = self.check_replica_quota(incoming)? {
+            return Err(anyhow::anyhow!(e));
+        }
+        let hashes = self.replica_hashes();
+        for id in std::mem::take(&mut job.pulls) {
+            let unchanged = job.expected.get(&id) == Some(&hashes.get(&id).cloned());
+            // Gone there since it was listed, or edited here since: compared again next sync
+            let Some(item) = fetched.remove(&id).filter(|_| unchanged) else {
+                job.agreed.retain(|(agreed_id, _)| *agreed_id != id);
+                continue;
+            };
+            self.apply_replica_change(&id, Some(item))?;
+            job.pulled += 1;
+        }
+        for id in &job.conflicts {
+            if let Some(ReplicaItem::Note(mut replica)) = fetched.remove(id) {
+                let copy_id = self.generate_unique_id();
+                replica.note.id = copy_id.clone();
+                replica.note.name = format!("{} (from {})", replica.note.name, job.node);
+                self.apply_replica_change(&copy_id, Some(ReplicaItem::Note(replica)))?;
+                job.pulled += 1;
+            }
+        }
+        Ok(())
+    }
 
-                            // Remove invite
-                            invites.remove(&our().node);
+    // Remember the hashes both sides now have, except for items the replica changed meanwhile
+    fn record_replica_sync(&mut self, agreed: Vec<(String, Option<String>)>, skipped: &[String]) {
+        for (id, hash) in agreed {
+            if skipped.contains(&id) {
+                continue;
+            }
+            match hash {
+                Some(hash) => self.replica.synced.insert(id, hash),
+                None => self.replica.synced.remove(&id),
+            };
+        }
+        self.replica.last_sync = Some(now_secs());
+        self.replica.last_error = None;
+    }
 
-                            state.save_to_disk()?;
-                            // Return full Note for API compatibility
-                            match state.get_full_note(&metadata) {
-                                Ok(note) => NoteResponse::AcceptInvite(Ok(note)),
-                                Err(_) => NoteResponse::AcceptInvite(Err(
-                                    "Error loading note content".to_string(),
-                                )),
-                            }
-                        } else {
-                            NoteResponse::AcceptInvite(Err("Note not found".to_string()))
-                        }
-                    } else {
-                        NoteResponse::AcceptInvite(Err("Invalid inviter".to_string()))
-                    }
-                } else {
-                    NoteResponse::AcceptInvite(Err("No invite found".to_string()))
+    // A folder we share and everything in it as mirrored to other nodes, parents before their
+    // subfolders, with a hash of each item. Notes with a password stay here.
+    fn mirror_items(&self, folder_id: &str) -> (Vec<Folder>, Vec<Note>, HashMap<String, String>) {
+        let mut folders = Vec::new();
+        let mut pending = VecDeque::from([folder_id.to_string()]);
+        while let Some(id) = pending.pop_front() {
+            let Some(folder) = self.folders.get(&id) else {
+                continue;
+            };
+            let mut children: Vec<&Folder> = self
+                .folders
+                .values()
+                .filter(|f| f.parent_id.as_ref() == Some(&id))
+                .collect();
+            children.sort_by(|a, b| a.id.cmp(&b.id));
+            pending.extend(children.into_iter().map(|f| f.id.clone()));
+            folders.push(folder.clone());
+        }
+        let folder_ids: HashSet<&String> = folders.iter().map(|f| &f.id).collect();
+        let notes: Vec<Note> = self
+            .notes
+            .values()
+            .filter(|n| {
+                !n.is_locked && n.folder_id.as_ref().is_some_and(|f| folder_ids.contains(f))
+            })
+            .filter_map(|n| self.get_full_note(n).ok())
+            .map(|note| Note {
+                collaborators: Vec::new(),
+                ..note
+            })
+            .collect();
+        let mut hashes = HashMap::new();
+        for folder in &folders {
+            let json = serde_json::to_vec(folder).unwrap_or_default();
+            hashes.insert(folder.id.clone(), sha256_hex(&json));
+        }
+        for note in &notes {
+            let json = serde_json::to_vec(note).unwrap_or_default();
+            hashes.insert(note.id.clone(), sha256_hex(&json));
+        }
+        (folders, notes, hashes)
+    }
+
+    // Everything in a folder we share, for a node starting to mirror it
+    fn full_mirror_update(&self, folder_id: &str) -> FolderMirrorUpdate {
+        let (folders, notes, _) = self.mirror_items(folder_id);
+        FolderMirrorUpdate {
+            folder_id: folder_id.to_string(),
+            full: true,
+            folders,
+            notes,
+            removed: Vec::new(),
+        }
+    }
+
+    // What changed in a folder we share since the last push to the nodes mirroring it, if anything
+    fn next_mirror_update(&mut self, folder_id: &str) -> Option<FolderMirrorUpdate> {
+        let (folders, notes, hashes) = self.mirror_items(folder_id);
+        let update = match self.mirror_sent.get(folder_id) {
+            // Nothing pushed since we started, so mirrors may have missed anything
+            None => FolderMirrorUpdate {
+                folder_id: folder_id.to_string(),
+                full: true,
+                folders,
+                notes,
+                removed: Vec::new(),
+            },
+            Some(sent) => {
+                let changed = |id: &String| sent.get(id) != hashes.get(id);
+                let update = FolderMirrorUpdate {
+                    folder_id: folder_id.to_string(),
+                    full: false,
+                    folders: folders.into_iter().filter(|f| changed(&f.id)).collect(),
+                    notes: notes.into_iter().filter(|n| changed(&n.id)).collect(),
+                    removed: sent
+                        .keys()
+                        .filter(|id| !hashes.contains_key(*id))
+                        .cloned()
+                        .collect(),
+                };
+                if update.folders.is_empty() && update.notes.is_empty() && update.removed.is_empty()
+                {
+                    return None;
                 }
+                update
             }
+        };
+        self.mirror_sent.insert(folder_id.to_string(), hashes);
+        Some(update)
+    }
 
-            NoteRequest::RejectInvite((note_id, inviter_node_id)) => {
-                if let Some(invites) = state.collaboration_invites.get_mut(&note_id) {
-                    if invites.get(&our().node) == Some(&inviter_node_id) {
-                        // Remove invite
-                        invites.remove(&our().node);
-                        state.save_to_disk()?;
-                        NoteResponse::RejectInvite(Ok(()))
-                    } else {
-                        NoteResponse::RejectInvite(Err("Invalid inviter".to_string()))
+    // Apply an update from the owner of a folder we mirror. Returns false if we don't mirror it.
+    fn apply_mirror_update(
+        &mut self,
+        owner: &str,
+        update: FolderMirrorUpdate,
+    ) -> anyhow::Result<bool> {
+        let Some(index) = self
+            .mirrors
+            .iter()
+            .position(|m| m.owner == owner && m.folder_id == update.folder_id)
+        else {
+            return Ok(false);
+        };
+        let mut mirror = self.mirrors[index].clone();
+        let mut removed = update.removed;
+        if update.full {
+            let present: HashSet<&String> = update
+                .folders
+                .iter()
+                .map(|f| &f.id)
+                .chain(update.notes.iter().map(|n| &n.id))
+                .collect();
+            removed.extend(
+                mirror
+                    .items
+                    .keys()
+                    .filter(|id| !present.contains(id))
+                    .cloned(),
+            );
+        }
+        for id in removed {
+            // Our folder holding the mirror stays even if the owner deletes theirs
+            if id == mirror.folder_id {
+                continue;
+            }
+            if let Some(local_id) = mirror.items.remove(&id) {
+                self.apply_replica_change(&local_id, None)?;
+            }
+        }
+        let local_id = |mirror: &mut MirroredFolder, state: &Self, id: &str| {
+            mirror
+                .items
+                .entry(id.to_string())
+                .or_insert_with(|| state.generate_unique_id())
+                .clone()
+        };
+        for folder in update.folders {
+            // Our folder holding the mirror keeps the name and place we gave it
+            if folder.id == mirror.folder_id {
+                continue;
+            }
+            let parent_id = folder
+                .parent_id
+                .and_then(|p| mirror.items.get(&p).cloned())
+                .unwrap_or_else(|| mirror.local_folder_id.clone());
+            let id = local_id(&mut mirror, self, &folder.id);
+            self.root_items.remove(&id);
+            self.folders.insert(
+                id.clone(),
+                Folder {
+                    id,
+                    name: folder.name,
+                    parent_id: Some(parent_id),
+                    mirror_of: None,
+                },
+            );
+        }
+        for note in update.notes {
+            let folder_id = note
+                .folder_id
+                .and_then(|f| mirror.items.get(&f).cloned())
+                .unwrap_or_else(|| mirror.local_folder_id.clone());
+            let id = local_id(&mut mirror, self, &note.id);
+            let updated = self.notes.get(&id).map(|m| m.updated).unwrap_or_default();
+            let created = self.notes.get(&id).map_or_else(now_secs, |m| m.created);
+            self.notes.insert(
+                id.clone(),
+                NoteMetadata {
+                    id: id.clone(),
+                    name: note.name,
+                    folder_id: Some(folder_id),
+                    note_type: note.note_type,
+                    is_public: false,
+                    collaborators: Vec::new(),
+                    tags: note.tags,
+                    is_locked: false,
+                    read_only: false,
+                    noindex: false,
+                    compressed: false,
+                    archive: None,
+                    published: None,
+                    updated,
+                    created,
+                    due: None,
+                    aliases: Vec::new(),
+                    alias_folders: Vec::new(),
+                },
+            );
+            self.save_note_content(&id, &note.content)?;
+        }
+        self.mirrors[index] = mirror;
+        Ok(true)
+    }
+
+    // Where a backup pushed to us by another node is kept
+    fn backup_path(&self, node: &str) -> String {
+        format!("{}/backup_{}", &self.drive, sanitize_file_name(node))
+    }
+
+    // Helper to generate a unique ID
+    fn generate_id() -> String {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        format!("{}", time)
+    }
+
+    // Generate an ID not already used by a folder or note, for bulk creation
+    fn generate_unique_id(&self) -> String {
+        loop {
+            let id = Self::generate_id();
+            if !self.folders.contains_key(&id)
+                && !self.notes.contains_key(&id)
+                && !self.attachments.contains_key(&id)
+            {
+                return id;
+            }
+        }
+    }
+
+    // Create a note with the given content, returning its ID
+    fn add_note(
+        &mut self,
+        name: String,
+        folder_id: Option<String>,
+        note_type: NoteType,
+        content: &[u8],
+    ) -> anyhow::Result<String> {
+        let id = self.generate_unique_id();
+        if folder_id.is_none() {
+            self.root_items.insert(id.clone());
+        }
+        self.notes.insert(
+            id.clone(),
+            NoteMetadata {
+                id: id.clone(),
+                name,
+                folder_id,
+                note_type,
+                is_public: false,
+                collaborators: Vec::new(),
+                tags: Vec::new(),
+                is_locked: false,
+                read_only: false,
+                noindex: false,
+                compressed: false,
+                archive: None,
+                published: None,
+                updated: 0,
+                created: now_secs(),
+                due: None,
+                aliases: Vec::new(),
+                alias_folders: Vec::new(),
+            },
+        );
+        self.save_note_content(&id, content)?;
+        Ok(id)
+    }
+
+    // Find or create the folder for an imported directory path, creating parents as needed
+    fn ensure_import_folder(
+        &mut self,
+        dirs: &[String],
+        folder_ids: &mut HashMap<String, String>,
+        strip_notion_ids: bool,
+    ) -> Option<String> {
+        let mut parent_id = None;
+        for depth in 1..=dirs.len() {
+            let key = dirs[..depth].join("/");
+            let id = match folder_ids.get(&key) {
+                Some(id) => id.clone(),
+                None => {
+                    let id = self.generate_unique_id();
+                    if parent_id.is_none() {
+                        self.root_items.insert(id.clone());
                     }
+                    self.folders.insert(
+                        id.clone(),
+                        Folder {
+                            id: id.clone(),
+                            name: if strip_notion_ids {
+                                strip_notion_id(&dirs[depth - 1]).to_string()
+                            } else {
+                                dirs[depth - 1].clone()
+                            },
+                            parent_id: parent_id.clone(),
+                            mirror_of: None,
+                        },
+                    );
+                    folder_ids.insert(key, id.clone());
+                    id
+                }
+            };
+            parent_id = Some(id);
+        }
+        parent_id
+    }
+
+    // Import a zip of markdown/HTML files below `base_dirs`, creating folders from its directories.
+    // Notion exports are recognized by the page ID suffixes Notion appends to every name.
+    // `depth` counts the zips this one is nested inside; deeper nesting is skipped.
+    fn import_zip(
+        &mut self,
+        zip_bytes: &[u8],
+        base_dirs: &[String],
+        folder_ids: &mut HashMap<String, String>,
+        depth: u32,
+    ) -> anyhow::Result<()> {
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(zip_bytes))
+            .map_err(|e| anyhow::anyhow!("Failed to read zip archive: {}", e))?;
+
+        // (entry index, path components, is directory)
+        let mut entries = Vec::new();
+        for i in 0..archive.len() {
+            let entry = archive
+                .by_index(i)
+                .map_err(|e| anyhow::anyhow!("Failed to read zip entry: {}", e))?;
+            // Skip entries that would escape the archive root
+            let Some(path) = entry.enclosed_name() else {
+                continue;
+            };
+            let mut components = base_dirs.to_vec();
+            components.extend(path.components().filter_map(|c| match c {
+                std::path::Component::Normal(c) => Some(c.to_string_lossy().to_string()),
+                _ => None,
+            }));
+            // Skip hidden files and macOS metadata
+            if components
+                .iter()
+                .any(|c| c.starts_with('.') || c == "__MACOSX")
+            {
+                continue;
+            }
+            entries.push((i, components, entry.is_dir()));
+        }
+
+        // Google Takeout puts Keep notes in a "Keep" directory as JSON (and older exports HTML)
+        let is_keep = entries.iter().any(|(_, components, is_dir)| {
+            !is_dir
+                && components.len() >= 2
+                && components[components.len() - 2] == "Keep"
+                && (components[components.len() - 1].ends_with(".json")
+                    || components[components.len() - 1].ends_with(".html"))
+        });
+        if is_keep {
+            return self.import_keep_takeout(&mut archive, &entries, base_dirs, folder_ids);
+        }
+
+        let is_notion = entries.iter().any(|(_, components, _)| {
+            components.iter().any(|c| {
+                let stem = c.rsplit_once('.').map_or(c.as_str(), |(stem, _)| stem);
+                strip_notion_id(stem) != stem
+            })
+        });
+        // Every directory in the archive, whether or not it has its own entry
+        let dir_keys: HashSet<String> = entries
+            .iter()
+            .flat_map(|(_, components, is_dir)| {
+                let depth = if *is_dir {
+                    components.len()
                 } else {
-                    NoteResponse::RejectInvite(Err("No invite found".to_string()))
+                    components.len() - 1
+                };
+                (1..=depth).map(|d| components[..d].join("/"))
+            })
+            .collect();
+
+        for (i, components, is_dir) in entries {
+            if is_dir {
+                self.ensure_import_folder(&components, folder_ids, is_notion);
+                continue;
+            }
+            let Some((file_name, dirs)) = components.split_last() else {
+                continue;
+            };
+            let Some((stem, ext)) = file_name.rsplit_once('.') else {
+                continue;
+            };
+
+            let content = read_zip_entry(&mut archive, i)?;
+            let content = match ext.to_lowercase().as_str() {
+                "md" | "markdown" => content,
+                "html" | "htm" => {
+                    // Notion's HTML export has a table of contents at the root
+                    if is_notion && dirs.len() == base_dirs.len() && stem == "index" {
+                        continue;
+                    }
+                    html_to_markdown(&String::from_utf8_lossy(&content)).into_bytes()
+                }
+                // Notion splits large exports into zips nested inside the outer archive
+                "zip" => {
+                    if depth < MAX_NESTED_ZIP_DEPTH {
+                        self.import_zip(&content, dirs, folder_ids, depth + 1)?;
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+
+            let mut dirs = dirs.to_vec();
+            // A Notion page with subpages has a sibling directory of the same name; keep it inside
+            if is_notion {
+                let mut page_dir = dirs.clone();
+                page_dir.push(stem.to_string());
+                if dir_keys.contains(&page_dir.join("/")) {
+                    dirs = page_dir;
                 }
             }
+            let folder_id = self.ensure_import_folder(&dirs, folder_ids, is_notion);
+            let name = if is_notion {
+                strip_notion_id(stem)
+            } else {
+                stem
+            };
+            let id = self.add_note(name.to_string(), folder_id, NoteType::Markdown, &content)?;
+            self.apply_frontmatter(&id, &content);
+        }
+        Ok(())
+    }
 
-            NoteRequest::GetInvites => {
-                let mut invites = Vec::new();
-                for (note_id, note_invites) in &state.collaboration_invites {
-                    for (invitee_id, inviter_id) in note_invites {
-                        if invitee_id == &our().node {
-                            if let Some(metadata) = state.notes.get(note_id) {
-                                invites.push(Invite {
-                                    note_id: note_id.clone(),
-                                    inviter_node_id: inviter_id.clone(),
-                                    note_name: metadata.name.clone(),
-                                });
-                            }
-                        }
+    // Import Keep notes from a Google Takeout archive into a "Google Keep" folder.
+    // JSON notes are preferred; HTML is only used for notes without a JSON version.
+    fn import_keep_takeout<R: Read + std::io::Seek>(
+        &mut self,
+        archive: &mut zip::ZipArchive<R>,
+        entries: &[(usize, Vec<String>, bool)],
+        base_dirs: &[String],
+        folder_ids: &mut HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        let mut keep_dir = base_dirs.to_vec();
+        keep_dir.push("Google Keep".to_string());
+        let mut archived_dir = keep_dir.clone();
+        archived_dir.push("Archived".to_string());
+
+        let json_stems: HashSet<String> = entries
+            .iter()
+            .filter_map(|(_, components, _)| components.last()?.strip_suffix(".json"))
+            .map(|stem| stem.to_string())
+            .collect();
+        for (i, components, is_dir) in entries {
+            let in_keep_dir = components.len() >= 2 && components[components.len() - 2] == "Keep";
+            if *is_dir || !in_keep_dir {
+                continue;
+            }
+            let file_name = &components[components.len() - 1];
+            let (name, content, tags, is_archived) = if let Some(stem) =
+                file_name.strip_suffix(".json")
+            {
+                let Ok(note) = serde_json::from_slice::<KeepNote>(&read_zip_entry(archive, *i)?)
+                else {
+                    continue;
+                };
+                if note.is_trashed {
+                    continue;
+                }
+                let markdown = note.to_markdown();
+                let name = keep_note_title(&note.title, &markdown, stem);
+                let tags = note.labels.into_iter().map(|l| l.name).collect();
+                (name, markdown, tags, note.is_archived)
+            } else if let Some(stem) = file_name.strip_suffix(".html") {
+                if json_stems.contains(stem) {
+                    continue;
+                }
+                let mut html = String::from_utf8_lossy(&read_zip_entry(archive, *i)?).to_string();
+                let title =
+                    take_html_element(&mut html, "<div class=\"title\">").unwrap_or_default();
+                take_html_element(&mut html, "<div class=\"heading\">");
+                let labels =
+                    take_html_element(&mut html, "<div class=\"chips\">").unwrap_or_default();
+                let tags = labels
+                    .split("<span class=\"label-name\">")
+                    .skip(1)
+                    .filter_map(|label| label.split('<').next())
+                    .map(|label| decode_html_entities(label).trim().to_string())
+                    .filter(|label| !label.is_empty())
+                    .collect();
+                // Keep renders checklist bullets as ballot box characters
+                let markdown = html_to_markdown(&html)
+                    .replace("- \u{2610} ", "- [ ] ")
+                    .replace("- \u{2611} ", "- [x] ");
+                let name = keep_note_title(&decode_html_entities(&title), &markdown, stem);
+                (name, markdown, tags, false)
+            } else {
+                continue;
+            };
+
+            let dirs = if is_archived {
+                &archived_dir
+            } else {
+                &keep_dir
+            };
+            let folder_id = self.ensure_import_folder(dirs, folder_ids, false);
+            let id = self.add_note(name, folder_id, NoteType::Markdown, content.as_bytes())?;
+            if let Some(metadata) = self.notes.get_mut(&id) {
+                metadata.tags = normalize_tags(tags);
+            }
+        }
+        Ok(())
+    }
+
+    // Import a Joplin JEX export: notebooks become folders, notes keep their tags, and
+    // resources referenced from note bodies become attachments
+    fn import_jex(&mut self, jex_bytes: &[u8]) -> anyhow::Result<()> {
+        let mut items = Vec::new();
+        let mut resource_files = HashMap::new();
+        for (path, content) in read_tar_entries(jex_bytes)? {
+            if let Some(file_name) = path.strip_prefix("resources/") {
+                let id = file_name.split('.').next().unwrap_or(file_name);
+                resource_files.insert(id.to_string(), content);
+            } else if path.ends_with(".md") && !path.contains('/') {
+                items.push(JoplinItem::parse(&String::from_utf8_lossy(&content)));
+            }
+        }
+
+        // Notebooks first, then their parents once every notebook has an ID
+        let mut folder_ids = HashMap::new();
+        for item in items
+            .iter()
+            .filter(|item| item.item_type() == JOPLIN_TYPE_FOLDER)
+        {
+            let id = self.generate_unique_id();
+            self.folders.insert(
+                id.clone(),
+                Folder {
+                    id: id.clone(),
+                    name: item.title.clone(),
+                    parent_id: None,
+                    mirror_of: None,
+                },
+            );
+            folder_ids.insert(item.prop("id").to_string(), id);
+        }
+        for item in items
+            .iter()
+            .filter(|item| item.item_type() == JOPLIN_TYPE_FOLDER)
+        {
+            let id = &folder_ids[item.prop("id")];
+            let parent_id = folder_ids.get(item.prop("parent_id")).cloned();
+            if parent_id.is_none() {
+                self.root_items.insert(id.clone());
+            }
+            if let Some(folder) = self.folders.get_mut(id) {
+                folder.parent_id = parent_id;
+            }
+        }
+
+        let tag_names: HashMap<&str, &str> = items
+            .iter()
+            .filter(|item| item.item_type() == JOPLIN_TYPE_TAG)
+            .map(|item| (item.prop("id"), item.title.as_str()))
+            .collect();
+        let mut note_tags: HashMap<&str, Vec<String>> = HashMap::new();
+        for item in items
+            .iter()
+            .filter(|item| item.item_type() == JOPLIN_TYPE_NOTE_TAG)
+        {
+            if let Some(tag) = tag_names.get(item.prop("tag_id")) {
+                note_tags
+                    .entry(item.prop("note_id"))
+                    .or_default()
+                    .push(tag.to_string());
+            }
+        }
+        let resources: Vec<&JoplinItem> = items
+            .iter()
+            .filter(|item| item.item_type() == JOPLIN_TYPE_RESOURCE)
+            .collect();
+
+        for item in items
+            .iter()
+            .filter(|item| item.item_type() == JOPLIN_TYPE_NOTE)
+        {
+            let folder_id = folder_ids.get(item.prop("parent_id")).cloned();
+            let id = self.add_note(item.title.clone(), folder_id, NoteType::Markdown, &[])?;
+            let mut body = item.body.clone();
+            for resource in &resources {
+                let link = format!(":/{}", resource.prop("id"));
+                let Some(content) = resource_files.get(resource.prop("id")) else {
+                    continue;
+                };
+                if !body.contains(&link) {
+                    continue;
+                }
+                let mime = Some(resource.prop("mime").to_string()).filter(|m| !m.is_empty());
+                let attachment_id =
+                    self.add_attachment(&id, resource.title.clone(), mime, content)?;
+                body = body.replace(&link, &attachment_url(&attachment_id));
+            }
+            self.save_note_content(&id, body.as_bytes())?;
+            if let Some(metadata) = self.notes.get_mut(&id) {
+                metadata.tags =
+                    normalize_tags(note_tags.remove(item.prop("id")).unwrap_or_default());
+            }
+        }
+        Ok(())
+    }
+
+    // Keep by-name/ in the drive laid out like the folder tree, with notes named by title, so
+    // other processes and anyone browsing the drive see meaningful names. Files of notes that
+    // moved, were renamed or are in `changed` are rewritten. Locked notes are left out, and so
+    // are notes in cold storage, and gzipped notes are copied as stored, with a ".gz" suffix, so
+    // the tree never takes more room than the notes themselves. Its files count towards the
+    // drive's quota.
+    fn sync_by_name_tree(&mut self, changed: &HashSet<String>) -> anyhow::Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let root = format!("{}/by-name", &self.drive);
+        let paths = self.export_paths();
+        let mut wanted: HashMap<String, String> = paths.folders.into_iter().collect();
+        wanted.extend(paths.notes.into_iter().filter_map(|(id, path)| {
+            let note = &self.notes[&id];
+            if note.is_locked || note.archive.is_some() {
+                None
+            } else if note.compressed {
+                Some((id, format!("{}.gz", path)))
+            } else {
+                Some((id, path))
+            }
+        }));
+
+        // Folder paths end with a slash; remove stale notes first, so their directories empty
+        let mut stale: Vec<&String> = self
+            .by_name
+            .iter()
+            .filter(|(id, path)| wanted.get(*id) != Some(path))
+            .map(|(_, path)| path)
+            .collect();
+        stale.sort_by_key(|path| (path.ends_with('/'), std::cmp::Reverse(path.len())));
+        for path in stale {
+            let full_path = format!("{}/{}", root, path.trim_end_matches('/'));
+            // Directories still holding something, such as a folder's new contents, stay
+            let _ = if path.ends_with('/') {
+                vfs::remove_dir(&full_path, None)
+            } else {
+                self.drive_usage.remove(&full_path)
+            };
+        }
+
+        vfs::open_dir(&root, true, None)?;
+        let mut written: Vec<(&String, &String)> = wanted
+            .iter()
+            .filter(|(id, path)| self.by_name.get(*id) != Some(path) || changed.contains(*id))
+            .collect();
+        // Parents before their children
+        written.sort_by_key(|(_, path)| path.len());
+        for (id, path) in written {
+            let full_path = format!("{}/{}", root, path.trim_end_matches('/'));
+            if path.ends_with('/') {
+                vfs::open_dir(&full_path, true, None)?;
+            } else {
+                let content = self
+                    .notes
+                    .get(id.as_str())
+                    .and_then(|note| self.read_note_file(note).ok())
+                    .unwrap_or_default();
+                self.drive_usage.write(&full_path, &content)?;
+            }
+        }
+        if self.by_name != wanted {
+            self.by_name = wanted;
+            self.save_to_disk()?;
+        }
+        Ok(())
+    }
+
+    // Archive paths mirroring the folder tree, with sanitized and de-duplicated names
+    fn export_paths(&self) -> ExportPaths {
+        let mut paths = ExportPaths::default();
+        let mut visited = HashSet::new();
+        // Folders whose parent no longer exists are treated as root items
+        let mut pending: Vec<(Option<String>, String)> = vec![(None, String::new())];
+        while let Some((parent_id, prefix)) = pending.pop() {
+            let is_child = |item_parent: &Option<String>| match &parent_id {
+                Some(parent_id) => item_parent.as_ref() == Some(parent_id),
+                None => item_parent
+                    .as_ref()
+                    .is_none_or(|p| !self.folders.contains_key(p)),
+            };
+
+            let mut folders: Vec<&Folder> = self
+                .folders
+                .values()
+                .filter(|f| is_child(&f.parent_id))
+                .collect();
+            folders.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+            let mut notes: Vec<&NoteMetadata> = self
+                .notes
+                .values()
+                .filter(|n| is_child(&n.folder_id))
+                .collect();
+            notes.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+
+            let mut used_names = HashSet::new();
+            for folder in folders {
+                // Guard against parent cycles
+                if !visited.insert(folder.id.clone()) {
+                    continue;
+                }
+                let name = unique_file_name(&sanitize_file_name(&folder.name), "", &mut used_names);
+                let path = format!("{}{}/", prefix, name);
+                paths.folders.push((folder.id.clone(), path.clone()));
+                pending.push((Some(folder.id.clone()), path));
+            }
+            for note in notes {
+                let ext = Self::get_note_extension(&note.note_type);
+                let name = unique_file_name(&sanitize_file_name(&note.name), ext, &mut used_names);
+                paths
+                    .notes
+                    .push((note.id.clone(), format!("{}{}", prefix, name)));
+            }
+        }
+        paths
+    }
+
+    // The name a note should get in a folder under the duplicate names setting, or why it
+    // can't have it. `note_id` is the note being renamed or moved, which doesn't clash with
+    // itself.
+    fn note_name_in_folder(
+        &self,
+        name: &str,
+        folder_id: Option<&str>,
+        note_id: Option<&str>,
+    ) -> Result<String, String> {
+        let taken = |candidate: &str| {
+            self.notes.values().any(|n| {
+                n.folder_id.as_deref() == folder_id
+                    && n.name == candidate
+                    && Some(n.id.as_str()) != note_id
+            })
+        };
+        if !taken(name) {
+            return Ok(name.to_string());
+        }
+        match self.settings.duplicate_note_names {
+            NameClash::Allow => Ok(name.to_string()),
+            NameClash::Reject => Err(format!(
+                "A note named \"{}\" is already in this folder",
+                name
+            )),
+            NameClash::Suffix => Ok((2..)
+                .map(|n| format!("{} ({})", name, n))
+                .find(|candidate| !taken(candidate))
+                .unwrap_or_default()),
+        }
+    }
+
+    // The folder or note at a path of names separated by '/', walking down from the root. A
+    // name two siblings share can't be resolved.
+    fn resolve_path(&self, path: &str) -> Result<PathItem, String> {
+        let names: Vec<&str> = path
+            .split('/')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        let Some((last, parents)) = names.split_last() else {
+            return Err("Empty path".to_string());
+        };
+        let mut folder_id: Option<&str> = None;
+        for (i, name) in names.iter().enumerate() {
+            let folders: Vec<&Folder> = self
+                .folders
+                .values()
+                .filter(|f| f.parent_id.as_deref() == folder_id && f.name == *name)
+                .collect();
+            let notes: Vec<&NoteMetadata> = if i == parents.len() {
+                self.notes
+                    .values()
+                    .filter(|n| n.folder_id.as_deref() == folder_id && n.name == *last)
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            let prefix = names[..=i].join("/");
+            match (folders.as_slice(), notes.as_slice()) {
+                ([], []) => return Err(format!("Nothing at {}", prefix)),
+                ([folder], []) => folder_id = Some(&folder.id),
+                ([], [note]) => return Ok(PathItem::Note(note.id.clone())),
+                _ => return Err(format!("More than one item at {}", prefix)),
+            }
+        }
+        Ok(PathItem::Folder(folder_id.unwrap_or_default().to_string()))
+    }
+
+    // A note's folders from the root down, then the note; None if there is no such note
+    fn note_path(&self, note_id: &str) -> Option<Vec<Breadcrumb>> {
+        let note = self.notes.get(note_id)?;
+        let mut path = vec![Breadcrumb {
+            id: note.id.clone(),
+            name: note.name.clone(),
+        }];
+        let mut current = note.folder_id.as_ref();
+        while let Some(folder) = current.and_then(|id| self.folders.get(id)) {
+            // Guard against parent cycles
+            if path.iter().any(|crumb| crumb.id == folder.id) {
+                break;
+            }
+            path.push(Breadcrumb {
+                id: folder.id.clone(),
+                name: folder.name.clone(),
+            });
+            current = folder.parent_id.as_ref();
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    // IDs of a folder and every folder nested beneath it
+    fn folder_subtree(&self, folder_id: &str) -> HashSet<String> {
+        let mut subtree = HashSet::from([folder_id.to_string()]);
+        let mut pending = vec![folder_id.to_string()];
+        while let Some(parent_id) = pending.pop() {
+            for folder in self.folders.values() {
+                if folder.parent_id.as_ref() == Some(&parent_id)
+                    && subtree.insert(folder.id.clone())
+                {
+                    pending.push(folder.id.clone());
+                }
+            }
+        }
+        subtree
+    }
+
+    // Gzipped JSON export of the whole notebook, or of a single folder subtree
+    fn export_archive(
+        &self,
+        folder_id: Option<&str>,
+        on_progress: &mut dyn FnMut(u32, u32),
+    ) -> anyhow::Result<Vec<u8>> {
+        self.write_export_archive(folder_id, None, Vec::new(), on_progress)
+    }
+
+    // Write the whole-notebook export of `source` to a file in this drive, for
+    // `read_export_chunk` to hand out in pieces. Returns its size.
+    fn spool_export(
+        &self,
+        source: &State,
+        on_progress: &mut dyn FnMut(u32, u32),
+    ) -> anyhow::Result<u64> {
+        let path = format!("{}/{}", self.drive, EXPORT_SPOOL_FILE);
+        let replaced = self.drive_usage.file_bytes(&path);
+        let file = vfs::create_file(&path, None)?;
+        self.drive_usage.count(replaced, 0);
+        let appender = VfsAppender {
+            file,
+            buffer: Vec::new(),
+        };
+        let mut appender = source.write_export_archive(None, None, appender, on_progress)?;
+        appender.flush()?;
+        let size = vfs::metadata(&path, None)?.len;
+        self.drive_usage.count(0, size);
+        Ok(size)
+    }
+
+    // Up to `length` bytes of the spooled export from `offset`, capped at a chunk
+    fn read_export_chunk(
+        &self,
+        offset: u64,
+        length: u64,
+    ) -> anyhow::Result<Result<Vec<u8>, String>> {
+        let path = format!("{}/{}", self.drive, EXPORT_SPOOL_FILE);
+        let Ok(mut file) = vfs::open_file(&path, false, None) else {
+            return Ok(Err("No export has been started".to_string()));
+        };
+        let size = file.metadata()?.len;
+        if offset > size {
+            return Ok(Err(format!(
+                "Offset {} is past the end of the export",
+                offset
+            )));
+        }
+        let length = length.min(EXPORT_CHUNK_BYTES as u64).min(size - offset);
+        file.seek(vfs::SeekFrom::Start(offset))?;
+        let mut chunk = vec![0; length as usize];
+        let read = file.read_at(&mut chunk)?;
+        chunk.truncate(read);
+        Ok(Ok(chunk))
+    }
+
+    // Stream the export into `writer` one note at a time, so that only the compressed
+    // output is held in full rather than every note's content plus the JSON built from it.
+    // The layout matches a serialized v0 `ExportData`.
+    fn write_export_archive<W: Write>(
+        &self,
+        folder_id: Option<&str>,
+        filter: Option<&ExportFilter>,
+        writer: W,
+        on_progress: &mut dyn FnMut(u32, u32),
+    ) -> anyhow::Result<W> {
+        let subtree = folder_id.map(|id| self.folder_subtree(id));
+        let in_subtree = |folder_id: &Option<String>| match (&subtree, folder_id) {
+            (None, _) => true,
+            (Some(subtree), Some(folder_id)) => subtree.contains(folder_id),
+            (Some(_), None) => false,
+        };
+
+        let mut folders: Vec<Folder> = self
+            .folders
+            .values()
+            .filter(|f| in_subtree(&Some(f.id.clone())))
+            .cloned()
+            .collect();
+        // The exported folder becomes a root item wherever it is imported
+        for folder in folders.iter_mut() {
+            if Some(folder.id.as_str()) == folder_id {
+                folder.parent_id = None;
+            }
+        }
+        let exported: Vec<&NoteMetadata> = self
+            .notes
+            .values()
+            .filter(|n| in_subtree(&n.folder_id))
+            .filter(|n| filter.is_none_or(|filter| export_filter_keeps(filter, n)))
+            .collect();
+        if filter.is_some() {
+            // Only the folders leading to a kept note
+            let mut kept = HashSet::new();
+            for note in &exported {
+                let mut current = note.folder_id.clone();
+                while let Some(id) = current {
+                    if !kept.insert(id.clone()) {
+                        break;
                     }
+                    current = self.folders.get(&id).and_then(|f| f.parent_id.clone());
                 }
-                NoteResponse::GetInvites(Ok(invites))
             }
+            folders.retain(|f| kept.contains(&f.id));
+        }
+        let note_ids: HashSet<&str> = exported.iter().map(|n| n.id.as_str()).collect();
+        let attachments: Vec<&Attachment> = self
+            .attachments
+            .values()
+            .filter(|a| note_ids.contains(a.note_id.as_str()))
+            .filter(|_| !filter.is_some_and(|filter| filter.exclude_attachments))
+            .collect();
+        let collaboration_invites: HashMap<&String, &HashMap<String, String>> = self
+            .collaboration_invites
+            .iter()
+            .filter(|(note_id, _)| note_ids.contains(note_id.as_str()))
+            .collect();
+
+        let mut encoder = GzEncoder::new(writer, Compression::default());
+        // Export as v0, with full notes, for compatibility
+        write!(encoder, "{{\"version\":0,\"folders\":")?;
+        serde_json::to_writer(&mut encoder, &folders)?;
+        write!(encoder, ",\"notes\":[")?;
+        for (i, metadata) in exported.iter().enumerate() {
+            on_progress(i as u32, exported.len() as u32);
+            // If we can't load content, export the note with empty content
+            let mut note = self.get_full_note(metadata).unwrap_or_else(|_| Note {
+                id: metadata.id.clone(),
+                name: metadata.name.clone(),
+                folder_id: metadata.folder_id.clone(),
+                note_type: metadata.note_type,
+                content: vec![],
+                is_public: metadata.is_public,
+                collaborators: metadata.collaborators.clone(),
+                tags: metadata.tags.clone(),
+            });
+            // Locked notes are exported still encrypted, and stay locked when imported
+            if metadata.is_locked {
+                note.content = self.load_note_content(&metadata.id).unwrap_or_default();
+            }
+            if i > 0 {
+                write!(encoder, ",")?;
+            }
+            serde_json::to_writer(&mut encoder, &note)?;
+        }
+        on_progress(exported.len() as u32, exported.len() as u32);
+        write!(encoder, "],\"note_metadata\":[],\"collaboration_invites\":")?;
+        serde_json::to_writer(&mut encoder, &collaboration_invites)?;
+        // Aliases only into folders the export holds
+        let folder_ids: HashSet<&str> = folders.iter().map(|f| f.id.as_str()).collect();
+        let note_aliases: HashMap<&str, Vec<&String>> = exported
+            .iter()
+            .map(|n| {
+                let aliases = n
+                    .alias_folders
+                    .iter()
+                    .filter(|f| folder_ids.contains(f.as_str()))
+                    .collect();
+                (n.id.as_str(), aliases)
+            })
+            .filter(|(_, aliases): &(_, Vec<_>)| !aliases.is_empty())
+            .collect();
+        write!(encoder, ",\"note_aliases\":")?;
+        serde_json::to_writer(&mut encoder, &note_aliases)?;
+        let locked_notes: Vec<&str> = exported
+            .iter()
+            .filter(|n| n.is_locked)
+            .map(|n| n.id.as_str())
+            .collect();
+        write!(encoder, ",\"locked_notes\":")?;
+        serde_json::to_writer(&mut encoder, &locked_notes)?;
+        write!(encoder, ",\"attachments\":")?;
+        serde_json::to_writer(&mut encoder, &attachments)?;
+        write!(encoder, ",\"attachment_contents\":{{")?;
+        let mut first = true;
+        for attachment in &attachments {
+            let Ok(content) = self.load_attachment_content(&attachment.id) else {
+                continue;
+            };
+            if !first {
+                write!(encoder, ",")?;
+            }
+            first = false;
+            serde_json::to_writer(&mut encoder, &attachment.id)?;
+            write!(encoder, ":")?;
+            serde_json::to_writer(&mut encoder, &content)?;
+        }
+        write!(encoder, "}}}}")?;
+        Ok(encoder.finish()?)
+    }
+
+    // A Markdown note typeset as a PDF, the same whichever client asks for it
+    fn export_note_pdf(&self, note_id: &str) -> anyhow::Result<Result<Vec<u8>, String>> {
+        let Some(metadata) = self.notes.get(note_id) else {
+            return Ok(Err("Note not found".to_string()));
+        };
+        if metadata.note_type != NoteType::Markdown || metadata.is_locked {
+            return Ok(Err(
+                "Can only export Markdown notes without a password as PDF".to_string(),
+            ));
+        }
+        let content = self.load_note_content(note_id).unwrap_or_default();
+        Ok(Ok(markdown_to_pdf(
+            &metadata.name,
+            &String::from_utf8_lossy(&content),
+        )?))
+    }
+
+    // A Markdown note as a single HTML file, its attached images inlined as data URIs
+    fn export_note_html(&self, note_id: &str) -> anyhow::Result<Result<String, String>> {
+        use base64::Engine;
+        let Some(metadata) = self.notes.get(note_id) else {
+            return Ok(Err("Note not found".to_string()));
+        };
+        if metadata.note_type != NoteType::Markdown || metadata.is_locked {
+            return Ok(Err(
+                "Can only export Markdown notes without a password as HTML".to_string(),
+            ));
+        }
+        let content = self.load_note_content(note_id).unwrap_or_default();
+        let embed = |url: &str| {
+            let attachment = self
+                .attachments
+                .values()
+                .find(|attachment| attachment_url(&attachment.id) == url)?;
+            let bytes = self.load_attachment_content(&attachment.id).ok()?;
+            Some(format!(
+                "data:{};base64,{}",
+                attachment
+                    .mime
+                    .as_deref()
+                    .unwrap_or("application/octet-stream"),
+                base64::engine::general_purpose::STANDARD.encode(bytes)
+            ))
+        };
+        Ok(Ok(markdown_to_html(
+            &metadata.name,
+            &String::from_utf8_lossy(&content),
+            embed,
+        )?))
+    }
+
+    // A zip laid out as an Obsidian vault: notes carry YAML frontmatter, links between notes
+    // become wiki-links, and attachments are written alongside under `attachments/`
+    fn export_obsidian(&self) -> anyhow::Result<Vec<u8>> {
+        let paths = self.export_paths();
+        // Wiki-links use the vault path without the extension
+        let link_targets: HashMap<&str, &str> = paths
+            .notes
+            .iter()
+            .map(|(id, path)| (id.as_str(), path.strip_suffix(".md").unwrap_or(path)))
+            .collect();
+        let mut attachment_paths = HashMap::new(); // attachment URL -> (attachment ID, vault path)
+        let mut used_names = HashSet::new();
+        let mut attachments: Vec<&Attachment> = self.attachments.values().collect();
+        attachments.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+        for attachment in attachments {
+            let name = sanitize_file_name(&attachment.name);
+            let (base, ext) = match name.rsplit_once('.') {
+                Some((base, ext)) if !base.is_empty() => (base, ext),
+                _ => (name.as_str(), ""),
+            };
+            let file_name = unique_file_name(base, ext, &mut used_names);
+            attachment_paths.insert(
+                attachment_url(&attachment.id),
+                (&attachment.id, format!("attachments/{}", file_name)),
+            );
+        }
+
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default();
+        for (_, path) in &paths.folders {
+            writer.add_directory(path.as_str(), options)?;
+        }
+        for (note_id, path) in &paths.notes {
+            let Some(metadata) = self.notes.get(note_id) else {
+                continue;
+            };
+            let content = self.load_note_content(note_id).unwrap_or_default();
+            writer.start_file(path.as_str(), options)?;
+            if metadata.note_type != NoteType::Markdown {
+                writer.write_all(&content)?;
+                continue;
+            }
+            let body = rewrite_markdown_links(
+                &String::from_utf8_lossy(&content),
+                |is_image, text, target| {
+                    if let Some((_, path)) = attachment_paths.get(target) {
+                        let embed = if is_image { "!" } else { "" };
+                        return Some(format!("{}[[{}]]", embed, path));
+                    }
+                    let note_id = target.rsplit("/public/").next().unwrap_or(target);
+                    let link_target = link_targets.get(note_id)?;
+                    if text.is_empty() || text == self.notes.get(note_id)?.name {
+                        Some(format!("[[{}]]", link_target))
+                    } else {
+                        Some(format!("[[{}|{}]]", link_target, text))
+                    }
+                },
+            );
+            let body = write_frontmatter(&body, &obsidian_frontmatter(metadata));
+            writer.write_all(body.as_bytes())?;
+        }
+        for (attachment_id, path) in attachment_paths.values() {
+            let content = self
+                .load_attachment_content(attachment_id)
+                .unwrap_or_default();
+            writer.start_file(path.as_str(), options)?;
+            writer.write_all(&content)?;
+        }
+        Ok(writer.finish()?.into_inner())
+    }
+
+    // Snapshots marking every folder and note missing from `previous` as newly created
+    fn snapshot_created_items(&self, previous: &State) -> Vec<ItemSnapshot> {
+        let mut snapshots = Vec::new();
+        for id in self.folders.keys() {
+            if !previous.folders.contains_key(id) {
+                snapshots.push(ItemSnapshot::Folder(id.clone(), None));
+            }
+        }
+        for id in self.notes.keys() {
+            if !previous.notes.contains_key(id) {
+                snapshots.push(ItemSnapshot::Note(id.clone(), None, None));
+            }
+        }
+        for id in self.attachments.keys() {
+            if !previous.attachments.contains_key(id) {
+                snapshots.push(ItemSnapshot::Attachment(id.clone(), None, None));
+            }
+        }
+        snapshots
+    }
+
+    fn snapshot_folder(&self, id: &str) -> ItemSnapshot {
+        ItemSnapshot::Folder(id.to_string(), self.folders.get(id).cloned())
+    }
+
+    fn snapshot_note(&self, id: &str, with_content: bool) -> ItemSnapshot {
+        let metadata = self.notes.get(id).cloned();
+        let content = if with_content && metadata.is_some() {
+            self.load_note_content(id).ok()
+        } else {
+            None
+        };
+        ItemSnapshot::Note(id.to_string(), metadata, content)
+    }
+
+    fn snapshot_attachment(&self, id: &str, with_content: bool) -> ItemSnapshot {
+        let attachment = self.attachments.get(id).cloned();
+        let content = if with_content && attachment.is_some() {
+            self.load_attachment_content(id).ok()
+        } else {
+            None
+        };
+        ItemSnapshot::Attachment(id.to_string(), attachment, content)
+    }
+
+    // Drop undo and redo history touching a note. Used when its password is set or removed, since
+    // snapshots from before hold content in the other form, and undoing to plaintext would get
+    // around the password. Also when the note is transferred to another node.
+    fn forget_note_history(&mut self, id: &str) {
+        let touches_note = |entry: &HistoryEntry| {
+            entry.snapshots.iter().any(|snapshot| match snapshot {
+                ItemSnapshot::Note(note_id, _, _) => note_id == id,
+                ItemSnapshot::Attachment(_, Some(attachment), _) => attachment.note_id == id,
+                _ => false,
+            })
+        };
+        self.undo_stack.retain(|entry| !touches_note(entry));
+        self.redo_stack.retain(|entry| !touches_note(entry));
+    }
+
+    // Push a mutation onto the undo stack; a new mutation invalidates any redo history
+    fn record_history(&mut self, description: String, snapshots: Vec<ItemSnapshot>) {
+        self.undo_stack.push(HistoryEntry {
+            description,
+            snapshots,
+        });
+        if self.undo_stack.len() > MAX_HISTORY_ENTRIES {
+            self.undo_stack.remove(0);
+        }
+        self.redo_stack.clear();
+    }
+
+    // Restore items to their snapshotted state, returning snapshots of what they replaced
+    fn restore_snapshots(
+        &mut self,
+        snapshots: Vec<ItemSnapshot>,
+    ) -> anyhow::Result<Vec<ItemSnapshot>> {
+        let mut replaced = Vec::new();
+        // Apply in reverse so the earliest snapshot of an item wins
+        for snapshot in snapshots.into_iter().rev() {
+            match snapshot {
+                ItemSnapshot::Folder(id, folder) => {
+                    replaced.push(self.snapshot_folder(&id));
+                    match folder {
+                        Some(folder) => {
+                            if folder.parent_id.is_none() {
+                                self.root_items.insert(id.clone());
+                            } else {
+                                self.root_items.remove(&id);
+                            }
+                            self.folders.insert(id, folder);
+                        }
+                        None => {
+                            self.folders.remove(&id);
+                            self.root_items.remove(&id);
+                        }
+                    }
+                }
+                ItemSnapshot::Note(id, metadata, content) => {
+                    let content_changes = metadata.is_none() || content.is_some();
+                    replaced.push(self.snapshot_note(&id, content_changes));
+                    match metadata {
+                        Some(mut metadata) => {
+                            if metadata.folder_id.is_none() {
+                                self.root_items.insert(id.clone());
+                            } else {
+                                self.root_items.remove(&id);
+                            }
+                            // Unless its content comes back too, the file stays as it is stored
+                            if let Some(current) = self.notes.get(&id).filter(|_| content.is_none())
+                            {
+                                metadata.compressed = current.compressed;
+                                metadata.archive = current.archive.clone();
+                            }
+                            self.notes.insert(id.clone(), metadata);
+                            if let Some(content) = content {
+                                self.save_note_content(&id, &content)?;
+                            }
+                        }
+                        None => {
+                            if let Some(metadata) = self.notes.remove(&id) {
+                                self.forget_note(&metadata);
+                            }
+                        }
+                    }
+                }
+                ItemSnapshot::Attachment(id, attachment, content) => {
+                    let content_changes = attachment.is_none() || content.is_some();
+                    replaced.push(self.snapshot_attachment(&id, content_changes));
+                    match attachment {
+                        Some(attachment) => {
+                            if let Some(content) = content {
+                                self.save_attachment_content(&id, &content)?;
+                            }
+                            self.attachments.insert(id, attachment);
+                        }
+                        None => self.remove_attachment(&id),
+                    }
+                }
+            }
+        }
+        Ok(replaced)
+    }
+
+    // Save within SAVE_DELAY_SECS rather than now, for changes cheap to lose in a crash. Any
+    // save_to_disk before then writes them too.
+    fn save_later(&mut self) -> anyhow::Result<()> {
+        if !self.save_pending {
+            self.save_pending = true;
+            timer::set_timer(
+                SAVE_DELAY_SECS * 1000,
+                Some(SAVE_TIMER_CONTEXT.as_bytes().to_vec()),
+            );
+        }
+        Ok(())
+    }
+
+    fn save_to_disk(&self) -> anyhow::Result<()> {
+        if self.defer_saves {
+            return Ok(());
+        }
+        let data = serde_json::to_vec(&ExportData {
+            version: CURRENT_STATE_VERSION,
+            folders: self.folders.values().cloned().collect(),
+            notes: Vec::new(), // No longer store full notes in v1+
+            note_metadata: self.notes.values().cloned().collect(),
+            collaboration_invites: self.collaboration_invites.clone(),
+            attachments: self.attachments.values().cloned().collect(),
+            attachment_contents: HashMap::new(),
+            note_aliases: HashMap::new(),
+            locked_notes: HashSet::new(),
+            backup: self.backup.clone(),
+            git_sync: self.git_sync.clone(),
+            replica: self.replica.clone(),
+            webdav: self.webdav.clone(),
+            settings: self.settings.clone(),
+            share_keys: self.share_keys.clone(),
+            received_share_keys: self.received_share_keys.clone(),
+            recent_notes: self.recent_notes.clone(),
+            mentions: self.mentions.clone(),
+            comments: self.comments.clone(),
+            feeds: self.feeds.clone(),
+            shared_notes: self.shared_notes.clone(),
+            pending_shared_edits: self.pending_shared_edits.clone(),
+            received_invites: self.received_invites.clone(),
+            invite_expiry: self.invite_expiry.clone(),
+            blocked_nodes: self.blocked_nodes.clone(),
+            collaborator_activity: self.collaborator_activity.clone(),
+            shared_folders: self.shared_folders.clone(),
+            folder_subscribers: self.folder_subscribers.clone(),
+            mirrors: self.mirrors.clone(),
+            share_links: self.share_links.clone(),
+            by_name: self.by_name.clone(),
+            attachment_blobs: self.attachment_blobs.clone(),
+            manual_order: self.manual_order.clone(),
+            publish_history: self.publish_history.clone(),
+            transfer_offers: self.transfer_offers.clone(),
+            outgoing_transfers: self.outgoing_transfers.clone(),
+        })?;
+
+        let path = format!("{}/state.json", &self.drive);
+        self.timed_vfs(|| self.drive_usage.write(&path, &data))
+    }
+
+    fn load_from_disk(drive: String) -> anyhow::Result<Self> {
+        let file = match vfs::open_file(&format!("{}/state.json", &drive), false, None) {
+            Ok(file) => file,
+            Err(_) => return Ok(State::new(drive)), // If file doesn't exist, return new state
+        };
+
+        let data = file.read()?;
+        let export_data: ExportData = serde_json::from_slice(&data)?;
+        // Nothing counts the drive's usage yet, so the files migrated need no counting
+        let usage = DriveUsage::default();
+        let export_data = migrate_export_data(export_data, &drive, &usage, &mut |_, _| {})?;
+        let mut state = State::new(drive);
+
+        // Reconstruct shared state
+        state.collaboration_invites = export_data.collaboration_invites;
+        state.backup = export_data.backup;
+        state.git_sync = export_data.git_sync;
+        state.replica = export_data.replica;
+        state.webdav = export_data.webdav;
+        state.settings = export_data.settings;
+        state.share_keys = export_data.share_keys;
+        state.received_share_keys = export_data.received_share_keys;
+        state.recent_notes = export_data.recent_notes;
+        state.mentions = export_data.mentions;
+        state.comments = export_data.comments;
+        state.feeds = export_data.feeds;
+        state.shared_notes = export_data.shared_notes;
+        state.pending_shared_edits = export_data.pending_shared_edits;
+        state.received_invites = export_data.received_invites;
+        state.invite_expiry = export_data.invite_expiry;
+        state.blocked_nodes = export_data.blocked_nodes;
+        state.collaborator_activity = export_data.collaborator_activity;
+        state.shared_folders = export_data.shared_folders;
+        state.folder_subscribers = export_data.folder_subscribers;
+        state.mirrors = export_data.mirrors;
+        state.share_links = export_data.share_links;
+        state.by_name = export_data.by_name;
+        state.attachment_blobs = export_data.attachment_blobs;
+        state.manual_order = export_data.manual_order;
+        state.publish_history = export_data.publish_history;
+        state.transfer_offers = export_data.transfer_offers;
+        state.outgoing_transfers = export_data.outgoing_transfers;
+        // Older states kept the backup passphrase; swap it for a key derived from it
+        let legacy_target = state.backup.target.take();
+        let drop_passphrase = legacy_target
+            .as_ref()
+            .is_some_and(|target| target.passphrase.is_some());
+        if let Some(target) = legacy_target {
+            state.backup.target = match target.passphrase {
+                Some(passphrase) => Some(
+                    StoredBackupTarget::new(BackupTarget {
+                        node: target.node,
+                        passphrase,
+                        interval_hours: target.interval_hours,
+                    })
+                    .map_err(|e| anyhow::anyhow!(e))?,
+                ),
+                None => Some(target),
+            };
+        }
+        for attachment in export_data.attachments {
+            state.attachments.insert(attachment.id.clone(), attachment);
+        }
+
+        // Reconstruct state from export data
+        for folder in export_data.folders {
+            if folder.parent_id.is_none() {
+                state.root_items.insert(folder.id.clone());
+            }
+            state.folders.insert(folder.id.clone(), folder);
+        }
+
+        // Load note metadata (v1+) or migrate from full notes (v0)
+        if !export_data.note_metadata.is_empty() {
+            // Version 1+: Load from metadata
+            for metadata in export_data.note_metadata {
+                if metadata.folder_id.is_none() {
+                    state.root_items.insert(metadata.id.clone());
+                }
+                state.notes.insert(metadata.id.clone(), metadata);
+            }
+        } else if !export_data.notes.is_empty() {
+            // Version 0: Migrate from full notes (this shouldn't happen after migration)
+            for note in export_data.notes {
+                if note.folder_id.is_none() {
+                    state.root_items.insert(note.id.clone());
+                }
+                let metadata = NoteMetadata::from(note);
+                state.notes.insert(metadata.id.clone(), metadata);
+            }
+        }
+
+        if drop_passphrase {
+            state.save_to_disk()?;
+        }
+        Ok(state)
+    }
+}
+
+// Make a note or folder name safe to use as a file name in an export archive
+fn sanitize_file_name(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // Leading dots would hide the file, trailing dots and spaces break Windows
+    let sanitized = sanitized.trim().trim_matches('.').trim();
+    if sanitized.is_empty() {
+        "Untitled".to_string()
+    } else {
+        sanitized.to_string()
+    }
+}
+
+// Pick a file name not yet used in a directory, appending " (2)", " (3)", ... on collision
+fn unique_file_name(base: &str, ext: &str, used_names: &mut HashSet<String>) -> String {
+    let with_ext = |name: String| {
+        if ext.is_empty() {
+            name
+        } else {
+            format!("{}.{}", name, ext)
+        }
+    };
+    let mut name = with_ext(base.to_string());
+    let mut n = 2;
+    // Compare case-insensitively so archives extract cleanly on case-insensitive filesystems
+    while !used_names.insert(name.to_lowercase()) {
+        name = with_ext(format!("{} ({})", base, n));
+        n += 1;
+    }
+    name
+}
+
+// Frontmatter entries carrying the metadata Obsidian has a place for
+fn obsidian_frontmatter(metadata: &NoteMetadata) -> Vec<(&'static str, Option<String>)> {
+    vec![
+        (
+            "wifenote-id",
+            Some(format!("wifenote-id: {}", yaml_quote(&metadata.id))),
+        ),
+        ("tags", yaml_list("tags", &metadata.tags)),
+        ("aliases", yaml_list("aliases", &metadata.aliases)),
+        (
+            "publish",
+            metadata.is_public.then(|| "publish: true".to_string()),
+        ),
+        (
+            "collaborators",
+            yaml_list("collaborators", &metadata.collaborators),
+        ),
+    ]
+}
+
+// Frontmatter entries kept in sync with a markdown note's metadata
+fn frontmatter_fields(metadata: &NoteMetadata) -> Vec<(&'static str, Option<String>)> {
+    vec![
+        (
+            "title",
+            Some(format!("title: {}", yaml_quote(&metadata.name))),
+        ),
+        ("tags", yaml_list("tags", &metadata.tags)),
+        ("aliases", yaml_list("aliases", &metadata.aliases)),
+    ]
+}
+
+// JSON strings are valid YAML scalars and take care of quoting
+fn yaml_quote(value: &str) -> String {
+    serde_json::Value::String(value.to_string()).to_string()
+}
+
+// A frontmatter entry holding a block list; None when there is nothing in it
+fn yaml_list(key: &str, values: &[String]) -> Option<String> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut entry = format!("{}:", key);
+    for value in values {
+        entry.push_str(&format!("\n  - {}", yaml_quote(value)));
+    }
+    Some(entry)
+}
+
+// Metadata a markdown note's frontmatter sets
+#[derive(Debug, Default)]
+struct Frontmatter {
+    title: Option<String>,
+    tags: Option<Vec<String>>,
+    aliases: Option<Vec<String>>,
+}
+
+type FrontmatterEntry<'a> = (String, Vec<&'a str>);
+
+// The entries of a leading YAML frontmatter block, each as (key, its lines), and the text after
+// the block. None if the text does not start with one.
+fn split_frontmatter(text: &str) -> Option<(Vec<FrontmatterEntry<'_>>, &str)> {
+    let rest = text
+        .strip_prefix("---\n")
+        .or_else(|| text.strip_prefix("---\r\n"))?;
+    let mut entries: Vec<FrontmatterEntry> = Vec::new();
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        offset += line.len();
+        let line = line.trim_end();
+        if line == "---" || line == "..." {
+            return Some((entries, &rest[offset..]));
+        }
+        // Keys start a line; indented lines, list items and comments continue the last entry
+        let key = if line.starts_with([' ', '\t', '-', '#']) {
+            None
+        } else {
+            line.split_once(':').map(|(key, _)| key.trim())
+        };
+        match (key, entries.last_mut()) {
+            (Some(key), _) => entries.push((key.to_string(), vec![line])),
+            (None, Some((_, lines))) => lines.push(line),
+            (None, None) => {}
+        }
+    }
+    None
+}
+
+// Values of a frontmatter entry: a scalar, a [flow, list] or a block list of "- item" lines
+fn frontmatter_values(lines: &[&str]) -> Vec<String> {
+    let unquote = |value: &str| -> String {
+        let value = value.trim();
+        if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+            serde_json::from_str(value).unwrap_or_else(|_| value[1..value.len() - 1].to_string())
+        } else if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+            value[1..value.len() - 1].replace("''", "'")
+        } else {
+            value.to_string()
+        }
+    };
+    let first = lines
+        .first()
+        .and_then(|line| line.split_once(':'))
+        .map_or("", |(_, value)| value.trim());
+    let values: Vec<String> = if let Some(flow) = first
+        .strip_prefix('[')
+        .and_then(|flow| flow.strip_suffix(']'))
+    {
+        flow.split(',').map(unquote).collect()
+    } else if first.is_empty() {
+        lines
+            .iter()
+            .skip(1)
+            .filter_map(|line| line.trim().strip_prefix('-'))
+            .map(unquote)
+            .collect()
+    } else {
+        vec![unquote(first)]
+    };
+    values
+        .into_iter()
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+// Text of a markdown document's first level-one ATX heading, outside frontmatter and code blocks
+fn first_heading(text: &str) -> Option<String> {
+    let body = split_frontmatter(text).map_or(text, |(_, body)| body);
+    let mut fence: Option<&str> = None;
+    for line in body.lines() {
+        let trimmed = line.trim_start();
+        // At most three spaces of indentation, or it is an indented code block
+        if line.len() - trimmed.len() > 3 {
+            continue;
+        }
+        if let Some(open) = fence {
+            if trimmed.starts_with(open) {
+                fence = None;
+            }
+            continue;
+        }
+        if trimmed.starts_with("```") {
+            fence = Some("```");
+        } else if trimmed.starts_with("~~~") {
+            fence = Some("~~~");
+        } else if let Some(heading) = trimmed.strip_prefix("# ") {
+            // A closing sequence of #s after a space is not part of the heading
+            let heading = heading.trim();
+            let heading = match heading.trim_end_matches('#') {
+                open if open.ends_with(' ') => open.trim_end(),
+                _ => heading,
+            };
+            if !heading.is_empty() {
+                return Some(heading.to_string());
+            }
+        }
+    }
+    None
+}
+
+fn parse_frontmatter(text: &str) -> Option<Frontmatter> {
+    let (entries, _) = split_frontmatter(text)?;
+    let mut frontmatter = Frontmatter::default();
+    for (key, lines) in entries {
+        let values = frontmatter_values(&lines);
+        match key.to_lowercase().as_str() {
+            "title" => frontmatter.title = values.into_iter().next(),
+            // Tags may also be written "tags: one, #two three"
+            "tags" | "tag" => {
+                frontmatter.tags = Some(normalize_tags(
+                    values
+                        .iter()
+                        .flat_map(|value| value.split([',', ' ']))
+                        .map(|tag| tag.trim_start_matches('#').to_string())
+                        .collect(),
+                ))
+            }
+            "aliases" | "alias" => frontmatter.aliases = Some(values),
+            _ => {}
+        }
+    }
+    Some(frontmatter)
+}
+
+// Text with the given frontmatter entries replaced, added, or removed where None, keeping any
+// others. Text without frontmatter gets a block only if there is an entry to put in it.
+fn write_frontmatter(text: &str, fields: &[(&str, Option<String>)]) -> String {
+    let (mut entries, body): (Vec<(String, String)>, &str) = match split_frontmatter(text) {
+        Some((entries, body)) => (
+            entries
+                .into_iter()
+                .map(|(key, lines)| (key, lines.join("\n")))
+                .collect(),
+            body,
+        ),
+        None if fields.iter().all(|(_, value)| value.is_none()) => return text.to_string(),
+        None => (Vec::new(), text),
+    };
+    for (key, value) in fields {
+        let position = entries
+            .iter()
+            .position(|(k, _)| k.eq_ignore_ascii_case(key));
+        match (position, value) {
+            (Some(i), Some(value)) => entries[i].1 = value.clone(),
+            (None, Some(value)) => entries.push((key.to_string(), value.clone())),
+            (Some(i), None) => {
+                entries.remove(i);
+            }
+            (None, None) => {}
+        }
+    }
+    let mut written = "---\n".to_string();
+    for (_, entry) in &entries {
+        written.push_str(entry);
+        written.push('\n');
+    }
+    written.push_str("---\n");
+    written.push_str(body);
+    written
+}
+
+// Replace inline markdown links and images `[text](target)` with whatever `rewrite`
+// returns for them, leaving links it returns None for untouched
+fn rewrite_markdown_links(
+    markdown: &str,
+    mut rewrite: impl FnMut(bool, &str, &str) -> Option<String>,
+) -> String {
+    let mut out = String::with_capacity(markdown.len());
+    let mut rest = markdown;
+    while let Some(start) = rest.find('[') {
+        let link = rest[start + 1..]
+            .split_once("](")
+            .and_then(|(text, after)| {
+                let (target, _) = after.split_once(')')?;
+                if text.contains(['[', ']', '\n']) || target.contains([' ', '\n']) {
+                    return None;
+                }
+                Some((text, target))
+            });
+        let Some((text, target)) = link else {
+            out.push_str(&rest[..=start]);
+            rest = &rest[start + 1..];
+            continue;
+        };
+        let is_image = rest[..start].ends_with('!');
+        let link_len = 1 + text.len() + 2 + target.len() + 1;
+        match rewrite(is_image, text, target) {
+            Some(replacement) => {
+                out.push_str(&rest[..start - usize::from(is_image)]);
+                out.push_str(&replacement);
+            }
+            None => out.push_str(&rest[..start + link_len]),
+        }
+        rest = &rest[start + link_len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// Import into state, or with `preview_only` run the import against a scratch copy and
+// report what it would create
+fn import_or_preview(
+    state: &mut State,
+    preview_only: bool,
+    format: &str,
+    description: &str,
+    import: impl FnOnce(&mut State) -> anyhow::Result<()>,
+) -> anyhow::Result<Result<Option<ImportPreview>, String>> {
+    if !preview_only {
+        return Ok(import_transaction(state, description, import)?.map(|()| None));
+    }
+    let mut scratch = state.clone();
+    scratch.dry_run = true;
+    if let Err(e) = import(&mut scratch) {
+        return Ok(Err(e.to_string()));
+    }
+    let (mut folders, mut notes, mut attachments) = (0, 0, 0);
+    for snapshot in scratch.snapshot_created_items(state) {
+        match snapshot {
+            ItemSnapshot::Folder(..) => folders += 1,
+            ItemSnapshot::Note(..) => notes += 1,
+            ItemSnapshot::Attachment(..) => attachments += 1,
+        }
+    }
+    Ok(Ok(Some(ImportPreview {
+        format: format.to_string(),
+        version: None,
+        folders,
+        notes,
+        attachments,
+        conflicts: Vec::new(), // Imported items always get fresh IDs
+    })))
+}
+
+// How an import's IDs land among ours. An item whose ID we already use for something different
+// gets a new ID; one identical to what we have is left out. Importing an old backup so never
+// overwrites newer work.
+#[derive(Debug, Default)]
+struct ImportIds {
+    remapped: HashMap<String, String>, // Imported id -> new id
+    skipped: HashSet<String>,          // Imported ids we already have unchanged
+}
+
+impl ImportIds {
+    fn get(&self, id: &str) -> String {
+        self.remapped
+            .get(id)
+            .cloned()
+            .unwrap_or_else(|| id.to_string())
+    }
+}
+
+fn plan_import_ids(state: &State, data: &ExportData) -> ImportIds {
+    let mut ids = ImportIds::default();
+    let new_id = |ids: &mut ImportIds, id: &str| {
+        let mut fresh = state.generate_unique_id();
+        while ids.remapped.values().any(|taken| *taken == fresh) {
+            fresh = state.generate_unique_id();
+        }
+        ids.remapped.insert(id.to_string(), fresh);
+    };
+
+    // A folder only matches ours under the same parent, so parents are decided first
+    let imported: HashSet<&str> = data.folders.iter().map(|f| f.id.as_str()).collect();
+    let mut pending: Vec<&Folder> = data.folders.iter().collect();
+    while !pending.is_empty() {
+        let count = pending.len();
+        let mut waiting = Vec::new();
+        for folder in pending {
+            let parent_pending = folder.parent_id.as_deref().is_some_and(|parent| {
+                imported.contains(parent)
+                    && !ids.remapped.contains_key(parent)
+                    && !ids.skipped.contains(parent)
+                    && state.folders.contains_key(parent)
+            });
+            // A cycle of folders can't wait on itself forever
+            if parent_pending && count > 1 {
+                waiting.push(folder);
+                continue;
+            }
+            match state.folders.get(&folder.id) {
+                None => {}
+                Some(ours)
+                    if ours.name == folder.name
+                        && ours.parent_id == folder.parent_id.as_deref().map(|p| ids.get(p)) =>
+                {
+                    ids.skipped.insert(folder.id.clone());
+                }
+                Some(_) => new_id(&mut ids, &folder.id),
+            }
+        }
+        if waiting.len() == count {
+            // Only a cycle is left; settle it as if its parents were ours
+            for folder in &waiting {
+                if state.folders.contains_key(&folder.id) {
+                    new_id(&mut ids, &folder.id);
+                }
+            }
+            break;
+        }
+        pending = waiting;
+    }
+
+    for note in &data.notes {
+        let Some(ours) = state.notes.get(&note.id) else {
+            continue;
+        };
+        let same = ours.name == note.name
+            && ours.folder_id == note.folder_id.as_deref().map(|f| ids.get(f))
+            && state.load_note_content(&note.id).ok().as_ref() == Some(&note.content);
+        if same {
+            ids.skipped.insert(note.id.clone());
+        } else {
+            new_id(&mut ids, &note.id);
+        }
+    }
+    // Metadata without content can only describe a content file already here, which is ours
+    for metadata in &data.note_metadata {
+        if state.notes.contains_key(&metadata.id) {
+            ids.skipped.insert(metadata.id.clone());
+        }
+    }
+    for attachment in &data.attachments {
+        let Some(ours) = state.attachments.get(&attachment.id) else {
+            continue;
+        };
+        let same = ours.note_id == ids.get(&attachment.note_id)
+            && state.load_attachment_content(&attachment.id).ok().as_ref()
+                == data.attachment_contents.get(&attachment.id);
+        if same {
+            ids.skipped.insert(attachment.id.clone());
+        } else {
+            new_id(&mut ids, &attachment.id);
+        }
+    }
+    ids
+}
+
+// Rewrite an import to the IDs it will have here, leaving out what we already have
+fn remap_import(state: &State, mut data: ExportData) -> ExportData {
+    let ids = &plan_import_ids(state, &data);
+    let kept = |id: &String| !ids.skipped.contains(id);
+    let parent = |id: Option<String>| id.map(|id| ids.get(&id));
+    data.folders = std::mem::take(&mut data.folders)
+        .into_iter()
+        .filter(|folder| kept(&folder.id))
+        .map(|folder| Folder {
+            id: ids.get(&folder.id),
+            parent_id: parent(folder.parent_id),
+            ..folder
+        })
+        .collect();
+    // Markdown links attachments by ID
+    let attachment_urls: Vec<(String, String)> = data
+        .attachments
+        .iter()
+        .filter_map(|a| {
+            let new = ids.remapped.get(&a.id)?;
+            Some((attachment_url(&a.id), attachment_url(new)))
+        })
+        .collect();
+    data.notes = std::mem::take(&mut data.notes)
+        .into_iter()
+        .filter(|note| kept(&note.id))
+        .map(|mut note| {
+            if note.note_type == NoteType::Markdown && !attachment_urls.is_empty() {
+                if let Ok(mut content) = String::from_utf8(note.content.clone()) {
+                    for (old, new) in &attachment_urls {
+                        content = content.replace(old, new);
+                    }
+                    note.content = content.into_bytes();
+                }
+            }
+            Note {
+                id: ids.get(&note.id),
+                folder_id: parent(note.folder_id),
+                ..note
+            }
+        })
+        .collect();
+    data.note_metadata = std::mem::take(&mut data.note_metadata)
+        .into_iter()
+        .filter(|metadata| kept(&metadata.id))
+        .map(|metadata| NoteMetadata {
+            id: ids.get(&metadata.id),
+            folder_id: parent(metadata.folder_id),
+            alias_folders: metadata
+                .alias_folders
+                .iter()
+                .map(|folder_id| ids.get(folder_id))
+                .collect(),
+            ..metadata
+        })
+        .collect();
+    data.note_aliases = std::mem::take(&mut data.note_aliases)
+        .into_iter()
+        .filter(|(note_id, _)| kept(note_id))
+        .map(|(note_id, folders)| {
+            let folders = folders.iter().map(|folder_id| ids.get(folder_id)).collect();
+            (ids.get(&note_id), folders)
+        })
+        .collect();
+    data.locked_notes = std::mem::take(&mut data.locked_notes)
+        .into_iter()
+        .filter(kept)
+        .map(|note_id| ids.get(&note_id))
+        .collect();
+    data.attachments = std::mem::take(&mut data.attachments)
+        .into_iter()
+        .filter(|attachment| kept(&attachment.id))
+        .map(|attachment| Attachment {
+            id: ids.get(&attachment.id),
+            note_id: ids.get(&attachment.note_id),
+            ..attachment
+        })
+        .collect();
+    data.attachment_contents = std::mem::take(&mut data.attachment_contents)
+        .into_iter()
+        .filter(|(id, _)| kept(id))
+        .map(|(id, content)| (ids.get(&id), content))
+        .collect();
+    data.collaboration_invites = std::mem::take(&mut data.collaboration_invites)
+        .into_iter()
+        .map(|(note_id, invites)| (ids.get(&note_id), invites))
+        .collect();
+    data
+}
+
+fn preview_export_data(state: &State, data: &ExportData) -> anyhow::Result<ImportPreview> {
+    check_export_version(data.version)?;
+    let mut conflicts: Vec<String> = plan_import_ids(state, data).remapped.into_keys().collect();
+    conflicts.sort();
+    Ok(ImportPreview {
+        format: "wifenote".to_string(),
+        version: Some(data.version),
+        folders: data.folders.len() as u32,
+        notes: (data.notes.len() + data.note_metadata.len()) as u32,
+        attachments: data.attachments.len() as u32,
+        conflicts,
+    })
+}
+
+// Run an import as a single undoable operation, leaving state untouched on failure
+fn import_transaction(
+    state: &mut State,
+    description: &str,
+    import: impl FnOnce(&mut State) -> anyhow::Result<()>,
+) -> anyhow::Result<Result<(), String>> {
+    let mut new_state = state.clone();
+    if let Err(e) = import(&mut new_state) {
+        // The state is dropped, but the files of what was created before the failure are not
+        let created = new_state.snapshot_created_items(state);
+        new_state.restore_snapshots(created)?;
+        return Ok(Err(e.to_string()));
+    }
+    let snapshots = new_state.snapshot_created_items(state);
+    new_state.record_history(description.to_string(), snapshots);
+    *state = new_state;
+    state.save_to_disk()?;
+    Ok(Ok(()))
+}
+
+fn read_zip_entry<R: Read + std::io::Seek>(
+    archive: &mut zip::ZipArchive<R>,
+    index: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let mut entry = archive.by_index(index)?;
+    let mut content = Vec::new();
+    entry
+        .read_to_end(&mut content)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", entry.name(), e))?;
+    Ok(content)
+}
+
+// Trim tags and the segments of nested tags, dropping empty and duplicate ones while
+// keeping their order
+fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    tags.into_iter()
+        .map(|tag| {
+            tag.split('/')
+                .map(str::trim)
+                .filter(|segment| !segment.is_empty())
+                .collect::<Vec<_>>()
+                .join("/")
+        })
+        .filter(|tag| !tag.is_empty() && seen.insert(tag.clone()))
+        .collect()
+}
+
+// Whether an export filter keeps a note; tags match as in GetNotesByTagPrefix
+fn export_filter_keeps(filter: &ExportFilter, note: &NoteMetadata) -> bool {
+    if filter.exclude_private && !note.is_public {
+        return false;
+    }
+    if filter
+        .note_type
+        .as_ref()
+        .is_some_and(|note_type| *note_type != note.note_type)
+    {
+        return false;
+    }
+    let prefixes = normalize_tags(filter.tags.clone());
+    prefixes.is_empty()
+        || prefixes.iter().any(|prefix| {
+            let nested_prefix = format!("{}/", prefix);
+            note.tags
+                .iter()
+                .any(|t| t == prefix || t.starts_with(&nested_prefix))
+        })
+}
+
+// A parsed search query. Plain words and quoted phrases match case-insensitively anywhere in
+// a note's name or text, /patterns/ are regexes, and tag:, type: and folder: match metadata.
+// Terms are combined with AND (also implied between adjacent terms), OR, NOT or a leading
+// '-', and parentheses.
+#[derive(Debug)]
+enum SearchExpr {
+    Text(String), // Lowercased
+    Regex(regex::Regex),
+    Tag(String),
+    Type(NoteType),
+    Folder(String), // Folder name, lowercased
+    Not(Box<SearchExpr>),
+    And(Vec<SearchExpr>),
+    Or(Vec<SearchExpr>),
+}
+
+#[derive(Debug)]
+enum SearchToken {
+    Open,
+    Close,
+    Not,
+    And,
+    Or,
+    Term(SearchExpr),
+}
+
+// What a note is searched by
+struct SearchTarget<'a> {
+    metadata: &'a NoteMetadata,
+    folder_name: Option<&'a str>,
+    text: String, // Name and text content, one line apart
+    lowercase_text: String,
+}
+
+impl SearchExpr {
+    fn parse(query: &str) -> Result<Self, String> {
+        let mut tokens = tokenize_search(query)?.into_iter().peekable();
+        if tokens.peek().is_none() {
+            return Err("Search query is empty".to_string());
+        }
+        let expr = Self::parse_or(&mut tokens)?;
+        match tokens.next() {
+            None => Ok(expr),
+            Some(_) => Err("Unmatched ')' in search query".to_string()),
+        }
+    }
+
+    fn parse_or(
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<SearchToken>>,
+    ) -> Result<Self, String> {
+        let mut terms = vec![Self::parse_and(tokens)?];
+        while matches!(tokens.peek(), Some(SearchToken::Or)) {
+            tokens.next();
+            terms.push(Self::parse_and(tokens)?);
+        }
+        Ok(if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            SearchExpr::Or(terms)
+        })
+    }
+
+    fn parse_and(
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<SearchToken>>,
+    ) -> Result<Self, String> {
+        let mut terms = vec![Self::parse_unary(tokens)?];
+        loop {
+            match tokens.peek() {
+                Some(SearchToken::And) => {
+                    tokens.next();
+                }
+                Some(SearchToken::Or) | Some(SearchToken::Close) | None => break,
+                _ => {}
+            }
+            terms.push(Self::parse_unary(tokens)?);
+        }
+        Ok(if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            SearchExpr::And(terms)
+        })
+    }
+
+    fn parse_unary(
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<SearchToken>>,
+    ) -> Result<Self, String> {
+        match tokens.next() {
+            Some(SearchToken::Not) => Ok(SearchExpr::Not(Box::new(Self::parse_unary(tokens)?))),
+            Some(SearchToken::Open) => {
+                let expr = Self::parse_or(tokens)?;
+                match tokens.next() {
+                    Some(SearchToken::Close) => Ok(expr),
+                    _ => Err("Unmatched '(' in search query".to_string()),
+                }
+            }
+            Some(SearchToken::Term(term)) => Ok(term),
+            Some(_) => Err("Search query has an operator where a term should be".to_string()),
+            None => Err("Search query ends with an operator".to_string()),
+        }
+    }
+
+    fn matches(&self, target: &SearchTarget) -> bool {
+        match self {
+            SearchExpr::Text(text) => target.lowercase_text.contains(text.as_str()),
+            SearchExpr::Regex(regex) => regex.is_match(&target.text),
+            SearchExpr::Tag(tag) => target
+                .metadata
+                .tags
+                .iter()
+                .any(|t| t.to_lowercase() == *tag),
+            SearchExpr::Type(note_type) => target.metadata.note_type == *note_type,
+            SearchExpr::Folder(name) => target
+                .folder_name
+                .is_some_and(|folder| folder.to_lowercase() == *name),
+            SearchExpr::Not(expr) => !expr.matches(target),
+            SearchExpr::And(exprs) => exprs.iter().all(|e| e.matches(target)),
+            SearchExpr::Or(exprs) => exprs.iter().any(|e| e.matches(target)),
+        }
+    }
+
+    // First line of text matched by a term that is not negated, to show with the result
+    fn snippet(&self, target: &SearchTarget) -> Option<String> {
+        let line_matches = |line: &str| -> bool {
+            match self {
+                SearchExpr::Text(text) => line.to_lowercase().contains(text.as_str()),
+                SearchExpr::Regex(regex) => regex.is_match(line),
+                _ => false,
+            }
+        };
+        match self {
+            SearchExpr::Text(_) | SearchExpr::Regex(_) => target
+                .text
+                .lines()
+                .skip(1) // The name
+                .find(|line| line_matches(line))
+                .map(|line| line.trim().chars().take(SEARCH_SNIPPET_CHARS).collect()),
+            SearchExpr::And(exprs) | SearchExpr::Or(exprs) => {
+                exprs.iter().find_map(|e| e.snippet(target))
+            }
+            _ => None,
+        }
+    }
+}
+
+const SEARCH_SNIPPET_CHARS: usize = 160;
+const SEARCH_REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+fn tokenize_search(query: &str) -> Result<Vec<SearchToken>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(SearchToken::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(SearchToken::Close);
+            }
+            '-' => {
+                chars.next();
+                tokens.push(SearchToken::Not);
+            }
+            '"' => {
+                chars.next();
+                let phrase: String = chars.by_ref().take_while(|c| *c != '"').collect();
+                tokens.push(SearchToken::Term(SearchExpr::Text(phrase.to_lowercase())));
+            }
+            '/' => {
+                chars.next();
+                // A backslash keeps a '/' in the pattern
+                let mut pattern = String::new();
+                let mut closed = false;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' if chars.peek() == Some(&'/') => pattern.push(chars.next().unwrap()),
+                        '/' => {
+                            closed = true;
+                            break;
+                        }
+                        c => pattern.push(c),
+                    }
+                }
+                if !closed {
+                    return Err("Unterminated /regex/ in search query".to_string());
+                }
+                tokens.push(SearchToken::Term(SearchExpr::Regex(build_regex(&pattern)?)));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(search_word_token(&word)?);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+const MAX_TITLE_MATCHES: usize = 50;
+
+// Score a name containing the query's characters in order, favouring matches at the start,
+// at the start of words and in runs; None if the name does not contain them all
+fn fuzzy_score(query: &str, name: &str) -> Option<u32> {
+    let name: Vec<char> = name.to_lowercase().chars().collect();
+    let mut score = 0;
+    let mut position = 0;
+    let mut previous_match: Option<usize> = None;
+    for c in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let index = position + name[position..].iter().position(|n| *n == c)?;
+        score += 1;
+        if index == 0 {
+            score += 10;
+        } else if !name[index - 1].is_alphanumeric() {
+            score += 8;
+        }
+        if previous_match.is_some_and(|previous| previous + 1 == index) {
+            score += 5;
+        }
+        previous_match = Some(index);
+        position = index + 1;
+    }
+    Some(score)
+}
+
+fn build_regex(pattern: &str) -> Result<regex::Regex, String> {
+    regex::RegexBuilder::new(pattern)
+        .size_limit(SEARCH_REGEX_SIZE_LIMIT)
+        .build()
+        .map_err(|e| format!("Invalid regex /{}/: {}", pattern, e))
+}
+
+// Replace every match of a literal or /regex/ pattern, returning the new text and match count
+fn replace_matches(
+    text: &str,
+    pattern: &regex::Regex,
+    is_regex: bool,
+    replacement: &str,
+) -> (String, u32) {
+    let count = pattern.find_iter(text).count() as u32;
+    let replaced = if is_regex {
+        pattern.replace_all(text, replacement)
+    } else {
+        pattern.replace_all(text, regex::NoExpand(replacement))
+    };
+    (replaced.into_owned(), count)
+}
+
+fn search_word_token(word: &str) -> Result<SearchToken, String> {
+    let term = match word {
+        "AND" => return Ok(SearchToken::And),
+        "OR" => return Ok(SearchToken::Or),
+        "NOT" => return Ok(SearchToken::Not),
+        _ => match word.split_once(':') {
+            Some(("tag", tag)) => SearchExpr::Tag(tag.to_lowercase()),
+            Some(("folder", folder)) => SearchExpr::Folder(folder.to_lowercase()),
+            Some(("type", note_type)) => match note_type.to_lowercase().as_str() {
+                "markdown" => SearchExpr::Type(NoteType::Markdown),
+                "tldraw" => SearchExpr::Type(NoteType::Tldraw),
+                "table" => SearchExpr::Type(NoteType::Table),
+                "text" => SearchExpr::Type(NoteType::PlainText),
+                "audio" => SearchExpr::Type(NoteType::Audio),
+                "pdf" => SearchExpr::Type(NoteType::Pdf),
+                _ => return Err(format!("Unknown note type \"{}\"", note_type)),
+            },
+            _ => SearchExpr::Text(word.to_lowercase()),
+        },
+    };
+    Ok(SearchToken::Term(term))
+}
+
+// Text a note is searched by: text, markdown and tables as they are, and the text of a drawing's
+// shapes. Audio and PDF notes are only found by name.
+fn searchable_text(note_type: &NoteType, content: &[u8]) -> String {
+    fn collect_text(value: &serde_json::Value, out: &mut Vec<String>) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, value) in map {
+                    match value {
+                        serde_json::Value::String(text) if key == "text" => out.push(text.clone()),
+                        _ => collect_text(value, out),
+                    }
+                }
+            }
+            serde_json::Value::Array(values) => {
+                values.iter().for_each(|value| collect_text(value, out))
+            }
+            _ => {}
+        }
+    }
+    match note_type {
+        NoteType::Markdown | NoteType::Table | NoteType::PlainText => {
+            String::from_utf8_lossy(content).into_owned()
+        }
+        NoteType::Tldraw => {
+            let mut texts = Vec::new();
+            if let Ok(value) = serde_json::from_slice::<serde_json::Value>(content) {
+                collect_text(&value, &mut texts);
+            }
+            texts.join("\n")
+        }
+        NoteType::Audio | NoteType::Pdf => String::new(),
+    }
+}
+
+// Content of an audio note; the recording itself is an attachment of the note
+#[derive(Debug, Serialize, Deserialize)]
+struct AudioContent {
+    attachment_id: String,
+    mime: String,
+    duration_ms: Option<u64>,
+}
+
+// Length of a WAV recording, from its header; other formats rely on the uploader for it
+fn wav_duration_ms(data: &[u8]) -> Option<u64> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return None;
+    }
+    let read_u32 = |at: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+    };
+    let (mut byte_rate, mut data_size) = (None, None);
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let size = read_u32(pos + 4)? as usize;
+        match &data[pos..pos + 4] {
+            b"fmt " => byte_rate = read_u32(pos + 16),
+            b"data" => data_size = Some(size),
+            _ => {}
+        }
+        // Chunks are padded to an even length
+        pos = size
+            .checked_add(8 + size % 2)
+            .and_then(|n| pos.checked_add(n))?;
+    }
+    let byte_rate = byte_rate.filter(|rate| *rate > 0)?;
+    Some(data_size? as u64 * 1000 / byte_rate as u64)
+}
+
+// Inclusive byte range asked for by a Range header, for a body of the given length. None if the
+// header is not a single satisfiable byte range.
+fn parse_byte_range(header: &str, len: usize) -> Option<(usize, usize)> {
+    let (start, end) = header.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: usize = suffix.parse().ok()?;
+            (len.checked_sub(suffix.min(len))?, len.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, len.checked_sub(1)?),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<usize>().ok()?.min(len.checked_sub(1)?),
+        ),
+    };
+    (start <= end && start < len).then_some((start, end))
+}
+
+const MAX_TABLE_ROWS: u32 = 100_000;
+const MAX_TABLE_COLUMNS: u32 = 1_000;
+const MAX_TABLE_CELLS: usize = 1_000_000;
+
+// Whether a table of this many rows and columns is within the limits
+fn check_table_size(rows: usize, columns: usize) -> Result<(), String> {
+    if rows > MAX_TABLE_ROWS as usize {
+        return Err(format!("Tables can have at most {} rows", MAX_TABLE_ROWS));
+    }
+    if columns > MAX_TABLE_COLUMNS as usize {
+        return Err(format!(
+            "Tables can have at most {} columns",
+            MAX_TABLE_COLUMNS
+        ));
+    }
+    if rows * columns > MAX_TABLE_CELLS {
+        return Err(format!("Tables can have at most {} cells", MAX_TABLE_CELLS));
+    }
+    Ok(())
+}
+
+// Rows of CSV text, per RFC 4180: quoted fields may hold commas, quotes ("") and newlines
+fn parse_csv(text: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' => in_quotes = true,
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\r' | '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            _ => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows
+}
+
+// CSV text for rows, padded to the widest row, quoting only the fields that need it
+fn write_csv(rows: &[Vec<String>]) -> String {
+    let width = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let mut csv = String::new();
+    for row in rows {
+        let fields: Vec<String> = (0..width)
+            .map(|i| {
+                let field = row.get(i).map_or("", String::as_str);
+                if field.contains([',', '"', '\n', '\r']) {
+                    format!("\"{}\"", field.replace('"', "\"\""))
+                } else {
+                    field.to_string()
+                }
+            })
+            .collect();
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+// Apply a row or column edit to a table's rows; indexes start at 0. A cell may be set at most one
+// row or column past the table's edge, growing it by that row or column.
+fn apply_table_edit(rows: &mut Vec<Vec<String>>, edit: TableEdit) -> Result<(), String> {
+    let width = rows.iter().map(Vec::len).max().unwrap_or(0);
+    for row in rows.iter_mut() {
+        row.resize(width, String::new());
+    }
+    match edit {
+        TableEdit::InsertRow(index) if index as usize <= rows.len() => {
+            check_table_size(rows.len() + 1, width)?;
+            rows.insert(index as usize, vec![String::new(); width]);
+        }
+        TableEdit::DeleteRow(index) if (index as usize) < rows.len() => {
+            rows.remove(index as usize);
+        }
+        TableEdit::InsertColumn(index) if index as usize <= width => {
+            check_table_size(rows.len(), width + 1)?;
+            for row in rows.iter_mut() {
+                row.insert(index as usize, String::new());
+            }
+        }
+        TableEdit::DeleteColumn(index) if (index as usize) < width => {
+            for row in rows.iter_mut() {
+                row.remove(index as usize);
+            }
+        }
+        TableEdit::SetCell((row, column, value)) => {
+            let (row, column) = (row as usize, column as usize);
+            if row > rows.len() || column > width {
+                return Err(format!("Cell ({}, {}) is out of range", row, column));
+            }
+            let width = width.max(column + 1);
+            check_table_size(rows.len().max(row + 1), width)?;
+            if rows.len() == row {
+                rows.push(Vec::new());
+            }
+            for row in rows.iter_mut() {
+                row.resize(width, String::new());
+            }
+            rows[row][column] = value;
+        }
+        TableEdit::InsertRow(index) | TableEdit::DeleteRow(index) => {
+            return Err(format!("Row {} is out of range", index))
+        }
+        TableEdit::InsertColumn(index) | TableEdit::DeleteColumn(index) => {
+            return Err(format!("Column {} is out of range", index))
+        }
+    }
+    Ok(())
+}
+
+// Exported PDFs are A4, measured in points, and set in the standard fonts so none are embedded
+const PDF_PAGE_WIDTH: f32 = 595.0;
+const PDF_PAGE_HEIGHT: f32 = 842.0;
+const PDF_MARGIN: f32 = 56.0;
+
+// Helvetica and Helvetica-Bold advance widths for ASCII 32..=126, in thousandths of the font size
+const HELVETICA_WIDTHS: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278, 556, 556, 556,
+    556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556, 1015, 667, 667, 722, 722, 667,
+    611, 778, 722, 278, 500, 667, 556, 833, 722, 778, 667, 778, 722, 667, 611, 722, 667, 944, 667,
+    667, 611, 278, 278, 278, 469, 556, 333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500,
+    222, 833, 556, 556, 556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584,
+];
+const HELVETICA_BOLD_WIDTHS: [u16; 95] = [
+    278, 333, 474, 556, 556, 889, 722, 238, 333, 333, 389, 584, 278, 333, 278, 278, 556, 556, 556,
+    556, 556, 556, 556, 556, 556, 556, 333, 333, 584, 584, 584, 611, 975, 722, 722, 722, 722, 667,
+    611, 778, 722, 278, 556, 722, 611, 833, 722, 778, 667, 778, 722, 667, 611, 722, 667, 944, 667,
+    667, 611, 333, 278, 333, 584, 556, 333, 556, 611, 556, 611, 556, 333, 611, 611, 278, 278, 556,
+    278, 889, 611, 611, 611, 611, 389, 556, 333, 611, 556, 778, 556, 556, 500, 389, 280, 389, 584,
+];
+
+#[derive(Clone, Copy)]
+enum PdfFont {
+    Regular,
+    Bold,
+    Italic,
+    Mono,
+}
+
+impl PdfFont {
+    const ALL: [PdfFont; 4] = [
+        PdfFont::Regular,
+        PdfFont::Bold,
+        PdfFont::Italic,
+        PdfFont::Mono,
+    ];
+
+    // Name of the font in each page's resources
+    fn resource(self) -> &'static str {
+        match self {
+            PdfFont::Regular => "F1",
+            PdfFont::Bold => "F2",
+            PdfFont::Italic => "F3",
+            PdfFont::Mono => "F4",
+        }
+    }
+
+    fn base_font(self) -> &'static str {
+        match self {
+            PdfFont::Regular => "Helvetica",
+            PdfFont::Bold => "Helvetica-Bold",
+            PdfFont::Italic => "Helvetica-Oblique",
+            PdfFont::Mono => "Courier",
+        }
+    }
+
+    fn text_width(self, text: &str, size: f32) -> f32 {
+        let widths = match self {
+            PdfFont::Regular | PdfFont::Italic => &HELVETICA_WIDTHS,
+            PdfFont::Bold => &HELVETICA_BOLD_WIDTHS,
+            PdfFont::Mono => return text.chars().count() as f32 * 0.6 * size,
+        };
+        let total: u32 = text
+            .chars()
+            .map(|c| match c {
+                ' '..='~' => widths[c as usize - 32] as u32,
+                _ => 556,
+            })
+            .sum();
+        total as f32 * size / 1000.0
+    }
+}
+
+// A character's code in WinAnsiEncoding, the encoding of the standard fonts
+fn win_ansi_code(c: char) -> Option<u8> {
+    match c {
+        ' '..='~' | '\u{a0}'..='\u{ff}' => Some(c as u8),
+        '€' => Some(0x80),
+        '…' => Some(0x85),
+        '‘' => Some(0x91),
+        '’' => Some(0x92),
+        '“' => Some(0x93),
+        '”' => Some(0x94),
+        '•' => Some(0x95),
+        '–' => Some(0x96),
+        '—' => Some(0x97),
+        '™' => Some(0x99),
+        _ => None,
+    }
+}
+
+// A literal string for a content stream; characters the fonts cannot show become "?"
+fn pdf_string(text: &str) -> String {
+    let mut literal = String::from("(");
+    for c in text.chars() {
+        match win_ansi_code(c).unwrap_or(b'?') {
+            code @ (b'(' | b')' | b'\\') => {
+                literal.push('\\');
+                literal.push(code as char);
+            }
+            code if code >= 0x80 => literal.push_str(&format!("\\{:03o}", code)),
+            code => literal.push(code as char),
+        }
+    }
+    literal.push(')');
+    literal
+}
+
+// A document string such as the title, in UTF-16 so any character survives
+fn pdf_text_string(text: &str) -> String {
+    let hex: String = text
+        .encode_utf16()
+        .map(|unit| format!("{:04X}", unit))
+        .collect();
+    format!("<FEFF{}>", hex)
+}
+
+// Splits text into lines no wider than `width`, between words where possible
+fn wrap_pdf_text(text: &str, font: PdfFont, size: f32, width: f32) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let candidate = if line.is_empty() {
+            word.to_string()
+        } else {
+            format!("{} {}", line, word)
+        };
+        if font.text_width(&candidate, size) <= width {
+            line = candidate;
+            continue;
+        }
+        if !line.is_empty() {
+            lines.push(std::mem::take(&mut line));
+        }
+        // A word too long for a line of its own is broken wherever it runs out of room
+        for c in word.chars() {
+            line.push(c);
+            if line.chars().count() > 1 && font.text_width(&line, size) > width {
+                line.pop();
+                lines.push(std::mem::replace(&mut line, c.to_string()));
+            }
+        }
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+// Lays out lines top to bottom, starting a new page whenever one fills up
+struct PdfLayout {
+    pages: Vec<String>, // content streams
+    y: f32,             // baseline of the last line
+}
+
+impl PdfLayout {
+    fn new() -> Self {
+        PdfLayout {
+            pages: vec![String::new()],
+            y: PDF_PAGE_HEIGHT - PDF_MARGIN,
+        }
+    }
+
+    // Moves down by `height`, onto a new page if this one has no room for it
+    fn advance(&mut self, height: f32) {
+        if self.y - height < PDF_MARGIN {
+            self.pages.push(String::new());
+            self.y = PDF_PAGE_HEIGHT - PDF_MARGIN;
+        }
+        self.y -= height;
+    }
+
+    fn space(&mut self, height: f32) {
+        self.y = (self.y - height).max(PDF_MARGIN);
+    }
+
+    fn draw(&mut self, operations: &str) {
+        if let Some(page) = self.pages.last_mut() {
+            page.push_str(operations);
+            page.push('\n');
+        }
+    }
+
+    fn text_at(&mut self, x: f32, text: &str, font: PdfFont, size: f32) {
+        let operations = format!(
+            "BT /{} {} Tf {:.1} {:.1} Td {} Tj ET",
+            font.resource(),
+            size,
+            x,
+            self.y,
+            pdf_string(text)
+        );
+        self.draw(&operations);
+    }
+
+    // Text wrapped to the page, indented from the margin; a marker such as a bullet hangs to the
+    // left of its first line
+    fn paragraph(&mut self, text: &str, font: PdfFont, size: f32, indent: f32, marker: &str) {
+        let width = PDF_PAGE_WIDTH - 2.0 * PDF_MARGIN - indent;
+        let x = PDF_MARGIN + indent;
+        for (index, line) in wrap_pdf_text(text, font, size, width).iter().enumerate() {
+            self.advance(size * 1.4);
+            if index == 0 && !marker.is_empty() {
+                let marker_x = x - font.text_width(marker, size) - size * 0.5;
+                self.text_at(marker_x, marker, font, size);
+            }
+            self.text_at(x, line, font, size);
+        }
+    }
+
+    // Code keeps its spacing and is broken only where a line runs past the margin
+    fn code_line(&mut self, line: &str, size: f32) {
+        let line = line.replace('\t', "    ");
+        let per_line = ((PDF_PAGE_WIDTH - 2.0 * PDF_MARGIN) / (0.6 * size)) as usize;
+        let chars: Vec<char> = line.chars().collect();
+        for chunk in chars.chunks(per_line.max(1)) {
+            self.advance(size * 1.3);
+            self.text_at(
+                PDF_MARGIN,
+                &chunk.iter().collect::<String>(),
+                PdfFont::Mono,
+                size,
+            );
+        }
+        if chars.is_empty() {
+            self.advance(size * 1.3);
+        }
+    }
+
+    fn rule(&mut self) {
+        self.advance(12.0);
+        let y = self.y + 4.0;
+        let operations = format!(
+            "0.5 w {:.1} {:.1} m {:.1} {:.1} l S",
+            PDF_MARGIN,
+            y,
+            PDF_PAGE_WIDTH - PDF_MARGIN,
+            y
+        );
+        self.draw(&operations);
+    }
+}
+
+// A block of a Markdown document as the exporters lay it out, inline formatting still in its text
+enum MarkdownBlock {
+    Heading(usize, String), // (level, text)
+    Paragraph(String),      // its lines joined by spaces
+    Item {
+        depth: usize,           // nesting, from the indentation
+        number: Option<String>, // none for bullets
+        text: String,
+    },
+    Quote(String),
+    Code(Vec<String>),
+    Table(Vec<String>), // rows, pipes and all
+    Rule,
+}
+
+// The blocks of a Markdown document, after any frontmatter
+fn markdown_blocks(markdown: &str) -> Vec<MarkdownBlock> {
+    let body = split_frontmatter(markdown).map_or(markdown, |(_, body)| body);
+    let mut blocks = Vec::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut code: Option<Vec<String>> = None;
+    for line in body.lines().chain(std::iter::once("")) {
+        let trimmed = line.trim();
+        let is_fence = trimmed.starts_with("```") || trimmed.starts_with("~~~");
+        if let Some(lines) = code.as_mut() {
+            if is_fence {
+                blocks.push(MarkdownBlock::Code(std::mem::take(lines)));
+                code = None;
+            } else {
+                lines.push(line.trim_end().to_string());
+            }
+            continue;
+        }
+        let level = trimmed.chars().take_while(|&c| c == '#').count();
+        let heading = (1..=6).contains(&level) && trimmed[level..].starts_with(' ');
+        let compact: String = trimmed.chars().filter(|&c| c != ' ').collect();
+        let is_rule = compact.len() >= 3
+            && ['-', '*', '_']
+                .iter()
+                .any(|&mark| compact.chars().all(|c| c == mark));
+        let ordered = trimmed
+            .split_once(['.', ')'])
+            .filter(|(number, rest)| {
+                !number.is_empty()
+                    && number.chars().all(|c| c.is_ascii_digit())
+                    && rest.starts_with(' ')
+            })
+            .map(|(number, rest)| (Some(number.to_string()), rest));
+        let bullet = ["- ", "* ", "+ "]
+            .iter()
+            .find_map(|mark| trimmed.strip_prefix(mark))
+            .map(|rest| (None, rest));
+        let item = bullet.or(ordered);
+        let is_block = trimmed.is_empty()
+            || is_fence
+            || heading
+            || is_rule
+            || item.is_some()
+            || trimmed.starts_with('>')
+            || trimmed.starts_with('|');
+        if !is_block {
+            paragraph.push(trimmed);
+            continue;
+        }
+        if !paragraph.is_empty() {
+            blocks.push(MarkdownBlock::Paragraph(paragraph.join(" ")));
+            paragraph.clear();
+        }
+        if is_fence {
+            code = Some(Vec::new());
+        } else if heading {
+            blocks.push(MarkdownBlock::Heading(
+                level,
+                trimmed[level..].trim().to_string(),
+            ));
+        } else if is_rule {
+            blocks.push(MarkdownBlock::Rule);
+        } else if let Some((number, rest)) = item {
+            blocks.push(MarkdownBlock::Item {
+                depth: (line.len() - line.trim_start().len()) / 2,
+                number,
+                text: rest.trim().to_string(),
+            });
+        } else if let Some(quote) = trimmed.strip_prefix('>') {
+            blocks.push(MarkdownBlock::Quote(quote.trim().to_string()));
+        } else if trimmed.starts_with('|') {
+            match blocks.last_mut() {
+                Some(MarkdownBlock::Table(rows)) => rows.push(trimmed.to_string()),
+                _ => blocks.push(MarkdownBlock::Table(vec![trimmed.to_string()])),
+            }
+        }
+    }
+    // An unclosed fence runs to the end of the note
+    if let Some(mut lines) = code {
+        lines.pop();
+        blocks.push(MarkdownBlock::Code(lines));
+    }
+    blocks
+}
+
+// Compiles (pattern, replacement) pairs for rewriting inline Markdown
+fn inline_rewrites<'a>(
+    rewrites: &[(&str, &'a str)],
+) -> anyhow::Result<Vec<(regex::Regex, &'a str)>> {
+    rewrites
+        .iter()
+        .map(|(pattern, replacement)| Ok((regex::Regex::new(pattern)?, *replacement)))
+        .collect()
+}
+
+fn apply_rewrites(text: &str, rewrites: &[(regex::Regex, &str)]) -> String {
+    rewrites
+        .iter()
+        .fold(text.to_string(), |text, (pattern, replacement)| {
+            pattern.replace_all(&text, *replacement).into_owned()
+        })
+}
+
+// Typesets Markdown: headings, paragraphs, lists, quotes, code blocks, tables and rules. Inline
+// formatting is dropped, links are followed by their URL, and images are named but not drawn.
+fn markdown_to_pdf(title: &str, markdown: &str) -> anyhow::Result<Vec<u8>> {
+    let rewrites = inline_rewrites(&[
+        (r"!\[([^\]]*)\]\([^)]*\)", "[image: $1]"),
+        (r"\[\[([^\]|]+)\|([^\]]+)\]\]", "$2"),
+        (r"\[\[([^\]]+)\]\]", "$1"),
+        (r"\[([^\]]+)\]\(([^)\s]+)[^)]*\)", "$1 ($2)"),
+        (r"\*\*|__|~~|`", ""),
+    ])?;
+    let clean = |text: &str| apply_rewrites(text, &rewrites);
+
+    let mut layout = PdfLayout::new();
+    for block in markdown_blocks(markdown) {
+        match block {
+            MarkdownBlock::Heading(level, text) => {
+                let size = [20.0, 16.0, 14.0, 12.0, 11.0, 11.0][level - 1];
+                layout.space(size * 0.5);
+                layout.paragraph(&clean(&text), PdfFont::Bold, size, 0.0, "");
+                layout.space(4.0);
+            }
+            MarkdownBlock::Paragraph(text) => {
+                layout.paragraph(&clean(&text), PdfFont::Regular, 11.0, 0.0, "");
+                layout.space(6.0);
+            }
+            MarkdownBlock::Item {
+                depth,
+                number,
+                text,
+            } => {
+                let marker = number.map_or("•".to_string(), |number| format!("{}.", number));
+                let indent = 18.0 * (depth + 1) as f32;
+                layout.paragraph(&clean(&text), PdfFont::Regular, 11.0, indent, &marker);
+            }
+            MarkdownBlock::Quote(text) => {
+                layout.paragraph(&clean(&text), PdfFont::Italic, 11.0, 18.0, "");
+            }
+            MarkdownBlock::Code(lines) | MarkdownBlock::Table(lines) => {
+                layout.space(4.0);
+                for line in lines {
+                    layout.code_line(&line, 9.5);
+                }
+                layout.space(6.0);
+            }
+            MarkdownBlock::Rule => layout.rule(),
+        }
+    }
+    write_pdf(title, &layout.pages)
+}
+
+// A PDF file of laid-out pages, each content stream compressed
+fn write_pdf(title: &str, pages: &[String]) -> anyhow::Result<Vec<u8>> {
+    // Objects are numbered from 1: catalog, page tree, document info, fonts, then each page
+    // followed by its content stream
+    let first_page = 4 + PdfFont::ALL.len();
+    let mut objects: Vec<Vec<u8>> = Vec::new();
+    objects.push(b"<< /Type /Catalog /Pages 2 0 R >>".to_vec());
+    let kids: Vec<String> = (0..pages.len())
+        .map(|index| format!("{} 0 R", first_page + 2 * index))
+        .collect();
+    objects.push(
+        format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            kids.join(" "),
+            pages.len()
+        )
+        .into_bytes(),
+    );
+    objects.push(
+        format!(
+            "<< /Title {} /Producer (wifenote) >>",
+            pdf_text_string(title)
+        )
+        .into_bytes(),
+    );
+    let mut fonts = Vec::new();
+    for (index, font) in PdfFont::ALL.iter().enumerate() {
+        fonts.push(format!("/{} {} 0 R", font.resource(), 4 + index));
+        objects.push(
+            format!(
+                "<< /Type /Font /Subtype /Type1 /BaseFont /{} /Encoding /WinAnsiEncoding >>",
+                font.base_font()
+            )
+            .into_bytes(),
+        );
+    }
+    for (index, content) in pages.iter().enumerate() {
+        objects.push(
+            format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /Font << {} >> >> /Contents {} 0 R >>",
+                PDF_PAGE_WIDTH,
+                PDF_PAGE_HEIGHT,
+                fonts.join(" "),
+                first_page + 2 * index + 1
+            )
+            .into_bytes(),
+        );
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content.as_bytes())?;
+        let data = encoder.finish()?;
+        let mut stream = format!(
+            "<< /Length {} /Filter /FlateDecode >>\nstream\n",
+            data.len()
+        )
+        .into_bytes();
+        stream.extend(data);
+        stream.extend(b"\nendstream");
+        objects.push(stream);
+    }
+
+    let mut pdf = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+    let mut offsets = Vec::new();
+    for (index, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf.extend(format!("{} 0 obj\n", index + 1).into_bytes());
+        pdf.extend(object);
+        pdf.extend(b"\nendobj\n");
+    }
+    let xref = pdf.len();
+    pdf.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).into_bytes());
+    for offset in offsets {
+        pdf.extend(format!("{:010} 00000 n \n", offset).into_bytes());
+    }
+    pdf.extend(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R /Info 3 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref
+        )
+        .into_bytes(),
+    );
+    Ok(pdf)
+}
+
+// Styles inlined into exported HTML, so the file reads the same wherever it is opened
+const EXPORT_HTML_STYLE: &str = "body{max-width:46rem;margin:2rem auto;padding:0 1rem;font:16px/1.6 -apple-system,BlinkMacSystemFont,\"Segoe UI\",Helvetica,Arial,sans-serif;color:#1f2328}\
+h1,h2,h3,h4,h5,h6{line-height:1.25;margin:1.5em 0 .5em}\
+pre{background:#f6f8fa;padding:.75em 1em;border-radius:6px;overflow-x:auto}\
+code{font:.9em ui-monospace,SFMono-Regular,Menlo,Consolas,monospace}\
+:not(pre)>code{background:#f6f8fa;padding:.1em .3em;border-radius:4px}\
+blockquote{margin:1em 0;padding:0 1em;border-left:.25em solid #d0d7de;color:#59636e}\
+table{border-collapse:collapse;margin:1em 0}\
+th,td{border:1px solid #d0d7de;padding:.3em .7em}\
+img{max-width:100%}\
+hr{border:0;border-top:1px solid #d0d7de;margin:1.5em 0}";
+
+// A standalone HTML page of a Markdown note. `embed` turns an image URL into the data URI to
+// inline in its place; images it has none for keep their URL.
+fn markdown_to_html(
+    title: &str,
+    markdown: &str,
+    embed: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<String> {
+    let images = regex::Regex::new(r"!\[([^\]]*)\]\(([^)\s]+)[^)]*\)")?;
+    let rewrites = inline_rewrites(&[
+        (r"`([^`]+)`", "<code>$1</code>"),
+        (r"\[\[([^\]|]+)\|([^\]]+)\]\]", "$2"),
+        (r"\[\[([^\]]+)\]\]", "$1"),
+        (r"\[([^\]]+)\]\(([^)\s]+)[^)]*\)", "<a href=\"$2\">$1</a>"),
+        (r"\*\*(.+?)\*\*|__(.+?)__", "<strong>$1$2</strong>"),
+        (r"~~(.+?)~~", "<del>$1</del>"),
+        (r"\*([^*\s][^*]*)\*", "<em>$1</em>"),
+    ])?;
+    let inline = |text: &str| {
+        let text = escape_xml(text);
+        let text = images.replace_all(&text, |captures: &regex::Captures| {
+            let url = decode_html_entities(&captures[2]);
+            let src = embed(&url).unwrap_or_else(|| captures[2].to_string());
+            format!("<img alt=\"{}\" src=\"{}\">", &captures[1], src)
+        });
+        apply_rewrites(&text, &rewrites)
+    };
+
+    let mut html = String::new();
+    let mut lists: Vec<&str> = Vec::new(); // open list tags, outermost first
+    for block in markdown_blocks(markdown) {
+        if !matches!(block, MarkdownBlock::Item { .. }) {
+            while let Some(tag) = lists.pop() {
+                html.push_str(&format!("</li></{}>\n", tag));
+            }
+        }
+        match block {
+            MarkdownBlock::Heading(level, text) => {
+                html.push_str(&format!("<h{0}>{1}</h{0}>\n", level, inline(&text)));
+            }
+            MarkdownBlock::Paragraph(text) => {
+                html.push_str(&format!("<p>{}</p>\n", inline(&text)));
+            }
+            MarkdownBlock::Item {
+                depth,
+                number,
+                text,
+            } => {
+                let tag = if number.is_some() { "ol" } else { "ul" };
+                while lists.len() > depth + 1 {
+                    let closed = lists.pop().unwrap_or(tag);
+                    html.push_str(&format!("</li></{}>\n", closed));
+                }
+                // A sibling closes the previous item; a deeper item nests inside it
+                match lists.last() {
+                    Some(&open) if lists.len() == depth + 1 && open == tag => {
+                        html.push_str("</li>\n")
+                    }
+                    Some(&open) if lists.len() == depth + 1 => {
+                        lists.pop();
+                        html.push_str(&format!("</li></{}>\n", open));
+                    }
+                    _ => {}
+                }
+                while lists.len() < depth + 1 {
+                    lists.push(tag);
+                    html.push_str(&format!("<{}>\n", tag));
+                }
+                let text = if let Some(rest) = text.strip_prefix("[ ] ") {
+                    format!("<input type=\"checkbox\" disabled> {}", inline(rest))
+                } else if let Some(rest) = text
+                    .strip_prefix("[x] ")
+                    .or_else(|| text.strip_prefix("[X] "))
+                {
+                    format!(
+                        "<input type=\"checkbox\" checked disabled> {}",
+                        inline(rest)
+                    )
+                } else {
+                    inline(&text)
+                };
+                html.push_str(&format!("<li>{}", text));
+            }
+            MarkdownBlock::Quote(text) => {
+                html.push_str(&format!("<blockquote>{}</blockquote>\n", inline(&text)));
+            }
+            MarkdownBlock::Code(lines) => {
+                html.push_str(&format!(
+                    "<pre><code>{}</code></pre>\n",
+                    escape_xml(&lines.join("\n"))
+                ));
+            }
+            MarkdownBlock::Table(rows) => {
+                html.push_str("<table>\n");
+                for (index, row) in rows.iter().enumerate() {
+                    let row = row.trim().trim_start_matches('|').trim_end_matches('|');
+                    let cells: Vec<&str> = row.split('|').map(str::trim).collect();
+                    // The row under the header only sets alignment
+                    if cells
+                        .iter()
+                        .all(|cell| !cell.is_empty() && cell.chars().all(|c| "-:".contains(c)))
+                    {
+                        continue;
+                    }
+                    let tag = if index == 0 { "th" } else { "td" };
+                    let cells: String = cells
+                        .iter()
+                        .map(|cell| format!("<{0}>{1}</{0}>", tag, inline(cell)))
+                        .collect();
+                    html.push_str(&format!("<tr>{}</tr>\n", cells));
+                }
+                html.push_str("</table>\n");
+            }
+            MarkdownBlock::Rule => html.push_str("<hr>\n"),
+        }
+    }
+    while let Some(tag) = lists.pop() {
+        html.push_str(&format!("</li></{}>\n", tag));
+    }
+    Ok(format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+        escape_xml(title),
+        EXPORT_HTML_STYLE,
+        html
+    ))
+}
+
+// `attachment` Content-Disposition header value, with an ASCII fallback for older clients
+// and the exact name percent-encoded per RFC 6266
+fn content_disposition(file_name: &str) -> String {
+    let ascii_name: String = file_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        ascii_name,
+        percent_encode(file_name, b"!#$&+-.^_`|~")
+    )
+}
+
+// Percent-encode every byte other than ASCII alphanumerics and `safe`
+fn percent_encode(value: &str, safe: &[u8]) -> String {
+    value
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || safe.contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect()
+}
+
+fn hash_password(password: &str, salt: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut hash = vec![0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut hash)
+        .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e))?;
+    Ok(hash)
+}
+
+// Compare secrets without returning early, so timing does not reveal how much of a guess matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Encrypted archives: magic, then Argon2 salt, AES-GCM nonce and ciphertext
+const ENCRYPTED_ARCHIVE_MAGIC: &[u8] = b"WNENC1";
+const ENCRYPTION_SALT_LEN: usize = 16;
+const ENCRYPTION_NONCE_LEN: usize = 12;
+
+// Password-protected notes are stored in the same format, keyed by the note's password
+fn archive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; 32], String> {
+    let mut key = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("Failed to derive key: {}", e))?;
+    Ok(key)
+}
+
+fn new_archive_salt() -> Vec<u8> {
+    let mut salt = vec![0u8; ENCRYPTION_SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+// The salt an encrypted archive's key was derived with
+fn archive_salt(encrypted: &[u8]) -> Result<&[u8], String> {
+    let header_len = ENCRYPTED_ARCHIVE_MAGIC.len() + ENCRYPTION_SALT_LEN + ENCRYPTION_NONCE_LEN;
+    if encrypted.len() < header_len || !encrypted.starts_with(ENCRYPTED_ARCHIVE_MAGIC) {
+        return Err("Encrypted archive is truncated".to_string());
+    }
+    Ok(&encrypted[ENCRYPTED_ARCHIVE_MAGIC.len()..header_len - ENCRYPTION_NONCE_LEN])
+}
+
+fn encrypt_with_key(plaintext: &[u8], key: &[u8; 32], salt: &[u8]) -> Result<Vec<u8>, String> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = Aes256Gcm::new_from_slice(key)
+        .map_err(|e| e.to_string())?
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| "Failed to encrypt archive".to_string())?;
+    Ok([ENCRYPTED_ARCHIVE_MAGIC, salt, &nonce, &ciphertext].concat())
+}
+
+fn decrypt_with_key(encrypted: &[u8], key: &[u8; 32]) -> Result<Vec<u8>, String> {
+    let salt = archive_salt(encrypted)?;
+    let rest = &encrypted[ENCRYPTED_ARCHIVE_MAGIC.len() + salt.len()..];
+    let (nonce, ciphertext) = rest.split_at(ENCRYPTION_NONCE_LEN);
+    Aes256Gcm::new_from_slice(key)
+        .map_err(|e| e.to_string())?
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Wrong passphrase or corrupted archive".to_string())
+}
+
+fn encrypt_archive(archive: &[u8], passphrase: &str) -> Result<Vec<u8>, String> {
+    if passphrase.is_empty() {
+        return Err("Passphrase cannot be empty".to_string());
+    }
+    let salt = new_archive_salt();
+    encrypt_with_key(archive, &archive_key(passphrase, &salt)?, &salt)
+}
+
+fn decrypt_archive(encrypted: &[u8], passphrase: &str) -> Result<Vec<u8>, String> {
+    decrypt_with_key(
+        encrypted,
+        &archive_key(passphrase, archive_salt(encrypted)?)?,
+    )
+}
+
+// Path at which an attachment is served to authenticated clients
+fn attachment_url(id: &str) -> String {
+    format!("/{}/api/attachments/{}", our().process, id)
+}
+
+fn is_tar_archive(bytes: &[u8]) -> bool {
+    bytes.len() >= 262 && &bytes[257..262] == b"ustar"
+}
+
+// Sizes of the files a zip or tar import could store, nested zips included, so the import can
+// be checked against the quotas before anything is written
+fn archive_file_sizes(bytes: &[u8], depth: u32) -> anyhow::Result<Vec<u64>> {
+    if is_tar_archive(bytes) {
+        let entries = read_tar_entries(bytes)?;
+        return Ok(entries
+            .iter()
+            .map(|(_, content)| content.len() as u64)
+            .collect());
+    }
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes))
+        .map_err(|e| anyhow::anyhow!("Failed to read zip archive: {}", e))?;
+    let mut sizes = Vec::new();
+    for i in 0..archive.len() {
+        let (name, size) = {
+            let entry = archive
+                .by_index(i)
+                .map_err(|e| anyhow::anyhow!("Failed to read zip entry: {}", e))?;
+            if entry.is_dir() {
+                continue;
+            }
+            (entry.name().to_lowercase(), entry.size())
+        };
+        // Only the note formats the zip importers read become notes
+        let ext = name.rsplit_once('.').map_or("", |(_, ext)| ext);
+        match ext {
+            "zip" if depth < MAX_NESTED_ZIP_DEPTH => sizes.extend(archive_file_sizes(
+                &read_zip_entry(&mut archive, i)?,
+                depth + 1,
+            )?),
+            "md" | "markdown" | "html" | "htm" | "json" => sizes.push(size),
+            _ => {}
+        }
+    }
+    Ok(sizes)
+}
+
+// Regular files (path, contents) in an uncompressed tar archive
+fn read_tar_entries(bytes: &[u8]) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset + 512 <= bytes.len() {
+        let header = &bytes[offset..offset + 512];
+        // The archive ends with zeroed blocks
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let field = |range: std::ops::Range<usize>| {
+            let raw = &header[range];
+            let end = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+            String::from_utf8_lossy(&raw[..end]).to_string()
+        };
+        let size = usize::from_str_radix(field(124..136).trim(), 8)
+            .map_err(|_| anyhow::anyhow!("Invalid tar entry size"))?;
+        let data_start = offset + 512;
+        // The size comes from the archive, so it may be anything
+        let Some(data_end) = data_start
+            .checked_add(size)
+            .filter(|&end| end <= bytes.len())
+        else {
+            return Err(anyhow::anyhow!("Truncated tar archive"));
+        };
+        let type_flag = header[156];
+        if type_flag == b'0' || type_flag == 0 {
+            let (name, prefix) = (field(0..100), field(345..500));
+            let path = if prefix.is_empty() {
+                name
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            entries.push((path, bytes[data_start..data_end].to_vec()));
+        }
+        offset = data_start.saturating_add(size.div_ceil(512) * 512);
+    }
+    Ok(entries)
+}
+
+// Joplin item types, from the `type_` property
+const JOPLIN_TYPE_NOTE: u32 = 1;
+const JOPLIN_TYPE_FOLDER: u32 = 2;
+const JOPLIN_TYPE_RESOURCE: u32 = 4;
+const JOPLIN_TYPE_TAG: u32 = 5;
+const JOPLIN_TYPE_NOTE_TAG: u32 = 6;
+
+// A serialized Joplin item: a title line, an optional body, then trailing `key: value` properties
+#[derive(Debug)]
+struct JoplinItem {
+    title: String,
+    body: String,
+    props: HashMap<String, String>,
+}
+
+impl JoplinItem {
+    fn parse(text: &str) -> Self {
+        let lines: Vec<&str> = text.lines().collect();
+        let is_prop = |line: &str| {
+            line.split_once(':').is_some_and(|(key, _)| {
+                !key.is_empty() && key.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+            })
+        };
+        let mut props_start = lines.len();
+        while props_start > 1 && is_prop(lines[props_start - 1]) {
+            props_start -= 1;
+        }
+        let props = lines[props_start..]
+            .iter()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.to_string(), value.trim().to_string()))
+            .collect();
+        let body = lines
+            .get(1..props_start)
+            .unwrap_or_default()
+            .join("\n")
+            .trim_matches('\n')
+            .to_string();
+        JoplinItem {
+            title: lines.first().unwrap_or(&"").trim().to_string(),
+            body,
+            props,
+        }
+    }
+
+    fn prop(&self, key: &str) -> &str {
+        self.props.get(key).map(String::as_str).unwrap_or("")
+    }
+
+    fn item_type(&self) -> u32 {
+        self.prop("type_").parse().unwrap_or(0)
+    }
+}
+
+// Note format in Google Takeout's Keep export
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeepNote {
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    text_content: String,
+    #[serde(default)]
+    list_content: Vec<KeepListItem>,
+    #[serde(default)]
+    labels: Vec<KeepLabel>,
+    #[serde(default)]
+    annotations: Vec<KeepAnnotation>,
+    #[serde(default)]
+    is_trashed: bool,
+    #[serde(default)]
+    is_archived: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeepListItem {
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    is_checked: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeepLabel {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeepAnnotation {
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    url: String,
+}
+
+impl KeepNote {
+    fn to_markdown(&self) -> String {
+        let mut markdown = self.text_content.trim_end().to_string();
+        if !self.list_content.is_empty() {
+            if !markdown.is_empty() {
+                markdown.push_str("\n\n");
+            }
+            let items: Vec<String> = self
+                .list_content
+                .iter()
+                .map(|item| {
+                    let checkbox = if item.is_checked { "[x]" } else { "[ ]" };
+                    format!("- {} {}", checkbox, item.text.trim())
+                })
+                .collect();
+            markdown.push_str(&items.join("\n"));
+        }
+        let links: Vec<String> = self
+            .annotations
+            .iter()
+            .filter(|a| !a.url.is_empty())
+            .map(|a| {
+                let title = if a.title.is_empty() { &a.url } else { &a.title };
+                format!("- [{}]({})", title, a.url)
+            })
+            .collect();
+        if !links.is_empty() {
+            if !markdown.is_empty() {
+                markdown.push_str("\n\n");
+            }
+            markdown.push_str(&links.join("\n"));
+        }
+        markdown
+    }
+}
+
+// Keep notes often have no title; fall back to the first line of content, then the file name
+fn keep_note_title(title: &str, markdown: &str, file_stem: &str) -> String {
+    let title = title.trim();
+    if !title.is_empty() {
+        return title.to_string();
+    }
+    let first_line = markdown
+        .lines()
+        .map(|line| {
+            let line = line.trim().trim_start_matches(['#', '>', '-', '*', ' ']);
+            line.strip_prefix("[ ]")
+                .or_else(|| line.strip_prefix("[x]"))
+                .unwrap_or(line)
+                .trim()
+        })
+        .find(|line| !line.is_empty());
+    match first_line {
+        Some(line) if line.chars().count() > 50 => {
+            format!("{}...", line.chars().take(50).collect::<String>())
+        }
+        Some(line) => line.to_string(),
+        None => file_stem.to_string(),
+    }
+}
+
+// Remove the first `<div ...>` starting with `start_marker` (up to its first closing div),
+// returning its inner HTML
+fn take_html_element(html: &mut String, start_marker: &str) -> Option<String> {
+    let start = html.find(start_marker)?;
+    let inner_start = start + start_marker.len();
+    let inner_end = inner_start + html[inner_start..].find("</div>")?;
+    let inner = html[inner_start..inner_end].to_string();
+    html.replace_range(start..inner_end + "</div>".len(), "");
+    Some(inner)
+}
+
+// Notion appends " <32 hex digit page id>" to exported file and directory names
+fn strip_notion_id(name: &str) -> &str {
+    match name.rsplit_once(' ') {
+        Some((title, id)) if id.len() == 32 && id.chars().all(|c| c.is_ascii_hexdigit()) => title,
+        _ => name,
+    }
+}
+
+// Decode the HTML character references that show up in exported pages
+fn decode_html_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|&end| end <= 10)
+            .map(|end| &rest[1..end + 1]);
+        let replacement = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => {
+                let code = entity.strip_prefix('#')?;
+                let code = match code.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => code.parse().ok()?,
+                };
+                char::from_u32(code)
+            }
+        });
+        match (entity, replacement) {
+            (Some(entity), Some(c)) => {
+                decoded.push(c);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+// Parse the inside of an HTML tag into (lowercase name, is closing tag, attributes)
+fn parse_html_tag(raw: &str) -> (String, bool, Vec<(String, String)>) {
+    let raw = raw.trim().trim_end_matches('/');
+    let (is_closing, raw) = match raw.strip_prefix('/') {
+        Some(raw) => (true, raw),
+        None => (false, raw),
+    };
+    let name_end = raw.find(|c: char| c.is_whitespace()).unwrap_or(raw.len());
+    let name = raw[..name_end].to_lowercase();
+
+    let mut attrs = Vec::new();
+    let mut rest = raw[name_end..].trim_start();
+    while !rest.is_empty() {
+        let key_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let key = rest[..key_end].to_lowercase();
+        rest = rest[key_end..].trim_start();
+        let mut value = String::new();
+        if let Some(after) = rest.strip_prefix('=') {
+            let after = after.trim_start();
+            let (raw_value, remaining) = match after.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let end = after[1..].find(quote).map_or(after.len(), |end| end + 1);
+                    (&after[1..end], after.get(end + 1..).unwrap_or(""))
+                }
+                _ => {
+                    let end = after
+                        .find(|c: char| c.is_whitespace())
+                        .unwrap_or(after.len());
+                    (&after[..end], &after[end..])
+                }
+            };
+            value = decode_html_entities(raw_value);
+            rest = remaining.trim_start();
+        }
+        if !key.is_empty() {
+            attrs.push((key, value));
+        }
+    }
+    (name, is_closing, attrs)
+}
+
+// Incremental markdown output for `html_to_markdown`
+#[derive(Default)]
+struct MarkdownWriter {
+    out: String,
+    at_line_start: bool,
+    after_marker: bool, // only a list marker or checkbox has been written on this line
+    lists: Vec<Option<u32>>, // None for unordered lists, Some(next number) for ordered
+    links: Vec<String>, // hrefs of currently open links
+    skip_depth: usize,  // inside <head>, <script> or <style>
+    pre_depth: usize,
+    quote_depth: usize,
+    table_cells: usize,
+    table_header_row: bool,
+    table_header_done: bool,
+}
+
+impl MarkdownWriter {
+    fn push(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        if self.at_line_start || self.out.is_empty() {
+            for _ in 0..self.quote_depth {
+                self.out.push_str("> ");
+            }
+        }
+        self.out.push_str(text);
+        self.at_line_start = false;
+        self.after_marker = false;
+    }
+
+    // List markers and checkboxes stay on the same line as the item's content
+    fn push_marker(&mut self, marker: &str) {
+        self.push(marker);
+        self.after_marker = true;
+    }
+
+    fn newline(&mut self) {
+        if self.out.is_empty() || self.at_line_start || self.after_marker {
+            return;
+        }
+        let trimmed = self.out.trim_end_matches(' ').len();
+        self.out.truncate(trimmed);
+        self.out.push('\n');
+        self.at_line_start = true;
+    }
+
+    // Paragraph break, or just a line break inside list items
+    fn block(&mut self) {
+        self.newline();
+        if self.lists.is_empty() && !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.skip_depth > 0 {
+            return;
+        }
+        if self.pre_depth > 0 {
+            for (i, line) in text.split('\n').enumerate() {
+                if i > 0 {
+                    self.out.push('\n');
+                    self.at_line_start = true;
+                }
+                self.push(line);
+            }
+            return;
+        }
+        let mut collapsed = String::new();
+        for (i, word) in text.split_whitespace().enumerate() {
+            if i > 0 {
+                collapsed.push(' ');
+            }
+            collapsed.push_str(word);
+        }
+        let leading_space = text.starts_with(char::is_whitespace)
+            && !self.at_line_start
+            && !self.out.is_empty()
+            && !self.out.ends_with([' ', '\n']);
+        if leading_space && !self.after_marker {
+            self.out.push(' ');
+        }
+        self.push(&collapsed);
+        if !collapsed.is_empty() && text.ends_with(char::is_whitespace) {
+            self.out.push(' ');
+        }
+    }
+
+    fn open_tag(&mut self, name: &str, attrs: &[(String, String)]) {
+        let attr = |key: &str| {
+            attrs
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+        };
+        if matches!(name, "head" | "script" | "style" | "title") {
+            self.skip_depth += 1;
+            return;
+        }
+        if self.skip_depth > 0 {
+            return;
+        }
+        match name {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.block();
+                let level = name[1..].parse().unwrap_or(1);
+                self.push(&format!("{} ", "#".repeat(level)));
+            }
+            "p" | "div" | "section" | "article" | "header" | "footer" | "figure" | "details"
+            | "summary" | "dl" | "dt" | "dd" => self.block(),
+            "br" => {
+                self.push("  ");
+                self.newline();
+            }
+            "hr" => {
+                self.block();
+                self.push("---");
+                self.block();
+            }
+            "strong" | "b" => self.push("**"),
+            "em" | "i" => self.push("*"),
+            "del" | "s" | "strike" => self.push("~~"),
+            "code" if self.pre_depth == 0 => self.push("`"),
+            "pre" => {
+                self.block();
+                self.push("```");
+                self.newline();
+                self.pre_depth += 1;
+            }
+            "blockquote" => {
+                self.block();
+                self.quote_depth += 1;
+            }
+            "ul" => {
+                self.newline();
+                self.lists.push(None);
+            }
+            "ol" => {
+                self.newline();
+                let start = attr("start").and_then(|s| s.parse().ok()).unwrap_or(1);
+                self.lists.push(Some(start));
+            }
+            "li" => {
+                self.newline();
+                let indent = "  ".repeat(self.lists.len().saturating_sub(1));
+                let marker = match self.lists.last_mut() {
+                    Some(Some(n)) => {
+                        *n += 1;
+                        format!("{}. ", *n - 1)
+                    }
+                    _ => "- ".to_string(),
+                };
+                self.push_marker(&format!("{}{}", indent, marker));
+            }
+            "input" if attr("type") == Some("checkbox") => {
+                self.push_marker(if attr("checked").is_some() {
+                    "[x] "
+                } else {
+                    "[ ] "
+                });
+            }
+            "a" => {
+                self.links.push(attr("href").unwrap_or("").to_string());
+                self.push("[");
+            }
+            "img" => {
+                if let Some(src) = attr("src") {
+                    self.push(&format!("![{}]({})", attr("alt").unwrap_or(""), src));
+                }
+            }
+            "table" => {
+                self.block();
+                self.table_header_done = false;
+            }
+            "tr" => {
+                self.newline();
+                self.push("|");
+                self.table_cells = 0;
+                self.table_header_row = false;
+            }
+            "th" | "td" => {
+                self.table_header_row |= name == "th";
+                self.table_cells += 1;
+                self.push(" ");
+            }
+            _ => {}
+        }
+        // Notion renders to-do items as styled divs rather than checkboxes
+        if let Some(class) = attr("class") {
+            if class.split_whitespace().any(|c| c == "checkbox-on") {
+                self.push_marker("[x] ");
+            } else if class.split_whitespace().any(|c| c == "checkbox-off") {
+                self.push_marker("[ ] ");
+            }
+        }
+    }
+
+    fn close_tag(&mut self, name: &str) {
+        if matches!(name, "head" | "script" | "style" | "title") {
+            self.skip_depth = self.skip_depth.saturating_sub(1);
+            return;
+        }
+        if self.skip_depth > 0 {
+            return;
+        }
+        match name {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "p" | "div" | "section" | "article"
+            | "header" | "footer" | "figure" | "details" | "summary" | "dl" | "dt" | "dd"
+            | "table" => self.block(),
+            "strong" | "b" => self.push("**"),
+            "em" | "i" => self.push("*"),
+            "del" | "s" | "strike" => self.push("~~"),
+            "code" if self.pre_depth == 0 => self.push("`"),
+            "pre" => {
+                self.pre_depth = self.pre_depth.saturating_sub(1);
+                self.newline();
+                self.push("```");
+                self.block();
+            }
+            "blockquote" => {
+                self.newline();
+                self.quote_depth = self.quote_depth.saturating_sub(1);
+                self.block();
+            }
+            "ul" | "ol" => {
+                self.lists.pop();
+                self.block();
+            }
+            "li" => self.newline(),
+            "a" => {
+                let href = self.links.pop().unwrap_or_default();
+                self.push(&format!("]({})", href));
+            }
+            "th" | "td" => self.push(" |"),
+            "tr" => {
+                if self.table_header_row && !self.table_header_done {
+                    self.newline();
+                    self.push(&format!("|{}", " --- |".repeat(self.table_cells)));
+                    self.table_header_done = true;
+                }
+                self.newline();
+            }
+            _ => {}
+        }
+    }
+}
+
+// Minimal HTML to markdown conversion for imported pages
+fn html_to_markdown(html: &str) -> String {
+    let mut writer = MarkdownWriter::default();
+    let mut rest = html;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("<!--") {
+            rest = after.find("-->").map_or("", |end| &after[end + 3..]);
+            continue;
+        }
+        let is_tag = rest.starts_with('<')
+            && rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '/' || c == '!');
+        if is_tag {
+            let end = rest.find('>').unwrap_or(rest.len());
+            let (name, is_closing, attrs) = parse_html_tag(&rest[1..end]);
+            if is_closing {
+                writer.close_tag(&name);
+            } else if !name.starts_with('!') {
+                writer.open_tag(&name, &attrs);
+            }
+            rest = rest.get(end + 1..).unwrap_or("");
+        } else {
+            // A stray '<' that doesn't open a tag is kept as text
+            let skip = if rest.starts_with('<') { 1 } else { 0 };
+            let end = rest[skip..].find('<').map_or(rest.len(), |end| end + skip);
+            writer.text(&decode_html_entities(&rest[..end]));
+            rest = &rest[end..];
+        }
+    }
+    let markdown = writer.out.trim();
+    if markdown.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n", markdown)
+    }
+}
+
+const CLIP_TIMEOUT_SECS: u64 = 30;
+// Images are fetched one at a time while the clip request waits, so both how many are stored
+// and how long they may take in all are kept small; the rest keep linking to the web
+const MAX_CLIPPED_IMAGES: usize = 10;
+const CLIP_IMAGE_TIMEOUT_SECS: u64 = 5;
+const CLIP_IMAGES_TOTAL_SECS: u64 = 10;
+// Larger images keep linking to the web rather than being stored
+const MAX_CLIPPED_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+
+// Send an HTTP request through http-client without waiting for it; the response, or a send
+// error, arrives later carrying this context
+fn send_http_request(
+    method: http::Method,
+    url: &url::Url,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+    timeout: u64,
+    context: &str,
+) -> anyhow::Result<()> {
+    use http::client::{HttpClientAction, OutgoingHttpRequest};
+    let action = HttpClientAction::Http(OutgoingHttpRequest {
+        method: method.to_string(),
+        version: None,
+        url: url.to_string(),
+        headers,
+    });
+    Request::to((our().node(), "http-client", "distro", "sys"))
+        .body(serde_json::to_vec(&action)?)
+        .blob_bytes(body)
+        .context(context.as_bytes())
+        .expects_response(timeout)
+        .send()?;
+    Ok(())
+}
+
+// The status and body of http-client's response to send_http_request
+fn http_response_parts(message: &Message) -> Result<(http::StatusCode, Vec<u8>), String> {
+    use http::client::{HttpClientError, HttpClientResponse};
+    match serde_json::from_slice::<Result<HttpClientResponse, HttpClientError>>(message.body()) {
+        Ok(Ok(HttpClientResponse::Http(response))) => Ok((
+            http::StatusCode::from_u16(response.status).unwrap_or_default(),
+            message.blob().map(|blob| blob.bytes).unwrap_or_default(),
+        )),
+        Ok(Ok(_)) => Err("Unexpected response from http-client".to_string()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(e) => Err(format!("Invalid response from http-client: {}", e)),
+    }
+}
+
+// Fetch a web page or file, returning its Content-Type and body
+fn http_get(url: &url::Url) -> Result<(Option<String>, Vec<u8>), String> {
+    http_get_with(url, HashMap::new(), CLIP_TIMEOUT_SECS)
+}
+
+// As http_get, with extra request headers and a timeout in seconds
+fn http_get_with(
+    url: &url::Url,
+    mut headers: HashMap<String, String>,
+    timeout: u64,
+) -> Result<(Option<String>, Vec<u8>), String> {
+    headers.insert("User-Agent".to_string(), "wifenote".to_string());
+    let response = http::client::send_request_await_response(
+        http::Method::GET,
+        url.clone(),
+        Some(headers),
+        timeout,
+        Vec::new(),
+    )
+    .map_err(|e| format!("Failed to fetch {}: {}", url, e))?;
+    if !response.status().is_success() {
+        return Err(format!("{} returned {}", url, response.status()));
+    }
+    let mime = response
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_ascii_lowercase());
+    Ok((mime, response.into_body()))
+}
+
+// A page's title, and the part of it holding its content: its <article>, else its <main>,
+// else the whole page
+fn readable_html(html: &str) -> (Option<String>, &str) {
+    // ASCII lowercasing keeps byte offsets valid in the original
+    let lowercase = html.to_ascii_lowercase();
+    let element = |name: &str| -> Option<(usize, usize)> {
+        let start = lowercase.find(&format!("<{}", name))?;
+        let inner_start = start + lowercase[start..].find('>')? + 1;
+        let inner_end = inner_start + lowercase[inner_start..].find(&format!("</{}>", name))?;
+        Some((inner_start, inner_end))
+    };
+    let title = element("title")
+        .map(|(start, end)| decode_html_entities(html[start..end].trim()))
+        .map(|title| title.split_whitespace().collect::<Vec<_>>().join(" "));
+    let content = element("article")
+        .or_else(|| element("main"))
+        .map_or(html, |(start, end)| &html[start..end]);
+    (title, content)
+}
+
+// Only the start of a page is parsed for its preview, since the <head> comes first. The fetch
+// asks for no more with a Range header, but servers that ignore it send the whole page.
+const UNFURL_MAX_BYTES: usize = 256 * 1024;
+
+// Whether a URL names a host on the public internet rather than this machine or a private
+// network. Names are judged as written: one that resolves to a private address gets through.
+fn is_public_host(url: &url::Url) -> bool {
+    match url.host() {
+        Some(url::Host::Domain(domain)) => {
+            let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+            domain != "localhost"
+                && !domain.ends_with(".localhost")
+                && !domain.ends_with(".local")
+                && !domain.ends_with(".internal")
+        }
+        Some(url::Host::Ipv4(ip)) => is_public_ipv4(ip),
+        Some(url::Host::Ipv6(ip)) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ipv4(ip),
+            None => {
+                let first = ip.segments()[0];
+                !ip.is_loopback()
+                    && !ip.is_unspecified()
+                    && (first & 0xfe00) != 0xfc00 // Unique local
+                    && (first & 0xffc0) != 0xfe80 // Link-local
+            }
+        },
+        None => false,
+    }
+}
+
+fn is_public_ipv4(ip: std::net::Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    let carrier_nat = a == 100 && (64..128).contains(&b);
+    let reserved = ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || carrier_nat
+        || a == 0;
+    !reserved
+}
+
+// Title, description, image and icon of a page, from its <title>, <meta> and <link> tags
+fn unfurl_html(url: &url::Url, html: &str) -> LinkPreview {
+    let mut preview = LinkPreview {
+        url: url.to_string(),
+        title: None,
+        description: None,
+        image: None,
+        favicon: None,
+    };
+    let mut fallback_title = None;
+    let mut fallback_description = None;
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = rest.find('>').unwrap_or(rest.len());
+        let (name, is_closing, attrs) = parse_html_tag(&rest[..end]);
+        rest = rest.get(end + 1..).unwrap_or("");
+        let attr = |key: &str| {
+            attrs
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        match (name.as_str(), is_closing) {
+            ("head", true) | ("body", false) => break,
+            ("title", false) => {
+                let end = rest.to_ascii_lowercase().find("</title>").unwrap_or(0);
+                let title = decode_html_entities(&rest[..end]);
+                fallback_title = Some(title.split_whitespace().collect::<Vec<_>>().join(" "))
+                    .filter(|title| !title.is_empty());
+            }
+            ("meta", false) => {
+                let Some(content) = attr("content") else {
+                    continue;
+                };
+                let key = attr("property")
+                    .or_else(|| attr("name"))
+                    .unwrap_or_default();
+                match key.to_ascii_lowercase().as_str() {
+                    "og:title" => preview.title = Some(content),
+                    "twitter:title" if preview.title.is_none() => preview.title = Some(content),
+                    "og:description" => preview.description = Some(content),
+                    "twitter:description" | "description" => fallback_description = Some(content),
+                    "og:image" => preview.image = url.join(&content).ok().map(String::from),
+                    "twitter:image" if preview.image.is_none() => {
+                        preview.image = url.join(&content).ok().map(String::from)
+                    }
+                    _ => {}
+                }
+            }
+            ("link", false) => {
+                let rel = attr("rel").unwrap_or_default().to_ascii_lowercase();
+                let is_icon = rel.split_whitespace().any(|rel| rel == "icon");
+                // A plain icon beats an apple-touch-icon, which is only a fallback
+                if is_icon || (rel.contains("apple-touch-icon") && preview.favicon.is_none()) {
+                    if let Some(href) = attr("href") {
+                        preview.favicon = url.join(&href).ok().map(String::from);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    preview.title = preview.title.or(fallback_title);
+    preview.description = preview.description.or(fallback_description);
+    preview.favicon = preview
+        .favicon
+        .or_else(|| url.join("/favicon.ico").ok().map(String::from));
+    preview
+}
+
+const SAVE_TIMER_CONTEXT: &str = "save";
+const SAVE_DELAY_SECS: u64 = 30;
+
+// Write the node's notebook and any profile notebook that has changes waiting on save_later
+fn flush_pending_saves(state: &mut State) -> anyhow::Result<()> {
+    for profile in state.profile_states.values_mut() {
+        if std::mem::take(&mut profile.save_pending) {
+            profile.save_to_disk()?;
+        }
+    }
+    if std::mem::take(&mut state.save_pending) {
+        state.save_to_disk()?;
+    }
+    Ok(())
+}
+
+const COLD_STORAGE_TIMER_CONTEXT: &str = "cold-storage";
+const COLD_STORAGE_INTERVAL_HOURS: u64 = 24;
+const COLD_ARCHIVE_PREFIX: &str = "cold_";
+
+// (Re)arm the daily cold storage compaction timer, invalidating any timer already pending
+fn schedule_cold_storage(state: &mut State) {
+    state.cold_storage_timer += 1;
+    if state.settings.cold_storage_months.is_some() {
+        timer::set_timer(
+            COLD_STORAGE_INTERVAL_HOURS * 60 * 60 * 1000,
+            Some(
+                format!(
+                    "{}:{}",
+                    COLD_STORAGE_TIMER_CONTEXT, state.cold_storage_timer
+                )
+                .into_bytes(),
+            ),
+        );
+    }
+}
+
+const FEED_TIMER_CONTEXT: &str = "feeds";
+const FEED_HTTP_CONTEXT: &str = "feed-http";
+const FEED_POLL_INTERVAL_MINUTES: u64 = 30;
+
+// (Re)arm the feed poll timer, invalidating any timer already pending
+fn schedule_feed_poll(state: &mut State) {
+    state.feed_timer += 1;
+    if !state.feeds.is_empty() {
+        timer::set_timer(
+            FEED_POLL_INTERVAL_MINUTES * 60 * 1000,
+            Some(format!("{}:{}", FEED_TIMER_CONTEXT, state.feed_timer).into_bytes()),
+        );
+    }
+}
+
+// An RSS item or Atom entry
+struct FeedItem {
+    id: String,
+    title: String,
+    link: Option<String>,
+    published: Option<String>,
+    html: String,
+}
+
+impl FeedItem {
+    fn to_markdown(&self) -> String {
+        let mut source = match &self.link {
+            Some(link) => format!("> From <{}>", link),
+            None => "> From a feed".to_string(),
+        };
+        if let Some(published) = &self.published {
+            source.push_str(&format!(", published {}", published));
+        }
+        format!("{}\n\n{}", source, html_to_markdown(&self.html))
+    }
+}
+
+// Parse http-client's answer to a feed request as an RSS or Atom feed
+fn feed_items(
+    url: &str,
+    response: Result<(http::StatusCode, Vec<u8>), String>,
+) -> Result<Vec<FeedItem>, String> {
+    let (status, body) = response.map_err(|e| format!("Failed to fetch {}: {}", url, e))?;
+    if !status.is_success() {
+        return Err(format!("{} returned {}", url, status));
+    }
+    let xml = String::from_utf8_lossy(&body);
+    if !xml.contains("<rss") && !xml.contains("<feed") && !xml.contains("<rdf:RDF") {
+        return Err(format!("{} is not an RSS or Atom feed", url));
+    }
+    Ok(parse_feed(&xml))
+}
+
+// The items of an RSS feed or the entries of an Atom feed, in the feed's order
+fn parse_feed(xml: &str) -> Vec<FeedItem> {
+    let mut blocks = xml_elements(xml, "item");
+    blocks.extend(xml_elements(xml, "entry"));
+    blocks
+        .into_iter()
+        .filter_map(|block| {
+            let text = |names: &[&str]| {
+                names
+                    .iter()
+                    .filter_map(|name| xml_elements(block, name).into_iter().next())
+                    .map(xml_text)
+                    .find(|text| !text.is_empty())
+            };
+            let title = text(&["title"]);
+            let link = feed_item_link(block);
+            let id = text(&["guid", "id"])
+                .or_else(|| link.clone())
+                .or_else(|| title.clone())?;
+            Some(FeedItem {
+                id,
+                title: title.unwrap_or_else(|| "Untitled".to_string()),
+                link,
+                published: text(&["pubDate", "published", "updated", "dc:date"]),
+                html: text(&["content:encoded", "content", "description", "summary"])
+                    .unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+// The inner XML of each element with the given name; empty for self-closing ones
+fn xml_elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let open = format!("<{}", name);
+    let close = format!("</{}>", name);
+    let mut elements = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        let after = &rest[start + open.len()..];
+        // Skip elements whose names only start with this one
+        if !after.starts_with(|c: char| c == '>' || c == '/' || c.is_whitespace()) {
+            rest = after;
+            continue;
+        }
+        let Some(tag_end) = after.find('>') else {
+            break;
+        };
+        if after[..tag_end].ends_with('/') {
+            elements.push("");
+            rest = &after[tag_end + 1..];
+            continue;
+        }
+        let inner = &after[tag_end + 1..];
+        let Some(end) = inner.find(&close) else {
+            break;
+        };
+        elements.push(&inner[..end]);
+        rest = &inner[end + close.len()..];
+    }
+    elements
+}
+
+// An element's text: its CDATA sections as they are, everything else with entities decoded
+fn xml_text(inner: &str) -> String {
+    let mut text = String::new();
+    let mut rest = inner;
+    while let Some(start) = rest.find("<![CDATA[") {
+        text.push_str(&decode_html_entities(&rest[..start]));
+        let cdata = &rest[start + "<![CDATA[".len()..];
+        let end = cdata.find("]]>").unwrap_or(cdata.len());
+        text.push_str(&cdata[..end]);
+        rest = cdata.get(end + "]]>".len()..).unwrap_or("");
+    }
+    text.push_str(&decode_html_entities(rest));
+    text.trim().to_string()
+}
+
+// An item's link: RSS's <link> text, or an Atom <link> whose rel, if any, is alternate
+fn feed_item_link(block: &str) -> Option<String> {
+    let mut rest = block;
+    while let Some(start) = rest.find("<link") {
+        let after = &rest[start + "<link".len()..];
+        let tag_end = after.find('>')?;
+        let (_, _, attrs) = parse_html_tag(&format!("link{}", &after[..tag_end]));
+        let attr = |key: &str| {
+            attrs
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+        };
+        if let Some(href) = attr("href") {
+            if attr("rel").is_none_or(|rel| rel == "alternate") {
+                return Some(href.to_string());
+            }
+        } else if let Some(end) = after.find("</link>") {
+            let text = xml_text(&after[tag_end + 1..end]);
+            if !text.is_empty() {
+                return Some(text);
+            }
+        }
+        rest = &after[tag_end + 1..];
+    }
+    None
+}
+
+// Helper function to migrate state data from older versions
+fn check_export_version(version: u32) -> anyhow::Result<()> {
+    // Return error if version is newer than current
+    if version > CURRENT_STATE_VERSION {
+        return Err(anyhow::anyhow!(
+            "Cannot import data from newer version {} (current version is {})",
+            version,
+            CURRENT_STATE_VERSION
+        ));
+    }
+    Ok(())
+}
+
+fn migrate_export_data(
+    mut data: ExportData,
+    drive: &str,
+    usage: &DriveUsage,
+    on_progress: &mut dyn FnMut(u32, u32),
+) -> anyhow::Result<ExportData> {
+    check_export_version(data.version)?;
+
+    // Apply migrations sequentially based on version
+    if data.version < STATE_VERSION_WITH_SEPARATE_FILES {
+        // Migrate from v0 to v1: Extract note contents to separate files
+        info!(
+            "Migrating state from version {} to {}",
+            data.version, STATE_VERSION_WITH_SEPARATE_FILES
+        );
+
+        // Convert full notes to metadata and save content separately
+        let mut note_metadata = Vec::new();
+        let total = data.notes.len() as u32;
+        for (i, note) in data.notes.iter().enumerate() {
+            on_progress(i as u32, total);
+            // Save note content to individual file with appropriate extension
+            let ext = match note.note_type {
+                NoteType::Markdown => "md",
+                NoteType::Tldraw => "json",
+                NoteType::Table => "csv",
+                NoteType::PlainText => "txt",
+                NoteType::Audio => "json",
+                NoteType::Pdf => "pdf",
+            };
+            let path = format!("{}/note_{}.{}", drive, note.id, ext);
+            if let Err(e) = usage.write(&path, &note.content) {
+                error!(
+                    "Failed to write note content during migration for note {}: {}",
+                    note.id, e
+                );
+            }
+
+            // Create metadata
+            note_metadata.push(NoteMetadata {
+                id: note.id.clone(),
+                name: note.name.clone(),
+                folder_id: note.folder_id.clone(),
+                note_type: note.note_type,
+                is_public: note.is_public,
+                collaborators: note.collaborators.clone(),
+                tags: note.tags.clone(),
+                is_locked: data.locked_notes.contains(&note.id),
+                read_only: false,
+                noindex: false,
+                compressed: false,
+                archive: None,
+                published: None,
+                updated: 0,
+                created: 0,
+                due: None,
+                aliases: Vec::new(),
+                alias_folders: data.note_aliases.remove(&note.id).unwrap_or_default(),
+            });
+        }
+
+        on_progress(total, total);
+        data.note_metadata = note_metadata;
+        data.notes = Vec::new(); // Clear old format notes
+        data.version = STATE_VERSION_WITH_SEPARATE_FILES;
+    }
+
+    data.version = CURRENT_STATE_VERSION;
+    Ok(data)
+}
+
+fn handle_http_request(
+    req: HttpServerRequest,
+    state: &mut State,
+    server: &mut http::server::HttpServer,
+) -> anyhow::Result<()> {
+    let is_public = if let HttpServerRequest::Http(ref http_request) = req {
+        http_request.path()?.starts_with("/public")
+    } else {
+        false
+    };
+
+    // WebSocket channels logged in to a profile act on that profile's notebook instead
+    let profile_channel = match req {
+        HttpServerRequest::WebSocketPush { channel_id, .. } => {
+            let session = state.profile_channels.get(&channel_id);
+            session.and_then(|session| state.profile_sessions.get(session).cloned())
+        }
+        HttpServerRequest::WebSocketClose(channel_id) => {
+            let session = state.profile_channels.remove(&channel_id);
+            session.and_then(|session| state.profile_sessions.get(&session).cloned())
+        }
+        _ => None,
+    };
+    if let Some(name) = profile_channel {
+        return with_profile(state, &name, |profile| {
+            handle_http_request(req, profile, server)?;
+            push_changes(profile, server)
+        });
+    }
+
+    match req {
+        HttpServerRequest::WebSocketOpen {
+            ref path,
+            channel_id,
+        } => {
+            if path == PRIVATE_WS_PATH {
+                state.ws_private_channels.insert(channel_id);
+            }
+            server.handle_websocket_open(path, channel_id);
+        }
+        HttpServerRequest::WebSocketClose(channel_id) => {
+            state.ws_subscriptions.remove(&channel_id);
+            state.ws_private_channels.remove(&channel_id);
+            for note_id in state.remove_channel_presence(channel_id, None) {
+                push_presence(state, &note_id);
+            }
+            server.handle_websocket_close(channel_id);
+        }
+        HttpServerRequest::Http(http_request) => {
+            let path = http_request.path()?;
+            if path == "/dav" || path.starts_with("/dav/") {
+                return handle_webdav_request(&http_request, &path["/dav".len()..], state, server);
+            }
+            if path == TOKEN_API_PATH {
+                return handle_token_api_request(&http_request, state, server);
+            }
+            if path == PROFILE_API_PATH {
+                return handle_profile_api_request(&http_request, state, server);
+            }
+            let is_bound =
+                |prefix: &str| path == prefix || path.starts_with(&format!("{}/", prefix));
+            if !["/api", "/public"].into_iter().any(is_bound) {
+                return handle_ui_request(&http_request, &path);
+            }
+            if path.starts_with("/public") && http_request.method()? == http::Method::OPTIONS {
+                return handle_public_preflight(&http_request, state);
+            }
+            if path == "/public/calendar.ics" {
+                return handle_public_calendar(&http_request, state, server);
+            }
+            if path == "/public/list" {
+                return handle_public_list(&http_request, state);
+            }
+            if path == "/public/sitemap.xml" {
+                return handle_public_sitemap(&http_request, state);
+            }
+            if let Some(token) = path.strip_prefix("/public/link/") {
+                return handle_public_link(&http_request, token, false, state);
+            }
+            if let Some(token) = path.strip_prefix("/public/snapshot/") {
+                return handle_public_link(&http_request, token, true, state);
+            }
+            if let Some(note_id) = path
+                .strip_prefix("/public/")
+                .and_then(|rest| rest.strip_suffix("/comments"))
+            {
+                return handle_public_comments(&http_request, note_id, state);
+            }
+            if handle_rest_request(&http_request, &path, state, server)? {
+                return Ok(());
+            }
+            info!("http: a");
+            match http_request.method()? {
+                http::Method::GET => {
+                    info!("http: GET");
+
+                    // Handle public note access through public server
+                    if is_public {
+                        // For backward compatibility, support both GET and POST
+                        if let Some(note_id) = http_request.path()?.strip_prefix("/public/") {
+                            let (status, headers, body) =
+                                public_note_response(&http_request, note_id, state)?;
+                            send_compressible(&http_request, status, headers, body)?;
+                            return Ok(());
+                        }
+                    }
+
+                    // Serve note attachments to authenticated clients
+                    if let Some(attachment_id) =
+                        http_request.path()?.strip_prefix("/api/attachments/")
+                    {
+                        let mut headers = HashMap::new();
+                        let (status_code, body) = match state.attachments.get(attachment_id) {
+                            Some(attachment) => {
+                                headers.insert(
+                                    "Content-Type".to_string(),
+                                    attachment
+                                        .mime
+                                        .clone()
+                                        .unwrap_or("application/octet-stream".to_string()),
+                                );
+                                (
+                                    http::StatusCode::OK,
+                                    state.load_attachment_content(attachment_id)?,
+                                )
+                            }
+                            None => (
+                                http::StatusCode::NOT_FOUND,
+                                "Attachment not found".as_bytes().to_vec(),
+                            ),
+                        };
+                        http::server::send_response(status_code, Some(headers), body);
+                        return Ok(());
+                    }
+
+                    // Deliver the export archive as a raw binary body, rather than as the
+                    // JSON number array an ExportAll response over /api would need. The
+                    // archive is spooled to the drive when `offset` is 0 or missing, and
+                    // handed out a chunk per request; X-Export-Size gives its full size.
+                    if http_request.path()? == "/api/export" {
+                        let query = http_request.query_params();
+                        let offset = query
+                            .get("offset")
+                            .and_then(|o| o.parse::<u64>().ok())
+                            .unwrap_or(0);
+                        let length = query
+                            .get("length")
+                            .and_then(|l| l.parse::<u64>().ok())
+                            .unwrap_or(EXPORT_CHUNK_BYTES as u64);
+                        if offset == 0 {
+                            // Profiles only ever export their own notebook
+                            let workspace =
+                                query.get("workspace").filter(|_| state.profile.is_none());
+                            let mut on_progress =
+                                |done, total| push_progress(server, "export", done, total);
+                            let spooled = match inactive_workspace(workspace.map(String::as_str))? {
+                                Ok(Some(other)) => state.spool_export(&other, &mut on_progress),
+                                Ok(None) => state.spool_export(state, &mut on_progress),
+                                Err(e) => {
+                                    http::server::send_response(
+                                        http::StatusCode::NOT_FOUND,
+                                        None,
+                                        e.into_bytes(),
+                                    );
+                                    return Ok(());
+                                }
+                            };
+                            spooled?;
+                        }
+                        let size =
+                            vfs::metadata(&format!("{}/{}", state.drive, EXPORT_SPOOL_FILE), None)
+                                .map(|m| m.len)
+                                .unwrap_or_default();
+                        let chunk = match state.read_export_chunk(offset, length)? {
+                            Ok(chunk) => chunk,
+                            Err(e) => {
+                                http::server::send_response(
+                                    http::StatusCode::RANGE_NOT_SATISFIABLE,
+                                    None,
+                                    e.into_bytes(),
+                                );
+                                return Ok(());
+                            }
+                        };
+                        let mut headers = HashMap::new();
+                        headers.insert("Content-Type".to_string(), "application/gzip".to_string());
+                        headers.insert(
+                            "Content-Disposition".to_string(),
+                            content_disposition("wifenote-export.json.gz"),
+                        );
+                        headers.insert("X-Export-Size".to_string(), size.to_string());
+                        http::server::send_response(http::StatusCode::OK, Some(headers), chunk);
+                        return Ok(());
+                    }
+
+                    // Serve a raw note file as a download named after the note
+                    if let Some(note_id) = http_request
+                        .path()?
+                        .strip_prefix("/api/notes/")
+                        .and_then(|rest| rest.strip_suffix("/download"))
+                    {
+                        let Some(metadata) = state.notes.get(note_id) else {
+                            http::server::send_response(
+                                http::StatusCode::NOT_FOUND,
+                                None,
+                                "Note not found".as_bytes().to_vec(),
+                            );
+                            return Ok(());
+                        };
+                        let content = match state.load_note_content(note_id) {
+                            Ok(content) => content,
+                            Err(e) => {
+                                error!("download: failed to read note {}: {}", note_id, e);
+                                http::server::send_response(
+                                    http::StatusCode::INTERNAL_SERVER_ERROR,
+                                    None,
+                                    "Failed to read note".as_bytes().to_vec(),
+                                );
+                                return Ok(());
+                            }
+                        };
+                        let content_type = match metadata.note_type {
+                            NoteType::Markdown => "text/markdown; charset=utf-8",
+                            NoteType::Tldraw => "application/json",
+                            NoteType::Table => "text/csv; charset=utf-8",
+                            NoteType::PlainText => "text/plain; charset=utf-8",
+                            NoteType::Audio => "application/json",
+                            NoteType::Pdf => "application/pdf",
+                        };
+                        let mut headers = HashMap::new();
+                        headers.insert("Content-Type".to_string(), content_type.to_string());
+                        headers.insert(
+                            "Content-Disposition".to_string(),
+                            content_disposition(&format!(
+                                "{}.{}",
+                                sanitize_file_name(&metadata.name),
+                                State::get_note_extension(&metadata.note_type)
+                            )),
+                        );
+                        http::server::send_response(http::StatusCode::OK, Some(headers), content);
+                        return Ok(());
+                    }
+
+                    // Download a Markdown note typeset as a PDF, or as a standalone HTML page
+                    if let Some((note_id, format)) = http_request
+                        .path()?
+                        .strip_prefix("/api/notes/")
+                        .and_then(|rest| rest.rsplit_once('/'))
+                        .filter(|(_, format)| matches!(*format, "pdf" | "html"))
+                    {
+                        let (export, content_type) = if format == "pdf" {
+                            (state.export_note_pdf(note_id)?, "application/pdf")
+                        } else {
+                            let html = state.export_note_html(note_id)?;
+                            (html.map(String::into_bytes), "text/html; charset=utf-8")
+                        };
+                        let export = match export {
+                            Ok(export) => export,
+                            Err(e) => {
+                                http::server::send_response(
+                                    http::StatusCode::NOT_FOUND,
+                                    None,
+                                    e.into_bytes(),
+                                );
+                                return Ok(());
+                            }
+                        };
+                        let mut headers = HashMap::new();
+                        headers.insert("Content-Type".to_string(), content_type.to_string());
+                        headers.insert(
+                            "Content-Disposition".to_string(),
+                            content_disposition(&format!(
+                                "{}.{}",
+                                sanitize_file_name(&state.notes[note_id].name),
+                                format
+                            )),
+                        );
+                        http::server::send_response(http::StatusCode::OK, Some(headers), export);
+                        return Ok(());
+                    }
+
+                    // Serve static files for all other GET requests
+                    let mut headers = HashMap::new();
+                    headers.insert("Content-Type".to_string(), "text/html".to_string());
+                    headers.insert(
+                        "Cache-Control".to_string(),
+                        UI_PAGE_CACHE_CONTROL.to_string(),
+                    );
+                    http::server::send_response(
+                        http::StatusCode::OK,
+                        Some(headers),
+                        include_bytes!("../../pkg/ui/index.html").to_vec(),
+                    );
+                }
+                // Link checkers ask for a public note's headers alone
+                http::Method::HEAD if is_public => {
+                    let Some(note_id) = path.strip_prefix("/public/") else {
+                        http::server::send_response(http::StatusCode::NOT_FOUND, None, vec![]);
+                        return Ok(());
+                    };
+                    let (status, mut headers, body) =
+                        public_note_response(&http_request, note_id, state)?;
+                    headers.insert("Content-Length".to_string(), body.len().to_string());
+                    http::server::send_response(status, Some(headers), vec![]);
+                }
+                http::Method::POST => {
+                    // Handle public note access via POST
+                    if is_public {
+                        if http_request.path()? == "/public" {
+                            let Some(body) = last_blob() else {
+                                http::server::send_response(
+                                    http::StatusCode::BAD_REQUEST,
+                                    None,
+                                    "Missing request body".as_bytes().to_vec(),
+                                );
+                                return Ok(());
+                            };
+
+                            // Parse request body
+                            let req: serde_json::Value = match serde_json::from_slice(&body.bytes) {
+                                Ok(req) => req,
+                                Err(_) => {
+                                    http::server::send_response(
+                                        http::StatusCode::BAD_REQUEST,
+                                        None,
+                                        "Invalid JSON".as_bytes().to_vec(),
+                                    );
+                                    return Ok(());
+                                }
+                            };
+
+                            let note_id = match req.get("note_id").and_then(|v| v.as_str()) {
+                                Some(id) => id,
+                                None => {
+                                    http::server::send_response(
+                                        http::StatusCode::BAD_REQUEST,
+                                        None,
+                                        "Missing note_id".as_bytes().to_vec(),
+                                    );
+                                    return Ok(());
+                                }
+                            };
+
+                            let (status, headers, body) =
+                                public_note_response(&http_request, note_id, state)?;
+                            send_compressible(&http_request, status, headers, body)?;
+                            return Ok(());
+                        }
+
+                        http::server::send_response(
+                            http::StatusCode::NOT_FOUND,
+                            None,
+                            "Invalid path".as_bytes().to_vec(),
+                        );
+                        return Ok(());
+                    }
+                    info!("http: POST");
+                    let Some(body) = last_blob() else {
+                        return Err(anyhow::anyhow!(
+                            "received a POST HTTP request with no body, skipping"
+                        ));
+                    };
+                    info!(
+                        "http: POST trying to into note {:?}",
+                        String::from_utf8(body.bytes.clone())
+                            .map(|s| s.chars().take(10).collect::<String>())
+                    );
+                    let resp: Vec<u8> = match body.bytes.as_slice().try_into()? {
+                        Msg::NoteRequest(req) => {
+                            handle_note_request(req, Some(&our()), state, server)?.into()
+                        }
+                        Msg::Batch(BatchRequest::Batch(requests)) => {
+                            handle_batch(requests, Some(&our()), state, server)?.into()
+                        }
+                        Msg::HttpRequest(_) => {
+                            http::server::send_response(
+                                http::StatusCode::BAD_REQUEST,
+                                None,
+                                vec![],
+                            );
+                            return Ok(());
+                        }
+                    };
+                    send_compressible(&http_request, http::StatusCode::OK, HashMap::new(), resp)?;
+                }
+                _ => {
+                    http::server::send_response(http::StatusCode::METHOD_NOT_ALLOWED, None, vec![]);
+                }
+            }
+        }
+        HttpServerRequest::WebSocketPush {
+            channel_id,
+            message_type: http::server::WsMessageType::Text,
+        } => {
+            let request =
+                last_blob().and_then(|blob| serde_json::from_slice::<WsRequest>(&blob.bytes).ok());
+            match request {
+                Some(WsRequest::Subscribe(mut note_ids)) => {
+                    // Unauthenticated channels may only follow public notes
+                    let is_private = state.ws_private_channels.contains(&channel_id);
+                    note_ids.retain(|note_id| {
+                        let is_shared = shared_note_ref(note_id).is_some_and(|(owner, id)| {
+                            state
+                                .shared_notes
+                                .iter()
+                                .any(|shared| shared.owner == owner && shared.note_id == id)
+                        });
+                        state
+                            .notes
+                            .get(note_id)
+                            .is_some_and(|metadata| is_private || metadata.is_public)
+                            || (is_private && is_shared)
+                    });
+                    // Send the current content first, so later pushes only have to carry changes
+                    for note_id in &note_ids {
+                        let Some(metadata) = state.notes.get(note_id) else {
+                            // A note shared with us goes as last fetched, and again once its
+                            // owner sends a fresh copy
+                            if let Some((owner, id)) = shared_note_ref(note_id) {
+                                if let Ok(note) = open_shared_note(state, owner, id)? {
+                                    send_ws_message(
+                                        channel_id,
+                                        NoteResponse::GetNote(Ok(note)).into(),
+                                    );
+                                }
+                            }
+                            continue;
+                        };
+                        let note = if is_private {
+                            state.get_full_note(metadata)?
+                        } else {
+                            Note {
+                                folder_id: metadata.folder_id.clone(),
+                                ..state.public_note(metadata)?
+                            }
+                        };
+                        send_ws_message(channel_id, NoteResponse::GetNote(Ok(note)).into());
+                    }
+                    // Only authenticated clients show up to others as having the note open
+                    if is_private {
+                        for note_id in &note_ids {
+                            state.update_presence(Presence {
+                                note_id: note_id.clone(),
+                                node_id: our().node,
+                                channel: Some(channel_id),
+                                cursor: None,
+                                seen: now_secs(),
+                            });
+                            push_presence(state, note_id);
+                        }
+                    }
+                    state
+                        .ws_subscriptions
+                        .entry(channel_id)
+                        .or_default()
+                        .extend(note_ids);
+                }
+                Some(WsRequest::Unsubscribe(note_ids)) => {
+                    if let Some(subscribed) = state.ws_subscriptions.get_mut(&channel_id) {
+                        for note_id in &note_ids {
+                            subscribed.remove(note_id);
+                        }
+                    }
+                    for note_id in state.remove_channel_presence(channel_id, Some(&note_ids)) {
+                        push_presence(state, &note_id);
+                    }
+                }
+                Some(WsRequest::Cursor(note_id, cursor)) => {
+                    let is_subscribed = state
+                        .ws_subscriptions
+                        .get(&channel_id)
+                        .is_some_and(|subscribed| subscribed.contains(&note_id));
+                    if is_subscribed && state.ws_private_channels.contains(&channel_id) {
+                        state.update_presence(Presence {
+                            note_id: note_id.clone(),
+                            node_id: our().node,
+                            channel: Some(channel_id),
+                            cursor,
+                            seen: now_secs(),
+                        });
+                        push_presence(state, &note_id);
+                    }
+                }
+                Some(WsRequest::Request(id, _)) | Some(WsRequest::Batch(id, _))
+                    if !state.ws_private_channels.contains(&channel_id) =>
+                {
+                    error!(
+                        "Ignoring request {id} from unauthenticated WebSocket channel {channel_id}"
+                    );
+                }
+                Some(WsRequest::Request(id, req)) => {
+                    let resp = handle_note_request(req, Some(&our()), state, server)?;
+                    send_ws_message(
+                        channel_id,
+                        serde_json::to_vec(&WsResponse::Response(id, resp))?,
+                    );
+                }
+                Some(WsRequest::Batch(id, requests)) => {
+                    let resp = handle_batch(requests, Some(&our()), state, server)?;
+                    send_ws_message(
+                        channel_id,
+                        serde_json::to_vec(&WsResponse::Batch(id, resp))?,
+                    );
+                }
+                Some(WsRequest::LogIn(session)) => {
+                    let Some(name) = state
+                        .profile_sessions
+                        .get(&session)
+                        .cloned()
+                        .filter(|_| !state.ws_private_channels.contains(&channel_id))
+                    else {
+                        let response = WsResponse::Profile(Err("Invalid profile session".into()));
+                        send_ws_message(channel_id, serde_json::to_vec(&response)?);
+                        return Ok(());
+                    };
+                    state.profile_channels.insert(channel_id, session);
+                    with_profile(state, &name, |profile| {
+                        profile.ws_private_channels.insert(channel_id);
+                        Ok(())
+                    })?;
+                    send_ws_message(
+                        channel_id,
+                        serde_json::to_vec(&WsResponse::Profile(Ok(name)))?,
+                    );
+                }
+                Some(WsRequest::Resync(cursor)) => {
+                    if !state.ws_private_channels.contains(&channel_id) {
+                        return Ok(());
+                    }
+                    let log = state.structure_log();
+                    let response = if cursor == Some(log.seq) {
+                        WsResponse::UpToDate(log.seq)
+                    } else {
+                        WsResponse::Delta(log.delta(cursor))
+                    };
+                    send_ws_message(channel_id, serde_json::to_vec(&response)?);
+                }
+                None => error!("Ignoring unrecognised WebSocket message on channel {channel_id}"),
+            }
+        }
+        HttpServerRequest::WebSocketPush { .. } => {}
+    }
+    Ok(())
+}
+
+// CORS headers for a /public response, if the settings allow the request's origin and method.
+// For a preflight the method is the one the browser asks about.
+fn cors_headers(
+    settings: &Settings,
+    http_request: &http::server::IncomingHttpRequest,
+) -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    let request_headers = http_request.headers();
+    let header = |name: &str| {
+        request_headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let Some(origin) = header("origin") else {
+        return headers;
+    };
+    let method = match http_request.method() {
+        Ok(http::Method::OPTIONS) => header("access-control-request-method").unwrap_or_default(),
+        Ok(method) => method.to_string(),
+        Err(_) => return headers,
+    };
+    let any_origin = settings.cors_origins.iter().any(|allowed| allowed == "*");
+    let origin_allowed = any_origin
+        || settings
+            .cors_origins
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/') == origin);
+    if !origin_allowed || !settings.cors_methods.contains(&method) {
+        return headers;
+    }
+    let allowed_origin = if any_origin { "*".to_string() } else { origin };
+    headers.insert("Access-Control-Allow-Origin".to_string(), allowed_origin);
+    headers.insert(
+        "Access-Control-Allow-Methods".to_string(),
+        settings.cors_methods.join(", "),
+    );
+    if !any_origin {
+        headers.insert("Vary".to_string(), "Origin".to_string());
+    }
+    headers
+}
+
+// OPTIONS on /public: browsers ask before a cross-origin POST, or a request with a JSON body
+fn handle_public_preflight(
+    http_request: &http::server::IncomingHttpRequest,
+    state: &State,
+) -> anyhow::Result<()> {
+    let mut headers = cors_headers(&state.settings, http_request);
+    headers.insert("Allow".to_string(), "GET, HEAD, POST, OPTIONS".to_string());
+    if headers.contains_key("Access-Control-Allow-Origin") {
+        headers.insert(
+            "Access-Control-Allow-Headers".to_string(),
+            "Content-Type".to_string(),
+        );
+        headers.insert("Access-Control-Max-Age".to_string(), "86400".to_string());
+    }
+    http::server::send_response(http::StatusCode::NO_CONTENT, Some(headers), vec![]);
+    Ok(())
+}
+
+// Built UI assets have a hash of their content in their names, so caches may keep them for good
+const UI_ASSET_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+// The page is revalidated on every load, so a new build's assets are picked up at once
+const UI_PAGE_CACHE_CONTROL: &str = "no-cache";
+// Other UI files, such as the favicon, keep their names from build to build
+const UI_FILE_CACHE_CONTROL: &str = "public, max-age=3600";
+// Public notes may be edited at any time, so caches hold them only briefly
+const PUBLIC_NOTE_CACHE_CONTROL: &str = "public, max-age=60";
+
+// Paths of the built UI's files, as served, e.g. "/assets/index-3f2a9c.js"
+fn ui_file_paths() -> anyhow::Result<Vec<String>> {
+    let root = format!("/{}/pkg/ui", our().package_id());
+    let mut paths = Vec::new();
+    let mut dirs = vec![root.clone()];
+    while let Some(dir) = dirs.pop() {
+        for entry in vfs::open_dir(&dir, false, None)?.read()? {
+            // Directory entries come without the leading slash paths are opened with
+            let path = format!("/{}", entry.path.trim_start_matches('/'));
+            match entry.file_type {
+                vfs::FileType::Directory => dirs.push(path),
+                vfs::FileType::File => paths.push(path[root.len()..].to_string()),
+                _ => {}
+            }
+        }
+    }
+    Ok(paths)
+}
+
+// A file of the built UI, "/" being its page, with headers telling browsers how long to keep it
+fn handle_ui_request(
+    http_request: &http::server::IncomingHttpRequest,
+    path: &str,
+) -> anyhow::Result<()> {
+    if http_request.method()? != http::Method::GET {
+        http::server::send_response(http::StatusCode::METHOD_NOT_ALLOWED, None, vec![]);
+        return Ok(());
+    }
+    let file = if path == "/" { "/index.html" } else { path };
+    let content = vfs::open_file(
+        &format!("/{}/pkg/ui{}", our().package_id(), file),
+        false,
+        None,
+    )
+    .and_then(|file| file.read());
+    let Ok(content) = content else {
+        http::server::send_response(http::StatusCode::NOT_FOUND, None, vec![]);
+        return Ok(());
+    };
+    let extension = file.rsplit_once('.').map_or("", |(_, extension)| extension);
+    let content_type = match extension {
+        "html" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" | "webmanifest" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    };
+    let cache_control = if extension == "html" {
+        UI_PAGE_CACHE_CONTROL
+    } else if file.starts_with("/assets/") {
+        UI_ASSET_CACHE_CONTROL
+    } else {
+        UI_FILE_CACHE_CONTROL
+    };
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(), content_type.to_string());
+    headers.insert("Cache-Control".to_string(), cache_control.to_string());
+    http::server::send_response(http::StatusCode::OK, Some(headers), content);
+    Ok(())
+}
+
+// A public note as served from /public: status, headers and JSON body. A GET or HEAD whose
+// If-None-Match names the current ETag is answered 304 with no body.
+fn public_note_response(
+    http_request: &http::server::IncomingHttpRequest,
+    note_id: &str,
+    state: &State,
+) -> anyhow::Result<(http::StatusCode, HashMap<String, String>, Vec<u8>)> {
+    let mut headers = cors_headers(&state.settings, http_request);
+    headers.insert("Content-Type".to_string(), "application/json".to_string());
+    if state.notes.get(note_id).is_some_and(|n| n.noindex) {
+        headers.insert("X-Robots-Tag".to_string(), "noindex".to_string());
+    }
+
+    let result = if let Some(metadata) = state.notes.get(note_id) {
+        if metadata.is_public {
+            state
+                .public_note(metadata)
+                .map_err(|_| "Error loading note content".to_string())
+        } else {
+            Err("Note is not public".to_string())
+        }
+    } else {
+        Err("Note not found".to_string())
+    };
+
+    let note = match result {
+        Ok(note) => note,
+        Err(msg) => {
+            let body = serde_json::to_vec(&serde_json::json!({ "Err": msg }))?;
+            return Ok((http::StatusCode::NOT_FOUND, headers, body));
+        }
+    };
+    let body = serde_json::to_vec(&serde_json::json!({ "Ok": note }))?;
+    let etag = format!("\"{}\"", sha256_hex(&body));
+    headers.insert(
+        "Cache-Control".to_string(),
+        PUBLIC_NOTE_CACHE_CONTROL.to_string(),
+    );
+    headers.insert("ETag".to_string(), etag.clone());
+    let conditional = matches!(
+        http_request.method()?,
+        http::Method::GET | http::Method::HEAD
+    );
+    let unchanged = http_request
+        .headers()
+        .get("if-none-match")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == etag || tag == "*")
+        });
+    if conditional && unchanged {
+        return Ok((http::StatusCode::NOT_MODIFIED, headers, Vec::new()));
+    }
+    Ok((http::StatusCode::OK, headers, body))
+}
+
+const DEFAULT_PUBLIC_LIST_PAGE_SIZE: usize = 50;
+const MAX_PUBLIC_LIST_PAGE_SIZE: usize = 200;
+
+// Directory of public notes, most recently updated first, paged with ?page=1&per_page=50
+fn handle_public_list(
+    http_request: &http::server::IncomingHttpRequest,
+    state: &State,
+) -> anyhow::Result<()> {
+    if http_request.method()? != http::Method::GET {
+        http::server::send_response(http::StatusCode::METHOD_NOT_ALLOWED, None, vec![]);
+        return Ok(());
+    }
+    let query = http_request.query_params();
+    let page: usize = query
+        .get("page")
+        .and_then(|page| page.parse().ok())
+        .unwrap_or(1)
+        .max(1);
+    let per_page: usize = query
+        .get("per_page")
+        .and_then(|per_page| per_page.parse().ok())
+        .unwrap_or(DEFAULT_PUBLIC_LIST_PAGE_SIZE)
+        .clamp(1, MAX_PUBLIC_LIST_PAGE_SIZE);
+
+    let mut notes: Vec<&NoteMetadata> = state
+        .notes
+        .values()
+        .filter(|n| n.is_public && !n.noindex)
+        .collect();
+    notes.sort_by(|a, b| b.updated.cmp(&a.updated).then_with(|| a.name.cmp(&b.name)));
+    let total = notes.len();
+    let listed: Vec<serde_json::Value> = notes
+        .iter()
+        .skip((page - 1).saturating_mul(per_page))
+        .take(per_page)
+        .map(|note| {
+            serde_json::json!({
+                "id": note.id,
+                "title": note.name,
+                "updated": note.updated,
+            })
+        })
+        .collect();
+    let next_page = (page.saturating_mul(per_page) < total).then_some(page + 1);
+
+    let mut headers = cors_headers(&state.settings, http_request);
+    headers.insert("Content-Type".to_string(), "application/json".to_string());
+    let body = serde_json::json!({ "Ok": {
+        "notes": listed,
+        "total": total,
+        "page": page,
+        "per_page": per_page,
+        "next_page": next_page,
+    } });
+    send_compressible(
+        http_request,
+        http::StatusCode::OK,
+        headers,
+        serde_json::to_vec(&body)?,
+    )
+}
+
+// Sitemap of the public notes for search engines, with each note's URL under the origin the
+// sitemap itself was fetched from
+fn handle_public_sitemap(
+    http_request: &http::server::IncomingHttpRequest,
+    state: &State,
+) -> anyhow::Result<()> {
+    if http_request.method()? != http::Method::GET {
+        http::server::send_response(http::StatusCode::METHOD_NOT_ALLOWED, None, vec![]);
+        return Ok(());
+    }
+    let sitemap_url = http_request.url()?;
+    let mut notes: Vec<&NoteMetadata> = state
+        .notes
+        .values()
+        .filter(|n| n.is_public && !n.noindex)
+        .collect();
+    notes.sort_by(|a, b| b.updated.cmp(&a.updated).then_with(|| a.id.cmp(&b.id)));
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    );
+    for note in notes {
+        xml.push_str(&format!(
+            "<url><loc>{}</loc>",
+            escape_xml(sitemap_url.join(&note.id)?.as_str())
+        ));
+        if note.updated > 0 {
+            let (date, _) = amz_dates(note.updated);
+            xml.push_str(&format!(
+                "<lastmod>{}-{}-{}</lastmod>",
+                &date[..4],
+                &date[4..6],
+                &date[6..]
+            ));
+        }
+        xml.push_str("</url>\n");
+    }
+    xml.push_str("</urlset>\n");
+
+    let mut headers = cors_headers(&state.settings, http_request);
+    headers.insert(
+        "Content-Type".to_string(),
+        "application/xml; charset=utf-8".to_string(),
+    );
+    http::server::send_response(http::StatusCode::OK, Some(headers), xml.into_bytes());
+    Ok(())
+}
+
+// Unguessable token for a share link, also its URL path
+fn share_link_token() -> String {
+    let mut token = [0u8; 16];
+    OsRng.fill_bytes(&mut token);
+    token.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Anyone holding a share link reads its note, public or not, until the link expires or is revoked.
+// Snapshot links serve the note as it was when the link was made.
+fn handle_public_link(
+    http_request: &http::server::IncomingHttpRequest,
+    token: &str,
+    snapshot: bool,
+    state: &mut State,
+) -> anyhow::Result<()> {
+    if http_request.method()? != http::Method::GET {
+        http::server::send_response(http::StatusCode::METHOD_NOT_ALLOWED, None, vec![]);
+        return Ok(());
+    }
+    let cors = cors_headers(&state.settings, http_request);
+    let send = |status: http::StatusCode, body: serde_json::Value| -> anyhow::Result<()> {
+        let mut headers = cors.clone();
+        headers.insert("Content-Type".to_string(), "application/json".to_string());
+        send_compressible(http_request, status, headers, serde_json::to_vec(&body)?)
+    };
+    let index = state
+        .share_links
+        .iter()
+        .position(|link| link.token == token && link.taken.is_some() == snapshot);
+    let Some(index) = index else {
+        return send(
+            http::StatusCode::NOT_FOUND,
+            serde_json::json!({ "Err": "Link not found" }),
+        );
+    };
+    let link = &state.share_links[index];
+    if link.expires.is_some_and(|expires| expires <= now_secs()) {
+        return send(
+            http::StatusCode::GONE,
+            serde_json::json!({ "Err": "Link has expired" }),
+        );
+    }
+    let note_id = link.note_id.clone();
+    if state.is_unpublished(&note_id) {
+        return send(
+            http::StatusCode::NOT_FOUND,
+            serde_json::json!({ "Err": "Note is unpublished" }),
+        );
+    }
+    let note = if snapshot {
+        state.load_snapshot(token)?
+    } else {
+        match state.notes.get(&note_id) {
+            Some(metadata) if !metadata.is_locked => state.get_full_note(metadata)?,
+            _ => {
+                return send(
+                    http::StatusCode::NOT_FOUND,
+                    serde_json::json!({ "Err": "Note not found" }),
+                )
+            }
+        }
+    };
+    // A hit count lost to a crash doesn't matter, so anonymous reads don't each write state
+    state.share_links[index].hits += 1;
+    state.save_later()?;
+    send(
+        http::StatusCode::OK,
+        serde_json::json!({ "Ok": Note {
+            folder_id: None,
+            collaborators: Vec::new(),
+            ..note
+        } }),
+    )
+}
+
+// Visitors read the shown comments on a public note with GET, and add one with a POST of
+// {"author": ..., "text": ...}
+fn handle_public_comments(
+    http_request: &http::server::IncomingHttpRequest,
+    note_id: &str,
+    state: &mut State,
+) -> anyhow::Result<()> {
+    let cors = cors_headers(&state.settings, http_request);
+    let send = |status: http::StatusCode, body: serde_json::Value| -> anyhow::Result<()> {
+        let mut headers = cors.clone();
+        headers.insert("Content-Type".to_string(), "application/json".to_string());
+        send_compressible(http_request, status, headers, serde_json::to_vec(&body)?)
+    };
+    let enabled = state.notes.get(note_id).is_some_and(|note| note.is_public)
+        && state.comments.get(note_id).is_some_and(|c| c.enabled);
+    if !enabled {
+        return send(
+            http::StatusCode::NOT_FOUND,
+            serde_json::json!({ "Err": "Comments are not enabled on this note" }),
+        );
+    }
+    match http_request.method()? {
+        http::Method::GET => {
+            let shown: Vec<&Comment> = state.comments[note_id]
+                .comments
+                .iter()
+                .filter(|c| !c.hidden)
+                .collect();
+            send(http::StatusCode::OK, serde_json::json!({ "Ok": shown }))
+        }
+        http::Method::POST => {
+            #[derive(Deserialize)]
+            struct NewComment {
+                #[serde(default)]
+                author: String,
+                text: String,
+            }
+            let Some(new) =
+                last_blob().and_then(|body| serde_json::from_slice::<NewComment>(&body.bytes).ok())
+            else {
+                return send(
+                    http::StatusCode::BAD_REQUEST,
+                    serde_json::json!({ "Err": "Expected {\"author\": ..., \"text\": ...}" }),
+                );
+            };
+            let text = new.text.trim();
+            let author = new.author.trim();
+            if text.is_empty()
+                || text.chars().count() > MAX_COMMENT_CHARS
+                || author.chars().count() > MAX_COMMENT_AUTHOR_CHARS
+            {
+                return send(
+                    http::StatusCode::BAD_REQUEST,
+                    serde_json::json!({ "Err": format!(
+                        "Comments need text of at most {} characters, and a name of at most {}",
+                        MAX_COMMENT_CHARS, MAX_COMMENT_AUTHOR_CHARS
+                    ) }),
+                );
+            }
+            let now = now_secs();
+            let client = client_ip(http_request);
+            // Forget clients whose comments have all left the window
+            state.comment_posts.retain(|_, posts| {
+                posts.retain(|time| now.saturating_sub(*time) < COMMENT_RATE_WINDOW_SECS);
+                !posts.is_empty()
+            });
+            let posts = state.comment_posts.entry(client).or_default();
+            let note_comments = state.comments.get_mut(note_id).unwrap();
+            if posts.len() >= COMMENT_RATE_LIMIT
+                || note_comments.comments.len() >= MAX_COMMENTS_PER_NOTE
+            {
+                return send(
+                    http::StatusCode::TOO_MANY_REQUESTS,
+                    serde_json::json!({ "Err": "Too many comments, try again later" }),
+                );
+            }
+            posts.push(now);
+            let comment = Comment {
+                id: State::generate_id(),
+                author: if author.is_empty() {
+                    "Anonymous"
+                } else {
+                    author
+                }
+                .to_string(),
+                text: text.to_string(),
+                created: now,
+                hidden: false,
+            };
+            note_comments.comments.push(comment.clone());
+            state.save_to_disk()?;
+            send(http::StatusCode::OK, serde_json::json!({ "Ok": comment }))
+        }
+        _ => send(
+            http::StatusCode::METHOD_NOT_ALLOWED,
+            serde_json::json!({ "Err": "Method not allowed" }),
+        ),
+    }
+}
+
+// The API's WIT definitions, from which the schema served at /api/schema is generated
+const API_WIT: &str = include_str!("../../api/wifenote:nick.hypr-v0.wit");
+
+// "note-type" -> "NoteType", as wit-bindgen names types and variant cases
+fn wit_camel_case(name: &str) -> String {
+    name.split('-')
+        .map(|part| {
+            let mut chars = part.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+// Split on commas outside of angle brackets and parentheses
+fn split_wit_list(list: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in list.char_indices() {
+        match c {
+            '<' | '(' => depth += 1,
+            '>' | ')' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(list[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(list[start..].trim());
+    items.retain(|item| !item.is_empty());
+    items
+}
+
+// JSON Schema of a WIT type as serde encodes it
+fn wit_type_schema(ty: &str) -> serde_json::Value {
+    let ty = ty.trim();
+    let (outer, inner) = match ty.split_once('<') {
+        Some((outer, rest)) => (outer, rest.strip_suffix('>').unwrap_or(rest)),
+        None => (ty, ""),
+    };
+    match outer {
+        "_" => serde_json::json!({ "type": "null" }),
+        "bool" => serde_json::json!({ "type": "boolean" }),
+        "string" | "char" => serde_json::json!({ "type": "string" }),
+        "u8" | "u16" | "u32" | "u64" | "s8" | "s16" | "s32" | "s64" => {
+            serde_json::json!({ "type": "integer" })
+        }
+        "f32" | "f64" => serde_json::json!({ "type": "number" }),
+        "list" => serde_json::json!({ "type": "array", "items": wit_type_schema(inner) }),
+        "option" => serde_json::json!({ "anyOf": [wit_type_schema(inner), { "type": "null" }] }),
+        "tuple" => {
+            let items: Vec<serde_json::Value> = split_wit_list(inner)
+                .into_iter()
+                .map(wit_type_schema)
+                .collect();
+            serde_json::json!({
+                "type": "array",
+                "prefixItems": items,
+                "minItems": items.len(),
+                "maxItems": items.len(),
+            })
+        }
+        "result" => {
+            let args = split_wit_list(inner);
+            let case = |name: &str, ty: Option<&&str>| {
+                serde_json::json!({
+                    "type": "object",
+                    "properties": { name: wit_type_schema(ty.copied().unwrap_or("_")) },
+                    "required": [name],
+                    "additionalProperties": false,
+                })
+            };
+            serde_json::json!({ "oneOf": [case("Ok", args.first()), case("Err", args.get(1))] })
+        }
+        name => serde_json::json!({ "$ref": format!("#/$defs/{}", wit_camel_case(name)) }),
+    }
+}
+
+// JSON Schema of every type in the API's WIT, as serde encodes them in requests and responses
+fn api_schema() -> serde_json::Value {
+    let mut defs = serde_json::Map::new();
+    let mut doc = String::new();
+    let mut lines = API_WIT.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        if let Some(comment) = line.strip_prefix("///") {
+            doc = comment.trim().to_string();
+            continue;
+        }
+        let line = line.split("//").next().unwrap_or_default().trim();
+        let mut words = line.split_whitespace();
+        let (Some(kind), Some(name)) = (words.next(), words.next()) else {
+            continue;
+        };
+        let schema = match kind {
+            "type" => match line.split_once('=') {
+                Some((_, ty)) => wit_type_schema(ty.trim_end_matches(';')),
+                None => continue,
+            },
+            "record" | "enum" | "variant" => {
+                let mut body = String::new();
+                for line in lines.by_ref().take_while(|line| *line != "}") {
+                    if !line.starts_with("///") {
+                        body.push_str(line.split("//").next().unwrap_or_default());
+                    }
+                }
+                let items = split_wit_list(&body);
+                match kind {
+                    "record" => {
+                        let properties: serde_json::Map<String, serde_json::Value> = items
+                            .iter()
+                            .filter_map(|item| item.split_once(':'))
+                            .map(|(field, ty)| {
+                                (field.trim().replace('-', "_"), wit_type_schema(ty))
+                            })
+                            .collect();
+                        let required: Vec<&String> = properties.keys().collect();
+                        serde_json::json!({
+                            "type": "object",
+                            "properties": properties,
+                            "required": required,
+                        })
+                    }
+                    "enum" => {
+                        let cases: Vec<String> =
+                            items.iter().map(|case| wit_camel_case(case)).collect();
+                        serde_json::json!({ "type": "string", "enum": cases })
+                    }
+                    _ => {
+                        let cases: Vec<serde_json::Value> = items
+                            .iter()
+                            .map(|case| match case.split_once('(') {
+                                Some((case, payload)) => {
+                                    let case = wit_camel_case(case.trim());
+                                    let payload = payload.strip_suffix(')').unwrap_or(payload);
+                                    serde_json::json!({
+                                        "type": "object",
+                                        "properties": { case.clone(): wit_type_schema(payload) },
+                                        "required": [case],
+                                        "additionalProperties": false,
+                                    })
+                                }
+                                None => serde_json::json!({ "const": wit_camel_case(case) }),
+                            })
+                            .collect();
+                        serde_json::json!({ "oneOf": cases })
+                    }
+                }
+            }
+            _ => {
+                doc.clear();
+                continue;
+            }
+        };
+        let mut schema = schema;
+        if !doc.is_empty() {
+            schema["description"] = serde_json::Value::String(std::mem::take(&mut doc));
+        }
+        defs.insert(wit_camel_case(name), schema);
+    }
+    serde_json::json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "wifenote API",
+        "description": "POST /api takes a Request as JSON and answers with the matching Response",
+        "$defs": defs,
+        "anyOf": [{ "$ref": "#/$defs/Request" }, { "$ref": "#/$defs/Response" }],
+    })
+}
+
+// A note as the REST routes show it, with its content as text
+#[derive(Debug, Serialize)]
+struct RestNote {
+    id: String,
+    name: String,
+    folder_id: Option<String>,
+    note_type: NoteType,
+    content: String,
+    is_public: bool,
+    collaborators: Vec<String>,
+    tags: Vec<String>,
+}
+
+impl From<Note> for RestNote {
+    fn from(note: Note) -> Self {
+        RestNote {
+            id: note.id,
+            name: note.name,
+            folder_id: note.folder_id,
+            note_type: note.note_type,
+            content: String::from_utf8_lossy(&note.content).to_string(),
+            is_public: note.is_public,
+            collaborators: note.collaborators,
+            tags: note.tags,
+        }
+    }
+}
+
+// Body of POST /api/notes
+#[derive(Debug, Deserialize)]
+struct RestNewNote {
+    name: String,
+    #[serde(default)]
+    folder_id: Option<String>,
+    #[serde(default)]
+    note_type: Option<NoteType>, // The default note type from settings if missing
+    #[serde(default)]
+    content: Option<String>,
+}
+
+fn send_json(
+    http_request: &http::server::IncomingHttpRequest,
+    status: http::StatusCode,
+    value: &impl Serialize,
+) -> anyhow::Result<()> {
+    let headers = HashMap::from([("Content-Type".to_string(), "application/json".to_string())]);
+    send_compressible(http_request, status, headers, serde_json::to_vec(value)?)
+}
+
+// Bodies smaller than this gain too little from compression to be worth it
+const GZIP_MIN_BYTES: usize = 1024;
+
+// Sends a response, gzipped if the request accepts gzip and the body is big enough
+fn send_compressible(
+    http_request: &http::server::IncomingHttpRequest,
+    status: http::StatusCode,
+    mut headers: HashMap<String, String>,
+    body: Vec<u8>,
+) -> anyhow::Result<()> {
+    let accepts_gzip = http_request
+        .headers()
+        .get("accept-encoding")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value.split(',').any(|coding| {
+                let mut params = coding.split(';').map(str::trim);
+                params.next() == Some("gzip")
+                    && params.all(|param| {
+                        param
+                            .strip_prefix("q=")
+                            .and_then(|q| q.parse::<f32>().ok())
+                            .is_none_or(|q| q > 0.0)
+                    })
+            })
+        });
+    match headers.get_mut("Vary") {
+        Some(vary) => vary.push_str(", Accept-Encoding"),
+        None => {
+            headers.insert("Vary".to_string(), "Accept-Encoding".to_string());
+        }
+    }
+    if !accepts_gzip || body.len() < GZIP_MIN_BYTES {
+        http::server::send_response(status, Some(headers), body);
+        return Ok(());
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&body)?;
+    headers.insert("Content-Encoding".to_string(), "gzip".to_string());
+    http::server::send_response(status, Some(headers), encoder.finish()?);
+    Ok(())
+}
+
+// Address of the client behind a request. A request from a reverse proxy on this machine or
+// the local network carries the client in X-Forwarded-For; the last entry is the one the proxy
+// added, since anything before it came from the client and can be made up.
+fn client_ip(http_request: &http::server::IncomingHttpRequest) -> String {
+    let Some(peer) = http_request.source_socket_addr().ok().map(|addr| addr.ip()) else {
+        return String::new();
+    };
+    let from_proxy = match peer {
+        std::net::IpAddr::V4(ip) => ip.is_loopback() || ip.is_private(),
+        std::net::IpAddr::V6(ip) => ip.is_loopback(),
+    };
+    let forwarded = http_request
+        .headers()
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .map(str::trim)
+        .and_then(|client| client.parse::<std::net::IpAddr>().ok());
+    match forwarded {
+        Some(client) if from_proxy => client.to_string(),
+        _ => peer.to_string(),
+    }
+}
+
+// Why a REST request failed, which decides its status
+enum RestError {
+    BadRequest,
+    NotFound,
+    Forbidden,
+    QuotaExceeded,
+    Internal,
+}
+
+impl RestError {
+    // A refused request on a note, judged by the note rather than the wording of the error
+    fn for_note(state: &State, id: &str) -> Self {
+        match state.notes.get(id) {
+            None => RestError::NotFound,
+            Some(note) if note.is_locked || note.read_only => RestError::Forbidden,
+            Some(_) => RestError::BadRequest,
+        }
+    }
+
+    // A refused request to create a note in a folder
+    fn for_folder(state: &State, folder_id: Option<&str>) -> Self {
+        match folder_id {
+            Some(id) if !state.folders.contains_key(id) => RestError::NotFound,
+            _ => RestError::BadRequest,
+        }
+    }
+
+    fn status(&self) -> http::StatusCode {
+        match self {
+            RestError::BadRequest => http::StatusCode::BAD_REQUEST,
+            RestError::NotFound => http::StatusCode::NOT_FOUND,
+            RestError::Forbidden => http::StatusCode::FORBIDDEN,
+            RestError::QuotaExceeded => http::StatusCode::INSUFFICIENT_STORAGE,
+            RestError::Internal => http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+fn send_rest_error(kind: RestError, error: &str) -> anyhow::Result<()> {
+    let status = kind.status();
+    let headers = HashMap::from([("Content-Type".to_string(), "application/json".to_string())]);
+    let body = serde_json::to_vec(&serde_json::json!({ "error": error }))?;
+    http::server::send_response(status, Some(headers), body);
+    Ok(())
+}
+
+// Resource-oriented routes over the same operations as POST /api, for standard HTTP tooling.
+// Returns false for paths that are not REST routes.
+fn handle_rest_request(
+    http_request: &http::server::IncomingHttpRequest,
+    path: &str,
+    state: &mut State,
+    server: &http::server::HttpServer,
+) -> anyhow::Result<bool> {
+    match handle_rest_route(http_request, path, state, server) {
+        Ok(handled) => Ok(handled),
+        Err(e) => {
+            if let Some(e) = e.downcast_ref::<QuotaError>() {
+                send_rest_error(RestError::QuotaExceeded, &e.to_string())?;
+                return Ok(true);
+            }
+            error!("rest: {} {}: {}", http_request.method()?, path, e);
+            send_rest_error(RestError::Internal, "Internal error")?;
+            Ok(true)
+        }
+    }
+}
+
+// Run a note request for a REST or WebDAV client. Content refused for the quota comes back as
+// a QuotaError, so the handler can answer 507 whichever request it made.
+fn quota_checked_request(
+    request: NoteRequest,
+    state: &mut State,
+    server: &http::server::HttpServer,
+) -> anyhow::Result<NoteResponse> {
+    match handle_note_request(request, Some(&our()), state, server)? {
+        NoteResponse::QuotaExceeded(e) => Err(e.into()),
+        resp => Ok(resp),
+    }
+}
+
+fn handle_rest_route(
+    http_request: &http::server::IncomingHttpRequest,
+    path: &str,
+    state: &mut State,
+    server: &http::server::HttpServer,
+) -> anyhow::Result<bool> {
+    let method = http_request.method()?;
+    let note_id = path
+        .strip_prefix("/api/notes/")
+        .filter(|id| !id.is_empty() && !id.contains('/'));
+    let body = || last_blob().map(|blob| blob.bytes).unwrap_or_default();
+    match (&method, path, note_id) {
+        (&http::Method::GET, "/api/schema", _) => {
+            send_json(http_request, http::StatusCode::OK, &api_schema())?
+        }
+
+        (&http::Method::GET, "/api/folders", _) => {
+            let mut folders: Vec<&Folder> = state.folders.values().collect();
+            folders.sort_by(|a, b| a.name.cmp(&b.name));
+            send_json(http_request, http::StatusCode::OK, &folders)?;
+        }
+
+        (&http::Method::GET, "/api/notes", _) => {
+            let request = NoteRequest::GetStructure;
+            match quota_checked_request(request, state, server)? {
+                NoteResponse::GetStructure(Ok((_, notes))) => {
+                    let notes: Vec<RestNote> = notes.into_iter().map(RestNote::from).collect();
+                    send_json(http_request, http::StatusCode::OK, &notes)?;
+                }
+                NoteResponse::GetStructure(Err(e)) => send_rest_error(RestError::BadRequest, &e)?,
+                _ => return Err(anyhow::anyhow!("Unexpected response to GetStructure")),
+            }
+        }
+
+        (&http::Method::POST, "/api/notes", _) => {
+            let Ok(new_note) = serde_json::from_slice::<RestNewNote>(&body()) else {
+                send_rest_error(
+                    RestError::BadRequest,
+                    "Body must be a JSON object with at least a name",
+                )?;
+                return Ok(true);
+            };
+            let note_type = new_note
+                .note_type
+                .unwrap_or(state.settings.default_note_type);
+            let error_kind = RestError::for_folder(state, new_note.folder_id.as_deref());
+            let request = NoteRequest::CreateNote((new_note.name, new_note.folder_id, note_type));
+            let mut note = match quota_checked_request(request, state, server)? {
+                NoteResponse::CreateNote(Ok(note)) => note,
+                NoteResponse::CreateNote(Err(e)) => {
+                    send_rest_error(error_kind, &e)?;
+                    return Ok(true);
+                }
+                _ => return Err(anyhow::anyhow!("Unexpected response to CreateNote")),
+            };
+            if let Some(content) = new_note.content {
+                let request =
+                    NoteRequest::UpdateNoteContent((note.id.clone(), content.clone().into_bytes()));
+                let updated = quota_checked_request(request, state, server);
+                if !matches!(updated, Ok(NoteResponse::UpdateNoteContent(Ok(())))) {
+                    // Leave nothing behind for content that could not be stored
+                    let request = NoteRequest::DeleteNote(note.id.clone());
+                    quota_checked_request(request, state, server)?;
+                }
+                match updated? {
+                    NoteResponse::UpdateNoteContent(Ok(())) => note.content = content.into_bytes(),
+                    NoteResponse::UpdateNoteContent(Err(e)) => {
+                        send_rest_error(RestError::BadRequest, &e)?;
+                        return Ok(true);
+                    }
+                    _ => return Err(anyhow::anyhow!("Unexpected response to UpdateNoteContent")),
+                }
+            }
+            let headers = HashMap::from([
+                ("Content-Type".to_string(), "application/json".to_string()),
+                (
+                    "Location".to_string(),
+                    format!("/{}/api/notes/{}", our().process, note.id),
+                ),
+            ]);
+            send_compressible(
+                http_request,
+                http::StatusCode::CREATED,
+                headers,
+                serde_json::to_vec(&RestNote::from(note))?,
+            )?;
+        }
+
+        (&http::Method::GET, _, Some(id)) => {
+            let request = NoteRequest::GetNote(id.to_string());
+            match quota_checked_request(request, state, server)? {
+                NoteResponse::GetNote(Ok(note)) => {
+                    send_json(http_request, http::StatusCode::OK, &RestNote::from(note))?
+                }
+                NoteResponse::GetNote(Err(e)) => {
+                    send_rest_error(RestError::for_note(state, id), &e)?
+                }
+                _ => return Err(anyhow::anyhow!("Unexpected response to GetNote")),
+            }
+        }
+
+        (&http::Method::PUT, _, Some(id)) => {
+            let request = NoteRequest::UpdateNoteContent((id.to_string(), body()));
+            match quota_checked_request(request, state, server)? {
+                NoteResponse::UpdateNoteContent(Ok(())) => {
+                    http::server::send_response(http::StatusCode::NO_CONTENT, None, vec![])
+                }
+                NoteResponse::UpdateNoteContent(Err(e)) => {
+                    send_rest_error(RestError::for_note(state, id), &e)?
+                }
+                _ => return Err(anyhow::anyhow!("Unexpected response to UpdateNoteContent")),
+            }
+        }
+
+        (&http::Method::DELETE, _, Some(id)) => {
+            let request = NoteRequest::DeleteNote(id.to_string());
+            match quota_checked_request(request, state, server)? {
+                NoteResponse::DeleteNote(Ok(())) => {
+                    http::server::send_response(http::StatusCode::NO_CONTENT, None, vec![])
+                }
+                NoteResponse::DeleteNote(Err(e)) => {
+                    send_rest_error(RestError::for_note(state, id), &e)?
+                }
+                _ => return Err(anyhow::anyhow!("Unexpected response to DeleteNote")),
+            }
+        }
+
+        // Table notes as CSV: POST a CSV body with ?name=...&folder_id=... to import one
+        (&http::Method::POST, "/api/tables", _) => {
+            let query = http_request.query_params();
+            let Some(name) = query.get("name").filter(|name| !name.is_empty()) else {
+                send_rest_error(RestError::BadRequest, "A name query parameter is required")?;
+                return Ok(true);
+            };
+            let request =
+                NoteRequest::ImportCsv((name.clone(), query.get("folder_id").cloned(), body()));
+            let note = match quota_checked_request(request, state, server)? {
+                NoteResponse::ImportCsv(Ok(note)) => note,
+                NoteResponse::ImportCsv(Err(e)) => {
+                    let folder_id = query.get("folder_id").map(String::as_str);
+                    send_rest_error(RestError::for_folder(state, folder_id), &e)?;
+                    return Ok(true);
+                }
+                _ => return Err(anyhow::anyhow!("Unexpected response to ImportCsv")),
+            };
+            let headers = HashMap::from([
+                ("Content-Type".to_string(), "application/json".to_string()),
+                (
+                    "Location".to_string(),
+                    format!("/{}/api/tables/{}", our().process, note.id),
+                ),
+            ]);
+            send_compressible(
+                http_request,
+                http::StatusCode::CREATED,
+                headers,
+                serde_json::to_vec(&RestNote::from(note))?,
+            )?;
+        }
+
+        (&http::Method::GET, _, _) if path.starts_with("/api/tables/") => {
+            let id = &path["/api/tables/".len()..];
+            let request = NoteRequest::GetNote(id.to_string());
+            match quota_checked_request(request, state, server)? {
+                NoteResponse::GetNote(Ok(note)) if note.note_type == NoteType::Table => {
+                    let headers = HashMap::from([
+                        (
+                            "Content-Type".to_string(),
+                            "text/csv; charset=utf-8".to_string(),
+                        ),
+                        (
+                            "Content-Disposition".to_string(),
+                            content_disposition(&format!("{}.csv", sanitize_file_name(&note.name))),
+                        ),
+                    ]);
+                    http::server::send_response(http::StatusCode::OK, Some(headers), note.content);
+                }
+                NoteResponse::GetNote(Ok(_)) => {
+                    send_rest_error(RestError::BadRequest, "Not a table note")?
+                }
+                NoteResponse::GetNote(Err(e)) => {
+                    send_rest_error(RestError::for_note(state, id), &e)?
+                }
+                _ => return Err(anyhow::anyhow!("Unexpected response to GetNote")),
+            }
+        }
+
+        // PDF documents: POST one to /api/pdfs?name=...&folder_id=..., and read it back from
+        // /api/notes/<note id>/download
+        (&http::Method::POST, "/api/pdfs", _) => {
+            let query = http_request.query_params();
+            let Some(name) = query.get("name").filter(|name| !name.is_empty()) else {
+                send_rest_error(RestError::BadRequest, "A name query parameter is required")?;
+                return Ok(true);
+            };
+            let request =
+                NoteRequest::ImportPdf((name.clone(), query.get("folder_id").cloned(), body()));
+            let note = match quota_checked_request(request, state, server)? {
+                NoteResponse::ImportPdf(Ok(note)) => note,
+                NoteResponse::ImportPdf(Err(e)) => {
+                    let folder_id = query.get("folder_id").map(String::as_str);
+                    send_rest_error(RestError::for_folder(state, folder_id), &e)?;
+                    return Ok(true);
+                }
+                _ => return Err(anyhow::anyhow!("Unexpected response to ImportPdf")),
+            };
+            let headers = HashMap::from([
+                ("Content-Type".to_string(), "application/json".to_string()),
+                (
+                    "Location".to_string(),
+                    format!("/{}/api/notes/{}/download", our().process, note.id),
+                ),
+            ]);
+            // The document is binary, so leave it out of the JSON
+            let mut rest_note = RestNote::from(note);
+            rest_note.content = String::new();
+            http::server::send_response(
+                http::StatusCode::CREATED,
+                Some(headers),
+                serde_json::to_vec(&rest_note)?,
+            );
+        }
+
+        // Voice memos: POST a recording, with its Content-Type, to
+        // /api/audio?name=...&folder_id=...&duration_ms=..., and play it back from
+        // /api/audio/<note id>, which answers Range requests so players can seek
+        (&http::Method::POST, "/api/audio", _) => {
+            let query = http_request.query_params();
+            let Some(name) = query.get("name").filter(|name| !name.is_empty()) else {
+                send_rest_error(RestError::BadRequest, "A name query parameter is required")?;
+                return Ok(true);
+            };
+            let mime = http_request
+                .headers()
+                .get("content-type")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            let request = NoteRequest::CreateAudioNote((
+                name.clone(),
+                query.get("folder_id").cloned(),
+                mime,
+                body(),
+                query.get("duration_ms").and_then(|ms| ms.parse().ok()),
+            ));
+            let note = match quota_checked_request(request, state, server)? {
+                NoteResponse::CreateAudioNote(Ok(note)) => note,
+                NoteResponse::CreateAudioNote(Err(e)) => {
+                    let folder_id = query.get("folder_id").map(String::as_str);
+                    send_rest_error(RestError::for_folder(state, folder_id), &e)?;
+                    return Ok(true);
+                }
+                _ => return Err(anyhow::anyhow!("Unexpected response to CreateAudioNote")),
+            };
+            let headers = HashMap::from([
+                ("Content-Type".to_string(), "application/json".to_string()),
+                (
+                    "Location".to_string(),
+                    format!("/{}/api/audio/{}", our().process, note.id),
+                ),
+            ]);
+            send_compressible(
+                http_request,
+                http::StatusCode::CREATED,
+                headers,
+                serde_json::to_vec(&RestNote::from(note))?,
+            )?;
+        }
+
+        (&http::Method::GET, _, _) if path.starts_with("/api/audio/") => {
+            let id = &path["/api/audio/".len()..];
+            let request = NoteRequest::GetNote(id.to_string());
+            let note = match quota_checked_request(request, state, server)? {
+                NoteResponse::GetNote(Ok(note)) => note,
+                NoteResponse::GetNote(Err(e)) => {
+                    send_rest_error(RestError::for_note(state, id), &e)?;
+                    return Ok(true);
+                }
+                _ => return Err(anyhow::anyhow!("Unexpected response to GetNote")),
+            };
+            let audio = match serde_json::from_slice::<AudioContent>(&note.content) {
+                Ok(audio) if note.note_type == NoteType::Audio => audio,
+                _ => {
+                    send_rest_error(RestError::BadRequest, "Not an audio note")?;
+                    return Ok(true);
+                }
+            };
+            let content = state.load_attachment_content(&audio.attachment_id)?;
+            let mut headers = HashMap::from([
+                ("Content-Type".to_string(), audio.mime),
+                ("Accept-Ranges".to_string(), "bytes".to_string()),
+            ]);
+            let range = http_request
+                .headers()
+                .get("range")
+                .and_then(|value| value.to_str().ok())
+                .map(|range| parse_byte_range(range, content.len()));
+            match range {
+                None => http::server::send_response(http::StatusCode::OK, Some(headers), content),
+                Some(Some((start, end))) => {
+                    headers.insert(
+                        "Content-Range".to_string(),
+                        format!("bytes {}-{}/{}", start, end, content.len()),
+                    );
+                    http::server::send_response(
+                        http::StatusCode::PARTIAL_CONTENT,
+                        Some(headers),
+                        content[start..=end].to_vec(),
+                    );
+                }
+                Some(None) => {
+                    headers.insert(
+                        "Content-Range".to_string(),
+                        format!("bytes */{}", content.len()),
+                    );
+                    http::server::send_response(
+                        http::StatusCode::RANGE_NOT_SATISFIABLE,
+                        Some(headers),
+                        vec![],
+                    );
+                }
+            }
+        }
+
+        (
+            _,
+            "/api/folders" | "/api/notes" | "/api/schema" | "/api/tables" | "/api/audio"
+            | "/api/pdfs",
+            _,
+        )
+        | (_, _, Some(_)) => {
+            http::server::send_response(http::StatusCode::METHOD_NOT_ALLOWED, None, vec![]);
+        }
+
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn send_dav_response(status: http::StatusCode, headers: Vec<(&str, String)>, body: Vec<u8>) {
+    let headers = headers
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+    http::server::send_response(status, Some(headers), body);
+}
+
+// Check HTTP Basic credentials against the WebDAV password
+fn is_webdav_authorized(
+    authorization: Option<&str>,
+    client: &str,
+    state: &mut State,
+) -> anyhow::Result<bool> {
+    let (Some(authorization), Some(credentials)) = (authorization, state.webdav.clone()) else {
+        return Ok(false);
+    };
+    if let Some(authorized) = &state.webdav_authorized {
+        if constant_time_eq(authorized.as_bytes(), authorization.as_bytes()) {
+            return Ok(true);
+        }
+    }
+    let Some(encoded) = authorization.strip_prefix("Basic ") else {
+        return Ok(false);
+    };
+    use base64::Engine;
+    let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(encoded.trim()) else {
+        return Ok(false);
+    };
+    let decoded = String::from_utf8_lossy(&decoded);
+    let Some((username, password)) = decoded.split_once(':') else {
+        return Ok(false);
+    };
+    // Refused before hashing, which is what makes guessing slow and costly
+    if !state.may_try_password(client) {
+        return Ok(false);
+    }
+    // Both checks always run, so a wrong username takes as long as a wrong password
+    let username_matches = constant_time_eq(username.as_bytes(), credentials.username.as_bytes());
+    let password_hash = hash_password(password, &credentials.salt)?;
+    let authorized =
+        username_matches && constant_time_eq(&password_hash, &credentials.password_hash);
+    if authorized {
+        state.webdav_authorized = Some(authorization.to_string());
+    } else {
+        state.record_wrong_password(client.to_string());
+    }
+    Ok(authorized)
+}
+
+// WebDAV paths of every folder (with a trailing '/') and note, the same names an export uses
+fn dav_items(state: &State) -> HashMap<String, DavItem> {
+    let paths = state.export_paths();
+    let mut items = HashMap::from([(String::new(), DavItem::Root)]);
+    for (id, path) in paths.folders {
+        items.insert(path, DavItem::Folder(id));
+    }
+    for (id, path) in paths.notes {
+        if state.notes.get(&id).is_some_and(|n| !n.is_locked) {
+            items.insert(path, DavItem::Note(id));
+        }
+    }
+    items
+}
+
+// Look a decoded WebDAV path up, accepting collections with or without their trailing '/'
+fn resolve_dav_path(items: &HashMap<String, DavItem>, path: &str) -> Option<(String, DavItem)> {
+    let path = path.trim_start_matches('/');
+    let folder_path = format!("{}/", path.trim_end_matches('/'));
+    [path.to_string(), folder_path]
+        .into_iter()
+        .find_map(|p| Some((p.clone(), items.get(&p)?.clone())))
+}
+
+// The folder a new item at `path` goes in, and its name; None if the parent does not exist
+fn dav_parent(items: &HashMap<String, DavItem>, path: &str) -> Option<(Option<String>, String)> {
+    let path = path.trim_matches('/');
+    let (parent, name) = match path.rsplit_once('/') {
+        Some((parent, name)) => (format!("{}/", parent), name),
+        None => (String::new(), path),
+    };
+    match items.get(&parent)? {
+        DavItem::Root => Some((None, name.to_string())),
+        DavItem::Folder(id) => Some((Some(id.clone()), name.to_string())),
+        DavItem::Note(_) => None,
+    }
+}
+
+// Split a note file name into the note name and type
+fn dav_note_name(file_name: &str) -> Option<(String, NoteType)> {
+    let (stem, ext) = file_name.rsplit_once('.')?;
+    let note_type = match ext.to_lowercase().as_str() {
+        "md" | "markdown" => NoteType::Markdown,
+        "txt" => NoteType::PlainText,
+        "json" => NoteType::Tldraw,
+        "csv" => NoteType::Table,
+        _ => return None,
+    };
+    Some((stem.to_string(), note_type))
+}
+
+fn dav_propfind_entry(state: &State, path: &str, item: &DavItem) -> String {
+    let href = format!(
+        "/{}/dav/{}",
+        our().process,
+        path.split('/')
+            .map(|segment| percent_encode(segment, b"-._~"))
+            .collect::<Vec<_>>()
+            .join("/")
+    );
+    let props = match item {
+        DavItem::Root => "<D:displayname>wifenote</D:displayname>\
+             <D:resourcetype><D:collection/></D:resourcetype>"
+            .to_string(),
+        DavItem::Folder(id) => format!(
+            "<D:displayname>{}</D:displayname><D:resourcetype><D:collection/></D:resourcetype>",
+            escape_xml(state.folders.get(id).map_or("", |f| f.name.as_str()))
+        ),
+        DavItem::Note(id) => {
+            let Some(metadata) = state.notes.get(id) else {
+                return String::new();
+            };
+            let size = state.load_note_content(id).map_or(0, |c| c.len());
+            let content_type = match metadata.note_type {
+                NoteType::Markdown => "text/markdown",
+                NoteType::Tldraw => "application/json",
+                NoteType::Table => "text/csv",
+                NoteType::PlainText => "text/plain",
+                NoteType::Audio => "application/json",
+                NoteType::Pdf => "application/pdf",
+            };
+            format!(
+                "<D:displayname>{}</D:displayname><D:resourcetype/>\
+                 <D:getcontentlength>{}</D:getcontentlength>\
+                 <D:getcontenttype>{}</D:getcontenttype>",
+                escape_xml(&metadata.name),
+                size,
+                content_type
+            )
+        }
+    };
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop>\
+         <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        escape_xml(&href),
+        props
+    )
+}
+
+// Serve the note tree over WebDAV: folders are collections, notes are files named as in exports.
+// Clients authenticate with HTTP Basic auth against the credentials set by SetWebdavCredentials,
+// since desktop and mobile WebDAV clients cannot log in to the node.
+fn handle_webdav_request(
+    http_request: &http::server::IncomingHttpRequest,
+    path: &str,
+    state: &mut State,
+    server: &http::server::HttpServer,
+) -> anyhow::Result<()> {
+    let headers = http_request.headers();
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    if state.webdav.is_none() {
+        send_dav_response(
+            http::StatusCode::FORBIDDEN,
+            vec![],
+            b"WebDAV access is disabled".to_vec(),
+        );
+        return Ok(());
+    }
+    if !is_webdav_authorized(header("authorization"), &client_ip(http_request), state)? {
+        send_dav_response(
+            http::StatusCode::UNAUTHORIZED,
+            vec![(
+                "WWW-Authenticate",
+                "Basic realm=\"wifenote\", charset=\"UTF-8\"".to_string(),
+            )],
+            vec![],
+        );
+        return Ok(());
+    }
+    if let Err(e) = handle_webdav_method(http_request, path, state, server) {
+        match e.downcast_ref::<QuotaError>() {
+            Some(e) => send_dav_error(http::StatusCode::INSUFFICIENT_STORAGE, e.to_string()),
+            None => {
+                error!("webdav: {}", e);
+                send_dav_response(http::StatusCode::INTERNAL_SERVER_ERROR, vec![], vec![]);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Run a note request for a WebDAV client, returning the error a failed request gives
+fn dav_note_request(
+    request: NoteRequest,
+    state: &mut State,
+    server: &http::server::HttpServer,
+) -> anyhow::Result<Result<NoteResponse, String>> {
+    let response = quota_checked_request(request, state, server)?;
+    Ok(match response_error(&response) {
+        Some(error) => Err(error),
+        None => Ok(response),
+    })
+}
+
+fn send_dav_error(status: http::StatusCode, error: String) {
+    send_dav_response(
+        status,
+        vec![("Content-Type", "text/plain; charset=utf-8".to_string())],
+        error.into_bytes(),
+    );
+}
+
+fn handle_webdav_method(
+    http_request: &http::server::IncomingHttpRequest,
+    path: &str,
+    state: &mut State,
+    server: &http::server::HttpServer,
+) -> anyhow::Result<()> {
+    let headers = http_request.headers();
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let path = percent_decode(path);
+    let items = dav_items(state);
+    let target = resolve_dav_path(&items, &path);
+    let method = http_request.method()?;
+    match (method.as_str(), target) {
+        ("OPTIONS", _) => send_dav_response(
+            http::StatusCode::OK,
+            vec![
+                ("DAV", "1, 2".to_string()),
+                (
+                    "Allow",
+                    "OPTIONS, PROPFIND, GET, HEAD, PUT, DELETE, MKCOL, MOVE, LOCK, UNLOCK"
+                        .to_string(),
+                ),
+            ],
+            vec![],
+        ),
+
+        ("PROPFIND", Some((item_path, item))) => {
+            let mut body = String::from(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?><D:multistatus xmlns:D=\"DAV:\">",
+            );
+            body.push_str(&dav_propfind_entry(state, &item_path, &item));
+            // Depth infinity is answered as depth 1
+            if header("depth") != Some("0") && !matches!(item, DavItem::Note(_)) {
+                let mut children: Vec<(&String, &DavItem)> = items
+                    .iter()
+                    .filter(|(child_path, _)| {
+                        let Some(rest) = child_path.strip_prefix(item_path.as_str()) else {
+                            return false;
+                        };
+                        let rest = rest.trim_end_matches('/');
+                        !rest.is_empty() && !rest.contains('/')
+                    })
+                    .collect();
+                children.sort_by_key(|(child_path, _)| *child_path);
+                for (child_path, child) in children {
+                    body.push_str(&dav_propfind_entry(state, child_path, child));
+                }
+            }
+            body.push_str("</D:multistatus>");
+            send_dav_response(
+                http::StatusCode::MULTI_STATUS,
+                vec![("Content-Type", "application/xml; charset=utf-8".to_string())],
+                body.into_bytes(),
+            );
+        }
+
+        ("GET" | "HEAD", Some((_, DavItem::Note(id)))) => {
+            let content = state.load_note_content(&id)?;
+            let content_length = content.len().to_string();
+            let body = if method == http::Method::HEAD {
+                vec![]
+            } else {
+                content
+            };
+            send_dav_response(
+                http::StatusCode::OK,
+                vec![
+                    ("Content-Type", "application/octet-stream".to_string()),
+                    ("Content-Length", content_length),
+                ],
+                body,
+            );
+        }
+
+        ("PUT", Some((_, DavItem::Note(id)))) => {
+            let content = last_blob().map(|blob| blob.bytes).unwrap_or_default();
+            let request = NoteRequest::UpdateNoteContent((id, content));
+            match dav_note_request(request, state, server)? {
+                Ok(_) => send_dav_response(http::StatusCode::NO_CONTENT, vec![], vec![]),
+                Err(e) => send_dav_error(http::StatusCode::FORBIDDEN, e),
+            }
+        }
+
+        ("PUT", None) => {
+            let Some((folder_id, file_name)) = dav_parent(&items, &path) else {
+                send_dav_response(http::StatusCode::CONFLICT, vec![], vec![]);
+                return Ok(());
+            };
+            // Only note files can be stored; this also turns away editors' hidden temp files
+            let Some((name, note_type)) =
+                dav_note_name(&file_name).filter(|_| !file_name.starts_with('.'))
+            else {
+                send_dav_response(http::StatusCode::FORBIDDEN, vec![], vec![]);
+                return Ok(());
+            };
+            let request = NoteRequest::CreateNote((name, folder_id, note_type));
+            let note = match dav_note_request(request, state, server)? {
+                Ok(NoteResponse::CreateNote(Ok(note))) => note,
+                Ok(_) => return Err(anyhow::anyhow!("Unexpected response to CreateNote")),
+                Err(e) => {
+                    send_dav_error(http::StatusCode::CONFLICT, e);
+                    return Ok(());
+                }
+            };
+            let content = last_blob().map(|blob| blob.bytes).unwrap_or_default();
+            let request = NoteRequest::UpdateNoteContent((note.id.clone(), content));
+            let updated = dav_note_request(request, state, server);
+            if !matches!(updated, Ok(Ok(_))) {
+                // Leave nothing behind for content that could not be stored
+                dav_note_request(NoteRequest::DeleteNote(note.id), state, server)?.ok();
+            }
+            if let Err(e) = updated? {
+                send_dav_error(http::StatusCode::FORBIDDEN, e);
+                return Ok(());
+            }
+            send_dav_response(http::StatusCode::CREATED, vec![], vec![]);
+        }
+
+        ("MKCOL", None) => {
+            let Some((parent_id, name)) = dav_parent(&items, &path) else {
+                send_dav_response(http::StatusCode::CONFLICT, vec![], vec![]);
+                return Ok(());
+            };
+            let request = NoteRequest::CreateFolder((name, parent_id));
+            match dav_note_request(request, state, server)? {
+                Ok(_) => send_dav_response(http::StatusCode::CREATED, vec![], vec![]),
+                Err(e) => send_dav_error(http::StatusCode::CONFLICT, e),
+            }
+        }
+
+        ("DELETE", Some((_, item))) => match delete_dav_item(&item, state, server)? {
+            Ok(()) => send_dav_response(http::StatusCode::NO_CONTENT, vec![], vec![]),
+            Err(e) => send_dav_error(http::StatusCode::FORBIDDEN, e),
+        },
+
+        ("MOVE", Some((_, item))) => {
+            // The destination is an absolute URL, or at least an absolute path
+            let prefix = format!("/{}/dav", our().process);
+            let destination = header("destination").unwrap_or_default();
+            let destination = url::Url::parse(destination)
+                .map(|url| url.path().to_string())
+                .unwrap_or(destination.to_string());
+            let Some(destination) = destination.strip_prefix(&prefix) else {
+                send_dav_response(http::StatusCode::BAD_GATEWAY, vec![], vec![]);
+                return Ok(());
+            };
+            let destination = percent_decode(destination);
+            if let Some((_, existing)) = resolve_dav_path(&items, &destination) {
+                if existing == item {
+                    send_dav_response(http::StatusCode::FORBIDDEN, vec![], vec![]);
+                    return Ok(());
+                }
+                if header("overwrite") == Some("F") {
+                    send_dav_response(http::StatusCode::PRECONDITION_FAILED, vec![], vec![]);
+                    return Ok(());
+                }
+                if let Err(e) = delete_dav_item(&existing, state, server)? {
+                    send_dav_error(http::StatusCode::FORBIDDEN, e);
+                    return Ok(());
+                }
+            }
+            let Some((parent_id, name)) = dav_parent(&items, &destination) else {
+                send_dav_response(http::StatusCode::CONFLICT, vec![], vec![]);
+                return Ok(());
+            };
+            let requests = match item {
+                DavItem::Note(id) => {
+                    let name = dav_note_name(&name).map_or(name, |(name, _)| name);
+                    vec![
+                        NoteRequest::MoveNote((id.clone(), parent_id)),
+                        NoteRequest::RenameNote((id, name)),
+                    ]
+                }
+                DavItem::Folder(id) => vec![
+                    NoteRequest::MoveFolder((id.clone(), parent_id)),
+                    NoteRequest::RenameFolder((id, name)),
+                ],
+                DavItem::Root => {
+                    send_dav_response(http::StatusCode::FORBIDDEN, vec![], vec![]);
+                    return Ok(());
+                }
+            };
+            for request in requests {
+                if let Err(e) = dav_note_request(request, state, server)? {
+                    send_dav_error(http::StatusCode::CONFLICT, e);
+                    return Ok(());
+                }
+            }
+            send_dav_response(http::StatusCode::CREATED, vec![], vec![]);
+        }
+
+        // Locks are not enforced, but clients such as macOS Finder will not write without one
+        ("LOCK", _) => {
+            let token = format!("opaquelocktoken:{}", State::generate_id());
+            let body = format!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?><D:prop xmlns:D=\"DAV:\">\
+                 <D:lockdiscovery><D:activelock><D:locktype><D:write/></D:locktype>\
+                 <D:lockscope><D:exclusive/></D:lockscope><D:depth>0</D:depth>\
+                 <D:timeout>Second-3600</D:timeout>\
+                 <D:locktoken><D:href>{}</D:href></D:locktoken>\
+                 </D:activelock></D:lockdiscovery></D:prop>",
+                token
+            );
+            send_dav_response(
+                http::StatusCode::OK,
+                vec![
+                    ("Lock-Token", format!("<{}>", token)),
+                    ("Content-Type", "application/xml; charset=utf-8".to_string()),
+                ],
+                body.into_bytes(),
+            );
+        }
+        ("UNLOCK", _) => send_dav_response(http::StatusCode::NO_CONTENT, vec![], vec![]),
+
+        ("MKCOL", Some(_)) => {
+            send_dav_response(http::StatusCode::METHOD_NOT_ALLOWED, vec![], vec![])
+        }
+        (_, None) => send_dav_response(http::StatusCode::NOT_FOUND, vec![], vec![]),
+        _ => send_dav_response(http::StatusCode::METHOD_NOT_ALLOWED, vec![], vec![]),
+    }
+    Ok(())
+}
+
+// Delete a note, or a folder with everything in it, stopping at the first request that fails
+fn delete_dav_item(
+    item: &DavItem,
+    state: &mut State,
+    server: &http::server::HttpServer,
+) -> anyhow::Result<Result<(), String>> {
+    let requests = match item {
+        DavItem::Note(id) => vec![NoteRequest::DeleteNote(id.clone())],
+        DavItem::Folder(id) => {
+            let subtree = state.folder_subtree(id);
+            let mut requests: Vec<NoteRequest> = state
+                .notes
+                .values()
+                .filter(|n| n.folder_id.as_ref().is_some_and(|f| subtree.contains(f)))
+                .map(|n| NoteRequest::DeleteNote(n.id.clone()))
+                .collect();
+            requests.extend(subtree.into_iter().map(NoteRequest::DeleteFolder));
+            requests
+        }
+        DavItem::Root => vec![],
+    };
+    for request in requests {
+        if let Err(e) = dav_note_request(request, state, server)? {
+            return Ok(Err(e));
+        }
+    }
+    Ok(Ok(()))
+}
+
+fn handle_batch(
+    requests: Vec<NoteRequest>,
+    source: Option<&Address>,
+    state: &mut State,
+    server: &http::server::HttpServer,
+) -> anyhow::Result<BatchResponse> {
+    state.defer_saves = true;
+    let responses: anyhow::Result<Vec<NoteResponse>> = requests
+        .into_iter()
+        .map(|req| {
+            // A request that fails gets an error response in its place rather than failing
+            // the whole batch, so the caller still learns what the others did
+            let name = request_name(&req);
+            handle_note_request(req, source, state, server)
+                .or_else(|e| named_error_response(&name, &e.to_string()))
+        })
+        .collect();
+    // Save whatever was done, even if a request failed part way through
+    state.defer_saves = false;
+    state.save_to_disk()?;
+    Ok(BatchResponse::Batch(responses?))
+}
+
+// Request and response bodies are cut to this many characters when traced
+const TRACE_BODY_MAX_CHARS: usize = 2000;
+
+fn log_verbosity(level: &LogLevel) -> u8 {
+    match level {
+        LogLevel::Info => 0,
+        LogLevel::Debug => 1,
+        LogLevel::Trace => 2,
+    }
+}
+
+// Log a line of request tracing, tagged with the correlation ID of the message being handled,
+// if the level set with set-log-level includes `level`
+fn trace_log(state: &State, level: LogLevel, line: impl FnOnce() -> String) {
+    if log_verbosity(&state.log_level) >= log_verbosity(&level) {
+        info!("[{}] {}", state.message_id, line());
+    }
+}
+
+fn trace_body(value: &impl Serialize) -> String {
+    let body = serde_json::to_string(value).unwrap_or_default();
+    match body.char_indices().nth(TRACE_BODY_MAX_CHARS) {
+        Some((end, _)) => format!("{}... ({} bytes)", &body[..end], body.len()),
+        None => body,
+    }
+}
+
+// The error a response carries, e.g. the message in CreateNote(Err(..))
+fn response_error(resp: &NoteResponse) -> Option<String> {
+    if let NoteResponse::QuotaExceeded(e) = resp {
+        return Some(e.to_string());
+    }
+    let value = serde_json::to_value(resp).ok()?;
+    let error = value.as_object()?.values().next()?.get("Err")?;
+    Some(
+        error
+            .as_str()
+            .map_or_else(|| error.to_string(), str::to_string),
+    )
+}
+
+fn handle_note_request(
+    req: NoteRequest,
+    source: Option<&Address>,
+    state: &mut State,
+    server: &http::server::HttpServer,
+) -> anyhow::Result<NoteResponse> {
+    let name = request_name(&req);
+    let started = std::time::Instant::now();
+    let vfs_before = state.vfs_us.get();
+    if state.log_level == LogLevel::Info {
+        let result = run_note_request(req, &name, source, state, server);
+        state.record_request_timing(&name, started, vfs_before);
+        return result;
+    }
+    let from = source.map_or_else(
+        || "an unauthenticated client".to_string(),
+        Address::to_string,
+    );
+    trace_log(state, LogLevel::Debug, || format!("{} from {}", name, from));
+    trace_log(state, LogLevel::Trace, || {
+        format!("request: {}", trace_body(&req))
+    });
+    let result = run_note_request(req, &name, source, state, server);
+    state.record_request_timing(&name, started, vfs_before);
+    match &result {
+        Ok(resp) => {
+            match response_error(resp) {
+                Some(e) => trace_log(state, LogLevel::Debug, || format!("{} failed: {}", name, e)),
+                None => trace_log(state, LogLevel::Debug, || format!("{} succeeded", name)),
+            }
+            trace_log(state, LogLevel::Trace, || {
+                format!("response: {}", trace_body(resp))
+            });
+        }
+        Err(e) => trace_log(state, LogLevel::Debug, || {
+            format!("{} errored: {:?}", name, e)
+        }),
+    }
+    result
+}
+
+fn run_note_request(
+    req: NoteRequest,
+    name: &str,
+    source: Option<&Address>,
+    state: &mut State,
+    server: &http::server::HttpServer,
+) -> anyhow::Result<NoteResponse> {
+    if state.profile.is_some() && !is_profile_request_allowed(&req) {
+        return error_response(&req, "Profiles may not make this request");
+    }
+    if state.profile.is_some() && changes_node_settings(&state.settings, &req) {
+        return error_response(&req, "Only the node owner may change these settings");
+    }
+    let req = resolve_note_alias(state, req);
+    if let Some(owner) = mirror_refusal(state, &req) {
+        return error_response(
+            &req,
+            &format!("Mirrored from {}, so it cannot be changed here", owner),
+        );
+    }
+    let edits_read_only_note = match &req {
+        NoteRequest::UpdateNoteContent((id, _))
+        | NoteRequest::AppendNoteContent((id, _))
+        | NoteRequest::EditTable((id, _))
+        | NoteRequest::RenameNote((id, _)) => state.notes.get(id).is_some_and(|n| n.read_only),
+        _ => false,
+    };
+    if edits_read_only_note {
+        return error_response(&req, "Note is read-only");
+    }
+    // Content uploads, as (bytes, bytes of the file they replace), checked against the quotas.
+    // Edits are checked once the sender is known to be allowed to make them.
+    let upload = match &req {
+        NoteRequest::CreateAudioNote((_, _, _, audio, _)) => Some((audio.len() as u64, 0)),
+        NoteRequest::ImportPdf((_, _, pdf)) => Some((pdf.len() as u64, 0)),
+        NoteRequest::ImportCsv((_, _, csv)) => Some((csv.len() as u64, 0)),
+        _ => None,
+    };
+    if let Some((bytes, replaced)) = upload {
+        if let Err(e) = state.check_quota(bytes, replaced)? {
+            return Ok(NoteResponse::QuotaExceeded(e));
+        }
+    }
+    *state.request_counts.entry(name.to_string()).or_default() += 1;
+    let syncs_to_git = state.git_sync.remote.is_some() && is_note_mutation(&req);
+    let syncs_to_replica = state.replica.node.is_some() && is_note_mutation(&req);
+    let resp = 'resp: {
+        match req {
+            NoteRequest::CreateFolder((name, parent)) => {
+                let id = State::generate_id();
+                let folder = Folder {
+                    id: id.clone(),
+                    name,
+                    parent_id: parent,
+                    mirror_of: None,
+                };
+                state.record_history(
+                    format!("Create folder \"{}\"", folder.name),
+                    vec![state.snapshot_folder(&id)],
+                );
+                state.folders.insert(id.clone(), folder.clone());
+                state.root_items.insert(id);
+                state.save_to_disk()?;
+                NoteResponse::CreateFolder(Ok(folder))
+            }
+
+            NoteRequest::RenameFolder((id, new_name)) => {
+                if let Some(mut folder) = state.folders.get(&id).cloned() {
+                    state.record_history(
+                        format!("Rename folder \"{}\"", folder.name),
+                        vec![state.snapshot_folder(&id)],
+                    );
+                    folder.name = new_name;
+                    state.folders.insert(id, folder.clone());
+                    state.save_to_disk()?;
+                    NoteResponse::RenameFolder(Ok(folder))
+                } else {
+                    NoteResponse::RenameFolder(Err("Folder not found".to_string()))
+                }
+            }
+
+            NoteRequest::DeleteFolder(id) => {
+                // Snapshot the folder and its direct children, which get moved to root
+                let mut snapshots = vec![state.snapshot_folder(&id)];
+                for note in state.notes.values() {
+                    if note.folder_id.as_ref() == Some(&id) || note.alias_folders.contains(&id) {
+                        snapshots.push(state.snapshot_note(&note.id, false));
+                    }
+                }
+                for subfolder in state.folders.values() {
+                    if subfolder.parent_id.as_ref() == Some(&id) {
+                        snapshots.push(state.snapshot_folder(&subfolder.id));
+                    }
+                }
+                if let Some(folder) = state.folders.remove(&id) {
+                    state.record_history(format!("Delete folder \"{}\"", folder.name), snapshots);
+                    state.root_items.remove(&id);
+                    // Move child items to root if any
+                    for note in state.notes.values_mut() {
+                        if note.folder_id.as_ref() == Some(&folder.id) {
+                            note.folder_id = None;
+                            state.root_items.insert(note.id.clone());
+                        }
+                        note.alias_folders.retain(|f| *f != folder.id);
+                    }
+                    for subfolder in state.folders.values_mut() {
+                        if subfolder.parent_id.as_ref() == Some(&folder.id) {
+                            subfolder.parent_id = None;
+                            state.root_items.insert(subfolder.id.clone());
+                        }
+                    }
+                    let settings = &mut state.settings;
+                    for setting in [&mut settings.default_folder, &mut settings.journal_folder] {
+                        if setting.as_ref() == Some(&folder.id) {
+                            *setting = None;
+                        }
+                    }
+                    state.save_to_disk()?;
+                    NoteResponse::DeleteFolder(Ok(()))
+                } else {
+                    NoteResponse::DeleteFolder(Err("Folder not found".to_string()))
+                }
+            }
+
+            NoteRequest::MoveFolder((id, new_parent_id)) => {
+                if let Some(mut folder) = state.folders.get(&id).cloned() {
+                    // Validate new parent exists if some
+                    if let Some(ref parent_id) = new_parent_id {
+                        if !state.folders.contains_key(parent_id) {
+                            return Ok(NoteResponse::MoveFolder(Err(
+                                "Parent folder not found".to_string()
+                            )));
+                        }
+                    }
+
+                    state.record_history(
+                        format!("Move folder \"{}\"", folder.name),
+                        vec![state.snapshot_folder(&id)],
+                    );
+
+                    // Remove from old parent's children or root
+                    if folder.parent_id.is_some() {
+                        state.root_items.remove(&id);
+                    }
+
+                    // Update folder
+                    folder.parent_id = new_parent_id;
+                    state.folders.insert(id.clone(), folder.clone());
+
+                    // Add to root if needed
+                    if folder.parent_id.is_none() {
+                        state.root_items.insert(id);
+                    }
+
+                    state.save_to_disk()?;
+                    NoteResponse::MoveFolder(Ok(folder))
+                } else {
+                    NoteResponse::MoveFolder(Err("Folder not found".to_string()))
+                }
+            }
+
+            NoteRequest::CreateNote((name, folder_id, note_type)) => {
+                // Validate folder exists if some
+                if let Some(ref folder_id) = folder_id {
+                    if !state.folders.contains_key(folder_id) {
+                        return Ok(NoteResponse::CreateNote(Err(
+                            "Parent folder not found".to_string()
+                        )));
+                    }
+                }
+                let name = match state.note_name_in_folder(&name, folder_id.as_deref(), None) {
+                    Ok(name) => name,
+                    Err(e) => break 'resp NoteResponse::CreateNote(Err(e)),
+                };
+
+                let id = State::generate_id();
+                let metadata = NoteMetadata {
+                    id: id.clone(),
+                    name: name.clone(),
+                    folder_id: folder_id.clone(),
+                    note_type,
+                    is_public: false,
+                    collaborators: Vec::new(),
+                    tags: Vec::new(),
+                    is_locked: false,
+                    read_only: false,
+                    noindex: false,
+                    compressed: false,
+                    archive: None,
+                    published: None,
+                    updated: 0,
+                    created: now_secs(),
+                    due: None,
+                    aliases: Vec::new(),
+                    alias_folders: Vec::new(),
+                };
+
+                state.record_history(
+                    format!("Create note \"{}\"", name),
+                    vec![state.snapshot_note(&id, false)],
+                );
+
+                // Insert metadata first so save_note_content can access it
+                state.notes.insert(id.clone(), metadata);
+
+                // Save empty content to file
+                state.save_note_content(&id, &[])?;
+                if folder_id.is_none() {
+                    state.root_items.insert(id.clone());
+                }
+
+                state.save_to_disk()?;
+
+                // Return full Note for API compatibility
+                let note = Note {
+                    id,
+                    name,
+                    folder_id,
+                    note_type,
+                    content: vec![],
+                    is_public: false,
+                    collaborators: Vec::new(),
+                    tags: Vec::new(),
+                };
+                NoteResponse::CreateNote(Ok(note))
+            }
+
+            NoteRequest::RenameNote((id, new_name)) => {
+                if let Some(mut metadata) = state.notes.get(&id).cloned() {
+                    let new_name = match state.note_name_in_folder(
+                        &new_name,
+                        metadata.folder_id.as_deref(),
+                        Some(&id),
+                    ) {
+                        Ok(name) => name,
+                        Err(e) => break 'resp NoteResponse::RenameNote(Err(e)),
+                    };
+                    state.record_history(
+                        format!("Rename note \"{}\"", metadata.name),
+                        vec![state.snapshot_note(&id, state.has_frontmatter(&id))],
+                    );
+                    metadata.name = new_name;
+                    state.notes.insert(id.clone(), metadata.clone());
+                    state.sync_frontmatter(&id)?;
+                    state.save_to_disk()?;
+                    // Return full Note for API compatibility
+                    match state.get_full_note(&metadata) {
+                        Ok(note) => NoteResponse::RenameNote(Ok(note)),
+                        Err(_) => {
+                            NoteResponse::RenameNote(Err("Error loading note content".to_string()))
+                        }
+                    }
+                } else {
+                    NoteResponse::RenameNote(Err("Note not found".to_string()))
+                }
+            }
+
+            NoteRequest::DeleteNote(id) => {
+                // Deleting an alias entry takes the note out of that folder only
+                if let Some((note_id, folder_id)) = note_alias_ref(&id) {
+                    let Some(metadata) = state.notes.get(note_id) else {
+                        break 'resp NoteResponse::DeleteNote(Err("Note not found".to_string()));
+                    };
+                    if !metadata.alias_folders.iter().any(|f| f == folder_id) {
+                        break 'resp NoteResponse::DeleteNote(Err("Note not found".to_string()));
+                    }
+                    state.record_history(
+                        format!("Remove \"{}\" from a folder", metadata.name),
+                        vec![state.snapshot_note(note_id, false)],
+                    );
+                    if let Some(metadata) = state.notes.get_mut(note_id) {
+                        metadata.alias_folders.retain(|f| f != folder_id);
+                    }
+                    state.save_to_disk()?;
+                    break 'resp NoteResponse::DeleteNote(Ok(()));
+                }
+                let attachment_ids: Vec<String> = state
+                    .attachments
+                    .values()
+                    .filter(|a| a.note_id == id)
+                    .map(|a| a.id.clone())
+                    .collect();
+                let mut snapshots = vec![state.snapshot_note(&id, true)];
+                for attachment_id in &attachment_ids {
+                    snapshots.push(state.snapshot_attachment(attachment_id, true));
+                }
+                if let Some(metadata) = state.notes.remove(&id) {
+                    state.record_history(format!("Delete note \"{}\"", metadata.name), snapshots);
+                    for attachment_id in &attachment_ids {
+                        state.remove_attachment(attachment_id);
+                    }
+                    state.forget_note(&metadata);
+                    state.save_to_disk()?;
+                    NoteResponse::DeleteNote(Ok(()))
+                } else {
+                    NoteResponse::DeleteNote(Err("Note not found".to_string()))
+                }
+            }
+
+            NoteRequest::MoveNote((id, new_folder_id)) => {
+                // Validate new folder exists if some
+                if let Some(ref folder_id) = new_folder_id {
+                    if !state.folders.contains_key(folder_id) {
+                        return Ok(NoteResponse::MoveNote(Err(
+                            "Parent folder not found".to_string()
+                        )));
+                    }
+                }
+
+                if let Some(mut metadata) = state.notes.get(&id).cloned() {
+                    let name = match state.note_name_in_folder(
+                        &metadata.name,
+                        new_folder_id.as_deref(),
+                        Some(&id),
+                    ) {
+                        Ok(name) => name,
+                        Err(e) => break 'resp NoteResponse::MoveNote(Err(e)),
+                    };
+                    state.record_history(
+                        format!("Move note \"{}\"", metadata.name),
+                        vec![state.snapshot_note(
+                            &id,
+                            name != metadata.name && state.has_frontmatter(&id),
+                        )],
+                    );
+
+                    // Update root items tracking
+                    if metadata.folder_id.is_none() {
+                        state.root_items.remove(&id);
+                    }
+                    if new_folder_id.is_none() {
+                        state.root_items.insert(id.clone());
+                    }
+
+                    let renamed = name != metadata.name;
+                    // A note already listed in the folder it moves to no longer needs the alias
+                    metadata
+                        .alias_folders
+                        .retain(|f| Some(f) != new_folder_id.as_ref());
+                    metadata.folder_id = new_folder_id;
+                    metadata.name = name;
+                    state.notes.insert(id.clone(), metadata.clone());
+                    if renamed {
+                        state.sync_frontmatter(&id)?;
+                    }
+                    state.save_to_disk()?;
+                    // Return full Note for API compatibility
+                    match state.get_full_note(&metadata) {
+                        Ok(note) => NoteResponse::MoveNote(Ok(note)),
+                        Err(_) => {
+                            NoteResponse::MoveNote(Err("Error loading note content".to_string()))
+                        }
+                    }
+                } else {
+                    NoteResponse::MoveNote(Err("Note not found".to_string()))
+                }
+            }
+
+            NoteRequest::AddNoteAlias((id, folder_id)) => {
+                if !state.folders.contains_key(&folder_id) {
+                    break 'resp NoteResponse::AddNoteAlias(Err("Folder not found".to_string()));
+                }
+                let Some(metadata) = state.notes.get(&id) else {
+                    break 'resp NoteResponse::AddNoteAlias(Err("Note not found".to_string()));
+                };
+                if metadata.folder_id.as_ref() == Some(&folder_id)
+                    || metadata.alias_folders.contains(&folder_id)
+                {
+                    break 'resp NoteResponse::AddNoteAlias(Err(
+                        "Note is already in that folder".to_string()
+                    ));
+                }
+                state.record_history(
+                    format!("Add \"{}\" to a folder", metadata.name),
+                    vec![state.snapshot_note(&id, false)],
+                );
+                if let Some(metadata) = state.notes.get_mut(&id) {
+                    metadata.alias_folders.push(folder_id);
+                }
+                state.save_to_disk()?;
+                NoteResponse::AddNoteAlias(Ok(()))
+            }
+
+            NoteRequest::RemoveNoteAlias((id, folder_id)) => {
+                let Some(metadata) = state.notes.get(&id) else {
+                    break 'resp NoteResponse::RemoveNoteAlias(Err("Note not found".to_string()));
+                };
+                if !metadata.alias_folders.contains(&folder_id) {
+                    break 'resp NoteResponse::RemoveNoteAlias(Err(
+                        "Note is not listed in that folder".to_string(),
+                    ));
+                }
+                state.record_history(
+                    format!("Remove \"{}\" from a folder", metadata.name),
+                    vec![state.snapshot_note(&id, false)],
+                );
+                if let Some(metadata) = state.notes.get_mut(&id) {
+                    metadata.alias_folders.retain(|f| *f != folder_id);
+                }
+                state.save_to_disk()?;
+                NoteResponse::RemoveNoteAlias(Ok(()))
+            }
+
+            NoteRequest::GetNote(id) => {
+                // Notes shared with us are fetched from their owner on our behalf
+                if let Some((owner, note_id)) =
+                    shared_note_ref(&id).filter(|_| source == Some(&our()))
+                {
+                    break 'resp NoteResponse::GetNote(open_shared_note(state, owner, note_id)?);
+                }
+                // Allow access if:
+                // 1. Note is public
+                // 2. Current node is owner (checking against process name should be enough)
+                // 3. Current node is a collaborator
+                let Some(metadata) = state.notes.get(&id).cloned() else {
+                    break 'resp NoteResponse::GetNote(Err(
+                        "Not found or not authorized".to_string()
+                    ));
+                };
+                let is_member = source.is_some_and(|source| {
+                    source == &our() || metadata.collaborators.contains(&source.node)
+                });
+                // Only those who may read the note learn that it is locked
+                if !metadata.is_public && !is_member {
+                    break 'resp NoteResponse::GetNote(Err(
+                        "Not found or not authorized".to_string()
+                    ));
+                }
+                if metadata.is_locked {
+                    break 'resp NoteResponse::GetNote(Err(
+                        "Note is password protected".to_string()
+                    ));
+                }
+                state.rehydrate_note(&id)?;
+                // Only our own opens count as recent, not reads by visitors or collaborators
+                if source == Some(&our()) {
+                    state.record_open(&id)?;
+                }
+                // Anyone else reading a published note gets what was published
+                if metadata.is_public && metadata.published.is_some() && !is_member {
+                    match state.public_note(&metadata) {
+                        Ok(note) => break 'resp NoteResponse::GetNote(Ok(note)),
+                        Err(_) => {
+                            break 'resp NoteResponse::GetNote(Err(
+                                "Error loading note content".to_string()
+                            ))
+                        }
+                    }
+                }
+                if metadata.is_public {
+                    match state.get_full_note(&metadata) {
+                        Ok(note) => break 'resp NoteResponse::GetNote(Ok(note)),
+                        Err(_) => {
+                            break 'resp NoteResponse::GetNote(Err(
+                                "Error loading note content".to_string()
+                            ))
+                        }
+                    }
+                }
+                let Some(source) = source else {
+                    break 'resp NoteResponse::GetNote(Err(
+                        "Not found or not authorized".to_string()
+                    ));
+                };
+                if source == &our() || metadata.collaborators.contains(&source.node) {
+                    let Ok(mut note) = state.get_full_note(&metadata) else {
+                        break 'resp NoteResponse::GetNote(Err(
+                            "Error loading note content".to_string()
+                        ));
+                    };
+                    // Collaborators on other nodes get content encrypted with the note's share key
+                    if source.node != our().node {
+                        let key = state.share_key(&id)?;
+                        note.content =
+                            encrypt_with_key(&note.content, &key, &[0; ENCRYPTION_SALT_LEN])
+                                .map_err(|e| anyhow::anyhow!(e))?;
+                        state.collaborator_activity(&id, &source.node).last_read = Some(now_secs());
+                        state.save_later()?;
+                    }
+                    NoteResponse::GetNote(Ok(note))
+                } else {
+                    NoteResponse::GetNote(Err("Not found or not authorized".to_string()))
+                }
+            }
+
+            NoteRequest::UpdateNoteContent((id, content)) => {
+                if let Some((owner, note_id)) =
+                    shared_note_ref(&id).filter(|_| source == Some(&our()))
+                {
+                    save_shared_note(state, owner, note_id, content)?;
+                    break 'resp NoteResponse::UpdateNoteContent(Ok(()));
+                }
+                let Some(metadata) = state.notes.get(&id).cloned() else {
+                    break 'resp NoteResponse::UpdateNoteContent(Err(
+                        "Not found or not authorized".to_string(),
+                    ));
+                };
+                if matches!(metadata.note_type, NoteType::Audio | NoteType::Pdf) {
+                    break 'resp NoteResponse::UpdateNoteContent(Err(
+                        "Audio and PDF notes cannot be edited".to_string(),
+                    ));
+                }
+                let Some(source) = source else {
+                    break 'resp NoteResponse::UpdateNoteContent(Err(
+                        "Not found or not authorized".to_string(),
+                    ));
+                };
+                if source == &our() || metadata.collaborators.contains(&source.node) {
+                    let replaced = state.note_file_bytes(&metadata);
+                    if let Err(e) = state.check_quota(content.len() as u64, replaced)? {
+                        break 'resp NoteResponse::QuotaExceeded(e);
+                    }
+                    // Collaborators on other nodes send content encrypted with the share key
+                    let content = if source.node != our().node {
+                        let decrypted = state
+                            .share_keys
+                            .get(&id)
+                            .ok_or_else(|| SHARE_KEY_MISMATCH.to_string())
+                            .and_then(|key| decrypt_with_key(&content, key));
+                        let Ok(decrypted) = decrypted else {
+                            break 'resp NoteResponse::UpdateNoteContent(Err(
+                                SHARE_KEY_MISMATCH.to_string()
+                            ));
+                        };
+                        decrypted
+                    } else {
+                        content
+                    };
+                    let mentioned = if metadata.collaborators.is_empty() || metadata.is_locked {
+                        Vec::new()
+                    } else {
+                        let previous = state.load_note_content(&id).unwrap_or_default();
+                        new_mentions(&previous, &content)
+                    };
+                    let before = state.notes.get(&id).cloned();
+                    if metadata.note_type == NoteType::Markdown && !metadata.is_locked {
+                        state.apply_frontmatter(&id, &content);
+                        if state.settings.title_from_heading {
+                            state.apply_heading_title(&id, &content);
+                        }
+                    }
+                    // Locked notes can only be written once unlocked, so the content can be encrypted
+                    let content = if metadata.is_locked {
+                        let Some((key, salt)) = state.note_keys.get(&id) else {
+                            break 'resp NoteResponse::UpdateNoteContent(Err(
+                                "Note is password protected".to_string(),
+                            ));
+                        };
+                        match encrypt_with_key(&content, key, salt) {
+                            Ok(encrypted) => encrypted,
+                            Err(e) => break 'resp NoteResponse::UpdateNoteContent(Err(e)),
+                        }
+                    } else {
+                        content
+                    };
+                    if source.node != our().node {
+                        state.collaborator_activity(&id, &source.node).last_edit = Some(now_secs());
+                    }
+                    // Save content to file with appropriate extension
+                    state.save_note_content(&id, &content)?;
+                    // Content is stored separately. Metadata the edit changed, such as frontmatter
+                    // tags or whether the file is compressed, is saved now; a new edit time alone
+                    // can wait for the save timer rather than rewrite the state on every autosave.
+                    let after = state.notes.get(&id).map(|m| NoteMetadata {
+                        updated: before.as_ref().map_or(0, |b| b.updated),
+                        ..m.clone()
+                    });
+                    if after == before {
+                        state.save_later()?;
+                    } else {
+                        state.save_to_disk()?;
+                    }
+                    notify_mentions(state, &metadata, &mentioned, &source.node)?;
+                    if source.node != our().node {
+                        let now = now_secs();
+                        let last = state.edit_notified.get(&id).copied().unwrap_or_default();
+                        if now.saturating_sub(last) >= EDIT_NOTIFICATION_INTERVAL_SECS {
+                            state.edit_notified.insert(id.clone(), now);
+                            push_notification(
+                                &format!("{} edited \"{}\"", source.node, metadata.name),
+                                "A shared note changed",
+                            );
+                        }
+                    }
+                    NoteResponse::UpdateNoteContent(Ok(()))
+                } else {
+                    NoteResponse::UpdateNoteContent(Err("Not found or not authorized".to_string()))
+                }
+            }
+
+            NoteRequest::EditTable((id, edits)) => {
+                let Some(metadata) = state.notes.get(&id).cloned() else {
+                    break 'resp NoteResponse::EditTable(Err("Note not found".to_string()));
+                };
+                if metadata.note_type != NoteType::Table || metadata.is_locked {
+                    break 'resp NoteResponse::EditTable(Err(
+                        "Can only edit table notes without a password".to_string(),
+                    ));
+                }
+                let content = state.load_note_content(&id).unwrap_or_default();
+                let mut rows = parse_csv(&String::from_utf8_lossy(&content));
+                for edit in edits {
+                    if let Err(e) = apply_table_edit(&mut rows, edit) {
+                        break 'resp NoteResponse::EditTable(Err(e));
+                    }
+                }
+                let csv = write_csv(&rows);
+                // Checked here rather than with other uploads, as the size is only known now
+                let replaced = state.note_file_bytes(&metadata);
+                if let Err(e) = state.check_quota(csv.len() as u64, replaced)? {
+                    break 'resp NoteResponse::QuotaExceeded(e);
+                }
+                state.record_history(
+                    format!("Edit table \"{}\"", metadata.name),
+                    vec![state.snapshot_note(&id, true)],
+                );
+                state.save_note_content(&id, csv.as_bytes())?;
+                state.save_to_disk()?;
+                match state.get_full_note(&state.notes[&id]) {
+                    Ok(note) => NoteResponse::EditTable(Ok(note)),
+                    Err(_) => {
+                        NoteResponse::EditTable(Err("Error loading note content".to_string()))
+                    }
+                }
+            }
+
+            NoteRequest::CreateAudioNote((name, folder_id, mime, audio, duration_ms)) => {
+                if let Some(folder_id) = &folder_id {
+                    if !state.folders.contains_key(folder_id) {
+                        break 'resp NoteResponse::CreateAudioNote(Err(
+                            "Folder not found".to_string()
+                        ));
+                    }
+                }
+                let mime = mime.to_ascii_lowercase();
+                if !mime.starts_with("audio/") || audio.is_empty() {
+                    break 'resp NoteResponse::CreateAudioNote(Err(
+                        "Audio notes need an audio/* recording".to_string(),
+                    ));
+                }
+                let id = state.add_note(name.clone(), folder_id, NoteType::Audio, &[])?;
+                let attachment_id =
+                    state.add_attachment(&id, name.clone(), Some(mime.clone()), &audio)?;
+                let content = AudioContent {
+                    attachment_id: attachment_id.clone(),
+                    duration_ms: duration_ms.or_else(|| wav_duration_ms(&audio)),
+                    mime,
+                };
+                state.save_note_content(&id, &serde_json::to_vec(&content)?)?;
+                state.record_history(
+                    format!("Record \"{}\"", name),
+                    vec![
+                        ItemSnapshot::Note(id.clone(), None, None),
+                        ItemSnapshot::Attachment(attachment_id, None, None),
+                    ],
+                );
+                state.save_to_disk()?;
+                match state.get_full_note(&state.notes[&id]) {
+                    Ok(note) => NoteResponse::CreateAudioNote(Ok(note)),
+                    Err(_) => {
+                        NoteResponse::CreateAudioNote(Err("Error loading note content".to_string()))
+                    }
+                }
+            }
+
+            NoteRequest::ImportPdf((name, folder_id, pdf)) => {
+                if let Some(folder_id) = &folder_id {
+                    if !state.folders.contains_key(folder_id) {
+                        break 'resp NoteResponse::ImportPdf(Err("Folder not found".to_string()));
+                    }
+                }
+                if !pdf.starts_with(b"%PDF-") {
+                    break 'resp NoteResponse::ImportPdf(Err("Not a PDF file".to_string()));
+                }
+                let id = state.add_note(name, folder_id, NoteType::Pdf, &pdf)?;
+                state.record_history(
+                    format!("Import PDF \"{}\"", state.notes[&id].name),
+                    vec![ItemSnapshot::Note(id.clone(), None, None)],
+                );
+                state.save_to_disk()?;
+                match state.get_full_note(&state.notes[&id]) {
+                    Ok(note) => NoteResponse::ImportPdf(Ok(note)),
+                    Err(_) => {
+                        NoteResponse::ImportPdf(Err("Error loading note content".to_string()))
+                    }
+                }
+            }
+
+            NoteRequest::ImportCsv((name, folder_id, csv)) => {
+                if let Some(folder_id) = &folder_id {
+                    if !state.folders.contains_key(folder_id) {
+                        break 'resp NoteResponse::ImportCsv(Err("Folder not found".to_string()));
+                    }
+                }
+                let Ok(csv) = String::from_utf8(csv) else {
+                    break 'resp NoteResponse::ImportCsv(Err("CSV must be UTF-8".to_string()));
+                };
+                let content = write_csv(&parse_csv(&csv));
+                let id = state.add_note(name, folder_id, NoteType::Table, content.as_bytes())?;
+                state.record_history(
+                    format!("Import table \"{}\"", state.notes[&id].name),
+                    vec![ItemSnapshot::Note(id.clone(), None, None)],
+                );
+                state.save_to_disk()?;
+                match state.get_full_note(&state.notes[&id]) {
+                    Ok(note) => NoteResponse::ImportCsv(Ok(note)),
+                    Err(_) => {
+                        NoteResponse::ImportCsv(Err("Error loading note content".to_string()))
+                    }
+                }
+            }
+
+            NoteRequest::AppendNoteContent((id, content)) => {
+                let Some(metadata) = state.notes.get(&id) else {
+                    break 'resp NoteResponse::AppendNoteContent(Err(
+                        "Not found or not authorized".to_string(),
+                    ));
+                };
+                let is_text =
+                    matches!(metadata.note_type, NoteType::Markdown | NoteType::PlainText);
+                if !is_text || metadata.is_locked {
+                    break 'resp NoteResponse::AppendNoteContent(Err(
+                        "Can only append to text notes without a password".to_string(),
+                    ));
+                }
+                let mut updated = state.load_note_content(&id).unwrap_or_default();
+                if !updated.is_empty() && !updated.ends_with(b"\n") {
+                    updated.push(b'\n');
+                }
+                updated.extend(content);
+                let request = NoteRequest::UpdateNoteContent((id, updated));
+                match handle_note_request(request, source, state, server)? {
+                    NoteResponse::UpdateNoteContent(result) => {
+                        NoteResponse::AppendNoteContent(result)
+                    }
+                    resp @ NoteResponse::QuotaExceeded(_) => resp,
+                    _ => NoteResponse::AppendNoteContent(Err("Failed to append".to_string())),
+                }
+            }
+
+            NoteRequest::SetNoteTags((id, tags)) => {
+                if let Some(mut metadata) = state.notes.get(&id).cloned() {
+                    state.record_history(
+                        format!("Change tags of note \"{}\"", metadata.name),
+                        vec![state.snapshot_note(&id, state.has_frontmatter(&id))],
+                    );
+                    metadata.tags = normalize_tags(tags);
+                    state.notes.insert(id.clone(), metadata.clone());
+                    state.sync_frontmatter(&id)?;
+                    state.save_to_disk()?;
+                    // Return full Note for API compatibility
+                    match state.get_full_note(&metadata) {
+                        Ok(note) => NoteResponse::SetNoteTags(Ok(note)),
+                        Err(_) => {
+                            NoteResponse::SetNoteTags(Err("Error loading note content".to_string()))
+                        }
+                    }
+                } else {
+                    NoteResponse::SetNoteTags(Err("Note not found".to_string()))
+                }
+            }
+
+            NoteRequest::SetNoteDue((id, due)) => {
+                let Some(name) = state.notes.get(&id).map(|note| note.name.clone()) else {
+                    break 'resp NoteResponse::SetNoteDue(Err("Note not found".to_string()));
+                };
+                state.record_history(
+                    format!("Change due date of note \"{}\"", name),
+                    vec![state.snapshot_note(&id, false)],
+                );
+                if let Some(note) = state.notes.get_mut(&id) {
+                    note.due = due;
+                }
+                state.save_to_disk()?;
+                NoteResponse::SetNoteDue(Ok(()))
+            }
+
+            NoteRequest::SetNoteReadOnly((id, read_only)) => {
+                let Some(note) = state.notes.get_mut(&id) else {
+                    break 'resp NoteResponse::SetNoteReadOnly(Err("Note not found".to_string()));
+                };
+                note.read_only = read_only;
+                state.save_to_disk()?;
+                NoteResponse::SetNoteReadOnly(Ok(()))
+            }
+
+            NoteRequest::SetNoteNoindex((id, noindex)) => {
+                let Some(note) = state.notes.get_mut(&id) else {
+                    break 'resp NoteResponse::SetNoteNoindex(Err("Note not found".to_string()));
+                };
+                note.noindex = noindex;
+                state.save_to_disk()?;
+                NoteResponse::SetNoteNoindex(Ok(()))
+            }
+
+            NoteRequest::GetDueNotes => NoteResponse::GetDueNotes(Ok(state.due_notes())),
+
+            NoteRequest::GetNotesByTagPrefix(prefix) => {
+                let prefix = prefix.trim().trim_end_matches('/');
+                let nested_prefix = format!("{}/", prefix);
+                let mut notes: Vec<Note> = state
+                    .notes
+                    .values()
+                    .filter(|n| {
+                        n.tags
+                            .iter()
+                            .any(|t| t == prefix || t.starts_with(&nested_prefix))
+                    })
+                    .filter_map(|n| state.get_full_note(n).ok())
+                    .collect();
+                notes.sort_by_key(|note| note.name.to_lowercase());
+                NoteResponse::GetNotesByTagPrefix(Ok(notes))
+            }
+
+            NoteRequest::GetTagTree => {
+                // Segments -> (notes with exactly this tag, notes with it or a nested tag).
+                // Keyed by segments so that every tag sorts directly before the ones under it.
+                let mut counts: BTreeMap<Vec<&str>, (u32, HashSet<&str>)> = BTreeMap::new();
+                for note in state.notes.values() {
+                    for tag in &note.tags {
+                        let segments: Vec<&str> = tag.split('/').collect();
+                        for depth in 1..=segments.len() {
+                            let entry = counts.entry(segments[..depth].to_vec()).or_default();
+                            entry.1.insert(&note.id);
+                            if depth == segments.len() {
+                                entry.0 += 1;
+                            }
+                        }
+                    }
+                }
+                let tree = counts
+                    .into_iter()
+                    .map(|(segments, (notes, all_notes))| TagNode {
+                        path: segments.join("/"),
+                        name: segments[segments.len() - 1].to_string(),
+                        parent: (segments.len() > 1)
+                            .then(|| segments[..segments.len() - 1].join("/")),
+                        notes,
+                        total_notes: all_notes.len() as u32,
+                    })
+                    .collect();
+                NoteResponse::GetTagTree(Ok(tree))
+            }
+
+            NoteRequest::SetNotePassword((id, password)) => {
+                let Some(mut metadata) = state.notes.get(&id).cloned() else {
+                    break 'resp NoteResponse::SetNotePassword(Err("Note not found".to_string()));
+                };
+                if metadata.is_locked {
+                    break 'resp NoteResponse::SetNotePassword(Err(
+                        "Note already has a password".to_string()
+                    ));
+                }
+                if password.is_empty() {
+                    break 'resp NoteResponse::SetNotePassword(Err(
+                        "Password cannot be empty".to_string()
+                    ));
+                }
+                let content = state.load_note_content(&id).unwrap_or_default();
+                let salt = new_archive_salt();
+                let encrypted = match archive_key(&password, &salt)
+                    .and_then(|key| Ok((encrypt_with_key(&content, &key, &salt)?, key)))
+                {
+                    Ok((encrypted, key)) => {
+                        state.note_keys.insert(id.clone(), (key, salt));
+                        encrypted
+                    }
+                    Err(e) => break 'resp NoteResponse::SetNotePassword(Err(e)),
+                };
+                metadata.is_locked = true;
+                state.notes.insert(id.clone(), metadata);
+                state.save_note_content(&id, &encrypted)?;
+                state.forget_note_history(&id);
+                state.save_to_disk()?;
+                NoteResponse::SetNotePassword(Ok(()))
+            }
+
+            NoteRequest::UnlockNote((id, password)) => {
+                let Some(metadata) = state.notes.get(&id).cloned() else {
+                    break 'resp NoteResponse::UnlockNote(Err("Note not found".to_string()));
+                };
+                if !metadata.is_locked {
+                    break 'resp NoteResponse::UnlockNote(Err(
+                        "Note is not password protected".to_string()
+                    ));
+                }
+                let encrypted = state.load_note_content(&id)?;
+                let unlocked = archive_salt(&encrypted).and_then(|salt| {
+                    let key = archive_key(&password, salt)?;
+                    let content = decrypt_with_key(&encrypted, &key)
+                        .map_err(|_| "Wrong password".to_string())?;
+                    Ok((key, salt.to_vec(), content))
+                });
+                let (key, salt, content) = match unlocked {
+                    Ok(unlocked) => unlocked,
+                    Err(e) => break 'resp NoteResponse::UnlockNote(Err(e)),
+                };
+                // Keep the key in memory so edits can be saved encrypted until restart
+                state.note_keys.insert(id, (key, salt));
+                let mut note = state.get_full_note(&metadata)?;
+                note.content = content;
+                NoteResponse::UnlockNote(Ok(note))
+            }
+
+            NoteRequest::RemoveNotePassword((id, password)) => {
+                let Some(mut metadata) = state.notes.get(&id).cloned() else {
+                    break 'resp NoteResponse::RemoveNotePassword(
+                        Err("Note not found".to_string()),
+                    );
+                };
+                if !metadata.is_locked {
+                    break 'resp NoteResponse::RemoveNotePassword(Err(
+                        "Note is not password protected".to_string(),
+                    ));
+                }
+                let encrypted = state.load_note_content(&id)?;
+                let content = match archive_salt(&encrypted)
+                    .and_then(|salt| archive_key(&password, salt))
+                    .and_then(|key| {
+                        decrypt_with_key(&encrypted, &key).map_err(|_| "Wrong password".to_string())
+                    }) {
+                    Ok(content) => content,
+                    Err(e) => break 'resp NoteResponse::RemoveNotePassword(Err(e)),
+                };
+                metadata.is_locked = false;
+                state.notes.insert(id.clone(), metadata);
+                state.note_keys.remove(&id);
+                state.save_note_content(&id, &content)?;
+                state.forget_note_history(&id);
+                state.save_to_disk()?;
+                NoteResponse::RemoveNotePassword(Ok(()))
+            }
+
+            NoteRequest::ClipUrl((url, folder_id)) => {
+                let url = match url::Url::parse(&url) {
+                    Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url,
+                    _ => {
+                        break 'resp NoteResponse::ClipUrl(Err(
+                            "Only http and https URLs can be clipped".to_string(),
+                        ))
+                    }
+                };
+                // Pages are fetched by the node, which must not become a way into its network
+                if !is_public_host(&url) {
+                    break 'resp NoteResponse::ClipUrl(Err(
+                        "Only public hosts can be clipped".to_string()
+                    ));
+                }
+                if let Some(folder_id) = &folder_id {
+                    if !state.folders.contains_key(folder_id) {
+                        break 'resp NoteResponse::ClipUrl(Err("Folder not found".to_string()));
+                    }
+                }
+                match state.clip_page(&url, folder_id)? {
+                    Ok(note_id) => match state.get_full_note(&state.notes[&note_id]) {
+                        Ok(note) => NoteResponse::ClipUrl(Ok(note)),
+                        Err(_) => {
+                            NoteResponse::ClipUrl(Err("Error loading note content".to_string()))
+                        }
+                    },
+                    Err(e) => NoteResponse::ClipUrl(Err(e)),
+                }
+            }
+
+            NoteRequest::UnfurlUrl(url) => {
+                let url = match url::Url::parse(&url) {
+                    Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url,
+                    _ => {
+                        break 'resp NoteResponse::UnfurlUrl(Err(
+                            "Only http and https URLs can be unfurled".to_string(),
+                        ))
+                    }
+                };
+                // Previews are fetched by the node, which must not become a way into its network
+                if !is_public_host(&url) {
+                    break 'resp NoteResponse::UnfurlUrl(Err(
+                        "Only public hosts can be unfurled".to_string()
+                    ));
+                }
+                let range = HashMap::from([(
+                    "Range".to_string(),
+                    format!("bytes=0-{}", UNFURL_MAX_BYTES - 1),
+                )]);
+                let (mime, mut body) = match http_get_with(&url, range, CLIP_TIMEOUT_SECS) {
+                    Ok(page) => page,
+                    Err(e) => break 'resp NoteResponse::UnfurlUrl(Err(e)),
+                };
+                let mime = mime.unwrap_or_default();
+                if !(mime.starts_with("text/html") || mime.is_empty()) {
+                    // Not a page, e.g. an image or a PDF: all there is to show is its URL
+                    break 'resp NoteResponse::UnfurlUrl(Ok(LinkPreview {
+                        url: url.to_string(),
+                        title: None,
+                        description: None,
+                        image: mime.starts_with("image/").then(|| url.to_string()),
+                        favicon: url.join("/favicon.ico").ok().map(String::from),
+                    }));
+                }
+                body.truncate(UNFURL_MAX_BYTES);
+                NoteResponse::UnfurlUrl(Ok(unfurl_html(&url, &String::from_utf8_lossy(&body))))
+            }
+
+            NoteRequest::AddFeed((url, folder_id)) => {
+                match url::Url::parse(&url) {
+                    Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => {}
+                    _ => {
+                        break 'resp NoteResponse::AddFeed(Err(
+                            "Only http and https feeds can be added".to_string(),
+                        ))
+                    }
+                }
+                if !state.folders.contains_key(&folder_id) {
+                    break 'resp NoteResponse::AddFeed(Err("Folder not found".to_string()));
+                }
+                if state.feeds.iter().any(|feed| feed.url == url) {
+                    break 'resp NoteResponse::AddFeed(Err("Feed already added".to_string()));
+                }
+                state.feeds.push(FeedSubscription {
+                    url,
+                    folder_id,
+                    items: HashMap::new(),
+                    last_fetched: None,
+                    last_error: None,
+                });
+                state.save_to_disk()?;
+                schedule_feed_poll(state);
+                NoteResponse::AddFeed(Ok(()))
+            }
+
+            NoteRequest::RemoveFeed(url) => {
+                let count = state.feeds.len();
+                state.feeds.retain(|feed| feed.url != url);
+                if state.feeds.len() == count {
+                    break 'resp NoteResponse::RemoveFeed(Err("Feed not found".to_string()));
+                }
+                state.save_to_disk()?;
+                schedule_feed_poll(state);
+                NoteResponse::RemoveFeed(Ok(()))
+            }
+
+            NoteRequest::ListFeeds => NoteResponse::ListFeeds(Ok(state
+                .feeds
+                .iter()
+                .map(|feed| Feed {
+                    url: feed.url.clone(),
+                    folder_id: feed.folder_id.clone(),
+                    items: feed.items.len() as u32,
+                    last_fetched: feed.last_fetched,
+                    last_error: feed.last_error.clone(),
+                })
+                .collect())),
+
+            NoteRequest::PollFeedsNow => {
+                // Re-arm first, so a failed poll does not stop the regular ones
+                let polled = state.start_feed_poll();
+                schedule_feed_poll(state);
+                NoteResponse::PollFeedsNow(polled.map_err(|e| e.to_string()))
+            }
+
+            NoteRequest::GetAttachments(note_id) => {
+                if state.notes.contains_key(&note_id) {
+                    let attachments = state
+                        .attachments
+                        .values()
+                        .filter(|a| a.note_id == note_id)
+                        .cloned()
+                        .collect();
+                    NoteResponse::GetAttachments(Ok(attachments))
+                } else {
+                    NoteResponse::GetAttachments(Err("Note not found".to_string()))
+                }
+            }
+
+            NoteRequest::GetRecentNotes(limit) => {
+                // Deleted notes drop out here rather than when they are deleted
+                let recent = state
+                    .recent_notes
+                    .iter()
+                    .filter_map(|(id, opened)| {
+                        state.notes.get(id).map(|note| RecentNote {
+                            note_id: id.clone(),
+                            name: note.name.clone(),
+                            opened: *opened,
+                        })
+                    })
+                    .take(limit as usize)
+                    .collect();
+                NoteResponse::GetRecentNotes(Ok(recent))
+            }
+
+            NoteRequest::GetOrphanReport(days) => {
+                NoteResponse::GetOrphanReport(Ok(state.orphan_notes(days)))
+            }
+
+            NoteRequest::ReplaceInNotes((pattern, replacement, scope, dry_run)) => {
+                let is_regex =
+                    pattern.len() > 1 && pattern.starts_with('/') && pattern.ends_with('/');
+                let regex = if is_regex {
+                    build_regex(&pattern[1..pattern.len() - 1])
+                } else if pattern.is_empty() {
+                    Err("Nothing to replace".to_string())
+                } else {
+                    build_regex(&regex::escape(&pattern))
+                };
+                let regex = match regex {
+                    Ok(regex) => regex,
+                    Err(e) => break 'resp NoteResponse::ReplaceInNotes(Err(e)),
+                };
+                let in_scope: Vec<&NoteMetadata> = match &scope {
+                    ReplaceScope::AllNotes => state.notes.values().collect(),
+                    ReplaceScope::Folder(id) => {
+                        if !state.folders.contains_key(id) {
+                            break 'resp NoteResponse::ReplaceInNotes(Err(
+                                "Folder not found".to_string()
+                            ));
+                        }
+                        let subtree = state.folder_subtree(id);
+                        state
+                            .notes
+                            .values()
+                            .filter(|note| {
+                                note.folder_id
+                                    .as_ref()
+                                    .is_some_and(|id| subtree.contains(id))
+                            })
+                            .collect()
+                    }
+                    ReplaceScope::Notes(ids) => {
+                        match ids.iter().map(|id| state.notes.get(id)).collect() {
+                            Some(notes) => notes,
+                            None => {
+                                break 'resp NoteResponse::ReplaceInNotes(Err(
+                                    "Note not found".to_string()
+                                ))
+                            }
+                        }
+                    }
+                };
+                // (note id, name, matches, replaced content)
+                let mut changes = Vec::new();
+                for note in in_scope {
+                    let is_text =
+                        matches!(note.note_type, NoteType::Markdown | NoteType::PlainText);
+                    if !is_text || note.is_locked || note.read_only {
+                        continue;
+                    }
+                    let Ok(text) = String::from_utf8(state.load_note_content(&note.id)?) else {
+                        continue;
+                    };
+                    let (replaced, matches) =
+                        replace_matches(&text, &regex, is_regex, &replacement);
+                    if matches > 0 {
+                        changes.push((note.id.clone(), note.name.clone(), matches, replaced));
+                    }
+                }
+                changes.sort_by_key(|(_, name, _, _)| name.to_lowercase());
+                if !dry_run && !changes.is_empty() {
+                    // Check the quota for every change first, so none are made if any is refused
+                    let mut bytes = 0;
+                    let mut replaced_bytes = 0;
+                    for (id, _, _, replaced) in &changes {
+                        if let Err(e) = state.check_note_size(replaced.len() as u64) {
+                            break 'resp NoteResponse::QuotaExceeded(e);
+                        }
+                        bytes += replaced.len() as u64;
+                        replaced_bytes +=
+                            state.notes.get(id).map_or(0, |n| state.note_file_bytes(n));
+                    }
+                    if let Err(e) = state.check_drive_quota(bytes, replaced_bytes)? {
+                        break 'resp NoteResponse::QuotaExceeded(e);
+                    }
+                    let snapshots = changes
+                        .iter()
+                        .map(|(id, _, _, _)| state.snapshot_note(id, true))
+                        .collect();
+                    state.record_history(
+                        format!("Replace \"{}\" in {} notes", pattern, changes.len()),
+                        snapshots,
+                    );
+                    // Written as edits, so frontmatter, titles and mentions update as they would
+                    // for an edit made in the editor
+                    for (id, _, _, replaced) in &changes {
+                        let request = NoteRequest::UpdateNoteContent((
+                            id.clone(),
+                            replaced.clone().into_bytes(),
+                        ));
+                        match handle_note_request(request, source, state, server)? {
+                            NoteResponse::UpdateNoteContent(Err(e)) => {
+                                break 'resp NoteResponse::ReplaceInNotes(Err(e))
+                            }
+                            resp @ NoteResponse::QuotaExceeded(_) => break 'resp resp,
+                            _ => {}
+                        }
+                    }
+                }
+                NoteResponse::ReplaceInNotes(Ok(changes
+                    .into_iter()
+                    .map(|(note_id, name, matches, _)| ReplaceMatch {
+                        note_id,
+                        name,
+                        matches,
+                    })
+                    .collect()))
+            }
+
+            NoteRequest::MatchNoteTitles(query) => {
+                let no_aliases: &[String] = &[];
+                let notes = state
+                    .notes
+                    .values()
+                    .map(|n| (&n.id, &n.name, n.aliases.as_slice(), false));
+                let folders = state
+                    .folders
+                    .values()
+                    .map(|f| (&f.id, &f.name, no_aliases, true));
+                let mut matches: Vec<TitleMatch> = notes
+                    .chain(folders)
+                    .filter_map(|(id, name, aliases, is_folder)| {
+                        let names = std::iter::once(name).chain(aliases);
+                        let score = names.filter_map(|name| fuzzy_score(&query, name)).max();
+                        score.map(|score| TitleMatch {
+                            id: id.clone(),
+                            name: name.clone(),
+                            is_folder,
+                            score,
+                        })
+                    })
+                    .collect();
+                // Among equal scores, shorter names match more of themselves
+                matches.sort_by(|a, b| {
+                    b.score
+                        .cmp(&a.score)
+                        .then(a.name.len().cmp(&b.name.len()))
+                        .then_with(|| a.name.cmp(&b.name))
+                });
+                matches.truncate(MAX_TITLE_MATCHES);
+                NoteResponse::MatchNoteTitles(Ok(matches))
+            }
+
+            NoteRequest::SearchNotes(query) => match SearchExpr::parse(&query) {
+                Ok(query) => NoteResponse::SearchNotes(Ok(state.search_notes(&query))),
+                Err(e) => NoteResponse::SearchNotes(Err(e)),
+            },
+
+            NoteRequest::GetStructure => NoteResponse::GetStructure(Ok(state.structure(None))),
+
+            NoteRequest::GetStructureSorted(sort) => {
+                NoteResponse::GetStructureSorted(Ok(state.structure(Some(&sort))))
+            }
+
+            NoteRequest::ResolvePath(path) => NoteResponse::ResolvePath(state.resolve_path(&path)),
+
+            NoteRequest::MergeContent((base, mine, theirs)) => {
+                match merge_text(&base, &mine, &theirs) {
+                    Some((content, conflicts)) => {
+                        NoteResponse::MergeContent(Ok(MergedContent { content, conflicts }))
+                    }
+                    None => NoteResponse::MergeContent(Err(
+                        "The texts are too large and different to merge".to_string(),
+                    )),
+                }
+            }
+
+            NoteRequest::GetNotePath(note_id) => match state.note_path(&note_id) {
+                Some(path) => NoteResponse::GetNotePath(Ok(path)),
+                None => NoteResponse::GetNotePath(Err("Note not found".to_string())),
+            },
+
+            NoteRequest::GetFolderContents((folder_id, sort)) => {
+                let sort = sort.unwrap_or(ListingSort {
+                    key: SortKey::Name,
+                    descending: false,
+                });
+                match state.folder_contents(folder_id.as_deref(), &sort) {
+                    Some(contents) => NoteResponse::GetFolderContents(Ok(contents)),
+                    None => NoteResponse::GetFolderContents(Err("Folder not found".to_string())),
+                }
+            }
+
+            NoteRequest::SetManualOrder(ids) => {
+                if let Some(id) = ids
+                    .iter()
+                    .find(|id| !state.folders.contains_key(*id) && !state.notes.contains_key(*id))
+                {
+                    break 'resp NoteResponse::SetManualOrder(Err(format!(
+                        "No folder or note {}",
+                        id
+                    )));
+                }
+                for (position, id) in ids.into_iter().enumerate() {
+                    state.manual_order.insert(id, position as u32);
+                }
+                // Forget deleted items while we are here
+                let State {
+                    manual_order,
+                    folders,
+                    notes,
+                    ..
+                } = &mut *state;
+                manual_order.retain(|id, _| folders.contains_key(id) || notes.contains_key(id));
+                state.save_to_disk()?;
+                NoteResponse::SetManualOrder(Ok(()))
+            }
+
+            NoteRequest::ExportAll((passphrase, workspace)) => {
+                let mut on_progress = |done, total| push_progress(server, "export", done, total);
+                let archive = match inactive_workspace(workspace.as_deref())? {
+                    Ok(Some(other)) => other.export_archive(None, &mut on_progress)?,
+                    Ok(None) => state.export_archive(None, &mut on_progress)?,
+                    Err(e) => break 'resp NoteResponse::ExportAll(Err(e)),
+                };
+                match passphrase {
+                    Some(passphrase) => {
+                        NoteResponse::ExportAll(encrypt_archive(&archive, &passphrase))
+                    }
+                    None => NoteResponse::ExportAll(Ok(archive)),
+                }
+            }
+
+            NoteRequest::StartExport(workspace) => {
+                let mut on_progress = |done, total| push_progress(server, "export", done, total);
+                NoteResponse::StartExport(Ok(match inactive_workspace(workspace.as_deref())? {
+                    Ok(Some(other)) => state.spool_export(&other, &mut on_progress)?,
+                    Ok(None) => state.spool_export(state, &mut on_progress)?,
+                    Err(e) => break 'resp NoteResponse::StartExport(Err(e)),
+                }))
+            }
+
+            NoteRequest::ExportChunk((offset, length)) => {
+                NoteResponse::ExportChunk(state.read_export_chunk(offset, length)?)
+            }
+
+            NoteRequest::ExportFolder(folder_id) => {
+                if state.folders.contains_key(&folder_id) {
+                    NoteResponse::ExportFolder(Ok(state
+                        .export_archive(Some(&folder_id), &mut |done, total| {
+                            push_progress(server, "export", done, total)
+                        })?))
+                } else {
+                    NoteResponse::ExportFolder(Err("Folder not found".to_string()))
+                }
+            }
+
+            NoteRequest::ExportFiltered(filter) => NoteResponse::ExportFiltered(Ok(state
+                .write_export_archive(None, Some(&filter), Vec::new(), &mut |done, total| {
+                    push_progress(server, "export", done, total)
+                })?)),
+
+            NoteRequest::ExportZip => {
+                let paths = state.export_paths();
+                let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+                let options = zip::write::SimpleFileOptions::default();
+                for (_, path) in paths.folders {
+                    writer.add_directory(path, options)?;
+                }
+                for (note_id, path) in paths.notes {
+                    let mut content = state.load_note_content(&note_id).unwrap_or_default();
+                    // Markdown notes with tags or aliases carry them in frontmatter
+                    let metadata = &state.notes[&note_id];
+                    if metadata.note_type == NoteType::Markdown
+                        && !metadata.is_locked
+                        && (!metadata.tags.is_empty() || !metadata.aliases.is_empty())
+                    {
+                        let text = String::from_utf8_lossy(&content);
+                        content =
+                            write_frontmatter(&text, &frontmatter_fields(metadata)).into_bytes();
+                    }
+                    writer.start_file(path, options)?;
+                    writer.write_all(&content)?;
+                }
+                let zip_bytes = writer.finish()?.into_inner();
+                NoteResponse::ExportZip(Ok(zip_bytes))
+            }
+
+            NoteRequest::ExportObsidian => {
+                NoteResponse::ExportObsidian(Ok(state.export_obsidian()?))
+            }
+
+            NoteRequest::ExportNotePdf(note_id) => {
+                NoteResponse::ExportNotePdf(state.export_note_pdf(&note_id)?)
+            }
+
+            NoteRequest::ExportNoteHtml(note_id) => {
+                NoteResponse::ExportNoteHtml(state.export_note_html(&note_id)?)
+            }
+
+            NoteRequest::SetNotePublic((note_id, is_public)) => {
+                if let Some(mut metadata) = state.notes.get(&note_id).cloned() {
+                    state.record_history(
+                        format!("Change visibility of note \"{}\"", metadata.name),
+                        vec![state.snapshot_note(&note_id, false)],
+                    );
+                    metadata.is_public = is_public;
+                    state.notes.insert(note_id.clone(), metadata.clone());
+                    // Making an unpublished note public again puts its links back up too
+                    if is_public && state.is_unpublished(&note_id) {
+                        state.record_publish(&note_id, PublishAction::Published);
+                    }
+                    state.save_to_disk()?;
+                    // Return full Note for API compatibility
+                    match state.get_full_note(&metadata) {
+                        Ok(note) => NoteResponse::SetNotePublic(Ok(note)),
+                        Err(_) => NoteResponse::SetNotePublic(Err(
+                            "Error loading note content".to_string()
+                        )),
+                    }
+                } else {
+                    NoteResponse::SetNotePublic(Err("Note not found".to_string()))
+                }
+            }
+
+            NoteRequest::PublishNote(note_id) => {
+                let Some(metadata) = state.notes.get(&note_id) else {
+                    break 'resp NoteResponse::PublishNote(Err("Note not found".to_string()));
+                };
+                if metadata.is_locked {
+                    break 'resp NoteResponse::PublishNote(Err(
+                        "Note is password protected".to_string()
+                    ));
+                }
+                state.record_history(
+                    format!("Publish note \"{}\"", metadata.name),
+                    vec![state.snapshot_note(&note_id, false)],
+                );
+                let content = state.load_note_content(&note_id)?;
+                let path = state.published_path(&state.notes[&note_id]);
+                state.drive_usage.write(&path, &content)?;
+                if let Some(metadata) = state.notes.get_mut(&note_id) {
+                    metadata.is_public = true;
+                    metadata.published = Some(now_secs());
+                }
+                state.record_publish(&note_id, PublishAction::Published);
+                // Visitors following the note get the published version
+                state.changed_notes.insert(note_id);
+                state.save_to_disk()?;
+                NoteResponse::PublishNote(Ok(()))
+            }
+
+            NoteRequest::UnpublishNote(note_id) => {
+                let Some(metadata) = state.notes.get(&note_id) else {
+                    break 'resp NoteResponse::UnpublishNote(Err("Note not found".to_string()));
+                };
+                if !metadata.is_public {
+                    break 'resp NoteResponse::UnpublishNote(Err("Note is not public".to_string()));
+                }
+                state.record_history(
+                    format!("Unpublish note \"{}\"", metadata.name),
+                    vec![state.snapshot_note(&note_id, false)],
+                );
+                // The published copy is kept for when it goes back up
+                if let Some(metadata) = state.notes.get_mut(&note_id) {
+                    metadata.is_public = false;
+                }
+                state.record_publish(&note_id, PublishAction::Unpublished);
+                state.save_to_disk()?;
+                NoteResponse::UnpublishNote(Ok(()))
+            }
+
+            NoteRequest::GetPublishHistory(note_id) => {
+                if !state.notes.contains_key(&note_id) {
+                    break 'resp NoteResponse::GetPublishHistory(Err("Note not found".to_string()));
+                }
+                let history = state
+                    .publish_history
+                    .get(&note_id)
+                    .cloned()
+                    .unwrap_or_default();
+                NoteResponse::GetPublishHistory(Ok(history))
+            }
+
+            NoteRequest::CreateShareLink((note_id, expires)) => {
+                let Some(metadata) = state.notes.get(&note_id) else {
+                    break 'resp NoteResponse::CreateShareLink(Err("Note not found".to_string()));
+                };
+                if metadata.is_locked {
+                    break 'resp NoteResponse::CreateShareLink(Err(
+                        "Note is password protected".to_string()
+                    ));
+                }
+                let link = ShareLink {
+                    token: share_link_token(),
+                    note_id,
+                    created: now_secs(),
+                    expires,
+                    hits: 0,
+                    taken: None,
+                };
+                state.share_links.push(link.clone());
+                state.save_to_disk()?;
+                NoteResponse::CreateShareLink(Ok(link))
+            }
+
+            NoteRequest::CreateSnapshotLink((note_id, expires)) => {
+                let Some(metadata) = state.notes.get(&note_id) else {
+                    break 'resp NoteResponse::CreateSnapshotLink(
+                        Err("Note not found".to_string()),
+                    );
+                };
+                if metadata.is_locked {
+                    break 'resp NoteResponse::CreateSnapshotLink(Err(
+                        "Note is password protected".to_string(),
+                    ));
+                }
+                let note = Note {
+                    folder_id: None,
+                    collaborators: Vec::new(),
+                    ..state.get_full_note(metadata)?
+                };
+                let now = now_secs();
+                let link = ShareLink {
+                    token: share_link_token(),
+                    note_id,
+                    created: now,
+                    expires,
+                    hits: 0,
+                    taken: Some(now),
+                };
+                state.save_snapshot(&link.token, &note)?;
+                state.share_links.push(link.clone());
+                state.save_to_disk()?;
+                NoteResponse::CreateSnapshotLink(Ok(link))
+            }
+
+            NoteRequest::ListShareLinks(note_id) => NoteResponse::ListShareLinks(Ok(state
+                .share_links
+                .iter()
+                .filter(|link| link.note_id == note_id)
+                .cloned()
+                .collect())),
+
+            NoteRequest::RevokeShareLink(token) => {
+                let Some(index) = state
+                    .share_links
+                    .iter()
+                    .position(|link| link.token == token)
+                else {
+                    break 'resp NoteResponse::RevokeShareLink(Err("Link not found".to_string()));
+                };
+                if state.share_links.remove(index).taken.is_some() {
+                    let path = format!("{}/snapshot_{}", &state.drive, token);
+                    if let Err(e) = state.drive_usage.remove(&path) {
+                        error!("Failed to delete snapshot {}: {}", token, e);
+                    }
+                }
+                state.save_to_disk()?;
+                NoteResponse::RevokeShareLink(Ok(()))
+            }
+
+            NoteRequest::SetNoteComments((note_id, enabled)) => {
+                if !state.notes.contains_key(&note_id) {
+                    break 'resp NoteResponse::SetNoteComments(Err("Note not found".to_string()));
+                }
+                state.comments.entry(note_id).or_default().enabled = enabled;
+                state.save_to_disk()?;
+                NoteResponse::SetNoteComments(Ok(()))
+            }
+
+            NoteRequest::GetComments(note_id) => {
+                if !state.notes.contains_key(&note_id) {
+                    break 'resp NoteResponse::GetComments(Err("Note not found".to_string()));
+                }
+                let comments = state.comments.get(&note_id).cloned().unwrap_or_default();
+                NoteResponse::GetComments(Ok((comments.enabled, comments.comments)))
+            }
+
+            NoteRequest::ModerateComments((note_id, actions)) => {
+                let Some(note_comments) = state.comments.get_mut(&note_id) else {
+                    break 'resp NoteResponse::ModerateComments(Err(
+                        "Note has no comments".to_string()
+                    ));
+                };
+                // Applied to a copy, so an unknown id leaves every comment as it was
+                let mut comments = note_comments.comments.clone();
+                for action in actions {
+                    let (id, hidden) = match action {
+                        CommentAction::Hide(id) => (id, Some(true)),
+                        CommentAction::Show(id) => (id, Some(false)),
+                        CommentAction::Delete(id) => (id, None),
+                    };
+                    let Some(index) = comments.iter().position(|c| c.id == id) else {
+                        break 'resp NoteResponse::ModerateComments(Err(format!(
+                            "Comment {} not found",
+                            id
+                        )));
+                    };
+                    match hidden {
+                        Some(hidden) => comments[index].hidden = hidden,
+                        None => {
+                            comments.remove(index);
+                        }
+                    }
+                }
+                note_comments.comments = comments.clone();
+                state.save_to_disk()?;
+                NoteResponse::ModerateComments(Ok(comments))
+            }
+
+            NoteRequest::InviteCollaborator((note_id, node_id)) => {
+                if state.notes.contains_key(&note_id) {
+                    let expires = state.add_invite(&note_id, &node_id);
+                    state.save_to_disk()?;
+                    let metadata = &state.notes[&note_id];
+                    send_invite(&node_id, &note_id, &metadata.name, expires)?;
+                    // Return full Note for API compatibility
+                    match state.get_full_note(metadata) {
+                        Ok(note) => NoteResponse::InviteCollaborator(Ok(note)),
+                        Err(_) => NoteResponse::InviteCollaborator(Err(
+                            "Error loading note content".to_string(),
+                        )),
+                    }
+                } else {
+                    NoteResponse::InviteCollaborator(Err("Note not found".to_string()))
+                }
+            }
+
+            NoteRequest::RemoveCollaborator((note_id, node_id)) => {
+                if let Some(mut metadata) = state.notes.get(&note_id).cloned() {
+                    // Remove from collaborators if present
+                    let invited = state
+                        .collaboration_invites
+                        .get(&note_id)
+                        .is_some_and(|invites| invites.contains_key(&node_id));
+                    if invited || metadata.collaborators.contains(&node_id) {
+                        state.collaborator_activity(&note_id, &node_id).revoked = Some(now_secs());
+                    }
+                    metadata.collaborators.retain(|id| id != &node_id);
+                    state.notes.insert(note_id.clone(), metadata.clone());
+
+                    // Remove any pending invites
+                    state.remove_invite(&note_id, &node_id);
+                    // Rotate the share key so the removed node cannot read later edits
+                    state.share_keys.remove(&note_id);
+
+                    state.save_to_disk()?;
+                    // Return full Note for API compatibility
+                    match state.get_full_note(&metadata) {
+                        Ok(note) => NoteResponse::RemoveCollaborator(Ok(note)),
+                        Err(_) => NoteResponse::RemoveCollaborator(Err(
+                            "Error loading note content".to_string(),
+                        )),
+                    }
+                } else {
+                    NoteResponse::RemoveCollaborator(Err("Note not found".to_string()))
+                }
+            }
+
+            NoteRequest::LeaveSharedNote(note_id) => {
+                // A collaborator's node leaving one of our notes
+                if let Some(collaborator) = source.filter(|source| source.node != our().node) {
+                    let Some(metadata) = state.notes.get_mut(&note_id) else {
+                        break 'resp NoteResponse::LeaveSharedNote(Err(
+                            "Note not found".to_string()
+                        ));
+                    };
+                    if !metadata.collaborators.contains(&collaborator.node) {
+                        break 'resp NoteResponse::LeaveSharedNote(Err(
+                            "Not a collaborator".to_string()
+                        ));
+                    }
+                    metadata.collaborators.retain(|id| id != &collaborator.node);
+                    state
+                        .collaborator_activity(&note_id, &collaborator.node)
+                        .revoked = Some(now_secs());
+                    // Rotate the share key so the departed node cannot read later edits
+                    state.share_keys.remove(&note_id);
+                    state.save_to_disk()?;
+                    break 'resp NoteResponse::LeaveSharedNote(Ok(()));
+                }
+                let Some((owner, owner_note_id)) = shared_note_ref(&note_id) else {
+                    break 'resp NoteResponse::LeaveSharedNote(Err(
+                        "Not a note shared with us".to_string()
+                    ));
+                };
+                state
+                    .shared_notes
+                    .retain(|shared| shared.owner != owner || shared.note_id != owner_note_id);
+                state
+                    .pending_shared_edits
+                    .retain(|edit| edit.owner != owner || edit.note_id != owner_note_id);
+                state.shared_note_cache.remove(&note_id);
+                state.shared_presence.remove(&note_id);
+                state.save_to_disk()?;
+                send_leave_notice(owner, owner_note_id)?;
+                NoteResponse::LeaveSharedNote(Ok(()))
+            }
+
+            NoteRequest::AcceptInvite((note_id, inviter_node_id)) => {
+                if state.prune_invites() {
+                    state.save_to_disk()?;
+                }
+                let remote_invitee = source
+                    .filter(|source| source.node != our().node)
+                    .map(|source| source.node.clone());
+                // Invites to a note on another node are accepted there, in the background;
+                // asking again once the inviter has answered returns the note
+                if remote_invitee.is_none() && inviter_node_id != our().node {
+                    let is_invited = state.received_invites.iter().any(|invite| {
+                        invite.note_id == note_id && invite.inviter_node_id == inviter_node_id
+                    });
+                    if !is_invited {
+                        let id = format!("{}/{}", inviter_node_id, note_id);
+                        let accepted = state
+                            .shared_section()
+                            .and_then(|(_, notes)| notes.into_iter().find(|note| note.id == id));
+                        break 'resp NoteResponse::AcceptInvite(
+                            accepted.ok_or_else(|| "No invite found".to_string()),
+                        );
+                    }
+                    let is_accepting = state.pending_replies.values().any(|reply| match reply {
+                        PendingReply::AcceptInvite {
+                            inviter,
+                            note_id: n,
+                        } => *inviter == inviter_node_id && *n == note_id,
+                        _ => false,
+                    });
+                    if !is_accepting {
+                        send_to_node(
+                            state,
+                            &inviter_node_id,
+                            NoteRequest::AcceptInvite((note_id.clone(), inviter_node_id.clone())),
+                            PendingReply::AcceptInvite {
+                                inviter: inviter_node_id.clone(),
+                                note_id,
+                            },
+                        )?;
+                    }
+                    break 'resp NoteResponse::AcceptInvite(Err(format!(
+                        "Accepting the invite on {}, try again shortly",
+                        inviter_node_id
+                    )));
+                }
+                let invitee = remote_invitee.unwrap_or(our().node);
+                // Verify invite exists
+                if let Some(invites) = state.collaboration_invites.get_mut(&note_id) {
+                    if invites.get(&invitee) == Some(&inviter_node_id) {
+                        if let Some(mut metadata) = state.notes.get(&note_id).cloned() {
+                            // Add to collaborators
+                            if !metadata.collaborators.contains(&invitee) {
+                                metadata.collaborators.push(invitee.clone());
+                            }
+                            state.notes.insert(note_id.clone(), metadata.clone());
+                            let activity = state.collaborator_activity(&note_id, &invitee);
+                            activity.revoked = None;
+                            activity.joined = Some(now_secs());
+
+                            // Remove invite
+                            state.remove_invite(&note_id, &invitee);
+
+                            state.save_to_disk()?;
+                            // Return full Note for API compatibility; other nodes fetch the content
+                            // encrypted with get-note
+                            match state.get_full_note(&metadata) {
+                                Ok(mut note) => {
+                                    if invitee != our().node {
+                                        note.content = Vec::new();
+                                    }
+                                    NoteResponse::AcceptInvite(Ok(note))
+                                }
+                                Err(_) => NoteResponse::AcceptInvite(Err(
+                                    "Error loading note content".to_string(),
+                                )),
+                            }
+                        } else {
+                            NoteResponse::AcceptInvite(Err("Note not found".to_string()))
+                        }
+                    } else {
+                        NoteResponse::AcceptInvite(Err("Invalid inviter".to_string()))
+                    }
+                } else {
+                    NoteResponse::AcceptInvite(Err("No invite found".to_string()))
+                }
+            }
+
+            NoteRequest::RejectInvite((note_id, inviter_node_id)) => {
+                let remote_invitee = source
+                    .filter(|source| source.node != our().node)
+                    .map(|source| source.node.clone());
+                if remote_invitee.is_none() && inviter_node_id != our().node {
+                    let count = state.received_invites.len();
+                    state.received_invites.retain(|invite| {
+                        invite.note_id != note_id || invite.inviter_node_id != inviter_node_id
+                    });
+                    if state.received_invites.len() == count {
+                        break 'resp NoteResponse::RejectInvite(Err("No invite found".to_string()));
+                    }
+                    state.save_to_disk()?;
+                    send_invite_rejection(&inviter_node_id, &note_id)?;
+                    break 'resp NoteResponse::RejectInvite(Ok(()));
+                }
+                let invitee = remote_invitee.unwrap_or(our().node);
+                if let Some(invites) = state.collaboration_invites.get_mut(&note_id) {
+                    if invites.get(&invitee) == Some(&inviter_node_id) {
+                        // Remove invite
+                        state.remove_invite(&note_id, &invitee);
+                        state.save_to_disk()?;
+                        NoteResponse::RejectInvite(Ok(()))
+                    } else {
+                        NoteResponse::RejectInvite(Err("Invalid inviter".to_string()))
+                    }
+                } else {
+                    NoteResponse::RejectInvite(Err("No invite found".to_string()))
+                }
+            }
+
+            NoteRequest::GetShareKey((note_id, public_key)) => {
+                let Some(metadata) = state.notes.get(&note_id) else {
+                    break 'resp NoteResponse::GetShareKey(Err(
+                        "Not found or not authorized".to_string()
+                    ));
+                };
+                let authorized = source.is_some_and(|source| {
+                    source == &our() || metadata.collaborators.contains(&source.node)
+                });
+                if !authorized || metadata.is_locked {
+                    break 'resp NoteResponse::GetShareKey(Err(
+                        "Not found or not authorized".to_string()
+                    ));
+                }
+                let asker = source.map_or_else(|| our().node, |source| source.node.clone());
+                let mut keys = load_exchange_keys()?;
+                match keys.pin(&asker, &public_key) {
+                    Ok(true) => save_exchange_keys(&keys)?,
+                    Ok(false) => {}
+                    Err(e) => break 'resp NoteResponse::GetShareKey(Err(e)),
+                }
+                let share_key = state.share_key(&note_id)?;
+                let sealed = keys
+                    .wrapping_key(&public_key, &note_id)
+                    .and_then(|wrapping| seal_share_key(&share_key, &wrapping));
+                match sealed {
+                    Ok(sealed) => NoteResponse::GetShareKey(Ok((keys.public_key()?, sealed))),
+                    Err(e) => NoteResponse::GetShareKey(Err(e)),
+                }
+            }
+
+            NoteRequest::ForgetExchangeKey(node) => {
+                let mut keys = load_exchange_keys()?;
+                if keys.peers.remove(&node).is_none() {
+                    break 'resp NoteResponse::ForgetExchangeKey(Err(format!(
+                        "No exchange key pinned for {}",
+                        node
+                    )));
+                }
+                save_exchange_keys(&keys)?;
+                NoteResponse::ForgetExchangeKey(Ok(()))
+            }
+
+            NoteRequest::OpenSharedNote((owner, note_id)) => {
+                let mut result = open_shared_note(state, &owner, &note_id)?;
+                // Callers of open-shared-note know the note by the owner's id
+                if let Ok(note) = &mut result {
+                    note.id = note_id;
+                }
+                NoteResponse::OpenSharedNote(result)
+            }
+
+            NoteRequest::SaveSharedNote((owner, note_id, content)) => {
+                save_shared_note(state, &owner, &note_id, content)?;
+                NoteResponse::SaveSharedNote(Ok(()))
+            }
+
+            NoteRequest::NotifyInvite((note_id, note_name, expires)) => {
+                let Some(inviter) = source.filter(|source| source.node != our().node) else {
+                    break 'resp NoteResponse::NotifyInvite(Err(
+                        "Invites come from the node that owns the note".to_string(),
+                    ));
+                };
+                if state.blocked_nodes.contains(&inviter.node) {
+                    send_invite_rejection(&inviter.node, &note_id)?;
+                    break 'resp NoteResponse::NotifyInvite(Ok(()));
+                }
+                let invite = Invite {
+                    note_id,
+                    inviter_node_id: inviter.node.clone(),
+                    note_name,
+                    expires,
+                };
+                state.received_invites.retain(|i| {
+                    i.note_id != invite.note_id || i.inviter_node_id != invite.inviter_node_id
+                });
+                push_notification(
+                    &format!(
+                        "{} invited you to \"{}\"",
+                        invite.inviter_node_id, invite.note_name
+                    ),
+                    "Accept the invite to open the note in wifenote",
+                );
+                state.received_invites.push(invite);
+                state.save_to_disk()?;
+                NoteResponse::NotifyInvite(Ok(()))
+            }
+
+            NoteRequest::NotifyMention((note_id, note_name, from_node_id)) => {
+                let Some(owner) = source.filter(|source| source.node != our().node) else {
+                    break 'resp NoteResponse::NotifyMention(Err(
+                        "Mentions come from the node that owns the note".to_string(),
+                    ));
+                };
+                // Only notes shared with us, which includes collaborations we accepted, so other
+                // nodes cannot fill the mentions list
+                if !state
+                    .shared_notes
+                    .iter()
+                    .any(|shared| shared.owner == owner.node && shared.note_id == note_id)
+                {
+                    break 'resp NoteResponse::NotifyMention(Err(
+                        "Note is not shared with us".to_string()
+                    ));
+                }
+                state.record_mention(Mention {
+                    owner_node_id: owner.node.clone(),
+                    note_id,
+                    note_name,
+                    from_node_id,
+                    received: now_secs(),
+                })?;
+                NoteResponse::NotifyMention(Ok(()))
+            }
+
+            NoteRequest::GetMentions => NoteResponse::GetMentions(Ok(state.mentions.clone())),
+
+            NoteRequest::DismissMention((owner_node_id, note_id)) => {
+                state
+                    .mentions
+                    .retain(|m| m.owner_node_id != owner_node_id || m.note_id != note_id);
+                state.save_to_disk()?;
+                NoteResponse::DismissMention(Ok(()))
+            }
+
+            NoteRequest::PingPresence((note_id, cursor)) => {
+                let Some(metadata) = state.notes.get(&note_id) else {
+                    break 'resp NoteResponse::PingPresence(Err(
+                        "Not found or not authorized".to_string()
+                    ));
+                };
+                let Some(source) = source.filter(|&source| {
+                    source == &our() || metadata.collaborators.contains(&source.node)
+                }) else {
+                    break 'resp NoteResponse::PingPresence(Err(
+                        "Not found or not authorized".to_string()
+                    ));
+                };
+                state.update_presence(Presence {
+                    note_id: note_id.clone(),
+                    node_id: source.node.clone(),
+                    channel: None,
+                    cursor,
+                    seen: now_secs(),
+                });
+                push_presence(state, &note_id);
+                NoteResponse::PingPresence(Ok(state.note_presence(&note_id)))
+            }
+
+            // Answered from the owner's reply to the previous ping, so this never waits on them
+            NoteRequest::PingSharedPresence((owner, note_id, cursor)) => {
+                request_shared_presence(&owner, &note_id, cursor)?;
+                let id = format!("{}/{}", owner, note_id);
+                NoteResponse::PingSharedPresence(Ok(state
+                    .shared_presence
+                    .get(&id)
+                    .cloned()
+                    .unwrap_or_default()))
+            }
+
+            NoteRequest::GetPresence(note_id) => {
+                if !state.notes.contains_key(&note_id) {
+                    break 'resp NoteResponse::GetPresence(Err("Note not found".to_string()));
+                }
+                NoteResponse::GetPresence(Ok(state.note_presence(&note_id)))
+            }
+
+            NoteRequest::ResendInvite((note_id, node_id)) => {
+                state.prune_invites();
+                let Some(name) = state.notes.get(&note_id).map(|m| m.name.clone()) else {
+                    break 'resp NoteResponse::ResendInvite(Err("Note not found".to_string()));
+                };
+                let invited = state
+                    .collaboration_invites
+                    .get(&note_id)
+                    .is_some_and(|invites| invites.contains_key(&node_id));
+                if !invited {
+                    break 'resp NoteResponse::ResendInvite(Err(
+                        "No pending invite; invite the node again".to_string(),
+                    ));
+                }
+                let expires = now_secs() + INVITE_TTL_SECS;
+                state
+                    .invite_expiry
+                    .entry(note_id.clone())
+                    .or_default()
+                    .insert(node_id.clone(), expires);
+                state.save_to_disk()?;
+                send_invite(&node_id, &note_id, &name, expires)?;
+                NoteResponse::ResendInvite(Ok(expires))
+            }
+
+            NoteRequest::BlockNode(node_id) => {
+                if node_id == our().node {
+                    break 'resp NoteResponse::BlockNode(Err("Cannot block this node".to_string()));
+                }
+                if !state.blocked_nodes.contains(&node_id) {
+                    state.blocked_nodes.push(node_id.clone());
+                }
+                // Reject whatever the node already sent
+                let (rejected, kept) = std::mem::take(&mut state.received_invites)
+                    .into_iter()
+                    .partition(|invite| invite.inviter_node_id == node_id);
+                state.received_invites = kept;
+                state.save_to_disk()?;
+                for invite in rejected {
+                    send_invite_rejection(&node_id, &invite.note_id)?;
+                }
+                NoteResponse::BlockNode(Ok(()))
+            }
+
+            NoteRequest::ListBlockedNodes => {
+                NoteResponse::ListBlockedNodes(Ok(state.blocked_nodes.clone()))
+            }
+
+            NoteRequest::UnblockNode(node_id) => {
+                let count = state.blocked_nodes.len();
+                state.blocked_nodes.retain(|blocked| blocked != &node_id);
+                if state.blocked_nodes.len() == count {
+                    break 'resp NoteResponse::UnblockNode(Err("Node is not blocked".to_string()));
+                }
+                state.save_to_disk()?;
+                NoteResponse::UnblockNode(Ok(()))
+            }
+
+            NoteRequest::TransferNote((note_id, target, keep_copy)) => {
+                if target == our().node {
+                    break 'resp NoteResponse::TransferNote(Err(
+                        "The note is already on this node".to_string(),
+                    ));
+                }
+                let Some(metadata) = state.notes.get(&note_id).cloned() else {
+                    break 'resp NoteResponse::TransferNote(Err("Note not found".to_string()));
+                };
+                if metadata.is_locked {
+                    break 'resp NoteResponse::TransferNote(Err(
+                        "Remove the note's password before transferring it".to_string(),
+                    ));
+                }
+                let bytes = state.note_file_bytes(&metadata)
+                    + state
+                        .attachments
+                        .values()
+                        .filter(|a| a.note_id == note_id)
+                        .map(|a| a.size)
+                        .sum::<u64>();
+                // Nothing moves until the target accepts, in accept-transfer
+                state
+                    .outgoing_transfers
+                    .insert(note_id.clone(), (target.clone(), keep_copy));
+                state.save_to_disk()?;
+                send_transfer_offer(&target, &note_id, &metadata.name, bytes)?;
+                NoteResponse::TransferNote(Ok(()))
+            }
+
+            NoteRequest::NotifyTransfer((note_id, note_name, bytes)) => {
+                let Some(sender) = source.filter(|source| source.node != our().node) else {
+                    break 'resp NoteResponse::NotifyTransfer(Err(
+                        "Transfers come from the node that owns the note".to_string(),
+                    ));
+                };
+                if state.blocked_nodes.contains(&sender.node) {
+                    send_transfer_rejection(&sender.node, &note_id)?;
+                    break 'resp NoteResponse::NotifyTransfer(Ok(()));
+                }
+                let offer = TransferOffer {
+                    note_id,
+                    sender_node_id: sender.node.clone(),
+                    note_name,
+                    bytes,
+                    received: now_secs(),
+                };
+                state.transfer_offers.retain(|o| {
+                    o.note_id != offer.note_id || o.sender_node_id != offer.sender_node_id
+                });
+                push_notification(
+                    &format!(
+                        "{} wants to transfer \"{}\" to you",
+                        offer.sender_node_id, offer.note_name
+                    ),
+                    "Accept the transfer to make the note yours",
+                );
+                state.transfer_offers.push(offer);
+                state.save_to_disk()?;
+                NoteResponse::NotifyTransfer(Ok(()))
+            }
+
+            NoteRequest::GetTransferOffers => {
+                NoteResponse::GetTransferOffers(Ok(state.transfer_offers.clone()))
+            }
+
+            NoteRequest::AcceptTransfer((note_id, sender_node_id)) => {
+                let key = (sender_node_id.clone(), note_id.clone());
+                if let Some(id) = state.accepted_transfers.get(&key) {
+                    break 'resp NoteResponse::AcceptTransfer(Ok(Some(id.clone())));
+                }
+                let is_offered = state.transfer_offers.iter().any(|offer| {
+                    offer.note_id == note_id && offer.sender_node_id == sender_node_id
+                });
+                if !is_offered {
+                    break 'resp NoteResponse::AcceptTransfer(Err("No transfer found".to_string()));
+                }
+                // The note is fetched from the sender in the background. Until it is here this
+                // answers none; asking again after returns its id.
+                let is_fetching = state.pending_replies.values().any(|reply| match reply {
+                    PendingReply::FetchTransfer { sender, note_id: n } => {
+                        *sender == sender_node_id && *n == note_id
+                    }
+                    _ => false,
+                });
+                if !is_fetching {
+                    send_to_node(
+                        state,
+                        &sender_node_id,
+                        NoteRequest::FetchTransfer(note_id.clone()),
+                        PendingReply::FetchTransfer {
+                            sender: sender_node_id.clone(),
+                            note_id,
+                        },
+                    )?;
+                }
+                NoteResponse::AcceptTransfer(Ok(None))
+            }
+
+            NoteRequest::RejectTransfer((note_id, sender_node_id)) => {
+                // From the target, withdrawing our offer
+                if let Some(target) = source.filter(|source| source.node != our().node) {
+                    if state
+                        .outgoing_transfers
+                        .get(&note_id)
+                        .is_some_and(|(node, _)| node == &target.node)
+                    {
+                        state.outgoing_transfers.remove(&note_id);
+                        state.save_to_disk()?;
+                    }
+                    break 'resp NoteResponse::RejectTransfer(Ok(()));
+                }
+                let count = state.transfer_offers.len();
+                state.transfer_offers.retain(|offer| {
+                    offer.note_id != note_id || offer.sender_node_id != sender_node_id
+                });
+                if state.transfer_offers.len() == count {
+                    break 'resp NoteResponse::RejectTransfer(Err("No transfer found".to_string()));
+                }
+                state.save_to_disk()?;
+                send_transfer_rejection(&sender_node_id, &note_id)?;
+                NoteResponse::RejectTransfer(Ok(()))
+            }
+
+            NoteRequest::FetchTransfer(note_id) => {
+                let target = source
+                    .filter(|source| source.node != our().node)
+                    .map(|source| source.node.clone());
+                let keep_copy = match (state.outgoing_transfers.get(&note_id), &target) {
+                    (Some((node, keep_copy)), Some(target)) if node == target => *keep_copy,
+                    _ => {
+                        break 'resp NoteResponse::FetchTransfer(Err(
+                            "No transfer of this note to you".to_string(),
+                        ))
+                    }
+                };
+                let Some(metadata) = state.notes.get(&note_id).cloned() else {
+                    break 'resp NoteResponse::FetchTransfer(Err("Note not found".to_string()));
+                };
+                if metadata.is_locked {
+                    break 'resp NoteResponse::FetchTransfer(Err(
+                        "The note has a password".to_string()
+                    ));
+                }
+                let mut note = state.get_full_note(&metadata)?;
+                note.collaborators
+                    .retain(|node| Some(node) != target.as_ref());
+                if keep_copy && !note.collaborators.contains(&our().node) {
+                    note.collaborators.push(our().node);
+                }
+                let mut attachments = Vec::new();
+                for attachment in state.attachments.values().filter(|a| a.note_id == note_id) {
+                    let content = state.load_attachment_content(&attachment.id)?;
+                    attachments.push((attachment.clone(), content));
+                }
+                NoteResponse::FetchTransfer(Ok(TransferredNote {
+                    note,
+                    due: metadata.due,
+                    aliases: metadata.aliases.clone(),
+                    attachments,
+                }))
+            }
+
+            NoteRequest::CompleteTransfer((note_id, new_id)) => {
+                let target = source
+                    .filter(|source| source.node != our().node)
+                    .map(|source| source.node.clone());
+                let keep_copy = match (state.outgoing_transfers.get(&note_id), &target) {
+                    (Some((node, keep_copy)), Some(target)) if node == target => *keep_copy,
+                    _ => {
+                        break 'resp NoteResponse::CompleteTransfer(Err(
+                            "No transfer of this note to you".to_string(),
+                        ))
+                    }
+                };
+                let target = target.unwrap_or_default();
+                state.outgoing_transfers.remove(&note_id);
+                let Some(metadata) = state.notes.get(&note_id).cloned() else {
+                    state.save_to_disk()?;
+                    break 'resp NoteResponse::CompleteTransfer(Ok(()));
+                };
+                let note = state.get_full_note(&metadata)?;
+                // The target has confirmed it holds the note, so ours goes
+                for invitee in state
+                    .collaboration_invites
+                    .get(&note_id)
+                    .map(|invites| invites.keys().cloned().collect::<Vec<_>>())
+                    .unwrap_or_default()
+                {
+                    state.remove_invite(&note_id, &invitee);
+                }
+                state.share_keys.remove(&note_id);
+                // Not a deletion to undo: the note is the target's now, so it goes along with
+                // any history that could bring it back here
+                let attachment_ids: Vec<String> = state
+                    .attachments
+                    .values()
+                    .filter(|a| a.note_id == note_id)
+                    .map(|a| a.id.clone())
+                    .collect();
+                state.notes.remove(&note_id);
+                for attachment_id in &attachment_ids {
+                    state.remove_attachment(attachment_id);
+                }
+                state.forget_note(&metadata);
+                state.forget_note_history(&note_id);
+                if keep_copy {
+                    state.remember_shared_note(&target, &new_id, &note)?;
+                }
+                state.save_to_disk()?;
+                push_notification(
+                    &format!("{} accepted \"{}\"", target, note.name),
+                    "The note is now theirs",
+                );
+                NoteResponse::CompleteTransfer(Ok(()))
+            }
+
+            NoteRequest::GetSharedActivity(days) => {
+                NoteResponse::GetSharedActivity(Ok(state.shared_activity(days)))
+            }
+
+            NoteRequest::GetNoteReadState(note_id) => {
+                let Some(metadata) = state.notes.get(&note_id) else {
+                    break 'resp NoteResponse::GetNoteReadState(Err("Note not found".to_string()));
+                };
+                let activity = state.collaborator_activity.get(&note_id);
+                let read_states = metadata
+                    .collaborators
+                    .iter()
+                    .map(|node| {
+                        let activity = activity.and_then(|a| a.get(node));
+                        let last_read = activity.and_then(|a| a.last_read);
+                        let last_seen = last_read.max(activity.and_then(|a| a.last_edit));
+                        ReadState {
+                            node_id: node.clone(),
+                            last_read,
+                            seen_latest: last_seen.is_some_and(|seen| seen >= metadata.updated),
+                        }
+                    })
+                    .collect();
+                NoteResponse::GetNoteReadState(Ok(read_states))
+            }
+
+            NoteRequest::GetCollaborators(note_id) => {
+                let Some(metadata) = state.notes.get(&note_id) else {
+                    break 'resp NoteResponse::GetCollaborators(Err("Note not found".to_string()));
+                };
+                let activity = state
+                    .collaborator_activity
+                    .get(&note_id)
+                    .cloned()
+                    .unwrap_or_default();
+                let invites = state
+                    .collaboration_invites
+                    .get(&note_id)
+                    .cloned()
+                    .unwrap_or_default();
+                let last_edit = |node: &str| activity.get(node).and_then(|a| a.last_edit);
+                let mut collaborators: Vec<Collaborator> = metadata
+                    .collaborators
+                    .iter()
+                    .map(|node| Collaborator {
+                        node_id: node.clone(),
+                        status: CollaboratorStatus::Accepted,
+                        last_edit: last_edit(node),
+                        can_edit: true,
+                    })
+                    .collect();
+                let mut pending: Vec<Collaborator> = invites
+                    .keys()
+                    .filter(|node| !metadata.collaborators.contains(node))
+                    .map(|node| Collaborator {
+                        node_id: node.clone(),
+                        status: CollaboratorStatus::Pending,
+                        last_edit: last_edit(node),
+                        can_edit: false,
+                    })
+                    .collect();
+                let mut revoked: Vec<Collaborator> = activity
+                    .iter()
+                    .filter(|(node, a)| {
+                        a.revoked.is_some()
+                            && !metadata.collaborators.contains(node)
+                            && !invites.contains_key(*node)
+                    })
+                    .map(|(node, a)| Collaborator {
+                        node_id: node.clone(),
+                        status: CollaboratorStatus::Revoked,
+                        last_edit: a.last_edit,
+                        can_edit: false,
+                    })
+                    .collect();
+                pending.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+                revoked.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+                collaborators.extend(pending);
+                collaborators.extend(revoked);
+                NoteResponse::GetCollaborators(Ok(collaborators))
+            }
+
+            NoteRequest::ShareFolder((folder_id, nodes)) => {
+                if !state.folders.contains_key(&folder_id) {
+                    break 'resp NoteResponse::ShareFolder(Err("Folder not found".to_string()));
+                }
+                state.share_folder(&folder_id, nodes);
+                state.save_to_disk()?;
+                NoteResponse::ShareFolder(Ok(()))
+            }
+
+            NoteRequest::ShareFolderWithNodes((folder_id, nodes, read_only)) => {
+                // Sharing the folder itself is ShareFolder's; this adds the invites
+                let request = NoteRequest::ShareFolder((folder_id.clone(), nodes.clone()));
+                match handle_note_request(request, source, state, server)? {
+                    NoteResponse::ShareFolder(Ok(())) => {}
+                    NoteResponse::ShareFolder(Err(e)) => {
+                        break 'resp NoteResponse::ShareFolderWithNodes(Err(e))
+                    }
+                    _ => {
+                        break 'resp NoteResponse::ShareFolderWithNodes(Err(
+                            "Failed to share the folder".to_string(),
+                        ))
+                    }
+                }
+                // Editing goes through each note's own collaboration
+                let mut invites = Vec::new();
+                if !read_only {
+                    let subtree = state.folder_subtree(&folder_id);
+                    let notes: Vec<(String, String, Vec<String>)> = state
+                        .notes
+                        .values()
+                        .filter(|n| n.folder_id.as_ref().is_some_and(|f| subtree.contains(f)))
+                        .map(|n| (n.id.clone(), n.name.clone(), n.collaborators.clone()))
+                        .collect();
+                    for node in nodes.iter().filter(|node| **node != our().node) {
+                        for (note_id, name, collaborators) in &notes {
+                            if !collaborators.contains(node) {
+                                let expires = state.add_invite(note_id, node);
+                                invites.push((
+                                    node.clone(),
+                                    note_id.clone(),
+                                    name.clone(),
+                                    expires,
+                                ));
+                            }
+                        }
+                    }
+                }
+                if !invites.is_empty() {
+                    state.save_to_disk()?;
+                }
+                for (node, note_id, name, expires) in invites {
+                    send_invite(&node, &note_id, &name, expires)?;
+                }
+                NoteResponse::ShareFolderWithNodes(Ok(()))
+            }
+
+            NoteRequest::PullSharedFolder(folder_id) => {
+                let Some(puller) = source.filter(|source| source.node != our().node) else {
+                    break 'resp NoteResponse::PullSharedFolder(Err(
+                        "Folders are pulled by other nodes".to_string(),
+                    ));
+                };
+                let allowed = state
+                    .shared_folders
+                    .get(&folder_id)
+                    .is_some_and(|nodes| nodes.contains(&puller.node));
+                if !allowed || !state.folders.contains_key(&folder_id) {
+                    break 'resp NoteResponse::PullSharedFolder(Err(
+                        "Not found or not authorized".to_string()
+                    ));
+                }
+                NoteResponse::PullSharedFolder(Ok(state.full_mirror_update(&folder_id)))
+            }
+
+            NoteRequest::SubscribeFolder(folder_id) => {
+                let Some(subscriber) = source.filter(|source| source.node != our().node) else {
+                    break 'resp NoteResponse::SubscribeFolder(Err(
+                        "Folders are mirrored by other nodes".to_string(),
+                    ));
+                };
+                let allowed = state
+                    .shared_folders
+                    .get(&folder_id)
+                    .is_some_and(|nodes| nodes.contains(&subscriber.node));
+                if !allowed || !state.folders.contains_key(&folder_id) {
+                    break 'resp NoteResponse::SubscribeFolder(Err(
+                        "Not found or not authorized".to_string()
+                    ));
+                }
+                state
+                    .folder_subscribers
+                    .entry(folder_id.clone())
+                    .or_default()
+                    .insert(subscriber.node.clone());
+                state.save_to_disk()?;
+                NoteResponse::SubscribeFolder(Ok(state.full_mirror_update(&folder_id)))
+            }
+
+            NoteRequest::UnsubscribeFolder(folder_id) => {
+                let Some(subscriber) = source.filter(|source| source.node != our().node) else {
+                    break 'resp NoteResponse::UnsubscribeFolder(Err(
+                        "Folders are mirrored by other nodes".to_string(),
+                    ));
+                };
+                if let Some(subscribers) = state.folder_subscribers.get_mut(&folder_id) {
+                    subscribers.remove(&subscriber.node);
+                }
+                state.save_to_disk()?;
+                NoteResponse::UnsubscribeFolder(Ok(()))
+            }
+
+            NoteRequest::MirrorFolder((owner, folder_id)) => {
+                if owner == our().node {
+                    break 'resp NoteResponse::MirrorFolder(Err(
+                        "The folder is already on this node".to_string(),
+                    ));
+                }
+                // The owner is subscribed to in the background; asking again once it has
+                // answered returns the folder
+                if let Some(mirror) = state
+                    .mirrors
+                    .iter()
+                    .find(|m| m.owner == owner && m.folder_id == folder_id)
+                {
+                    break 'resp match state.folders.get(&mirror.local_folder_id) {
+                        Some(folder) => NoteResponse::MirrorFolder(Ok(folder.clone())),
+                        None => NoteResponse::MirrorFolder(Err("Folder not found".to_string())),
+                    };
+                }
+                let is_subscribing = state.pending_replies.values().any(|reply| match reply {
+                    PendingReply::SubscribeFolder {
+                        owner: o,
+                        folder_id: f,
+                    } => *o == owner && *f == folder_id,
+                    _ => false,
+                });
+                if !is_subscribing {
+                    send_to_node(
+                        state,
+                        &owner,
+                        NoteRequest::SubscribeFolder(folder_id.clone()),
+                        PendingReply::SubscribeFolder {
+                            owner: owner.clone(),
+                            folder_id,
+                        },
+                    )?;
+                }
+                NoteResponse::MirrorFolder(Err(format!(
+                    "Subscribing to the folder on {}, try again shortly",
+                    owner
+                )))
+            }
+
+            NoteRequest::UnmirrorFolder(folder_id) => {
+                let Some(index) = state
+                    .mirrors
+                    .iter()
+                    .position(|m| m.local_folder_id == folder_id)
+                else {
+                    break 'resp NoteResponse::UnmirrorFolder(Err(
+                        "Not a mirrored folder".to_string()
+                    ));
+                };
+                let mirror = state.mirrors.remove(index);
+                if let Some(folder) = state.folders.get_mut(&folder_id) {
+                    folder.mirror_of = None;
+                }
+                state.save_to_disk()?;
+                // The acknowledgement is ignored when it comes back
+                Request::to(Address::new(&mirror.owner, our().process.clone()))
+                    .body(NoteRequest::UnsubscribeFolder(mirror.folder_id))
+                    .expects_response(COLLABORATION_TIMEOUT_SECS)
+                    .send()?;
+                NoteResponse::UnmirrorFolder(Ok(()))
+            }
+
+            NoteRequest::ListMirrors => NoteResponse::ListMirrors(Ok(state
+                .mirrors
+                .iter()
+                .map(|m| FolderMirror {
+                    folder_id: m.local_folder_id.clone(),
+                    owner: m.owner.clone(),
+                    owner_folder_id: m.folder_id.clone(),
+                })
+                .collect())),
+
+            NoteRequest::MirrorUpdate(update) => {
+                let Some(owner) = source.filter(|source| source.node != our().node) else {
+                    break 'resp NoteResponse::MirrorUpdate(Err(
+                        "Updates come from the folder's owner".to_string(),
+                    ));
+                };
+                if !state.apply_mirror_update(&owner.node, update)? {
+                    break 'resp NoteResponse::MirrorUpdate(Err(
+                        "Not mirroring this folder".to_string()
+                    ));
+                }
+                state.save_to_disk()?;
+                NoteResponse::MirrorUpdate(Ok(()))
+            }
+
+            NoteRequest::GetInvites => {
+                if state.prune_invites() {
+                    state.save_to_disk()?;
+                }
+                let mut invites = Vec::new();
+                for (note_id, note_invites) in state.collaboration_invites.clone() {
+                    for (invitee_id, inviter_id) in note_invites {
+                        if invitee_id == our().node && !state.blocked_nodes.contains(&inviter_id) {
+                            if let Some(name) = state.notes.get(&note_id).map(|m| m.name.clone()) {
+                                invites.push(Invite {
+                                    expires: state.invite_expires(&note_id, &invitee_id),
+                                    note_id: note_id.clone(),
+                                    inviter_node_id: inviter_id,
+                                    note_name: name,
+                                });
+                            }
+                        }
+                    }
+                }
+                invites.extend(state.received_invites.iter().cloned());
+                NoteResponse::GetInvites(Ok(invites))
+            }
+
+            NoteRequest::ImportAll((compressed_bytes, passphrase, preview_only, workspace)) => {
+                match inactive_workspace(workspace.as_deref())? {
+                    Ok(Some(mut other)) => {
+                        let request = NoteRequest::ImportAll((
+                            compressed_bytes,
+                            passphrase,
+                            preview_only,
+                            None,
+                        ));
+                        break 'resp handle_note_request(request, source, &mut other, server)?;
+                    }
+                    Ok(None) => {}
+                    Err(e) => break 'resp NoteResponse::ImportAll(Err(e)),
+                }
+                let compressed_bytes = if compressed_bytes.starts_with(ENCRYPTED_ARCHIVE_MAGIC) {
+                    let Some(passphrase) = passphrase else {
+                        break 'resp NoteResponse::ImportAll(Err(
+                            "Archive is encrypted; a passphrase is required".to_string(),
+                        ));
+                    };
+                    match decrypt_archive(&compressed_bytes, &passphrase) {
+                        Ok(decrypted) => decrypted,
+                        Err(e) => break 'resp NoteResponse::ImportAll(Err(e)),
+                    }
+                } else {
+                    compressed_bytes
+                };
+
+                let is_zip = compressed_bytes.starts_with(b"PK\x03\x04");
+                if !preview_only && (is_zip || is_tar_archive(&compressed_bytes)) {
+                    let sizes = match archive_file_sizes(&compressed_bytes, 0) {
+                        Ok(sizes) => sizes,
+                        Err(e) => break 'resp NoteResponse::ImportAll(Err(e.to_string())),
+                    };
+                    if let Err(e) = state.check_quota_all(&sizes, 0)? {
+                        break 'resp NoteResponse::QuotaExceeded(e);
+                    }
+                }
+                // Zip archives (e.g. Notion exports) are imported as markdown files
+                if is_zip {
+                    break 'resp NoteResponse::ImportAll(import_or_preview(
+                        state,
+                        preview_only,
+                        "zip",
+                        "Import zip archive",
+                        |state| state.import_zip(&compressed_bytes, &[], &mut HashMap::new(), 0),
+                    )?);
+                }
+                if is_tar_archive(&compressed_bytes) {
+                    break 'resp NoteResponse::ImportAll(import_or_preview(
+                        state,
+                        preview_only,
+                        "joplin",
+                        "Import Joplin export",
+                        |state| state.import_jex(&compressed_bytes),
+                    )?);
+                }
+
+                // Decompress data
+                let mut decoder = GzDecoder::new(&compressed_bytes[..]);
+                let mut decompressed = String::new();
+                match decoder.read_to_string(&mut decompressed) {
+                    Ok(_) => (),
+                    Err(e) => {
+                        return Ok(NoteResponse::ImportAll(Err(format!(
+                            "Failed to decompress data: {}",
+                            e
+                        ))))
+                    }
+                }
+
+                // Parse and migrate the JSON
+                let import_data = match serde_json::from_str::<ExportData>(&decompressed) {
+                    Ok(data) if preview_only => {
+                        break 'resp NoteResponse::ImportAll(
+                            preview_export_data(state, &data)
+                                .map(Some)
+                                .map_err(|e| e.to_string()),
+                        );
+                    }
+                    // Remapped before migrating, which writes content files under the notes' IDs
+                    Ok(data) => {
+                        let data = remap_import(state, data);
+                        let sizes: Vec<u64> = data
+                            .notes
+                            .iter()
+                            .map(|note| note.content.len())
+                            .chain(data.attachment_contents.values().map(Vec::len))
+                            .map(|bytes| bytes as u64)
+                            .collect();
+                        if let Err(e) = state.check_quota_all(&sizes, 0)? {
+                            break 'resp NoteResponse::QuotaExceeded(e);
+                        }
+                        match migrate_export_data(
+                            data,
+                            &state.drive,
+                            &state.drive_usage,
+                            &mut |done, total| push_progress(server, "import", done, total),
+                        ) {
+                            Ok(migrated) => migrated,
+                            Err(e) => return Ok(NoteResponse::ImportAll(Err(e.to_string()))),
+                        }
+                    }
+                    Err(e) => {
+                        return Ok(NoteResponse::ImportAll(Err(format!(
+                            "Failed to parse JSON data: {}",
+                            e
+                        ))))
+                    }
+                };
+
+                // Update state
+                let mut new_state = state.clone();
+                // Merge rather than replace, since the archive may hold a single folder
+                for (note_id, invites) in import_data.collaboration_invites {
+                    new_state
+                        .collaboration_invites
+                        .entry(note_id)
+                        .or_default()
+                        .extend(invites);
+                }
+                for attachment in import_data.attachments {
+                    if let Some(content) = import_data.attachment_contents.get(&attachment.id) {
+                        new_state.save_attachment_content(&attachment.id, content)?;
+                    }
+                    new_state
+                        .attachments
+                        .insert(attachment.id.clone(), attachment);
+                }
+
+                for folder in import_data.folders {
+                    if folder.parent_id.is_none() {
+                        new_state.root_items.insert(folder.id.clone());
+                    }
+                    new_state.folders.insert(folder.id.clone(), folder);
+                }
+
+                // Handle both old format (full notes) and new format (metadata)
+                if !import_data.notes.is_empty() {
+                    // Old format: save notes as files and create metadata
+                    for note in import_data.notes {
+                        if note.folder_id.is_none() {
+                            new_state.root_items.insert(note.id.clone());
+                        }
+                        // Create and store metadata first
+                        let metadata = NoteMetadata::from(note.clone());
+                        new_state.notes.insert(metadata.id.clone(), metadata);
+                        // Then save content to file with appropriate extension
+                        new_state.save_note_content(&note.id, &note.content)?;
+                    }
+                } else if !import_data.note_metadata.is_empty() {
+                    // New format: already have metadata
+                    for metadata in import_data.note_metadata {
+                        if metadata.folder_id.is_none() {
+                            new_state.root_items.insert(metadata.id.clone());
+                        }
+                        new_state.notes.insert(metadata.id.clone(), metadata);
+                    }
+                }
+
+                *state = new_state;
+                state.save_to_disk()?;
+                NoteResponse::ImportAll(Ok(None))
+            }
+
+            NoteRequest::ImportZip(zip_bytes) => {
+                let sizes = match archive_file_sizes(&zip_bytes, 0) {
+                    Ok(sizes) => sizes,
+                    Err(e) => break 'resp NoteResponse::ImportZip(Err(e.to_string())),
+                };
+                if let Err(e) = state.check_quota_all(&sizes, 0)? {
+                    break 'resp NoteResponse::QuotaExceeded(e);
+                }
+                NoteResponse::ImportZip(import_transaction(state, "Import zip archive", |state| {
+                    state.import_zip(&zip_bytes, &[], &mut HashMap::new(), 0)
+                })?)
+            }
+
+            NoteRequest::SetWebdavCredentials(credentials) => {
+                state.webdav = match credentials {
+                    Some((username, password)) => {
+                        if username.is_empty() || password.is_empty() {
+                            break 'resp NoteResponse::SetWebdavCredentials(Err(
+                                "Username and password cannot be empty".to_string(),
+                            ));
+                        }
+                        let mut salt = vec![0u8; ENCRYPTION_SALT_LEN];
+                        OsRng.fill_bytes(&mut salt);
+                        let password_hash = hash_password(&password, &salt)?;
+                        Some(WebDavCredentials {
+                            username,
+                            salt,
+                            password_hash,
+                        })
+                    }
+                    None => None,
+                };
+                state.webdav_authorized = None;
+                state.save_to_disk()?;
+                NoteResponse::SetWebdavCredentials(Ok(()))
+            }
+
+            NoteRequest::SetGitRemote(remote) => {
+                if let Some(remote) = &remote {
+                    if url::Url::parse(&remote.api_url).is_err() {
+                        break 'resp NoteResponse::SetGitRemote(Err(
+                            "Invalid git API URL".to_string()
+                        ));
+                    }
+                    if remote.interval_minutes == 0 {
+                        break 'resp NoteResponse::SetGitRemote(Err(
+                            "Sync interval must be non-zero".to_string(),
+                        ));
+                    }
+                }
+                // A different remote starts from scratch
+                state.git_job = None;
+                state.git_sync = GitSyncSettings {
+                    remote,
+                    ..GitSyncSettings::default()
+                };
+                state.save_to_disk()?;
+                schedule_git_sync(state, true);
+                NoteResponse::SetGitRemote(Ok(()))
+            }
+
+            NoteRequest::GetGitSyncStatus => NoteResponse::GetGitSyncStatus(Ok(GitSyncStatus {
+                remote: state
+                    .git_sync
+                    .remote
+                    .as_ref()
+                    .map(|r| format!("{}@{}", r.repo, r.branch)),
+                synced_notes: state.git_sync.files.len() as u32,
+                last_sync: state.git_sync.last_sync,
+                last_error: state.git_sync.last_error.clone(),
+                running: state.git_job.is_some(),
+            })),
+
+            NoteRequest::GitSyncNow => {
+                if state.git_sync.remote.is_none() {
+                    break 'resp NoteResponse::GitSyncNow(Err("No git remote set".to_string()));
+                }
+                NoteResponse::GitSyncNow(match state.start_git_sync() {
+                    Ok(()) => Ok("Git sync started".to_string()),
+                    Err(e) => Err(e.to_string()),
+                })
+            }
+
+            NoteRequest::SetReplica(node) => {
+                if node.as_ref() == Some(&our().node) {
+                    break 'resp NoteResponse::SetReplica(Err(
+                        "A node cannot replicate itself".to_string()
+                    ));
+                }
+                // A different replica starts from scratch
+                state.replica_job = None;
+                state.replica = ReplicaSettings {
+                    node,
+                    ..ReplicaSettings::default()
+                };
+                state.save_to_disk()?;
+                schedule_replica_sync(state, true);
+                NoteResponse::SetReplica(Ok(()))
+            }
+
+            NoteRequest::GetReplicaStatus => NoteResponse::GetReplicaStatus(Ok(ReplicaStatus {
+                node: state.replica.node.clone(),
+                synced_items: state.replica.synced.len() as u32,
+                last_sync: state.replica.last_sync,
+                last_error: state.replica.last_error.clone(),
+                running: state.replica_job.is_some(),
+            })),
+
+            NoteRequest::ReplicaSyncNow => {
+                let remote = source.is_some_and(|source| source.node != our().node);
+                if remote && !is_from_replica(state, source) {
+                    break 'resp NoteResponse::ReplicaSyncNow(Err("Not our replica".to_string()));
+                }
+                NoteResponse::ReplicaSyncNow(run_replica_sync(state)?)
+            }
+
+            NoteRequest::ReplicaChanges => {
+                if !is_from_replica(state, source) {
+                    break 'resp NoteResponse::ReplicaChanges(Err("Not our replica".to_string()));
+                }
+                NoteResponse::ReplicaChanges(Ok(state.replica_changes()))
+            }
+
+            NoteRequest::ReplicaFetch(ids) => {
+                if !is_from_replica(state, source) {
+                    break 'resp NoteResponse::ReplicaFetch(Err("Not our replica".to_string()));
+                }
+                let items = ids.iter().filter_map(|id| state.replica_item(id)).collect();
+                NoteResponse::ReplicaFetch(Ok(items))
+            }
+
+            NoteRequest::ReplicaApply((changes, agreed)) => {
+                if !is_from_replica(state, source) {
+                    break 'resp NoteResponse::ReplicaApply(Err("Not our replica".to_string()));
+                }
+                let incoming = changes
+                    .iter()
+                    .filter_map(|change| Some((change.id.as_str(), change.item.as_ref()?)));
+                if let Err(e) = state.check_replica_quota(incoming)? {
+                    break 'resp NoteResponse::ReplicaApply(Err(e.to_string()));
+                }
+                // Changes to items edited here since they were listed wait for the next sync
+                let hashes = state.replica_hashes();
+                let mut skipped = Vec::new();
+                for change in changes {
+                    if hashes.get(&change.id) != change.replaces.as_ref() {
+                        skipped.push(change.id);
+                        continue;
+                    }
+                    state.apply_replica_change(&change.id, change.item)?;
+                }
+                state.record_replica_sync(agreed, &skipped);
+                state.save_to_disk()?;
+                if state.git_sync.remote.is_some() {
+                    schedule_git_sync(state, true);
+                }
+                NoteResponse::ReplicaApply(Ok(skipped))
+            }
+
+            NoteRequest::SetBackupTarget(target) => {
+                if let Some(target) = &target {
+                    if target.passphrase.is_empty() || target.interval_hours == 0 {
+                        break 'resp NoteResponse::SetBackupTarget(Err(
+                            "Backups need a passphrase and a non-zero interval".to_string(),
+                        ));
+                    }
+                }
+                state.backup.target = match target.map(StoredBackupTarget::new).transpose() {
+                    Ok(target) => target,
+                    Err(e) => break 'resp NoteResponse::SetBackupTarget(Err(e)),
+                };
+                state.backup.last_error = None;
+                state.save_to_disk()?;
+                schedule_backup(state);
+                NoteResponse::SetBackupTarget(Ok(()))
+            }
+
+            NoteRequest::SetBackupSources(sources) => {
+                state.backup.sources = sources;
+                state.save_to_disk()?;
+                NoteResponse::SetBackupSources(Ok(()))
+            }
+
+            NoteRequest::GetBackupStatus => NoteResponse::GetBackupStatus(Ok(BackupStatus {
+                target: state.backup.target.as_ref().map(|t| t.node.clone()),
+                interval_hours: state.backup.target.as_ref().map(|t| t.interval_hours),
+                sources: state.backup.sources.clone(),
+                last_backup: state.backup.last_backup,
+                last_error: state.backup.last_error.clone(),
+                last_restore: state.backup.last_restore,
+                s3_target: state
+                    .backup
+                    .s3
+                    .as_ref()
+                    .map(|t| format!("{}/{}/{}", t.endpoint, t.bucket, t.prefix)),
+                last_s3_backup: state.backup.last_s3_backup,
+                last_s3_error: state.backup.last_s3_error.clone(),
+                last_s3_skipped: state.backup.last_s3_skipped.clone(),
+            })),
+
+            NoteRequest::SetS3Target(target) => {
+                if let Some(target) = &target {
+                    if url::Url::parse(&target.endpoint).is_err() {
+                        break 'resp NoteResponse::SetS3Target(Err(
+                            "Invalid S3 endpoint URL".to_string()
+                        ));
+                    }
+                    if target.bucket.is_empty() || target.interval_hours == 0 {
+                        break 'resp NoteResponse::SetS3Target(Err(
+                            "S3 backups need a bucket and a non-zero interval".to_string(),
+                        ));
+                    }
+                }
+                state.backup.s3 = target;
+                state.backup.last_s3_error = None;
+                state.save_to_disk()?;
+                schedule_s3_backup(state);
+                NoteResponse::SetS3Target(Ok(()))
+            }
+
+            NoteRequest::S3BackupNow => {
+                if state.backup.s3.is_none() {
+                    break 'resp NoteResponse::S3BackupNow(Err("No S3 target set".to_string()));
+                }
+                NoteResponse::S3BackupNow(run_s3_backup(state)?)
+            }
+
+            NoteRequest::BackupNow => {
+                if state.backup.target.is_none() {
+                    break 'resp NoteResponse::BackupNow(Err("No backup target set".to_string()));
+                }
+                send_backup(state)?;
+                NoteResponse::BackupNow(Ok(()))
+            }
+
+            NoteRequest::RestoreFromBackup => {
+                let Some(target) = &state.backup.target else {
+                    break 'resp NoteResponse::RestoreFromBackup(Err(
+                        "No backup target set".to_string()
+                    ));
+                };
+                // The backup is imported when the target answers, in restore_from_backup
+                Request::to(Address::new(&target.node, our().process.clone()))
+                    .body(NoteRequest::FetchBackup)
+                    .context(RESTORE_CONTEXT.as_bytes())
+                    .expects_response(BACKUP_TIMEOUT_SECS)
+                    .send()?;
+                NoteResponse::RestoreFromBackup(Ok(()))
+            }
+
+            NoteRequest::StoreBackup(archive) => {
+                let Some(source) = source else {
+                    break 'resp NoteResponse::StoreBackup(Err("Not authorized".to_string()));
+                };
+                if !state.backup.sources.contains(&source.node) {
+                    break 'resp NoteResponse::StoreBackup(Err(
+                        "Not authorized to store backups here".to_string(),
+                    ));
+                }
+                // Only ever hold other nodes' data encrypted
+                if !archive.starts_with(ENCRYPTED_ARCHIVE_MAGIC) {
+                    break 'resp NoteResponse::StoreBackup(Err(
+                        "Backups must be encrypted".to_string()
+                    ));
+                }
+                // The note size limit is for notes; a backup only has to fit the drive
+                let path = state.backup_path(&source.node);
+                let replaced = vfs::metadata(&path, None).map_or(0, |m| m.len);
+                if let Err(e) = state.check_drive_quota(archive.len() as u64, replaced)? {
+                    break 'resp NoteResponse::StoreBackup(Err(e.to_string()));
+                }
+                state.drive_usage.write(&path, &archive)?;
+                NoteResponse::StoreBackup(Ok(()))
+            }
+
+            NoteRequest::FetchBackup => {
+                let Some(source) = source else {
+                    break 'resp NoteResponse::FetchBackup(Err("Not authorized".to_string()));
+                };
+                match vfs::open_file(&state.backup_path(&source.node), false, None)
+                    .and_then(|file| file.read())
+                {
+                    Ok(archive) => NoteResponse::FetchBackup(Ok(archive)),
+                    Err(_) => NoteResponse::FetchBackup(Err("No backup stored".to_string())),
+                }
+            }
+
+            NoteRequest::ListWorkspaces => {
+                let workspaces = load_workspaces()?;
+                NoteResponse::ListWorkspaces(Ok(workspaces
+                    .names
+                    .iter()
+                    .map(|name| Workspace {
+                        name: name.clone(),
+                        active: *name == workspaces.active,
+                    })
+                    .collect()))
+            }
+
+            NoteRequest::CreateWorkspace(name) => {
+                if !is_valid_drive_name(&name) {
+                    break 'resp NoteResponse::CreateWorkspace(Err(
+                        "Workspace names are up to 32 lowercase letters, digits or '-'".to_string(),
+                    ));
+                }
+                let mut workspaces = load_workspaces()?;
+                if workspaces.names.contains(&name) {
+                    break 'resp NoteResponse::CreateWorkspace(Err(
+                        "Workspace already exists".to_string()
+                    ));
+                }
+                State::new(workspace_drive(&name)?).save_to_disk()?;
+                workspaces.names.push(name);
+                save_workspaces(&workspaces)?;
+                NoteResponse::CreateWorkspace(Ok(()))
+            }
+
+            NoteRequest::SwitchWorkspace(name) => {
+                let mut workspaces = load_workspaces()?;
+                if !workspaces.names.contains(&name) {
+                    break 'resp NoteResponse::SwitchWorkspace(Err(
+                        "Workspace not found".to_string()
+                    ));
+                }
+                // A batch's deferred save has to land before the workspace goes away
+                state.defer_saves = false;
+                state.save_to_disk()?;
+                let mut switched = State::load_from_disk(workspace_drive(&name)?)?;
+                // Keep timer generations rising so timers armed for the old workspace are ignored
+                switched.backup_timer = state.backup_timer;
+                switched.s3_backup_timer = state.s3_backup_timer;
+                switched.git_sync_timer = state.git_sync_timer;
+                switched.replica_timer = state.replica_timer;
+                switched.feed_timer = state.feed_timer;
+                switched.cold_storage_timer = state.cold_storage_timer;
+                switched.shared_edit_timer = state.shared_edit_timer;
+                // Answers to the old workspace's requests must not match the new one's
+                switched.next_reply_id = state.next_reply_id;
+                // Profiles are node-wide, not part of any workspace
+                switched.profile_sessions = std::mem::take(&mut state.profile_sessions);
+                switched.profile_states = std::mem::take(&mut state.profile_states);
+                switched.profile_channels = std::mem::take(&mut state.profile_channels);
+                // Open WebSockets stay authenticated and keep following their notes
+                switched.ws_private_channels = std::mem::take(&mut state.ws_private_channels);
+                switched.ws_subscriptions = std::mem::take(&mut state.ws_subscriptions);
+                switched.presence = std::mem::take(&mut state.presence);
+                switched.request_counts = std::mem::take(&mut state.request_counts);
+                // Wrong passwords count against a client whichever workspace is active
+                switched.password_failures = std::mem::take(&mut state.password_failures);
+                switched.log_level = state.log_level;
+                switched.metrics = std::mem::take(&mut state.metrics);
+                switched.message_id = state.message_id;
+                // Clients are sent how the new workspace differs from the old one
+                switched.structure_log =
+                    std::mem::replace(&mut state.structure_log, StructureLog::new());
+                *state = switched;
+                schedule_backup(state);
+                schedule_s3_backup(state);
+                schedule_git_sync(state, false);
+                schedule_replica_sync(state, false);
+                schedule_feed_poll(state);
+                schedule_shared_edit_replay(state);
+                schedule_cold_storage(state);
+                workspaces.active = name;
+                save_workspaces(&workspaces)?;
+                NoteResponse::SwitchWorkspace(Ok(()))
+            }
+
+            NoteRequest::CreateApiToken(scope) => {
+                if let Some(id) = scope
+                    .folders
+                    .iter()
+                    .find(|id| !state.folders.contains_key(*id))
+                {
+                    break 'resp NoteResponse::CreateApiToken(Err(format!(
+                        "Folder {} not found",
+                        id
+                    )));
+                }
+                let mut tokens = load_api_tokens()?;
+                let mut secret = [0u8; 32];
+                OsRng.fill_bytes(&mut secret);
+                let secret: String = secret.iter().map(|b| format!("{:02x}", b)).collect();
+                let id = State::generate_id();
+                tokens.push(StoredApiToken {
+                    id: id.clone(),
+                    secret_hash: sha256_hex(secret.as_bytes()),
+                    scope,
+                    created: now_secs(),
+                });
+                save_api_tokens(&tokens)?;
+                NoteResponse::CreateApiToken(Ok(format!("{}.{}", id, secret)))
+            }
+
+            NoteRequest::RevokeApiToken(id) => {
+                let mut tokens = load_api_tokens()?;
+                let count = tokens.len();
+                tokens.retain(|token| token.id != id);
+                if tokens.len() == count {
+                    break 'resp NoteResponse::RevokeApiToken(Err("Token not found".to_string()));
+                }
+                save_api_tokens(&tokens)?;
+                NoteResponse::RevokeApiToken(Ok(()))
+            }
+
+            NoteRequest::ListApiTokens => NoteResponse::ListApiTokens(Ok(load_api_tokens()?
+                .into_iter()
+                .map(|token| ApiToken {
+                    id: token.id,
+                    scope: token.scope,
+                    created: token.created,
+                })
+                .collect())),
+
+            NoteRequest::GrantProcess((process, scope)) => {
+                let parts: Vec<&str> = process.split(':').collect();
+                if parts.len() != 3 || parts.iter().any(|part| part.is_empty()) {
+                    break 'resp NoteResponse::GrantProcess(Err(
+                        "Process must be \"name:package:publisher\"".to_string(),
+                    ));
+                }
+                if let Some(id) = scope
+                    .folders
+                    .iter()
+                    .find(|id| !state.folders.contains_key(*id))
+                {
+                    break 'resp NoteResponse::GrantProcess(Err(format!(
+                        "Folder {} not found",
+                        id
+                    )));
+                }
+                // A new grant replaces the process's old one
+                let mut grants = load_process_grants()?;
+                grants.retain(|grant| grant.process != process);
+                grants.push(ProcessGrant {
+                    process,
+                    scope,
+                    granted: now_secs(),
+                });
+                save_process_grants(&grants)?;
+                NoteResponse::GrantProcess(Ok(()))
+            }
+
+            NoteRequest::RevokeProcess(process) => {
+                let mut grants = load_process_grants()?;
+                let count = grants.len();
+                grants.retain(|grant| grant.process != process);
+                if grants.len() == count {
+                    break 'resp NoteResponse::RevokeProcess(Err("Grant not found".to_string()));
+                }
+                save_process_grants(&grants)?;
+                NoteResponse::RevokeProcess(Ok(()))
+            }
+
+            NoteRequest::ListProcessGrants => {
+                NoteResponse::ListProcessGrants(Ok(load_process_grants()?))
+            }
+
+            NoteRequest::ListProfiles => NoteResponse::ListProfiles(Ok(load_profiles()?
+                .into_iter()
+                .map(|profile| profile.name)
+                .collect())),
+
+            NoteRequest::CreateProfile((name, password)) => {
+                if !is_valid_drive_name(&name) {
+                    break 'resp NoteResponse::CreateProfile(Err(
+                        "Profile names are up to 32 lowercase letters, digits or '-'".to_string(),
+                    ));
+                }
+                if password.is_empty() {
+                    break 'resp NoteResponse::CreateProfile(Err(
+                        "Password cannot be empty".to_string()
+                    ));
+                }
+                let mut profiles = load_profiles()?;
+                if profiles.iter().any(|profile| profile.name == name) {
+                    break 'resp NoteResponse::CreateProfile(Err(
+                        "Profile already exists".to_string()
+                    ));
+                }
+                let salt = new_archive_salt();
+                let password_hash = hash_password(&password, &salt)?;
+                profiles.push(ProfileAccount {
+                    name,
+                    salt,
+                    password_hash,
+                });
+                save_profiles(&profiles)?;
+                NoteResponse::CreateProfile(Ok(()))
+            }
+
+            NoteRequest::LogInProfile((name, password)) => {
+                let profiles = load_profiles()?;
+                let Some(profile) = profiles.iter().find(|profile| profile.name == name) else {
+                    break 'resp NoteResponse::LogInProfile(Err(
+                        "Wrong profile or password".to_string()
+                    ));
+                };
+                if !constant_time_eq(
+                    &hash_password(&password, &profile.salt)?,
+                    &profile.password_hash,
+                ) {
+                    break 'resp NoteResponse::LogInProfile(Err(
+                        "Wrong profile or password".to_string()
+                    ));
+                }
+                let mut token = [0u8; 32];
+                OsRng.fill_bytes(&mut token);
+                let token: String = token.iter().map(|b| format!("{:02x}", b)).collect();
+                state.profile_sessions.insert(token.clone(), name);
+                NoteResponse::LogInProfile(Ok(token))
+            }
+
+            NoteRequest::LogOutProfile(token) => {
+                // Channels logged in with the session stop getting the profile's pushes too
+                if let Some(name) = state.profile_sessions.remove(&token) {
+                    let channels: Vec<u32> = state
+                        .profile_channels
+                        .iter()
+                        .filter(|(_, session)| **session == token)
+                        .map(|(channel_id, _)| *channel_id)
+                        .collect();
+                    if let Some(profile) = state.profile_states.get_mut(&name) {
+                        for channel_id in &channels {
+                            profile.ws_private_channels.remove(channel_id);
+                            profile.ws_subscriptions.remove(channel_id);
+                        }
+                    }
+                    state
+                        .profile_channels
+                        .retain(|_, session| *session != token);
+                }
+                NoteResponse::LogOutProfile(Ok(()))
+            }
+
+            NoteRequest::GetSettings => NoteResponse::GetSettings(Ok(state.settings.clone())),
+
+            NoteRequest::GetMetrics => NoteResponse::GetMetrics(Ok(state.metrics.to_wit())),
+
+            NoteRequest::SetLogLevel(level) => {
+                state.log_level = level;
+                NoteResponse::SetLogLevel(Ok(()))
+            }
+
+            NoteRequest::GetStorageStats => match state.storage_stats() {
+                Ok(stats) => NoteResponse::GetStorageStats(Ok(stats)),
+                Err(e) => NoteResponse::GetStorageStats(Err(e.to_string())),
+            },
+
+            NoteRequest::UpdateSettings(settings) => {
+                let folders = [&settings.default_folder, &settings.journal_folder];
+                if folders
+                    .into_iter()
+                    .flatten()
+                    .any(|id| !state.folders.contains_key(id))
+                {
+                    break 'resp NoteResponse::UpdateSettings(Err("Folder not found".to_string()));
+                }
+                if settings.autosave_interval_ms == 0 || settings.editor_font_size == 0 {
+                    break 'resp NoteResponse::UpdateSettings(Err(
+                        "Autosave interval and font size must be positive".to_string(),
+                    ));
+                }
+                if let Some(origin) = settings.cors_origins.iter().find(|origin| {
+                    *origin != "*"
+                        && !(origin.starts_with("http://") || origin.starts_with("https://"))
+                }) {
+                    break 'resp NoteResponse::UpdateSettings(Err(format!(
+                        "Invalid CORS origin {}; expected \"*\" or a URL like https://example.com",
+                        origin
+                    )));
+                }
+                if let Some(method) = settings
+                    .cors_methods
+                    .iter()
+                    .find(|method| !["GET", "HEAD", "POST"].contains(&method.as_str()))
+                {
+                    break 'resp NoteResponse::UpdateSettings(Err(format!(
+                        "Invalid CORS method {}; expected GET, HEAD or POST",
+                        method
+                    )));
+                }
+                if settings.max_note_bytes == Some(0) || settings.drive_quota_bytes == Some(0) {
+                    break 'resp NoteResponse::UpdateSettings(Err(
+                        "Size limits must be positive; leave them unset for none".to_string(),
+                    ));
+                }
+                if settings.cold_storage_months == Some(0) {
+                    break 'resp NoteResponse::UpdateSettings(Err(
+                        "Cold storage months must be positive; leave it unset to turn it off"
+                            .to_string(),
+                    ));
+                }
+                let cold_storage_changed =
+                    settings.cold_storage_months != state.settings.cold_storage_months;
+                state.settings = settings;
+                state.save_to_disk()?;
+                if cold_storage_changed && state.profile.is_none() {
+                    schedule_cold_storage(state);
+                }
+                NoteResponse::UpdateSettings(Ok(state.settings.clone()))
+            }
+
+            NoteRequest::Undo => {
+                let Some(entry) = state.undo_stack.pop() else {
+                    break 'resp NoteResponse::Undo(Err("Nothing to undo".to_string()));
+                };
+                let replaced = state.restore_snapshots(entry.snapshots)?;
+                state.redo_stack.push(HistoryEntry {
+                    description: entry.description.clone(),
+                    snapshots: replaced,
+                });
+                state.save_to_disk()?;
+                NoteResponse::Undo(Ok(entry.description))
+            }
+
+            NoteRequest::Redo => {
+                let Some(entry) = state.redo_stack.pop() else {
+                    break 'resp NoteResponse::Redo(Err("Nothing to redo".to_string()));
+                };
+                let replaced = state.restore_snapshots(entry.snapshots)?;
+                state.undo_stack.push(HistoryEntry {
+                    description: entry.description.clone(),
+                    snapshots: replaced,
+                });
+                state.save_to_disk()?;
+                NoteResponse::Redo(Ok(entry.description))
+            }
+        }
+    };
+    if syncs_to_git {
+        schedule_git_sync(state, true);
+    }
+    if syncs_to_replica {
+        schedule_replica_sync(state, true);
+    }
+    Ok(resp)
+}
+
+const GIT_SYNC_TIMER_CONTEXT: &str = "git-sync";
+const GIT_SYNC_DEBOUNCE_MS: u64 = 30 * 1000;
+const GIT_HTTP_TIMEOUT_SECS: u64 = 60;
+const GIT_HTTP_CONTEXT: &str = "git-http";
+
+// Git object ID of a file with this content
+fn git_blob_sha(content: &[u8]) -> String {
+    use sha1::{Digest, Sha1};
+    let mut hasher = Sha1::new();
+    hasher.update(format!("blob {}\0", content.len()).as_bytes());
+    hasher.update(content);
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Call the remote's REST API (the GitHub API, which Gitea and Forgejo also implement). The
+// response arrives in handle_response with this context.
+fn git_send(
+    remote: &GitRemote,
+    method: http::Method,
+    endpoint: &str,
+    body: Option<serde_json::Value>,
+    context: &str,
+) -> anyhow::Result<()> {
+    let url = format!(
+        "{}/repos/{}/{}",
+        remote.api_url.trim_end_matches('/'),
+        remote.repo,
+        endpoint
+    );
+    let mut headers = HashMap::new();
+    headers.insert(
+        "Authorization".to_string(),
+        format!("token {}", remote.token),
+    );
+    headers.insert(
+        "Accept".to_string(),
+        "application/vnd.github+json".to_string(),
+    );
+    headers.insert("User-Agent".to_string(), "wifenote".to_string());
+    headers.insert("Content-Type".to_string(), "application/json".to_string());
+    let body = body.map(|b| b.to_string().into_bytes()).unwrap_or_default();
+    send_http_request(
+        method,
+        &url::Url::parse(&url)?,
+        headers,
+        body,
+        GIT_HTTP_TIMEOUT_SECS,
+        context,
+    )
+}
+
+fn git_context(job_id: u64, step: &str) -> String {
+    format!("{}:{}:{}", GIT_HTTP_CONTEXT, job_id, step)
+}
+
+fn git_api_error(status: http::StatusCode, json: &serde_json::Value) -> anyhow::Error {
+    let message = json["message"].as_str().unwrap_or("unknown error");
+    anyhow::anyhow!("Git remote returned {}: {}", status, message)
+}
+
+// Blob SHA of every file on the sync branch; empty if the branch does not exist yet
+fn git_tree_files(
+    status: http::StatusCode,
+    json: &serde_json::Value,
+) -> anyhow::Result<HashMap<String, String>> {
+    if status == http::StatusCode::NOT_FOUND || status == http::StatusCode::CONFLICT {
+        return Ok(HashMap::new());
+    }
+    if !status.is_success() {
+        return Err(git_api_error(status, json));
+    }
+    Ok(json["tree"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry["type"] == "blob")
+        .filter_map(|entry| {
+            Some((
+                entry["path"].as_str()?.to_string(),
+                entry["sha"].as_str()?.to_string(),
+            ))
+        })
+        .collect())
+}
+
+fn git_blob_content(status: http::StatusCode, json: &serde_json::Value) -> anyhow::Result<Vec<u8>> {
+    use base64::Engine;
+    if !status.is_success() {
+        return Err(git_api_error(status, json));
+    }
+    // The content is base64 wrapped across lines
+    let encoded: String = json["content"]
+        .as_str()
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect();
+    Ok(base64::engine::general_purpose::STANDARD.decode(encoded)?)
+}
+
+fn git_contents_endpoint(path: &str) -> String {
+    let segments: Vec<String> = path
+        .split('/')
+        .map(|segment| percent_encode(segment, b"-._~"))
+        .collect();
+    format!("contents/{}", segments.join("/"))
+}
+
+// Send the commit at the front of a sync's queue, or report the sync done if there is none
+fn git_send_next_write(job: &GitSyncJob) -> anyhow::Result<bool> {
+    use base64::Engine;
+    let context = git_context(job.id, "write");
+    match job.writes.front() {
+        Some(GitWrite::Delete {
+            path, sha, message, ..
+        }) => {
+            let body = serde_json::json!({
+                "message": message,
+                "sha": sha,
+                "branch": job.remote.branch,
+            });
+            git_send(
+                &job.remote,
+                http::Method::DELETE,
+                &git_contents_endpoint(path),
+                Some(body),
+                &context,
+            )?;
+        }
+        Some(GitWrite::Put {
+            path,
+            content,
+            existing_sha,
+            message,
+            ..
+        }) => {
+            let mut body = serde_json::json!({
+                "message": message,
+                "content": base64::engine::general_purpose::STANDARD.encode(content),
+                "branch": job.remote.branch,
+            });
+            if let Some(sha) = existing_sha {
+                body["sha"] = sha.as_str().into();
+            }
+            git_send(
+                &job.remote,
+                http::Method::PUT,
+                &git_contents_endpoint(path),
+                Some(body),
+                &context,
+            )?;
+        }
+        None => return Ok(true),
+    }
+    Ok(false)
+}
+
+// (Re)arm the git sync timer: shortly after a change, otherwise at the remote's pull interval
+fn schedule_git_sync(state: &mut State, after_change: bool) {
+    state.git_sync_timer += 1;
+    if let Some(remote) = &state.git_sync.remote {
+        let delay = if after_change {
+            GIT_SYNC_DEBOUNCE_MS
+        } else {
+            remote.interval_minutes as u64 * 60 * 1000
+        };
+        timer::set_timer(
+            delay,
+            Some(format!("{}:{}", GIT_SYNC_TIMER_CONTEXT, state.git_sync_timer).into_bytes()),
+        );
+    }
+}
+
+// Start a git sync from its timer. One already running syncs again once it finishes instead.
+fn run_git_sync(state: &mut State) -> anyhow::Result<()> {
+    if let Some(job) = &mut state.git_job {
+        job.rerun = true;
+        return Ok(());
+    }
+    if let Err(e) = state.start_git_sync() {
+        error!("Git sync failed: {e}");
+        state.git_sync.last_error = Some(e.to_string());
+        state.save_to_disk()?;
+        schedule_git_sync(state, false);
+    }
+    Ok(())
+}
+
+// Feed the running git sync an http-client response, or the failure to get one, recording its
+// outcome once it finishes
+fn continue_git_sync(
+    state: &mut State,
+    context: &str,
+    response: Result<(http::StatusCode, Vec<u8>), String>,
+) -> anyhow::Result<()> {
+    let Some((job_id, step)) = context.split_once(':') else {
+        return Ok(());
+    };
+    let Some(mut job) = state.git_job.take_if(|job| job.id.to_string() == job_id) else {
+        return Ok(());
+    };
+    match state.git_sync_step(&mut job, step, response) {
+        Ok(false) => {
+            state.git_job = Some(job);
+            return Ok(());
+        }
+        Ok(true) => {
+            info!(
+                "Git sync pulled {} and pushed {} notes",
+                job.pulled, job.pushed
+            );
+            state.git_sync.last_sync = Some(now_secs());
+            state.git_sync.last_error = None;
+        }
+        Err(e) => {
+            error!("Git sync failed: {e}");
+            state.git_sync.last_error = Some(e.to_string());
+        }
+    }
+    state.save_to_disk()?;
+    schedule_git_sync(state, job.rerun);
+    Ok(())
+}
+
+const REPLICA_TIMER_CONTEXT: &str = "replica";
+const REPLICA_SYNC_CONTEXT: &str = "replica-sync";
+const REPLICA_SYNC_DEBOUNCE_MS: u64 = 30 * 1000;
+const REPLICA_SYNC_MINUTES: u64 = 5;
+
+// (Re)arm the replica sync timer: shortly after a change, otherwise every few minutes
+fn schedule_replica_sync(state: &mut State, after_change: bool) {
+    state.replica_timer += 1;
+    if state.replica.node.is_some() {
+        let delay = if after_change {
+            REPLICA_SYNC_DEBOUNCE_MS
+        } else {
+            REPLICA_SYNC_MINUTES * 60 * 1000
+        };
+        timer::set_timer(
+            delay,
+            Some(format!("{}:{}", REPLICA_TIMER_CONTEXT, state.replica_timer).into_bytes()),
+        );
+    }
+}
+
+// Start a replica sync, or have the one running go again when it finishes. Only the node whose
+// name sorts first runs syncs, so the two never wait on each other; the other asks it to.
+fn run_replica_sync(state: &mut State) -> anyhow::Result<Result<String, String>> {
+    let Some(node) = state.replica.node.clone() else {
+        return Ok(Err("No replica set".to_string()));
+    };
+    schedule_replica_sync(state, false);
+    if our().node > node {
+        // The acknowledgement is ignored when it comes back
+        Request::to(Address::new(&node, our().process.clone()))
+            .body(NoteRequest::ReplicaSyncNow)
+            .expects_response(COLLABORATION_TIMEOUT_SECS)
+            .send()?;
+        return Ok(Ok(format!("Asked {} to sync", node)));
+    }
+    if let Some(job) = &mut state.replica_job {
+        job.rerun = true;
+        return Ok(Ok(
+            "A replica sync is running; another follows it".to_string()
+        ));
+    }
+    if let Err(e) = state.start_replica_sync() {
+        state.replica.last_error = Some(e.to_string());
+        state.save_to_disk()?;
+        return Ok(Err(e.to_string()));
+    }
+    Ok(Ok(format!("Replica sync with {} started", node)))
+}
+
+// Feed the running replica sync an answer from the replica, or the failure to get one,
+// recording its outcome once it finishes
+fn continue_replica_sync(
+    state: &mut State,
+    context: &str,
+    response: Result<NoteResponse, String>,
+) -> anyhow::Result<()> {
+    let Some((job_id, step)) = context.split_once(':') else {
+        return Ok(());
+    };
+    let Some(mut job) = state
+        .replica_job
+        .take_if(|job| job.id.to_string() == job_id)
+    else {
+        return Ok(());
+    };
+    match state.replica_sync_step(&mut job, step, response) {
+        Ok(false) => {
+            state.replica_job = Some(job);
+            return Ok(());
+        }
+        Ok(true) => {
+            if job.pulled > 0 && state.git_sync.remote.is_some() {
+                schedule_git_sync(state, true);
+            }
+        }
+        Err(e) => {
+            error!("Replica sync failed: {e}");
+            state.replica.last_error = Some(e.to_string());
+        }
+    }
+    state.save_to_disk()?;
+    schedule_replica_sync(state, job.rerun);
+    Ok(())
+}
+
+// Send one of a replica sync's requests to the replica; the answer arrives in handle_response
+// with this context
+fn replica_send(job: &ReplicaSyncJob, step: &str, request: NoteRequest) -> anyhow::Result<()> {
+    Request::to(Address::new(&job.node, our().process.clone()))
+        .body(request)
+        .context(format!("{}:{}:{}", REPLICA_SYNC_CONTEXT, job.id, step).into_bytes())
+        .expects_response(COLLABORATION_TIMEOUT_SECS)
+        .send()?;
+    Ok(())
+}
+
+// The owner of the mirrored folder or note a request would change, if any; our folder holding a
+// mirror can still be renamed and moved
+fn mirror_refusal(state: &State, req: &NoteRequest) -> Option<String> {
+    if state.mirrors.is_empty() {
+        return None;
+    }
+    let (ids, holder_may_change): (Vec<&String>, bool) = match req {
+        NoteRequest::UpdateNoteContent((id, _))
+        | NoteRequest::AppendNoteContent((id, _))
+        | NoteRequest::EditTable((id, _))
+        | NoteRequest::RenameNote((id, _))
+        | NoteRequest::SetNoteTags((id, _))
+        | NoteRequest::SetNoteDue((id, _))
+        | NoteRequest::SetNotePassword((id, _))
+        | NoteRequest::RemoveNotePassword((id, _))
+        | NoteRequest::DeleteNote(id)
+        | NoteRequest::DeleteFolder(id) => (vec![id], false),
+        NoteRequest::RenameFolder((id, _)) => (vec![id], true),
+        NoteRequest::MoveNote((id, folder_id)) => {
+            (std::iter::once(id).chain(folder_id).collect(), false)
+        }
+        NoteRequest::MoveFolder((id, parent_id)) => {
+            if let Some(parent_id) = parent_id {
+                if let Some(mirror) = state
+                    .mirrors
+                    .iter()
+                    .find(|m| m.items.values().any(|i| i == parent_id))
+                {
+                    return Some(mirror.owner.clone());
+                }
+            }
+            (vec![id], true)
+        }
+        NoteRequest::CreateFolder((_, Some(folder_id)))
+        | NoteRequest::CreateNote((_, Some(folder_id), _))
+        | NoteRequest::CreateAudioNote((_, Some(folder_id), _, _, _))
+        | NoteRequest::ImportPdf((_, Some(folder_id), _))
+        | NoteRequest::ImportCsv((_, Some(folder_id), _))
+        | NoteRequest::ClipUrl((_, Some(folder_id))) => (vec![folder_id], false),
+        _ => return None,
+    };
+    ids.into_iter().find_map(|id| {
+        state
+            .mirrors
+            .iter()
+            .filter(|m| !(holder_may_change && &m.local_folder_id == id))
+            .find(|m| m.items.values().any(|i| i == id))
+            .map(|m| m.owner.clone())
+    })
+}
+
+// Send the nodes mirroring our shared folders what changed in them
+fn push_folder_mirrors(state: &mut State) -> anyhow::Result<()> {
+    for (folder_id, subscribers) in state.folder_subscribers.clone() {
+        if subscribers.is_empty() {
+            continue;
+        }
+        let Some(update) = state.next_mirror_update(&folder_id) else {
+            continue;
+        };
+        for node in subscribers {
+            // The acknowledgement is ignored when it comes back
+            Request::to(Address::new(&node, our().process.clone()))
+                .body(NoteRequest::MirrorUpdate(update.clone()))
+                .expects_response(COLLABORATION_TIMEOUT_SECS)
+                .send()?;
+        }
+    }
+    Ok(())
+}
+
+// Ask the owners of the folders we mirror for everything in them, in case we missed updates
+fn resubscribe_mirrors(state: &State) -> anyhow::Result<()> {
+    for mirror in &state.mirrors {
+        Request::to(Address::new(&mirror.owner, our().process.clone()))
+            .body(NoteRequest::SubscribeFolder(mirror.folder_id.clone()))
+            .expects_response(COLLABORATION_TIMEOUT_SECS)
+            .send()?;
+    }
+    Ok(())
+}
+
+// Replication requests are only taken from the node we named as our replica
+fn is_from_replica(state: &State, source: Option<&Address>) -> bool {
+    source.is_some_and(|source| {
+        source.node != our().node && state.replica.node.as_deref() == Some(source.node.as_str())
+    })
+}
+
+const COLLABORATION_TIMEOUT_SECS: u64 = 30;
+const SHARE_KEY_MISMATCH: &str = "Content is not encrypted with the note's current share key";
+
+// Node names mentioned as @name.os in a note, lowercased. An @ inside a word, as in an email
+// address, is not a mention.
+fn note_mentions(content: &[u8]) -> HashSet<String> {
+    let text = String::from_utf8_lossy(content);
+    let mut mentions = HashSet::new();
+    let mut previous = ' ';
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '@' && !previous.is_alphanumeric() {
+            let mut name = String::new();
+            while let Some(&n) = chars.peek() {
+                if !(n.is_ascii_alphanumeric() || n == '-' || n == '.') {
+                    break;
+                }
+                name.push(n);
+                chars.next();
+            }
+            let name = name.trim_end_matches('.');
+            if name.contains('.') {
+                mentions.insert(name.to_lowercase());
+            }
+            previous = name.chars().last().unwrap_or(c);
+        } else {
+            previous = c;
+        }
+    }
+    mentions
+}
+
+// Nodes mentioned in the new content of a note but not in its previous content
+fn new_mentions(previous: &[u8], content: &[u8]) -> Vec<String> {
+    let before = note_mentions(previous);
+    let mut added: Vec<String> = note_mentions(content)
+        .into_iter()
+        .filter(|node| !before.contains(node))
+        .collect();
+    added.sort();
+    added
+}
+
+// Cells of the table a merge compares two texts' differing lines with, 16 MB at most
+const MERGE_MAX_CELLS: usize = 4 * 1024 * 1024;
+
+// Pairs of (base line, other line) that a longest common subsequence of the two texts' lines
+// matches up, in order; None when they differ in too many lines to compare
+fn matched_lines(base: &[&str], other: &[&str]) -> Option<Vec<(usize, usize)>> {
+    // Lines both start and end with alike need no table
+    let prefix = base.iter().zip(other).take_while(|(a, b)| a == b).count();
+    let suffix = base[prefix..]
+        .iter()
+        .rev()
+        .zip(other[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let base_middle = &base[prefix..base.len() - suffix];
+    let other_middle = &other[prefix..other.len() - suffix];
+    let (n, m) = (base_middle.len(), other_middle.len());
+    if (n + 1) * (m + 1) > MERGE_MAX_CELLS {
+        return None;
+    }
+    // Length of the longest common subsequence of base_middle[i..] and other_middle[j..]
+    let mut lengths = vec![0u32; (n + 1) * (m + 1)];
+    let at = |i: usize, j: usize| i * (m + 1) + j;
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lengths[at(i, j)] = if base_middle[i] == other_middle[j] {
+                lengths[at(i + 1, j + 1)] + 1
+            } else {
+                lengths[at(i + 1, j)].max(lengths[at(i, j + 1)])
+            };
+        }
+    }
+    let mut pairs: Vec<(usize, usize)> = (0..prefix).map(|i| (i, i)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if base_middle[i] == other_middle[j] {
+            pairs.push((prefix + i, prefix + j));
+            i += 1;
+            j += 1;
+        } else if lengths[at(i + 1, j)] >= lengths[at(i, j + 1)] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs.extend((0..suffix).map(|k| (base.len() - suffix + k, other.len() - suffix + k)));
+    Some(pairs)
+}
+
+// Three-way merge of two edits of a text, line by line. Where both changed the same lines
+// differently, both versions are kept between git-style conflict markers. Returns the merged
+// text and how many conflicts it holds, or None if the texts are too large to compare.
+fn merge_text(base: &str, mine: &str, theirs: &str) -> Option<(String, u32)> {
+    // A last line without a newline still matches the same line with one
+    let with_newline = |text: &str| match text.is_empty() || text.ends_with('\n') {
+        true => text.to_string(),
+        false => format!("{}\n", text),
+    };
+    let (base_text, mine_text, theirs_text) =
+        (with_newline(base), with_newline(mine), with_newline(theirs));
+    let base_lines: Vec<&str> = base_text.split_inclusive('\n').collect();
+    let mine_lines: Vec<&str> = mine_text.split_inclusive('\n').collect();
+    let theirs_lines: Vec<&str> = theirs_text.split_inclusive('\n').collect();
+    let in_mine: HashMap<usize, usize> = matched_lines(&base_lines, &mine_lines)?
+        .into_iter()
+        .collect();
+    let in_theirs: HashMap<usize, usize> = matched_lines(&base_lines, &theirs_lines)?
+        .into_iter()
+        .collect();
+    // Base lines both edits kept, which the merge lines up on, then the ends of the texts
+    let mut stable: Vec<(usize, usize, usize)> = (0..base_lines.len())
+        .filter_map(|o| Some((o, *in_mine.get(&o)?, *in_theirs.get(&o)?)))
+        .collect();
+    stable.push((base_lines.len(), mine_lines.len(), theirs_lines.len()));
+
+    let mut merged = String::new();
+    let mut conflicts = 0;
+    let (mut o, mut a, mut b) = (0, 0, 0);
+    for (next_o, next_a, next_b) in stable {
+        let base_chunk = &base_lines[o..next_o];
+        let mine_chunk = &mine_lines[a..next_a];
+        let theirs_chunk = &theirs_lines[b..next_b];
+        if mine_chunk == base_chunk || mine_chunk == theirs_chunk {
+            merged.extend(theirs_chunk.iter().copied());
+        } else if theirs_chunk == base_chunk {
+            merged.extend(mine_chunk.iter().copied());
+        } else {
+            conflicts += 1;
+            merged.push_str("<<<<<<< mine\n");
+            merged.extend(mine_chunk.iter().copied());
+            merged.push_str("=======\n");
+            merged.extend(theirs_chunk.iter().copied());
+            merged.push_str(">>>>>>> theirs\n");
+        }
+        if let Some(line) = base_lines.get(next_o) {
+            merged.push_str(line);
+        }
+        (o, a, b) = (next_o + 1, next_a + 1, next_b + 1);
+    }
+    if !mine.ends_with('\n') && !theirs.ends_with('\n') && merged.ends_with('\n') {
+        merged.pop();
+    }
+    Some((merged, conflicts))
+}
+
+// Let nodes a save of a shared note newly mentions know, as long as they can open the note.
+// We own the note, so edits by collaborators pass through here too.
+fn notify_mentions(
+    state: &mut State,
+    metadata: &NoteMetadata,
+    mentioned: &[String],
+    author: &str,
+) -> anyhow::Result<()> {
+    for node in mentioned {
+        if node == author {
+            continue;
+        }
+        if node == &our().node {
+            state.record_mention(Mention {
+                owner_node_id: our().node,
+                note_id: metadata.id.clone(),
+                note_name: metadata.name.clone(),
+                from_node_id: author.to_string(),
+                received: now_secs(),
+            })?;
+        } else if metadata.collaborators.contains(node) {
+            // The acknowledgement, or failure to deliver, is ignored when it comes back
+            Request::to(Address::new(node, our().process.clone()))
+                .body(NoteRequest::NotifyMention((
+                    metadata.id.clone(),
+                    metadata.name.clone(),
+                    author.to_string(),
+                )))
+                .expects_response(COLLABORATION_TIMEOUT_SECS)
+                .send()?;
+        }
+    }
+    Ok(())
+}
+
+// Invites not accepted or rejected within this long are dropped by both nodes
+const INVITE_TTL_SECS: u64 = 14 * 24 * 60 * 60;
+
+// Let an invitee's node know about an invite; the acknowledgement, or failure to deliver, is
+// ignored when it comes back
+fn send_invite(node: &str, note_id: &str, note_name: &str, expires: u64) -> anyhow::Result<()> {
+    if node == our().node {
+        return Ok(());
+    }
+    Request::to(Address::new(node, our().process.clone()))
+        .body(NoteRequest::NotifyInvite((
+            note_id.to_string(),
+            note_name.to_string(),
+            expires,
+        )))
+        .expects_response(COLLABORATION_TIMEOUT_SECS)
+        .send()?;
+    Ok(())
+}
+
+// Offer a note to the node it is being transferred to; the acknowledgement, or failure to
+// deliver, is ignored when it comes back
+fn send_transfer_offer(
+    node: &str,
+    note_id: &str,
+    note_name: &str,
+    bytes: u64,
+) -> anyhow::Result<()> {
+    Request::to(Address::new(node, our().process.clone()))
+        .body(NoteRequest::NotifyTransfer((
+            note_id.to_string(),
+            note_name.to_string(),
+            bytes,
+        )))
+        .expects_response(COLLABORATION_TIMEOUT_SECS)
+        .send()?;
+    Ok(())
+}
+
+// Tell a sender's node we rejected its transfer; the acknowledgement is ignored when it comes back
+fn send_transfer_rejection(sender: &str, note_id: &str) -> anyhow::Result<()> {
+    Request::to(Address::new(sender, our().process.clone()))
+        .body(NoteRequest::RejectTransfer((
+            note_id.to_string(),
+            sender.to_string(),
+        )))
+        .expects_response(COLLABORATION_TIMEOUT_SECS)
+        .send()?;
+    Ok(())
+}
+
+// Tell a sender's node we hold the note it transferred, so it can delete its copy; the
+// acknowledgement is ignored when it comes back
+fn send_transfer_completion(sender: &str, note_id: &str, new_id: &str) -> anyhow::Result<()> {
+    Request::to(Address::new(sender, our().process.clone()))
+        .body(NoteRequest::CompleteTransfer((
+            note_id.to_string(),
+            new_id.to_string(),
+        )))
+        .expects_response(COLLABORATION_TIMEOUT_SECS)
+        .send()?;
+    Ok(())
+}
+
+// The owner's answer to our subscribing to its folder. The first full update fills a new
+// read-only folder of ours; later ones come in as mirror-update requests.
+fn receive_mirror_subscription(
+    state: &mut State,
+    owner: &str,
+    folder_id: &str,
+    answer: Result<NoteResponse, String>,
+) -> anyhow::Result<()> {
+    let update = match answer {
+        Ok(NoteResponse::SubscribeFolder(Ok(update))) => update,
+        Ok(NoteResponse::SubscribeFolder(Err(e))) | Err(e) => {
+            report_failed_mirror(owner, folder_id, &e);
+            return Ok(());
+        }
+        Ok(_) => {
+            let error = format!("Unexpected response from {}", owner);
+            report_failed_mirror(owner, folder_id, &error);
+            return Ok(());
+        }
+    };
+    if state
+        .mirrors
+        .iter()
+        .any(|m| m.owner == owner && m.folder_id == folder_id)
+    {
+        if state.apply_mirror_update(owner, update)? {
+            state.save_to_disk()?;
+        }
+        return Ok(());
+    }
+    let name = update
+        .folders
+        .iter()
+        .find(|f| f.id == folder_id)
+        .map(|f| f.name.clone())
+        .unwrap_or_else(|| folder_id.to_string());
+    let id = state.generate_unique_id();
+    let folder = Folder {
+        id: id.clone(),
+        name,
+        parent_id: None,
+        mirror_of: Some(format!("{}/{}", owner, folder_id)),
+    };
+    state.folders.insert(id.clone(), folder);
+    state.root_items.insert(id.clone());
+    state.mirrors.push(MirroredFolder {
+        owner: owner.to_string(),
+        folder_id: folder_id.to_string(),
+        local_folder_id: id.clone(),
+        items: HashMap::from([(folder_id.to_string(), id)]),
+    });
+    state.apply_mirror_update(owner, update)?;
+    state.save_to_disk()?;
+    Ok(())
+}
+
+fn report_failed_mirror(owner: &str, folder_id: &str, error: &str) {
+    error!("Mirroring {}/{} failed: {}", owner, folder_id, error);
+    push_notification(
+        &format!("Could not mirror the folder from {}", owner),
+        error,
+    );
+}
+
+// The sender's answer to our fetching a note it offered us. The note becomes ours, and the
+// sender is told so it can let go of its copy.
+fn receive_transfer(
+    state: &mut State,
+    sender: &str,
+    note_id: &str,
+    answer: Result<NoteResponse, String>,
+) -> anyhow::Result<()> {
+    let transferred = match answer {
+        Ok(NoteResponse::FetchTransfer(Ok(transferred))) => transferred,
+        Ok(NoteResponse::FetchTransfer(Err(e))) | Err(e) => {
+            report_failed_transfer(state, sender, note_id, &e);
+            return Ok(());
+        }
+        Ok(_) => {
+            let error = format!("Unexpected response from {}", sender);
+            report_failed_transfer(state, sender, note_id, &error);
+            return Ok(());
+        }
+    };
+    // Rejected while the note was on its way
+    if !state
+        .transfer_offers
+        .iter()
+        .any(|offer| offer.note_id == note_id && offer.sender_node_id == sender)
+    {
+        return Ok(());
+    }
+    let TransferredNote {
+        note,
+        due,
+        aliases,
+        attachments,
+    } = transferred;
+    let sizes: Vec<u64> = std::iter::once(note.content.len() as u64)
+        .chain(attachments.iter().map(|(_, bytes)| bytes.len() as u64))
+        .collect();
+    if let Err(e) = state.check_quota_all(&sizes, 0)? {
+        report_failed_transfer(state, sender, note_id, &e.to_string());
+        return Ok(());
+    }
+    let id = state.add_note(note.name.clone(), None, note.note_type, &note.content)?;
+    // Markdown links attachments by id, which change on the way over
+    let mut content = String::from_utf8(note.content.clone()).ok();
+    for (attachment, bytes) in attachments {
+        let attachment_id = state.add_attachment(&id, attachment.name, attachment.mime, &bytes)?;
+        if let Some(content) = &mut content {
+            *content = content.replace(
+                &attachment_url(&attachment.id),
+                &attachment_url(&attachment_id),
+            );
+        }
+    }
+    if let Some(content) = content.filter(|c| c.as_bytes() != note.content) {
+        state.save_note_content(&id, content.as_bytes())?;
+    }
+    // Published only if we publish it ourselves
+    if let Some(metadata) = state.notes.get_mut(&id) {
+        metadata.collaborators = note.collaborators;
+        metadata.collaborators.retain(|node| node != &our().node);
+        metadata.tags = note.tags;
+        metadata.due = due;
+        metadata.aliases = aliases;
+    }
+    state
+        .transfer_offers
+        .retain(|offer| offer.note_id != note_id || offer.sender_node_id != sender);
+    state.save_to_disk()?;
+    state
+        .accepted_transfers
+        .insert((sender.to_string(), note_id.to_string()), id.clone());
+    send_transfer_completion(sender, note_id, &id)
+}
+
+// The offer stays, to accept again or reject
+fn report_failed_transfer(state: &State, sender: &str, note_id: &str, error: &str) {
+    error!(
+        "Accepting the transfer of {}/{} failed: {}",
+        sender, note_id, error
+    );
+    let name = state
+        .transfer_offers
+        .iter()
+        .find(|offer| offer.note_id == note_id && offer.sender_node_id == sender)
+        .map_or(note_id, |offer| offer.note_name.as_str());
+    push_notification(
+        &format!("Could not accept the transfer of \"{}\"", name),
+        error,
+    );
+}
+
+// The inviter's answer to our accepting its invite. Once it has added us, the note joins those
+// shared with us.
+fn receive_invite_acceptance(
+    state: &mut State,
+    inviter: &str,
+    note_id: &str,
+    answer: Result<NoteResponse, String>,
+) -> anyhow::Result<()> {
+    let note = match answer {
+        Ok(NoteResponse::AcceptInvite(Ok(note))) => note,
+        Ok(NoteResponse::AcceptInvite(Err(e))) | Err(e) => {
+            report_failed_acceptance(state, inviter, note_id, &e);
+            return Ok(());
+        }
+        Ok(_) => {
+            let error = format!("Unexpected response from {}", inviter);
+            report_failed_acceptance(state, inviter, note_id, &error);
+            return Ok(());
+        }
+    };
+    state
+        .received_invites
+        .retain(|invite| invite.note_id != note_id || invite.inviter_node_id != inviter);
+    state.remember_shared_note(inviter, note_id, &note)?;
+    state.save_to_disk()?;
+    // Fetch the key now, so it is here by the time the note is opened
+    request_share_key(inviter, note_id)
+}
+
+// The invite stays, to accept again or reject
+fn report_failed_acceptance(state: &State, inviter: &str, note_id: &str, error: &str) {
+    error!(
+        "Accepting the invite to {}/{} failed: {}",
+        inviter, note_id, error
+    );
+    let name = state
+        .received_invites
+        .iter()
+        .find(|invite| invite.note_id == note_id && invite.inviter_node_id == inviter)
+        .map_or(note_id, |invite| invite.note_name.as_str());
+    push_notification(
+        &format!("Could not accept the invite to \"{}\"", name),
+        error,
+    );
+}
+
+// Tell an inviter's node we rejected its invite; the acknowledgement is ignored when it comes back
+fn send_invite_rejection(inviter: &str, note_id: &str) -> anyhow::Result<()> {
+    Request::to(Address::new(inviter, our().process.clone()))
+        .body(NoteRequest::RejectInvite((
+            note_id.to_string(),
+            inviter.to_string(),
+        )))
+        .expects_response(COLLABORATION_TIMEOUT_SECS)
+        .send()?;
+    Ok(())
+}
+
+// Tell an owner's node we left its shared note; the acknowledgement is ignored when it comes back
+fn send_leave_notice(owner: &str, note_id: &str) -> anyhow::Result<()> {
+    Request::to(Address::new(owner, our().process.clone()))
+        .body(NoteRequest::LeaveSharedNote(note_id.to_string()))
+        .expects_response(COLLABORATION_TIMEOUT_SECS)
+        .send()?;
+    Ok(())
+}
+
+fn unreachable_error(node: &str) -> String {
+    format!("{} is unreachable", node)
+}
+
+const REPLY_CONTEXT: &str = "reply";
+
+// A request sent to wifenote on another node, kept until its answer comes back to
+// handle_response, or the send fails, and handled by receive_reply
+#[derive(Debug, Clone)]
+enum PendingReply {
+    // A note shared with us, fetched for get-note
+    FetchSharedNote {
+        owner: String,
+        note_id: String,
+    },
+    // A save to a note shared with us, with the content before it was encrypted and the hash
+    // of the cached copy it was made on
+    SaveSharedNote {
+        owner: String,
+        note_id: String,
+        content: Vec<u8>,
+        base_hash: Option<String>,
+    },
+    // An invite to a note on another node, accepted for accept-invite
+    AcceptInvite {
+        inviter: String,
+        note_id: String,
+    },
+    // A note another node offered us, fetched for accept-transfer
+    FetchTransfer {
+        sender: String,
+        note_id: String,
+    },
+    // A folder on another node, subscribed to for mirror-folder
+    SubscribeFolder {
+        owner: String,
+        folder_id: String,
+    },
+    // A note with a queued edit, fetched to check the owner's copy before the edit is sent
+    ReplayFetch {
+        owner: String,
+        note_id: String,
+    },
+    // A queued edit sent to the owner, with the content before it was encrypted
+    ReplaySave {
+        owner: String,
+        note_id: String,
+        content: Vec<u8>,
+    },
+}
+
+impl PendingReply {
+    // The node expected to answer
+    fn node(&self) -> &str {
+        match self {
+            PendingReply::FetchSharedNote { owner, .. }
+            | PendingReply::SaveSharedNote { owner, .. }
+            | PendingReply::SubscribeFolder { owner, .. }
+            | PendingReply::ReplayFetch { owner, .. }
+            | PendingReply::ReplaySave { owner, .. } => owner,
+            PendingReply::AcceptInvite { inviter, .. } => inviter,
+            PendingReply::FetchTransfer { sender, .. } => sender,
+        }
+    }
+}
+
+// Send a request to wifenote on another node without waiting for it; receive_reply gets the
+// answer, or the error if the node never answers
+fn send_to_node(
+    state: &mut State,
+    node: &str,
+    request: NoteRequest,
+    reply: PendingReply,
+) -> anyhow::Result<()> {
+    state.next_reply_id += 1;
+    let id = state.next_reply_id;
+    Request::to(Address::new(node, our().process.clone()))
+        .body(request)
+        .expects_response(COLLABORATION_TIMEOUT_SECS)
+        .context(format!("{}:{}", REPLY_CONTEXT, id).as_bytes())
+        .send()?;
+    state.pending_replies.insert(id, reply);
+    Ok(())
+}
+
+// Finish the request sent with reply context `id`, given what `from` answered
+fn receive_reply(
+    state: &mut State,
+    id: &str,
+    from: &str,
+    answer: Result<NoteResponse, String>,
+) -> anyhow::Result<()> {
+    let Ok(id) = id.parse::<u64>() else {
+        return Ok(());
+    };
+    if state
+        .pending_replies
+        .get(&id)
+        .is_none_or(|reply| reply.node() != from)
+    {
+        return Ok(());
+    }
+    let Some(reply) = state.pending_replies.remove(&id) else {
+        return Ok(());
+    };
+    match reply {
+        PendingReply::FetchSharedNote { owner, note_id } => {
+            receive_shared_note(state, &owner, &note_id, answer)
+        }
+        PendingReply::SaveSharedNote {
+            owner,
+            note_id,
+            content,
+            base_hash,
+        } => receive_shared_save(state, &owner, &note_id, (content, base_hash), answer),
+        PendingReply::AcceptInvite { inviter, note_id } => {
+            receive_invite_acceptance(state, &inviter, &note_id, answer)
+        }
+        PendingReply::FetchTransfer { sender, note_id } => {
+            receive_transfer(state, &sender, &note_id, answer)
+        }
+        PendingReply::SubscribeFolder { owner, folder_id } => {
+            receive_mirror_subscription(state, &owner, &folder_id, answer)
+        }
+        PendingReply::ReplayFetch { owner, note_id } => {
+            receive_replay_fetch(state, &owner, &note_id, answer)
+        }
+        PendingReply::ReplaySave {
+            owner,
+            note_id,
+            content,
+        } => receive_replay_save(state, &owner, &note_id, content, answer),
+    }
+}
+
+// A note listed in another folder has an entry there with id "<note id>@<folder id>"
+fn alias_entry_id(note_id: &str, folder_id: &str) -> String {
+    format!("{}@{}", note_id, folder_id)
+}
+
+fn note_alias_ref(id: &str) -> Option<(&str, &str)> {
+    id.split_once('@')
+        .filter(|(note_id, folder_id)| !note_id.is_empty() && !folder_id.is_empty())
+}
+
+// Alias entries open and edit the note they stand for
+fn resolve_note_alias(state: &State, req: NoteRequest) -> NoteRequest {
+    let target = |id: String| match note_alias_ref(&id) {
+        Some((note_id, _)) if state.notes.contains_key(note_id) => note_id.to_string(),
+        _ => id,
+    };
+    match req {
+        NoteRequest::GetNote(id) => NoteRequest::GetNote(target(id)),
+        NoteRequest::UpdateNoteContent((id, content)) => {
+            NoteRequest::UpdateNoteContent((target(id), content))
+        }
+        NoteRequest::AppendNoteContent((id, content)) => {
+            NoteRequest::AppendNoteContent((target(id), content))
+        }
+        req => req,
+    }
+}
+
+// Notes shared with us appear locally as "<owner node>/<owner's note id>"
+fn shared_note_ref(id: &str) -> Option<(&str, &str)> {
+    id.split_once('/')
+        .filter(|(owner, note_id)| !owner.is_empty() && !note_id.is_empty())
+}
+
+// A note shared with us as last fetched from its owner, with its shared note id. A fresh copy
+// is asked for each time; receive_shared_note keeps it and pushes it to the WebSocket clients
+// following the note.
+fn open_shared_note(
+    state: &mut State,
+    owner: &str,
+    note_id: &str,
+) -> anyhow::Result<Result<Note, String>> {
+    let is_fetching = state.pending_replies.values().any(|reply| match reply {
+        PendingReply::FetchSharedNote {
+            owner: o,
+            note_id: n,
+        } => o == owner && n == note_id,
+        _ => false,
+    });
+    if !is_fetching {
+        send_to_node(
+            state,
+            owner,
+            NoteRequest::GetNote(note_id.to_string()),
+            PendingReply::FetchSharedNote {
+                owner: owner.to_string(),
+                note_id: note_id.to_string(),
+            },
+        )?;
+    }
+    let id = format!("{}/{}", owner, note_id);
+    Ok(state
+        .shared_note_cache
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| shared_note_pending_error(owner)))
+}
+
+// Keep the copy of a shared note its owner sent back
+fn receive_shared_note(
+    state: &mut State,
+    owner: &str,
+    note_id: &str,
+    answer: Result<NoteResponse, String>,
+) -> anyhow::Result<()> {
+    let mut note = match answer {
+        Ok(NoteResponse::GetNote(Ok(note))) => note,
+        Ok(NoteResponse::GetNote(Err(e))) => {
+            // No longer shared with us, or gone
+            error!("Could not fetch {}/{}: {}", owner, note_id, e);
+            let count = state.shared_notes.len();
+            state
+                .shared_notes
+                .retain(|shared| shared.owner != owner || shared.note_id != note_id);
+            if state.shared_notes.len() != count {
+                state.save_to_disk()?;
+            }
+            return Ok(());
+        }
+        // The last copy is used until the owner is back
+        Err(_) | Ok(_) => return Ok(()),
+    };
+    let Some(content) = open_shared_content(state, owner, note_id, note.content)? else {
+        return Ok(());
+    };
+    note.content = content;
+    note.id = format!("{}/{}", owner, note_id);
+    state
+        .shared_note_cache
+        .insert(note.id.clone(), note.clone());
+    state.remember_shared_note(owner, note_id, &note)?;
+    let bytes: Vec<u8> = NoteResponse::GetNote(Ok(note.clone())).into();
+    for (channel_id, subscribed) in &state.ws_subscriptions {
+        if subscribed.contains(&note.id) && state.ws_private_channels.contains(channel_id) {
+            send_ws_message(*channel_id, bytes.clone());
+        }
+    }
+    Ok(())
+}
+
+fn shared_note_pending_error(owner: &str) -> String {
+    format!("Fetching the note from {}, try again shortly", owner)
+}
+
+// Save new content for a note shared with us. The owner's answer comes back to
+// receive_shared_save; until then, and while the owner is unreachable, later saves are queued
+// and replayed by replay_shared_edits.
+fn save_shared_note(
+    state: &mut State,
+    owner: &str,
+    note_id: &str,
+    content: Vec<u8>,
+) -> anyhow::Result<()> {
+    let id = format!("{}/{}", owner, note_id);
+    let base_hash = state
+        .shared_note_cache
+        .get(&id)
+        .map(|cached| sha256_hex(&cached.content));
+    let is_queued = state
+        .pending_shared_edits
+        .iter()
+        .any(|edit| edit.owner == owner && edit.note_id == note_id);
+    // Wait for earlier edits to land, or this one could overtake them
+    if is_queued || is_saving_shared_note(state, owner, note_id) {
+        return queue_shared_edit(state, owner, note_id, content, base_hash);
+    }
+    let Some(key) = known_share_key(state, owner, note_id) else {
+        request_share_key(owner, note_id)?;
+        return queue_shared_edit(state, owner, note_id, content, base_hash);
+    };
+    let encrypted = encrypt_with_key(&content, &key, &[0; ENCRYPTION_SALT_LEN])
+        .map_err(|e| anyhow::anyhow!(e))?;
+    send_to_node(
+        state,
+        owner,
+        NoteRequest::UpdateNoteContent((note_id.to_string(), encrypted)),
+        PendingReply::SaveSharedNote {
+            owner: owner.to_string(),
+            note_id: note_id.to_string(),
+            content,
+            base_hash,
+        },
+    )
+}
+
+// Queue a save to a shared note, replacing one already queued for it
+fn queue_shared_edit(
+    state: &mut State,
+    owner: &str,
+    note_id: &str,
+    content: Vec<u8>,
+    base_hash: Option<String>,
+) -> anyhow::Result<()> {
+    let queued = state
+        .pending_shared_edits
+        .iter_mut()
+        .find(|edit| edit.owner == owner && edit.note_id == note_id);
+    match queued {
+        Some(edit) => {
+            edit.content = content.clone();
+            edit.queued = now_secs();
+        }
+        None => state.pending_shared_edits.push(PendingSharedEdit {
+            owner: owner.to_string(),
+            note_id: note_id.to_string(),
+            content: content.clone(),
+            base_hash,
+            queued: now_secs(),
+        }),
+    }
+    let id = format!("{}/{}", owner, note_id);
+    if let Some(cached) = state.shared_note_cache.get_mut(&id) {
+        cached.content = content;
+    }
+    state.save_to_disk()?;
+    schedule_shared_edit_replay(state);
+    Ok(())
+}
+
+// The owner's answer to a save. Saves queued while it was on its way are sent next, made on
+// the content the owner now has if it took this one.
+fn receive_shared_save(
+    state: &mut State,
+    owner: &str,
+    note_id: &str,
+    (content, base_hash): (Vec<u8>, Option<String>),
+    answer: Result<NoteResponse, String>,
+) -> anyhow::Result<()> {
+    let id = format!("{}/{}", owner, note_id);
+    let queued = state
+        .pending_shared_edits
+        .iter()
+        .position(|edit| edit.owner == owner && edit.note_id == note_id);
+    let error = match answer {
+        Ok(NoteResponse::UpdateNoteContent(Ok(()))) => {
+            if let Some(index) = queued {
+                state.pending_shared_edits[index].base_hash = Some(sha256_hex(&content));
+            }
+            if let Some(cached) = state.shared_note_cache.get_mut(&id) {
+                cached.content = content;
+            }
+            None
+        }
+        Err(e) if e == unreachable_error(owner) => {
+            // A save queued meanwhile is newer, and was made on the content the owner still has
+            if queued.is_none() {
+                queue_shared_edit(state, owner, note_id, content, base_hash)?;
+            }
+            return Ok(());
+        }
+        Ok(NoteResponse::UpdateNoteContent(Err(e))) if e == SHARE_KEY_MISMATCH => {
+            request_share_key(owner, note_id)?;
+            if queued.is_none() {
+                queue_shared_edit(state, owner, note_id, content, base_hash)?;
+            }
+            return Ok(());
+        }
+        Ok(NoteResponse::UpdateNoteContent(Err(e))) | Err(e) => Some(e),
+        // The owner's limits apply to what we save into its note
+        Ok(NoteResponse::QuotaExceeded(e)) => Some(e.to_string()),
+        Ok(_) => Some(format!("Unexpected response from {}", owner)),
+    };
+    if let Some(e) = error {
+        error!("Save to {} failed: {}", id, e);
+        let name = state
+            .shared_notes
+            .iter()
+            .find(|shared| shared.owner == owner && shared.note_id == note_id)
+            .map_or(id.clone(), |shared| shared.name.clone());
+        push_notification(&format!("Your edit to \"{}\" was not saved", name), &e);
+    }
+    if queued.is_some() {
+        state.save_to_disk()?;
+        replay_shared_edit(state, owner, note_id)?;
+    }
+    Ok(())
+}
+
+const SHARED_EDIT_TIMER_CONTEXT: &str = "shared-edits";
+const SHARED_EDIT_RETRY_MINUTES: u64 = 5;
+
+fn schedule_shared_edit_replay(state: &mut State) {
+    state.shared_edit_timer += 1;
+    if !state.pending_shared_edits.is_empty() {
+        timer::set_timer(
+            SHARED_EDIT_RETRY_MINUTES * 60 * 1000,
+            Some(format!("{}:{}", SHARED_EDIT_TIMER_CONTEXT, state.shared_edit_timer).into_bytes()),
+        );
+    }
+}
+
+// Send queued saves to owners that are reachable again, first fetching each note to check the
+// owner's copy is still the one the edit was made on. Edits stay queued until the owner answers.
+fn replay_shared_edits(state: &mut State) -> anyhow::Result<()> {
+    let edits: Vec<(String, String)> = state
+        .pending_shared_edits
+        .iter()
+        .map(|edit| (edit.owner.clone(), edit.note_id.clone()))
+        .collect();
+    for (owner, note_id) in edits {
+        if !is_saving_shared_note(state, &owner, &note_id) {
+            replay_shared_edit(state, &owner, &note_id)?;
+        }
+    }
+    Ok(())
+}
+
+fn replay_shared_edit(state: &mut State, owner: &str, note_id: &str) -> anyhow::Result<()> {
+    send_to_node(
+        state,
+        owner,
+        NoteRequest::GetNote(note_id.to_string()),
+        PendingReply::ReplayFetch {
+            owner: owner.to_string(),
+            note_id: note_id.to_string(),
+        },
+    )
+}
+
+// Whether a save to a shared note is already on its way to the owner
+fn is_saving_shared_note(state: &State, owner: &str, note_id: &str) -> bool {
+    state.pending_replies.values().any(|reply| match reply {
+        PendingReply::ReplayFetch {
+            owner: o,
+            note_id: n,
+        }
+        | PendingReply::ReplaySave {
+            owner: o,
+            note_id: n,
+            ..
+        }
+        | PendingReply::SaveSharedNote {
+            owner: o,
+            note_id: n,
+            ..
+        } => o == owner && n == note_id,
+        _ => false,
+    })
+}
+
+fn drop_shared_edit(state: &mut State, owner: &str, note_id: &str, error: &str) {
+    error!("Dropping queued edit to {}/{}: {}", owner, note_id, error);
+    state
+        .pending_shared_edits
+        .retain(|edit| edit.owner != owner || edit.note_id != note_id);
+}
+
+// The owner's copy of a note with a queued edit. If it changed since the edit was made, the
+// owner's version is kept and ours is saved as a note of our own; otherwise the edit is sent.
+fn receive_replay_fetch(
+    state: &mut State,
+    owner: &str,
+    note_id: &str,
+    answer: Result<NoteResponse, String>,
+) -> anyhow::Result<()> {
+    let Some(edit) = state
+        .pending_shared_edits
+        .iter()
+        .find(|edit| edit.owner == owner && edit.note_id == note_id)
+        .cloned()
+    else {
+        return Ok(());
+    };
+    let note = match answer {
+        Ok(NoteResponse::GetNote(Ok(note))) => note,
+        Err(e) if e == unreachable_error(owner) => return Ok(()),
+        Ok(NoteResponse::GetNote(Err(e))) | Err(e) => {
+            // No longer shared with us, or gone
+            state
+                .shared_notes
+                .retain(|shared| shared.owner != owner || shared.note_id != note_id);
+            drop_shared_edit(state, owner, note_id, &e);
+            return state.save_to_disk();
+        }
+        Ok(_) => {
+            drop_shared_edit(state, owner, note_id, "unexpected response");
+            return state.save_to_disk();
+        }
+    };
+    // Without the owner's current key the edit waits for the next replay
+    let Some(content) = open_shared_content(state, owner, note_id, note.content)? else {
+        return Ok(());
+    };
+    let unchanged = edit
+        .base_hash
+        .as_ref()
+        .is_none_or(|base| *base == sha256_hex(&content));
+    if !unchanged {
+        let name = format!("{} (offline edit)", note.name);
+        state.add_note(name, None, note.note_type, &edit.content)?;
+        push_notification(
+            &format!("\"{}\" changed while you were offline", note.name),
+            "Your edit was saved as a separate note",
+        );
+        drop_shared_edit(state, owner, note_id, "the owner's copy changed");
+        return state.save_to_disk();
+    }
+    let Some(key) = known_share_key(state, owner, note_id) else {
+        return request_share_key(owner, note_id);
+    };
+    let encrypted = encrypt_with_key(&edit.content, &key, &[0; ENCRYPTION_SALT_LEN])
+        .map_err(|e| anyhow::anyhow!(e))?;
+    send_to_node(
+        state,
+        owner,
+        NoteRequest::UpdateNoteContent((note_id.to_string(), encrypted)),
+        PendingReply::ReplaySave {
+            owner: owner.to_string(),
+            note_id: note_id.to_string(),
+            content: edit.content,
+        },
+    )
+}
+
+// The owner's answer to a queued edit. One saved again while it was on its way is sent next.
+fn receive_replay_save(
+    state: &mut State,
+    owner: &str,
+    note_id: &str,
+    content: Vec<u8>,
+    answer: Result<NoteResponse, String>,
+) -> anyhow::Result<()> {
+    let Some(index) = state
+        .pending_shared_edits
+        .iter()
+        .position(|edit| edit.owner == owner && edit.note_id == note_id)
+    else {
+        return Ok(());
+    };
+    match answer {
+        Ok(NoteResponse::UpdateNoteContent(Ok(()))) => {
+            let edit = &mut state.pending_shared_edits[index];
+            let resend = edit.content != content;
+            if resend {
+                edit.base_hash = Some(sha256_hex(&content));
+            } else {
+                state.pending_shared_edits.remove(index);
+            }
+            let id = format!("{}/{}", owner, note_id);
+            if let Some(cached) = state.shared_note_cache.get_mut(&id) {
+                cached.content = content;
+            }
+            state.save_to_disk()?;
+            if resend {
+                replay_shared_edit(state, owner, note_id)?;
+            }
+            Ok(())
+        }
+        // Stays queued, to be sent with the new key
+        Ok(NoteResponse::UpdateNoteContent(Err(e))) if e == SHARE_KEY_MISMATCH => {
+            request_share_key(owner, note_id)
+        }
+        Err(e) if e == unreachable_error(owner) => Ok(()),
+        Ok(NoteResponse::UpdateNoteContent(Err(e))) | Err(e) => {
+            drop_shared_edit(state, owner, note_id, &e);
+            state.save_to_disk()
+        }
+        Ok(NoteResponse::QuotaExceeded(e)) => {
+            drop_shared_edit(state, owner, note_id, &e.to_string());
+            state.save_to_disk()
+        }
+        Ok(_) => {
+            drop_shared_edit(state, owner, note_id, "unexpected response");
+            state.save_to_disk()
+        }
+    }
+}
+
+// Key for a note shared with us by `owner`, once request_share_key has fetched it
+fn known_share_key(state: &State, owner: &str, note_id: &str) -> Option<[u8; 32]> {
+    state
+        .received_share_keys
+        .get(owner)
+        .and_then(|keys| keys.get(note_id))
+        .copied()
+}
+
+// Decrypt content of a note shared with us. Without the key it was encrypted with, this asks
+// the owner for the key and returns None.
+fn open_shared_content(
+    state: &State,
+    owner: &str,
+    note_id: &str,
+    content: Vec<u8>,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    // Published notes are sent in plaintext
+    if !content.starts_with(ENCRYPTED_ARCHIVE_MAGIC) {
+        return Ok(Some(content));
+    }
+    let opened = known_share_key(state, owner, note_id)
+        .and_then(|key| decrypt_with_key(&content, &key).ok());
+    if opened.is_none() {
+        request_share_key(owner, note_id)?;
+    }
+    Ok(opened)
+}
+
+const SHARE_KEY_CONTEXT: &str = "share-key";
+
+// Our node's key pair for agreeing on keys with other nodes, and the public keys other nodes
+// have used, each pinned the first time it is seen. Kept node-wide rather than per workspace,
+// since other nodes pin ours.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ExchangeKeys {
+    secret: Vec<u8>,                 // secp256k1 secret key
+    peers: HashMap<String, Vec<u8>>, // Node id -> its SEC1 public key
+}
+
+impl ExchangeKeys {
+    fn secret_key(&self) -> anyhow::Result<k256::SecretKey> {
+        k256::SecretKey::from_slice(&self.secret)
+            .map_err(|_| anyhow::anyhow!("Invalid exchange key"))
+    }
+
+    fn public_key(&self) -> anyhow::Result<Vec<u8>> {
+        use k256::elliptic_curve::sec1::ToEncodedPoint;
+        let public_key = self.secret_key()?.public_key();
+        Ok(public_key.to_encoded_point(true).as_bytes().to_vec())
+    }
+
+    // Remember the public key a node used, returning whether it was new. A node showing a
+    // different key than before is refused, since it may not be who it was.
+    fn pin(&mut self, node: &str, public_key: &[u8]) -> Result<bool, String> {
+        match self.peers.get(node) {
+            Some(pinned) if pinned == public_key => Ok(false),
+            Some(_) => Err(format!(
+                "{} sent a different exchange key than before; if it was reinstalled, forget \
+                 its old key with forget-exchange-key",
+                node
+            )),
+            None => {
+                self.peers.insert(node.to_string(), public_key.to_vec());
+                Ok(true)
+            }
+        }
+    }
+
+    // The key a note's share key is sealed under between us and the node with `public_key`:
+    // the x coordinate of the ECDH agreement, hashed with the note id
+    fn wrapping_key(&self, public_key: &[u8], note_id: &str) -> Result<[u8; 32], String> {
+        use k256::elliptic_curve::point::AffineCoordinates;
+        use sha2::Digest;
+        let secret = self.secret_key().map_err(|e| e.to_string())?;
+        let peer = k256::PublicKey::from_sec1_bytes(public_key)
+            .map_err(|_| "Invalid exchange public key".to_string())?;
+        let agreed = (peer.to_projective() * *secret.to_nonzero_scalar()).to_affine();
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(b"wifenote share key");
+        hasher.update(agreed.x());
+        hasher.update(note_id.as_bytes());
+        Ok(hasher.finalize().into())
+    }
+}
+
+fn new_exchange_secret() -> Vec<u8> {
+    loop {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        // Almost every 32 bytes is a valid key; zero and values past the curve order are not
+        if k256::SecretKey::from_slice(&secret).is_ok() {
+            return secret.to_vec();
+        }
+    }
+}
+
+// Our exchange keys, made the first time they are needed
+fn load_exchange_keys() -> anyhow::Result<ExchangeKeys> {
+    let path = format!("{}/exchange_keys.json", workspace_drive(DEFAULT_WORKSPACE)?);
+    if let Ok(file) = vfs::open_file(&path, false, None) {
+        return Ok(serde_json::from_slice(&file.read()?)?);
+    }
+    let keys = ExchangeKeys {
+        secret: new_exchange_secret(),
+        peers: HashMap::new(),
+    };
+    save_exchange_keys(&keys)?;
+    Ok(keys)
+}
+
+fn save_exchange_keys(keys: &ExchangeKeys) -> anyhow::Result<()> {
+    let path = format!("{}/exchange_keys.json", workspace_drive(DEFAULT_WORKSPACE)?);
+    vfs::create_file(&path, None)?.write(&serde_json::to_vec(keys)?)?;
+    Ok(())
+}
+
+// A share key sealed under a wrapping key: the nonce, then the ciphertext
+fn seal_share_key(key: &[u8; 32], wrapping: &[u8; 32]) -> Result<Vec<u8>, String> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = Aes256Gcm::new_from_slice(wrapping)
+        .map_err(|e| e.to_string())?
+        .encrypt(&nonce, key.as_slice())
+        .map_err(|_| "Failed to seal the share key".to_string())?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn open_share_key(sealed: &[u8], wrapping: &[u8; 32]) -> Result<[u8; 32], String> {
+    if sealed.len() < ENCRYPTION_NONCE_LEN {
+        return Err("Sealed share key is truncated".to_string());
+    }
+    let (nonce, ciphertext) = sealed.split_at(ENCRYPTION_NONCE_LEN);
+    let key = Aes256Gcm::new_from_slice(wrapping)
+        .map_err(|e| e.to_string())?
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Share key was not sealed for us".to_string())?;
+    <[u8; 32]>::try_from(key).map_err(|_| "Invalid share key".to_string())
+}
+const SHARED_PRESENCE_CONTEXT: &str = "shared-presence";
+
+// Ping the owner of a note shared with us; receive_shared_presence handles the answer
+fn request_shared_presence(
+    owner: &str,
+    note_id: &str,
+    cursor: Option<String>,
+) -> anyhow::Result<()> {
+    let context = format!("{}:{}:{}", SHARED_PRESENCE_CONTEXT, owner, note_id);
+    Request::to(Address::new(owner, our().process.clone()))
+        .body(NoteRequest::PingPresence((note_id.to_string(), cursor)))
+        .expects_response(COLLABORATION_TIMEOUT_SECS)
+        .context(context.as_bytes())
+        .send()?;
+    Ok(())
+}
+
+// Keep who the owner says has a shared note open, for the next ping to return; `target` is
+// "<owner>:<note id>" from the context
+fn receive_shared_presence(state: &mut State, target: &str, message: &Message) {
+    let Some((owner, note_id)) = target.split_once(':') else {
+        return;
+    };
+    if message.source().node != owner {
+        return;
+    }
+    let presence = match message.body().try_into() {
+        Ok(NoteResponse::PingPresence(Ok(presence))) => presence,
+        Ok(NoteResponse::PingPresence(Err(e))) => {
+            error!("No presence for {}/{}: {}", owner, note_id, e);
+            return;
+        }
+        _ => {
+            error!("Unexpected response to a presence ping from {}", owner);
+            return;
+        }
+    };
+    state
+        .shared_presence
+        .insert(format!("{}/{}", owner, note_id), presence);
+}
+
+// Ask a note's owner for its share key; receive_share_key handles the answer
+fn request_share_key(owner: &str, note_id: &str) -> anyhow::Result<()> {
+    let context = format!("{}:{}:{}", SHARE_KEY_CONTEXT, owner, note_id);
+    let public_key = load_exchange_keys()?.public_key()?;
+    Request::to(Address::new(owner, our().process.clone()))
+        .body(NoteRequest::GetShareKey((note_id.to_string(), public_key)))
+        .expects_response(COLLABORATION_TIMEOUT_SECS)
+        .context(context.as_bytes())
+        .send()?;
+    Ok(())
+}
+
+// Keep a share key the owner sent back; `target` is "<owner>:<note id>" from the context
+fn receive_share_key(state: &mut State, target: &str, message: &Message) -> anyhow::Result<()> {
+    let Some((owner, note_id)) = target.split_once(':') else {
+        return Ok(());
+    };
+    if message.source().node != owner {
+        return Ok(());
+    }
+    let (public_key, sealed) = match message.body().try_into() {
+        Ok(NoteResponse::GetShareKey(Ok(answer))) => answer,
+        Ok(NoteResponse::GetShareKey(Err(e))) => {
+            error!("No share key for {}/{}: {}", owner, note_id, e);
+            return Ok(());
+        }
+        _ => {
+            error!("Unexpected response to a share key request from {}", owner);
+            return Ok(());
+        }
+    };
+    let mut keys = load_exchange_keys()?;
+    let pinned = keys.pin(owner, &public_key);
+    let key = pinned.clone().and_then(|_| {
+        let wrapping = keys.wrapping_key(&public_key, note_id)?;
+        open_share_key(&sealed, &wrapping)
+    });
+    let key = match key {
+        Ok(key) => key,
+        Err(e) => {
+            error!("Refused the share key for {}/{}: {}", owner, note_id, e);
+            return Ok(());
+        }
+    };
+    if pinned == Ok(true) {
+        save_exchange_keys(&keys)?;
+    }
+    state
+        .received_share_keys
+        .entry(owner.to_string())
+        .or_default()
+        .insert(note_id.to_string(), key);
+    state.save_to_disk()
+}
+
+const DEFAULT_WORKSPACE: &str = "default";
+
+// Each workspace is a separate notebook with its own drive; the default one keeps the original
+fn workspace_drive(name: &str) -> anyhow::Result<String> {
+    let drive = if name == DEFAULT_WORKSPACE {
+        "notes".to_string()
+    } else {
+        format!("notes-{}", name)
+    };
+    Ok(vfs::create_drive(our().package_id(), &drive, None)?)
+}
+
+// Workspace and profile names become part of drive names
+fn is_valid_drive_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+fn load_workspaces() -> anyhow::Result<WorkspaceList> {
+    let path = format!("{}/workspaces.json", workspace_drive(DEFAULT_WORKSPACE)?);
+    match vfs::open_file(&path, false, None) {
+        Ok(file) => Ok(serde_json::from_slice(&file.read()?)?),
+        Err(_) => Ok(WorkspaceList::default()),
+    }
+}
+
+fn save_workspaces(workspaces: &WorkspaceList) -> anyhow::Result<()> {
+    let path = format!("{}/workspaces.json", workspace_drive(DEFAULT_WORKSPACE)?);
+    vfs::create_file(&path, None)?.write(&serde_json::to_vec(workspaces)?)?;
+    Ok(())
+}
+
+// Profiles reach their notebooks only through these unauthenticated paths, never the node's
+// /api, so the session from log-in-profile is all that grants access to one
+const PROFILE_SESSION_HEADER: &str = "x-wifenote-session";
+const PROFILE_API_PATH: &str = "/profile-api";
+const PROFILE_WS_PATH: &str = "/profile-ws";
+
+fn load_profiles() -> anyhow::Result<Vec<ProfileAccount>> {
+    let path = format!("{}/profiles.json", workspace_drive(DEFAULT_WORKSPACE)?);
+    match vfs::open_file(&path, false, None) {
+        Ok(file) => Ok(serde_json::from_slice(&file.read()?)?),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+fn save_profiles(profiles: &[ProfileAccount]) -> anyhow::Result<()> {
+    let path = format!("{}/profiles.json", workspace_drive(DEFAULT_WORKSPACE)?);
+    vfs::create_file(&path, None)?.write(&serde_json::to_vec(profiles)?)?;
+    Ok(())
+}
+
+fn load_profile_state(name: &str) -> anyhow::Result<State> {
+    let drive = vfs::create_drive(our().package_id(), &format!("profile-{}", name), None)?;
+    let mut state = State::load_from_disk(drive)?;
+    state.profile = Some(name.to_string());
+    Ok(state)
+}
+
+// Run f on a profile's notebook, loading it if no session has used it since startup. The
+// profile's requests are traced as part of the node's message, and timed in its metrics.
+fn with_profile<T>(
+    state: &mut State,
+    name: &str,
+    f: impl FnOnce(&mut State) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let mut profile = match state.profile_states.remove(name) {
+        Some(profile) => profile,
+        None => load_profile_state(name)?,
+    };
+    profile.log_level = state.log_level;
+    profile.message_id = state.message_id;
+    std::mem::swap(&mut profile.metrics, &mut state.metrics);
+    let result = f(&mut profile);
+    std::mem::swap(&mut profile.metrics, &mut state.metrics);
+    state.profile_states.insert(name.to_string(), profile);
+    result
+}
+
+// A profile's API: log-in-profile needs no session, and everything else needs a valid one in the
+// x-wifenote-session header
+fn handle_profile_api_request(
+    http_request: &http::server::IncomingHttpRequest,
+    state: &mut State,
+    server: &http::server::HttpServer,
+) -> anyhow::Result<()> {
+    if http_request.method()? != http::Method::POST {
+        http::server::send_response(http::StatusCode::METHOD_NOT_ALLOWED, None, vec![]);
+        return Ok(());
+    }
+    let body = last_blob().map(|blob| blob.bytes).unwrap_or_default();
+    let Ok(req) = NoteRequest::try_from(body.as_slice()) else {
+        http::server::send_response(
+            http::StatusCode::BAD_REQUEST,
+            None,
+            "Invalid request".as_bytes().to_vec(),
+        );
+        return Ok(());
+    };
+    if let NoteRequest::LogInProfile(_) = req {
+        let client = client_ip(http_request);
+        if !state.may_try_password(&client) {
+            http::server::send_response(
+                http::StatusCode::TOO_MANY_REQUESTS,
+                None,
+                "Too many wrong passwords, try again later"
+                    .as_bytes()
+                    .to_vec(),
+            );
+            return Ok(());
+        }
+        let resp = handle_note_request(req, Some(&our()), state, server)?;
+        if let NoteResponse::LogInProfile(Err(_)) = &resp {
+            state.record_wrong_password(client);
+        }
+        http::server::send_response(http::StatusCode::OK, None, resp.into());
+        return Ok(());
+    }
+    let session = http_request
+        .headers()
+        .get(PROFILE_SESSION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let Some((session, name)) = session.and_then(|session| {
+        let name = state.profile_sessions.get(&session).cloned()?;
+        Some((session, name))
+    }) else {
+        http::server::send_response(
+            http::StatusCode::UNAUTHORIZED,
+            None,
+            "Invalid profile session".as_bytes().to_vec(),
+        );
+        return Ok(());
+    };
+    // A session can only ever end itself
+    if let NoteRequest::LogOutProfile(_) = req {
+        let resp = handle_note_request(
+            NoteRequest::LogOutProfile(session),
+            Some(&our()),
+            state,
+            server,
+        )?;
+        http::server::send_response(http::StatusCode::OK, None, resp.into());
+        return Ok(());
+    }
+    if !is_profile_request_allowed(&req) {
+        http::server::send_response(
+            http::StatusCode::FORBIDDEN,
+            None,
+            "Profiles may not make this request".as_bytes().to_vec(),
+        );
+        return Ok(());
+    }
+    let result = with_profile(state, &name, |profile| {
+        let resp = handle_note_request(req, Some(&our()), profile, server)?;
+        push_changes(profile, server)?;
+        Ok(resp)
+    });
+    match result {
+        Ok(resp) => http::server::send_response(http::StatusCode::OK, None, resp.into()),
+        Err(e) => {
+            error!("Profile {} request failed: {:?}", name, e);
+            http::server::send_response(
+                http::StatusCode::INTERNAL_SERVER_ERROR,
+                None,
+                "Request failed".as_bytes().to_vec(),
+            );
+        }
+    }
+    Ok(())
+}
+
+// Whether a request changes settings only the node owner may: who may fetch /public, the size
+// limits and cold storage. Profiles and tokens may change the rest.
+fn changes_node_settings(current: &Settings, req: &NoteRequest) -> bool {
+    let NoteRequest::UpdateSettings(settings) = req else {
+        return false;
+    };
+    settings.cors_origins != current.cors_origins
+        || settings.cors_methods != current.cors_methods
+        || settings.max_note_bytes != current.max_note_bytes
+        || settings.drive_quota_bytes != current.drive_quota_bytes
+        || settings.cold_storage_months != current.cold_storage_months
+}
+
+// What a profile may do with its own notebook; node-wide features stay with the node owner
+fn is_profile_request_allowed(req: &NoteRequest) -> bool {
+    matches!(
+        req,
+        NoteRequest::CreateFolder(_)
+            | NoteRequest::RenameFolder(_)
+            | NoteRequest::DeleteFolder(_)
+            | NoteRequest::MoveFolder(_)
+            | NoteRequest::CreateNote(_)
+            | NoteRequest::RenameNote(_)
+            | NoteRequest::DeleteNote(_)
+            | NoteRequest::MoveNote(_)
+            | NoteRequest::SetManualOrder(_)
+            | NoteRequest::AddNoteAlias(_)
+            | NoteRequest::RemoveNoteAlias(_)
+            | NoteRequest::GetNote(_)
+            | NoteRequest::UpdateNoteContent(_)
+            | NoteRequest::AppendNoteContent(_)
+            | NoteRequest::EditTable(_)
+            | NoteRequest::ImportCsv(_)
+            | NoteRequest::CreateAudioNote(_)
+            | NoteRequest::ImportPdf(_)
+            | NoteRequest::SetNoteTags(_)
+            | NoteRequest::SetNoteDue(_)
+            | NoteRequest::GetDueNotes
+            | NoteRequest::SetNotePassword(_)
+            | NoteRequest::UnlockNote(_)
+            | NoteRequest::RemoveNotePassword(_)
+            | NoteRequest::GetAttachments(_)
+            | NoteRequest::GetRecentNotes(_)
+            | NoteRequest::GetOrphanReport(_)
+            | NoteRequest::SearchNotes(_)
+            | NoteRequest::MatchNoteTitles(_)
+            | NoteRequest::GetNotesByTagPrefix(_)
+            | NoteRequest::GetTagTree
+            | NoteRequest::ReplaceInNotes(_)
+            | NoteRequest::GetStructure
+            | NoteRequest::GetStructureSorted(_)
+            | NoteRequest::GetFolderContents(_)
+            | NoteRequest::ResolvePath(_)
+            | NoteRequest::GetNotePath(_)
+            | NoteRequest::MergeContent(_)
+            | NoteRequest::ExportAll((_, None))
+            | NoteRequest::StartExport(None)
+            | NoteRequest::ExportChunk(_)
+            | NoteRequest::ExportFolder(_)
+            | NoteRequest::ExportFiltered(_)
+            | NoteRequest::ExportZip
+            | NoteRequest::ExportObsidian
+            | NoteRequest::ExportNotePdf(_)
+            | NoteRequest::ExportNoteHtml(_)
+            | NoteRequest::ImportAll((_, _, _, None))
+            | NoteRequest::ImportZip(_)
+            | NoteRequest::GetSettings
+            | NoteRequest::UpdateSettings(_)
+            | NoteRequest::Undo
+            | NoteRequest::Redo
+    )
+}
+
+// Clients without node auth call the API here, with an "Authorization: Bearer" token
+const TOKEN_API_PATH: &str = "/token-api";
+
+fn load_api_tokens() -> anyhow::Result<Vec<StoredApiToken>> {
+    let path = format!("{}/api_tokens.json", workspace_drive(DEFAULT_WORKSPACE)?);
+    match vfs::open_file(&path, false, None) {
+        Ok(file) => Ok(serde_json::from_slice(&file.read()?)?),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+fn save_api_tokens(tokens: &[StoredApiToken]) -> anyhow::Result<()> {
+    let path = format!("{}/api_tokens.json", workspace_drive(DEFAULT_WORKSPACE)?);
+    vfs::create_file(&path, None)?.write(&serde_json::to_vec(tokens)?)?;
+    Ok(())
+}
+
+fn is_read_only_request(req: &NoteRequest) -> bool {
+    matches!(
+        req,
+        NoteRequest::GetNote(_)
+            | NoteRequest::GetAttachments(_)
+            | NoteRequest::GetRecentNotes(_)
+            | NoteRequest::GetOrphanReport(_)
+            | NoteRequest::SearchNotes(_)
+            | NoteRequest::MatchNoteTitles(_)
+            | NoteRequest::GetNotesByTagPrefix(_)
+            | NoteRequest::GetTagTree
+            | NoteRequest::GetDueNotes
+            | NoteRequest::GetStructure
+            | NoteRequest::GetStructureSorted(_)
+            | NoteRequest::GetFolderContents(_)
+            | NoteRequest::ResolvePath(_)
+            | NoteRequest::GetNotePath(_)
+            | NoteRequest::MergeContent(_)
+            | NoteRequest::ExportAll((_, None))
+            | NoteRequest::StartExport(None)
+            | NoteRequest::ExportChunk(_)
+            | NoteRequest::ExportFolder(_)
+            | NoteRequest::ExportFiltered(_)
+            | NoteRequest::ExportZip
+            | NoteRequest::ExportObsidian
+            | NoteRequest::ExportNotePdf(_)
+            | NoteRequest::ExportNoteHtml(_)
+            | NoteRequest::GetSettings
+    )
+}
+
+// The error response to a request, e.g. CreateNote(Err(..)) for a CreateNote request
+fn error_response(req: &NoteRequest, error: &str) -> anyhow::Result<NoteResponse> {
+    named_error_response(&request_name(req), error)
+}
+
+fn named_error_response(name: &str, error: &str) -> anyhow::Result<NoteResponse> {
+    let response = serde_json::json!({ name: { "Err": error } });
+    Ok(serde_json::from_value(response)?)
+}
+
+// Name of a request as it appears in JSON, e.g. "CreateNote"
+fn request_name(req: &NoteRequest) -> String {
+    match serde_json::to_value(req) {
+        Ok(serde_json::Value::String(name)) => name,
+        Ok(serde_json::Value::Object(map)) => map.keys().next().cloned().unwrap_or_default(),
+        _ => String::new(),
+    }
+}
+
+// Folders a token limited to some folders may use: those folders and everything under them
+fn token_folders(state: &State, scope: &TokenScope) -> Option<HashSet<String>> {
+    if scope.folders.is_empty() {
+        return None;
+    }
+    Some(
+        scope
+            .folders
+            .iter()
+            .flat_map(|id| state.folder_subtree(id))
+            .collect(),
+    )
+}
+
+fn is_token_request_allowed(state: &State, scope: &TokenScope, req: &NoteRequest) -> bool {
+    let access_allows = match scope.access {
+        TokenAccess::ReadOnly => is_read_only_request(req),
+        // The same notebook operations a profile gets; managing the node stays with its owner
+        TokenAccess::ReadWrite => is_profile_request_allowed(req),
+        TokenAccess::AppendOnly => is_append_only_request(req),
+    };
+    if !access_allows || changes_node_settings(&state.settings, req) {
+        return false;
+    }
+    if !scope.operations.is_empty() && !scope.operations.contains(&request_name(req)) {
+        return false;
+    }
+    let Some(folders) = token_folders(state, scope) else {
+        return true;
+    };
+    let folder_allowed = |id: Option<&String>| id.is_some_and(|id| folders.contains(id));
+    let note_allowed = |id: &String| {
+        state
+            .notes
+            .get(id)
+            .is_some_and(|note| folder_allowed(note.folder_id.as_ref()))
+    };
+    match req {
+        NoteRequest::CreateFolder((_, parent)) => folder_allowed(parent.as_ref()),
+        NoteRequest::RenameFolder((id, _))
+        | NoteRequest::DeleteFolder(id)
+        | NoteRequest::ExportFolder(id) => folder_allowed(Some(id)),
+        NoteRequest::GetFolderContents((id, _)) => folder_allowed(id.as_ref()),
+        NoteRequest::MoveFolder((id, parent)) => {
+            folder_allowed(Some(id)) && folder_allowed(parent.as_ref())
+        }
+        NoteRequest::CreateNote((_, folder, _))
+        | NoteRequest::ImportCsv((_, folder, _))
+        | NoteRequest::ImportPdf((_, folder, _))
+        | NoteRequest::CreateAudioNote((_, folder, _, _, _)) => folder_allowed(folder.as_ref()),
+        NoteRequest::MoveNote((id, folder)) => note_allowed(id) && folder_allowed(folder.as_ref()),
+        NoteRequest::RenameNote((id, _))
+        | NoteRequest::DeleteNote(id)
+        | NoteRequest::GetNote(id)
+        | NoteRequest::UpdateNoteContent((id, _))
+        | NoteRequest::AppendNoteContent((id, _))
+        | NoteRequest::EditTable((id, _))
+        | NoteRequest::SetNoteTags((id, _))
+        | NoteRequest::SetNoteDue((id, _))
+        | NoteRequest::SetNotePassword((id, _))
+        | NoteRequest::UnlockNote((id, _))
+        | NoteRequest::RemoveNotePassword((id, _))
+        | NoteRequest::GetAttachments(id)
+        | NoteRequest::GetNotePath(id)
+        | NoteRequest::ExportNotePdf(id)
+        | NoteRequest::ExportNoteHtml(id) => note_allowed(id),
+        // Answered with only the token's folders
+        NoteRequest::GetStructure
+        | NoteRequest::GetStructureSorted(_)
+        | NoteRequest::GetDueNotes => true,
+        // Anything touching the whole notebook
+        _ => false,
+    }
+}
+
+// Tokens are "<id>.<secret>"
+fn find_api_token<'a>(tokens: &'a [StoredApiToken], token: &str) -> Option<&'a StoredApiToken> {
+    let (id, secret) = token.trim().split_once('.')?;
+    tokens
+        .iter()
+        .find(|stored| stored.id == id && stored.secret_hash == sha256_hex(secret.as_bytes()))
+}
+
+// Adding notes and adding to them, without changing or reading what is already there. The
+// structure is allowed for finding where to add, and comes without content.
+fn is_append_only_request(req: &NoteRequest) -> bool {
+    matches!(
+        req,
+        NoteRequest::GetStructure | NoteRequest::CreateNote(_) | NoteRequest::AppendNoteContent(_)
+    )
+}
+
+// A structure cut down to the folders a scope is limited to; append-only scopes get no content
+fn scoped_structure(
+    state: &State,
+    scope: &TokenScope,
+    (mut folders, mut notes): (Vec<Folder>, Vec<Note>),
+) -> (Vec<Folder>, Vec<Note>) {
+    if let Some(allowed) = token_folders(state, scope) {
+        folders.retain(|folder| allowed.contains(&folder.id));
+        notes.retain(|note| note.folder_id.as_ref().is_some_and(|f| allowed.contains(f)));
+    }
+    if matches!(scope.access, TokenAccess::AppendOnly) {
+        for note in &mut notes {
+            note.content = Vec::new();
+        }
+    }
+    (folders, notes)
+}
+
+// A response cut down to the folders a scope is limited to
+fn scoped_response(state: &State, scope: &TokenScope, resp: NoteResponse) -> NoteResponse {
+    match resp {
+        NoteResponse::GetStructure(Ok(structure)) => {
+            NoteResponse::GetStructure(Ok(scoped_structure(state, scope, structure)))
+        }
+        NoteResponse::GetStructureSorted(Ok(structure)) => {
+            NoteResponse::GetStructureSorted(Ok(scoped_structure(state, scope, structure)))
+        }
+        NoteResponse::GetDueNotes(Ok(due)) => NoteResponse::GetDueNotes(Ok(due
+            .into_iter()
+            .filter(|due| is_token_note_visible(state, scope, &due.note_id))
+            .collect())),
+        NoteResponse::GetOrphanReport(Ok(orphans)) => NoteResponse::GetOrphanReport(Ok(orphans
+            .into_iter()
+            .filter(|orphan| is_token_note_visible(state, scope, &orphan.note_id))
+            .collect())),
+        resp => resp,
+    }
+}
+
+// Other processes on our node are granted a scope, kept alongside the API tokens
+fn load_process_grants() -> anyhow::Result<Vec<ProcessGrant>> {
+    let path = format!(
+        "{}/process_grants.json",
+        workspace_drive(DEFAULT_WORKSPACE)?
+    );
+    match vfs::open_file(&path, false, None) {
+        Ok(file) => Ok(serde_json::from_slice(&file.read()?)?),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+fn save_process_grants(grants: &[ProcessGrant]) -> anyhow::Result<()> {
+    let path = format!(
+        "{}/process_grants.json",
+        workspace_drive(DEFAULT_WORKSPACE)?
+    );
+    vfs::create_file(&path, None)?.write(&serde_json::to_vec(grants)?)?;
+    Ok(())
+}
+
+// The grant of a process on our node outside our package, if it has one
+fn find_process_grant(source: &Address) -> anyhow::Result<Option<ProcessGrant>> {
+    if source.node() != our().node() || source.package_id() == our().package_id() {
+        return Ok(None);
+    }
+    let process = source.process.to_string();
+    Ok(load_process_grants()?
+        .into_iter()
+        .find(|grant| grant.process == process))
+}
+
+// Whether a note is in the folders a token is limited to, if any
+fn is_token_note_visible(state: &State, scope: &TokenScope, note_id: &str) -> bool {
+    match token_folders(state, scope) {
+        Some(folders) => state
+            .notes
+            .get(note_id)
+            .and_then(|note| note.folder_id.as_ref())
+            .is_some_and(|folder| folders.contains(folder)),
+        None => true,
+    }
+}
+
+// Calendar apps subscribe to /public/calendar.ics?token=<API token>, which they cannot send as
+// a header. Each note with a due date is an event with a reminder when it falls due.
+fn handle_public_calendar(
+    http_request: &http::server::IncomingHttpRequest,
+    state: &mut State,
+    server: &http::server::HttpServer,
+) -> anyhow::Result<()> {
+    if http_request.method()? != http::Method::GET {
+        http::server::send_response(http::StatusCode::METHOD_NOT_ALLOWED, None, vec![]);
+        return Ok(());
+    }
+    let tokens = load_api_tokens()?;
+    let query = http_request.query_params();
+    let Some(stored) = query
+        .get("token")
+        .and_then(|token| find_api_token(&tokens, token))
+    else {
+        http::server::send_response(
+            http::StatusCode::UNAUTHORIZED,
+            None,
+            "Invalid API token".as_bytes().to_vec(),
+        );
+        return Ok(());
+    };
+    if !is_token_request_allowed(state, &stored.scope, &NoteRequest::GetDueNotes) {
+        http::server::send_response(
+            http::StatusCode::FORBIDDEN,
+            None,
+            "Not allowed with this API token".as_bytes().to_vec(),
+        );
+        return Ok(());
+    }
+    // Counted like any other request
+    let NoteResponse::GetDueNotes(Ok(due)) =
+        handle_note_request(NoteRequest::GetDueNotes, Some(&our()), state, server)?
+    else {
+        http::server::send_response(http::StatusCode::INTERNAL_SERVER_ERROR, None, vec![]);
+        return Ok(());
+    };
+    let (_, now) = amz_dates(now_secs());
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//wifenote//EN".to_string(),
+        "X-WR-CALNAME:wifenote".to_string(),
+    ];
+    for note in due
+        .iter()
+        .filter(|due| is_token_note_visible(state, &stored.scope, &due.note_id))
+    {
+        let (_, start) = amz_dates(note.due);
+        let summary = ics_escape(&note.name);
+        lines.extend([
+            "BEGIN:VEVENT".to_string(),
+            format!("UID:{}@{}", note.note_id, our().node()),
+            format!("DTSTAMP:{}", now),
+            format!("DTSTART:{}", start),
+            format!("SUMMARY:{}", summary),
+            "BEGIN:VALARM".to_string(),
+            "ACTION:DISPLAY".to_string(),
+            format!("DESCRIPTION:{}", summary),
+            "TRIGGER:PT0S".to_string(),
+            "END:VALARM".to_string(),
+            "END:VEVENT".to_string(),
+        ]);
+    }
+    lines.push("END:VCALENDAR".to_string());
+    let body: String = lines.iter().map(|line| ics_fold(line) + "\r\n").collect();
+
+    let mut headers = HashMap::new();
+    headers.insert(
+        "Content-Type".to_string(),
+        "text/calendar; charset=utf-8".to_string(),
+    );
+    http::server::send_response(http::StatusCode::OK, Some(headers), body.into_bytes());
+    Ok(())
+}
+
+// Escape text for an iCalendar property value
+fn ics_escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+        .replace('\r', "")
+}
+
+// Fold an iCalendar line so no line is longer than 75 bytes
+fn ics_fold(line: &str) -> String {
+    let mut folded = String::new();
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            width = 1;
+        }
+        folded.push(c);
+        width += c.len_utf8();
+    }
+    folded
+}
+
+fn handle_token_api_request(
+    http_request: &http::server::IncomingHttpRequest,
+    state: &mut State,
+    server: &http::server::HttpServer,
+) -> anyhow::Result<()> {
+    if http_request.method()? != http::Method::POST {
+        http::server::send_response(http::StatusCode::METHOD_NOT_ALLOWED, None, vec![]);
+        return Ok(());
+    }
+    let headers = http_request.headers();
+    let token = headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let tokens = load_api_tokens()?;
+    let Some(stored) = token.and_then(|token| find_api_token(&tokens, token)) else {
+        http::server::send_response(
+            http::StatusCode::UNAUTHORIZED,
+            Some(HashMap::from([(
+                "WWW-Authenticate".to_string(),
+                "Bearer".to_string(),
+            )])),
+            "Invalid API token".as_bytes().to_vec(),
+        );
+        return Ok(());
+    };
+    let body = last_blob().map(|blob| blob.bytes).unwrap_or_default();
+    let Ok(req) = NoteRequest::try_from(body.as_slice()) else {
+        http::server::send_response(
+            http::StatusCode::BAD_REQUEST,
+            None,
+            "Invalid request".as_bytes().to_vec(),
+        );
+        return Ok(());
+    };
+    if !is_token_request_allowed(state, &stored.scope, &req) {
+        http::server::send_response(
+            http::StatusCode::FORBIDDEN,
+            None,
+            "Not allowed with this API token".as_bytes().to_vec(),
+        );
+        return Ok(());
+    }
+    let resp = handle_note_request(req, Some(&our()), state, server)?;
+    let resp = scoped_response(state, &stored.scope, resp);
+    http::server::send_response(http::StatusCode::OK, None, resp.into());
+    Ok(())
+}
+
+// The named workspace's state when it is not the active one; None means the active workspace
+fn inactive_workspace(name: Option<&str>) -> anyhow::Result<Result<Option<State>, String>> {
+    let Some(name) = name else {
+        return Ok(Ok(None));
+    };
+    let workspaces = load_workspaces()?;
+    if !workspaces.names.iter().any(|n| n == name) {
+        return Ok(Err("Workspace not found".to_string()));
+    }
+    if workspaces.active == name {
+        return Ok(Ok(None));
+    }
+    Ok(Ok(Some(State::load_from_disk(workspace_drive(name)?)?)))
+}
+
+const BACKUP_TIMEOUT_SECS: u64 = 300;
+const BACKUP_TIMER_CONTEXT: &str = "backup";
+const RESTORE_CONTEXT: &str = "restore";
+
+// (Re)arm the periodic backup timer, invalidating any timer already pending
+fn schedule_backup(state: &mut State) {
+    state.backup_timer += 1;
+    if let Some(target) = &state.backup.target {
+        timer::set_timer(
+            target.interval_hours as u64 * 60 * 60 * 1000,
+            Some(format!("{}:{}", BACKUP_TIMER_CONTEXT, state.backup_timer).into_bytes()),
+        );
+    }
+}
+
+const S3_BACKUP_TIMER_CONTEXT: &str = "s3-backup";
+// Every upload of a backup shares this much time, as each holds up the process until done
+const S3_BACKUP_TOTAL_SECS: u64 = 300;
+
+fn schedule_s3_backup(state: &mut State) {
+    state.s3_backup_timer += 1;
+    if let Some(target) = &state.backup.s3 {
+        timer::set_timer(
+            target.interval_hours as u64 * 60 * 60 * 1000,
+            Some(format!("{}:{}", S3_BACKUP_TIMER_CONTEXT, state.s3_backup_timer).into_bytes()),
+        );
+    }
+}
+
+// Upload an export archive, and optionally every note file, to the S3 target, recording
+// the outcome. Returns the paths of note files skipped as unreadable.
+fn run_s3_backup(state: &mut State) -> anyhow::Result<Result<Vec<String>, String>> {
+    let result = s3_backup(state);
+    match &result {
+        Ok(skipped) => {
+            state.backup.last_s3_backup = Some(now_secs());
+            state.backup.last_s3_error = None;
+            state.backup.last_s3_skipped = skipped.clone();
+        }
+        Err(e) => state.backup.last_s3_error = Some(e.to_string()),
+    }
+    state.save_to_disk()?;
+    Ok(result.map_err(|e| e.to_string()))
+}
+
+fn s3_backup(state: &State) -> anyhow::Result<Vec<String>> {
+    let Some(target) = &state.backup.s3 else {
+        return Err(anyhow::anyhow!("No S3 target set"));
+    };
+    let archive = state.export_archive(None, &mut |_, _| {})?;
+    let (archive, ext) = match &target.passphrase {
+        Some(passphrase) => (
+            encrypt_archive(&archive, passphrase).map_err(|e| anyhow::anyhow!(e))?,
+            "json.gz.enc",
+        ),
+        None => (archive, "json.gz"),
+    };
+    let started = std::time::Instant::now();
+    let time_left = || {
+        let left = S3_BACKUP_TOTAL_SECS.saturating_sub(started.elapsed().as_secs());
+        if left == 0 {
+            Err(anyhow::anyhow!(
+                "S3 backup took longer than {} seconds",
+                S3_BACKUP_TOTAL_SECS
+            ))
+        } else {
+            Ok(left)
+        }
+    };
+    let key = format!("{}wifenote-export-{}.{}", target.prefix, now_secs(), ext);
+    s3_put_object(target, &key, archive, time_left()?)?;
+    let mut skipped = Vec::new();
+    if target.include_note_files {
+        for (note_id, path) in state.export_paths().notes {
+            // An empty object would overwrite the note's last good copy in the bucket
+            let content = match state.load_note_content(&note_id) {
+                Ok(content) => content,
+                Err(e) => {
+                    error!("S3 backup: skipping unreadable note {}: {}", note_id, e);
+                    skipped.push(path);
+                    continue;
+                }
+            };
+            let key = format!("{}notes/{}", target.prefix, path);
+            s3_put_object(target, &key, content, time_left()?)?;
+        }
+    }
+    Ok(skipped)
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    use hmac::Mac;
+    let mut mac = <hmac::Hmac<sha2::Sha256> as Mac>::new_from_slice(key)
+        .expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    use sha2::Digest;
+    sha2::Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// (YYYYMMDD, YYYYMMDDTHHMMSSZ) for a Unix timestamp, as AWS Signature Version 4 wants them
+fn amz_dates(secs: u64) -> (String, String) {
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let days = (secs / 86400) as i64;
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let time = secs % 86400;
+    let datetime = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    );
+    (date, datetime)
+}
+
+// PUT an object using a path-style URL signed with AWS Signature Version 4
+fn s3_put_object(target: &S3Target, key: &str, body: Vec<u8>, timeout: u64) -> anyhow::Result<()> {
+    let endpoint = url::Url::parse(&target.endpoint)?;
+    let host = match endpoint.port() {
+        Some(port) => format!("{}:{}", endpoint.host_str().unwrap_or_default(), port),
+        None => endpoint.host_str().unwrap_or_default().to_string(),
+    };
+    let mut canonical_uri = endpoint.path().trim_end_matches('/').to_string();
+    for segment in std::iter::once(target.bucket.as_str()).chain(key.split('/')) {
+        canonical_uri.push('/');
+        canonical_uri.push_str(&percent_encode(segment, b"-._~"));
+    }
+
+    let (date, datetime) = amz_dates(now_secs());
+    let payload_hash = sha256_hex(&body);
+    let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+    let canonical_request = format!(
+        "PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+        canonical_uri, host, payload_hash, datetime, signed_headers, payload_hash
+    );
+    let scope = format!("{}/{}/s3/aws4_request", date, target.region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        datetime,
+        scope,
+        sha256_hex(canonical_request.as_bytes())
+    );
+    let mut signing_key = format!("AWS4{}", target.secret_access_key).into_bytes();
+    for part in [date.as_str(), target.region.as_str(), "s3", "aws4_request"] {
+        signing_key = hmac_sha256(&signing_key, part.as_bytes());
+    }
+    let signature: String = hmac_sha256(&signing_key, string_to_sign.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    let mut headers = HashMap::new();
+    headers.insert("x-amz-content-sha256".to_string(), payload_hash);
+    headers.insert("x-amz-date".to_string(), datetime);
+    headers.insert(
+        "Authorization".to_string(),
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            target.access_key_id, scope, signed_headers, signature
+        ),
+    );
+    let mut url = endpoint.clone();
+    url.set_path(&canonical_uri);
+    let response = http::client::send_request_await_response(
+        http::Method::PUT,
+        url,
+        Some(headers),
+        timeout,
+        body,
+    )
+    .map_err(|e| anyhow::anyhow!("S3 request failed: {}", e))?;
+    if !response.status().is_success() {
+        return Err(anyhow::anyhow!(
+            "S3 returned {} for {}: {}",
+            response.status(),
+            key,
+            String::from_utf8_lossy(response.body())
+        ));
+    }
+    Ok(())
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// Push an encrypted export to the backup target; the outcome arrives as a response
+fn send_backup(state: &mut State) -> anyhow::Result<()> {
+    let Some(target) = &state.backup.target else {
+        return Ok(());
+    };
+    let archive = state.export_archive(None, &mut |_, _| {})?;
+    let encrypted = target
+        .key()
+        .and_then(|key| encrypt_with_key(&archive, &key, &target.salt))
+        .map_err(|e| anyhow::anyhow!(e))?;
+    Request::to(Address::new(&target.node, our().process.clone()))
+        .body(NoteRequest::StoreBackup(encrypted))
+        .expects_response(BACKUP_TIMEOUT_SECS)
+        .send()?;
+    Ok(())
+}
+
+// Import the backup a target sent back for RestoreFromBackup; the outcome is reported through
+// GetBackupStatus
+fn restore_from_backup(
+    message: &Message,
+    state: &mut State,
+    server: &mut http::server::HttpServer,
+) -> anyhow::Result<()> {
+    let Some(target) = &state.backup.target else {
+        return Ok(());
+    };
+    if message.source().node() != target.node {
+        return Ok(());
+    }
+    let result = match message.body().try_into() {
+        Ok(NoteResponse::FetchBackup(Ok(archive))) => target
+            .key()
+            .and_then(|key| decrypt_with_key(&archive, &key))
+            .map(|archive| NoteRequest::ImportAll((archive, None, false, None))),
+        Ok(NoteResponse::FetchBackup(Err(e))) => Err(e),
+        _ => Err("Unexpected response from backup target".to_string()),
+    };
+    let result = match result {
+        Ok(request) => match handle_note_request(request, Some(&our()), state, server)? {
+            NoteResponse::ImportAll(result) => result.map(|_| ()),
+            _ => Err("Restore failed".to_string()),
+        },
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => {
+            state.backup.last_restore = Some(now_secs());
+            state.backup.last_error = None;
+        }
+        Err(e) => state.backup.last_error = Some(format!("Restore failed: {e}")),
+    }
+    state.save_to_disk()
+}
+
+// Responses are either a timer firing, a backup target acknowledging a backup or returning one
+// to restore, a mirrored folder's update, or another node answering a request sent to it
+fn handle_response(
+    message: &Message,
+    state: &mut State,
+    server: &mut http::server::HttpServer,
+) -> anyhow::Result<()> {
+    if let Some(context) = message.context() {
+        let context = String::from_utf8_lossy(context);
+        if context == RESTORE_CONTEXT {
+            restore_from_backup(message, state, server)?;
+        } else if let Some(target) = context.strip_prefix(&format!("{}:", SHARED_PRESENCE_CONTEXT))
+        {
+            receive_shared_presence(state, target, message);
+        } else if let Some(target) = context.strip_prefix(&format!("{}:", SHARE_KEY_CONTEXT)) {
+            receive_share_key(state, target, message)?;
+        } else if let Some(id) = context.strip_prefix(&format!("{}:", REPLY_CONTEXT)) {
+            let from = message.source().node();
+            let answer = message
+                .body()
+                .try_into()
+                .map_err(|_| format!("Unexpected response from {}", from));
+            receive_reply(state, id, from, answer)?;
+            // Answers can add or rename notes, such as those shared with us
+            push_changes(state, server)?;
+        } else if context == SAVE_TIMER_CONTEXT {
+            flush_pending_saves(state)?;
+        } else if context == format!("{}:{}", GIT_SYNC_TIMER_CONTEXT, state.git_sync_timer) {
+            run_git_sync(state)?;
+        } else if let Some(rest) = context.strip_prefix(&format!("{}:", GIT_HTTP_CONTEXT)) {
+            continue_git_sync(state, rest, http_response_parts(message))?;
+        } else if context == format!("{}:{}", REPLICA_TIMER_CONTEXT, state.replica_timer) {
+            if let Err(e) = run_replica_sync(state)? {
+                error!("Replica sync failed: {e}");
+            }
+        } else if let Some(rest) = context.strip_prefix(&format!("{}:", REPLICA_SYNC_CONTEXT)) {
+            let response = message
+                .body()
+                .try_into()
+                .map_err(|_| format!("Unexpected response from {}", message.source().node()));
+            continue_replica_sync(state, rest, response)?;
+        } else if context == format!("{}:{}", S3_BACKUP_TIMER_CONTEXT, state.s3_backup_timer) {
+            if let Err(e) = run_s3_backup(state)? {
+                error!("S3 backup failed: {e}");
+            }
+            schedule_s3_backup(state);
+        } else if context == format!("{}:{}", SHARED_EDIT_TIMER_CONTEXT, state.shared_edit_timer) {
+            // Re-arm before a failure propagates, or queued edits would wait for a restart
+            let replayed = replay_shared_edits(state);
+            schedule_shared_edit_replay(state);
+            replayed?;
+        } else if context == format!("{}:{}", FEED_TIMER_CONTEXT, state.feed_timer) {
+            if let Err(e) = state.start_feed_poll() {
+                error!("Feed poll failed: {e}");
+            }
+            schedule_feed_poll(state);
+        } else if let Some(url) = context.strip_prefix(&format!("{}:", FEED_HTTP_CONTEXT)) {
+            let items = feed_items(url, http_response_parts(message));
+            let changed = state.receive_feed(url, items)?;
+            if changed > 0 && state.git_sync.remote.is_some() {
+                schedule_git_sync(state, true);
+            }
+        } else if context
+            == format!(
+                "{}:{}",
+                COLD_STORAGE_TIMER_CONTEXT, state.cold_storage_timer
+            )
+        {
+            match state.compact_cold_notes() {
+                Ok(count) if count > 0 => info!("Moved {count} notes to cold storage"),
+                Ok(_) => {}
+                Err(e) => error!("Cold storage compaction failed: {e}"),
+            }
+            schedule_cold_storage(state);
+        } else if context == format!("{}:{}", BACKUP_TIMER_CONTEXT, state.backup_timer) {
+            if let Err(e) = send_backup(state) {
+                state.backup.last_error = Some(e.to_string());
+                state.save_to_disk()?;
+            }
+            schedule_backup(state);
+        }
+        return Ok(());
+    }
+    if let Ok(NoteResponse::SubscribeFolder(Ok(update))) = message.body().try_into() {
+        if state.apply_mirror_update(message.source().node(), update)? {
+            state.save_to_disk()?;
+        }
+        return Ok(());
+    }
+    if let Ok(NoteResponse::StoreBackup(result)) = message.body().try_into() {
+        match result {
+            Ok(()) => {
+                state.backup.last_backup = Some(now_secs());
+                state.backup.last_error = None;
+            }
+            Err(e) => state.backup.last_error = Some(e),
+        }
+        state.save_to_disk()?;
+    }
+    Ok(())
+}
+
+// A backup push that never reached its target, a mirror update that never reached a node
+// mirroring our folder, a git sync or feed request http-client never answered, a replica
+// sync request our replica never answered, or a request another node never answered
+fn handle_send_error(send_error: &SendError, state: &mut State) -> anyhow::Result<()> {
+    let context = send_error.context().map(String::from_utf8_lossy);
+    if let Some(id) = context
+        .as_deref()
+        .and_then(|context| context.strip_prefix(&format!("{}:", REPLY_CONTEXT)))
+    {
+        let node = send_error.target().node();
+        return receive_reply(state, id, node, Err(unreachable_error(node)));
+    }
+    if let Some(rest) = context
+        .as_deref()
+        .and_then(|context| context.strip_prefix(&format!("{}:", GIT_HTTP_CONTEXT)))
+    {
+        let failure = if send_error.kind().is_timeout() {
+            "timed out"
+        } else {
+            "could not be sent"
+        };
+        return continue_git_sync(state, rest, Err(failure.to_string()));
+    }
+    if let Some(rest) = context
+        .as_deref()
+        .and_then(|context| context.strip_prefix(&format!("{}:", REPLICA_SYNC_CONTEXT)))
+    {
+        let error = unreachable_error(send_error.target().node());
+        return continue_replica_sync(state, rest, Err(error));
+    }
+    if let Some(url) = context
+        .as_deref()
+        .and_then(|context| context.strip_prefix(&format!("{}:", FEED_HTTP_CONTEXT)))
+    {
+        let error = format!("Failed to fetch {}: no answer from http-client", url);
+        state.receive_feed(url, Err(error))?;
+        return Ok(());
+    }
+    if let Ok(NoteRequest::MirrorUpdate(update)) = send_error.message().body().try_into() {
+        // Everything goes to every mirror next time, so the one that missed this catches up
+        state.mirror_sent.remove(&update.folder_id);
+        return Ok(());
+    }
+    if let Ok(NoteRequest::StoreBackup(_) | NoteRequest::FetchBackup) =
+        send_error.message().body().try_into()
+    {
+        state.backup.last_error = Some(format!(
+            "Backup target {} is unreachable",
+            send_error.target().node()
+        ));
+        state.save_to_disk()?;
+    }
+    Ok(())
+}
+
+// Requests that can change notes or their paths, and so call for a git sync
+fn is_note_mutation(req: &NoteRequest) -> bool {
+    matches!(
+        req,
+        NoteRequest::RenameFolder(_)
+            | NoteRequest::DeleteFolder(_)
+            | NoteRequest::MoveFolder(_)
+            | NoteRequest::CreateNote(_)
+            | NoteRequest::RenameNote(_)
+            | NoteRequest::DeleteNote(_)
+            | NoteRequest::MoveNote(_)
+            | NoteRequest::AddNoteAlias(_)
+            | NoteRequest::RemoveNoteAlias(_)
+            | NoteRequest::UpdateNoteContent(_)
+            | NoteRequest::ReplaceInNotes(_)
+            | NoteRequest::ClipUrl(_)
+            | NoteRequest::AcceptTransfer(_)
+            | NoteRequest::CompleteTransfer(_)
+            | NoteRequest::PollFeedsNow
+            | NoteRequest::ImportAll(_)
+            | NoteRequest::ImportZip(_)
+            | NoteRequest::Undo
+            | NoteRequest::Redo
+            | NoteRequest::RestoreFromBackup
+    )
+}
+
+// Requests other nodes (and other local packages) may make; each still checks its source.
+// Anything else reads or changes the whole notebook on the owner's behalf, e.g. delete-note,
+// export-all or set-settings, and checks no source because only the owner's own UI sends it.
+// Without this list any node that knew the process address could send those too.
+fn is_remote_request_allowed(req: &NoteRequest) -> bool {
+    matches!(
+        req,
+        NoteRequest::GetNote(_)
+            | NoteRequest::UpdateNoteContent(_)
+            | NoteRequest::GetShareKey(_)
+            | NoteRequest::NotifyMention(_)
+            | NoteRequest::NotifyInvite(_)
+            | NoteRequest::AcceptInvite(_)
+            | NoteRequest::RejectInvite(_)
+            | NoteRequest::LeaveSharedNote(_)
+            | NoteRequest::NotifyTransfer(_)
+            | NoteRequest::RejectTransfer(_)
+            | NoteRequest::FetchTransfer(_)
+            | NoteRequest::CompleteTransfer(_)
+            | NoteRequest::PingPresence(_)
+            | NoteRequest::StoreBackup(_)
+            | NoteRequest::FetchBackup
+            | NoteRequest::SubscribeFolder(_)
+            | NoteRequest::UnsubscribeFolder(_)
+            | NoteRequest::PullSharedFolder(_)
+            | NoteRequest::MirrorUpdate(_)
+            | NoteRequest::ReplicaSyncNow
+            | NoteRequest::ReplicaChanges
+            | NoteRequest::ReplicaFetch(_)
+            | NoteRequest::ReplicaApply(_)
+    )
+}
 
-            NoteRequest::ImportAll(compressed_bytes) => {
-                // Decompress data
-                let mut decoder = GzDecoder::new(&compressed_bytes[..]);
-                let mut decompressed = String::new();
-                match decoder.read_to_string(&mut decompressed) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        return Ok(NoteResponse::ImportAll(Err(format!(
-                            "Failed to decompress data: {}",
-                            e
-                        ))))
-                    }
-                }
+// Request understood by the node's notifications process
+#[derive(Debug, Serialize)]
+enum NotificationsAction {
+    PushNotification {
+        title: String,
+        body: String,
+        icon: Option<String>,
+        url: Option<String>, // Opened when the notification is clicked
+    },
+}
 
-                // Parse and migrate the JSON
-                let import_data: ExportData = match serde_json::from_str(&decompressed) {
-                    Ok(data) => match migrate_export_data(data, &state.drive) {
-                        Ok(migrated) => migrated,
-                        Err(e) => return Ok(NoteResponse::ImportAll(Err(e.to_string()))),
-                    },
-                    Err(e) => {
-                        return Ok(NoteResponse::ImportAll(Err(format!(
-                            "Failed to parse JSON data: {}",
-                            e
-                        ))))
-                    }
-                };
+// Notify the user through the node, which reaches them even without wifenote open
+fn push_notification(title: &str, body: &str) {
+    let action = NotificationsAction::PushNotification {
+        title: title.to_string(),
+        body: body.to_string(),
+        icon: None,
+        url: Some(format!("/{}/", our().process)),
+    };
+    let sent = serde_json::to_vec(&action)
+        .map_err(anyhow::Error::from)
+        .and_then(|body| {
+            Request::to((our().node(), "notifications", "distro", "sys"))
+                .body(body)
+                .send()
+                .map_err(anyhow::Error::from)
+        });
+    if let Err(e) = sent {
+        error!("Failed to send notification: {e}");
+    }
+}
 
-                // Update state
-                let mut new_state = state.clone();
-                new_state.collaboration_invites = import_data.collaboration_invites;
+// The UI's WebSocket path, bound with authentication. Channels on any other path are treated as
+// public: they never get the structure, and only follow public notes, without collaborators.
+const PRIVATE_WS_PATH: &str = "/";
 
-                for folder in import_data.folders {
-                    if folder.parent_id.is_none() {
-                        new_state.root_items.insert(folder.id.clone());
-                    }
-                    new_state.folders.insert(folder.id.clone(), folder);
-                }
+// Pushes larger than this are gzipped and sent as binary frames; tldraw JSON shrinks about 10x
+const WS_COMPRESSION_THRESHOLD: usize = 16 * 1024;
 
-                // Handle both old format (full notes) and new format (metadata)
-                if !import_data.notes.is_empty() {
-                    // Old format: save notes as files and create metadata
-                    for note in import_data.notes {
-                        if note.folder_id.is_none() {
-                            new_state.root_items.insert(note.id.clone());
-                        }
-                        // Create and store metadata first
-                        let metadata = NoteMetadata::from(note.clone());
-                        new_state.notes.insert(metadata.id.clone(), metadata);
-                        // Then save content to file with appropriate extension
-                        new_state.save_note_content(&note.id, &note.content)?;
-                    }
-                } else if !import_data.note_metadata.is_empty() {
-                    // New format: already have metadata
-                    for metadata in import_data.note_metadata {
-                        if metadata.folder_id.is_none() {
-                            new_state.root_items.insert(metadata.id.clone());
-                        }
-                        new_state.notes.insert(metadata.id.clone(), metadata);
-                    }
-                }
+// Frame type and blob for a JSON message pushed over the WebSocket
+fn ws_message(bytes: Vec<u8>) -> (http::server::WsMessageType, LazyLoadBlob) {
+    if bytes.len() > WS_COMPRESSION_THRESHOLD {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        if let Ok(compressed) = encoder.write_all(&bytes).and_then(|_| encoder.finish()) {
+            return (
+                http::server::WsMessageType::Binary,
+                LazyLoadBlob {
+                    mime: Some("application/gzip".to_string()),
+                    bytes: compressed,
+                },
+            );
+        }
+    }
+    (
+        http::server::WsMessageType::Text,
+        LazyLoadBlob { mime: None, bytes },
+    )
+}
 
-                *state = new_state;
-                state.save_to_disk()?;
-                NoteResponse::ImportAll(Ok(()))
-            }
+fn send_ws_message(channel_id: u32, bytes: Vec<u8>) {
+    let (message_type, blob) = ws_message(bytes);
+    http::server::send_ws_push(channel_id, message_type, blob);
+}
+
+fn push_ws_message(server: &http::server::HttpServer, path: &str, bytes: Vec<u8>) {
+    let (message_type, blob) = ws_message(bytes);
+    server.ws_push_all_channels(path, message_type, blob);
+}
+
+// Collaborators ping a few times a minute while they have a note open
+const PRESENCE_TIMEOUT_SECS: u64 = 60;
+
+// Send who has a note open to the authenticated WebSocket clients subscribed to it
+fn push_presence(state: &mut State, note_id: &str) {
+    let presence = state.note_presence(note_id);
+    let bytes: Vec<u8> = NoteResponse::GetPresence(Ok(presence)).into();
+    for (channel_id, subscribed) in &state.ws_subscriptions {
+        if subscribed.contains(note_id) && state.ws_private_channels.contains(channel_id) {
+            send_ws_message(*channel_id, bytes.clone());
         }
-    };
-    Ok(resp)
+    }
+}
+
+// Tell WebSocket clients how far a long-running import or export has got, roughly every 1%
+fn push_progress(server: &http::server::HttpServer, operation: &str, done: u32, total: u32) {
+    if done != total && !done.is_multiple_of((total / 100).max(1)) {
+        return;
+    }
+    push_ws_message(
+        server,
+        PRIVATE_WS_PATH,
+        NoteResponse::Progress(Progress {
+            operation: operation.to_string(),
+            done,
+            total,
+        })
+        .into(),
+    );
 }
 
 fn handle_message(
@@ -1028,10 +16147,66 @@ fn handle_message(
     state: &mut State,
     server: &mut http::server::HttpServer,
 ) -> anyhow::Result<()> {
+    state.message_id += 1;
+    trace_log(state, LogLevel::Debug, || {
+        let kind = if message.is_request() {
+            "request"
+        } else {
+            "response"
+        };
+        format!("{} message from {}", kind, message.source())
+    });
+    if !message.is_request() {
+        return handle_response(message, state, server);
+    }
     match message.body().try_into() {
         Ok(Msg::NoteRequest(req)) => {
-            let resp = handle_note_request(req, Some(message.source()), state)?;
-            Response::new().body(resp).send()?;
+            let source = message.source();
+            let is_trusted =
+                source.node() == our().node() && source.package_id() == our().package_id();
+            // Other processes on our node act as us, within the scope they were granted
+            if let Some(grant) = find_process_grant(source)? {
+                if !is_token_request_allowed(state, &grant.scope, &req) {
+                    return Err(anyhow::anyhow!("Rejected request from {}", source));
+                }
+                let resp = handle_note_request(req, Some(&our()), state, server)?;
+                let resp = scoped_response(state, &grant.scope, resp);
+                Response::new().body(resp).send()?;
+            } else {
+                if !is_trusted && !is_remote_request_allowed(&req) {
+                    return Err(anyhow::anyhow!("Rejected request from {}", source));
+                }
+                let resp = handle_note_request(req, Some(message.source()), state, server)?;
+                Response::new().body(resp).send()?;
+            }
+        }
+        Ok(Msg::Batch(BatchRequest::Batch(requests))) => {
+            let source = message.source();
+            let is_trusted =
+                source.node() == our().node() && source.package_id() == our().package_id();
+            if let Some(grant) = find_process_grant(source)? {
+                if !requests
+                    .iter()
+                    .all(|req| is_token_request_allowed(state, &grant.scope, req))
+                {
+                    return Err(anyhow::anyhow!("Rejected request from {}", source));
+                }
+                let BatchResponse::Batch(responses) =
+                    handle_batch(requests, Some(&our()), state, server)?;
+                let responses = responses
+                    .into_iter()
+                    .map(|resp| scoped_response(state, &grant.scope, resp))
+                    .collect();
+                Response::new()
+                    .body(BatchResponse::Batch(responses))
+                    .send()?;
+            } else {
+                if !is_trusted && !requests.iter().all(is_remote_request_allowed) {
+                    return Err(anyhow::anyhow!("Rejected request from {}", source));
+                }
+                let resp = handle_batch(requests, Some(message.source()), state, server)?;
+                Response::new().body(resp).send()?;
+            }
         }
         Ok(Msg::HttpRequest(req)) => handle_http_request(req, state, server)?,
         Err(e) => {
@@ -1042,38 +16217,83 @@ fn handle_message(
             ))
         }
     }
-    // Convert metadata to full notes for websocket updates
-    let mut notes = Vec::new();
-    for metadata in state.notes.values() {
-        match state.get_full_note(metadata) {
-            Ok(note) => notes.push(note),
-            Err(_) => {
-                // If we can't load content, create note with empty content
-                notes.push(Note {
-                    id: metadata.id.clone(),
-                    name: metadata.name.clone(),
-                    folder_id: metadata.folder_id.clone(),
-                    note_type: metadata.note_type.clone(),
-                    content: vec![],
-                    is_public: metadata.is_public,
-                    collaborators: metadata.collaborators.clone(),
-                });
+    push_changes(state, server)
+}
+
+// Authenticated clients get what changed in the structure, without content; a note's content
+// only goes to the clients subscribed to it, and only when it changed. A profile's changes only
+// go to the channels logged in to it.
+fn push_changes(state: &mut State, server: &http::server::HttpServer) -> anyhow::Result<()> {
+    let started = std::time::Instant::now();
+    let since = state.structure_log().seq;
+    let (folders, notes) = state.structure_summary();
+    let structure_changed = state.structure_log.update(folders, notes);
+    state.record_push_timing("structure-diff", started);
+    if (structure_changed || !state.changed_notes.is_empty())
+        && !state.folder_subscribers.is_empty()
+    {
+        let started = std::time::Instant::now();
+        push_folder_mirrors(state)?;
+        state.record_push_timing("folder-mirrors", started);
+    }
+    if structure_changed || !state.changed_notes.is_empty() {
+        let started = std::time::Instant::now();
+        let changed = state.changed_notes.clone();
+        if let Err(e) = state.sync_by_name_tree(&changed) {
+            error!("failed to update the by-name tree: {e:?}");
+        }
+        state.record_push_timing("by-name-tree", started);
+    }
+    if structure_changed {
+        let started = std::time::Instant::now();
+        let delta = serde_json::to_vec(&WsResponse::Delta(state.structure_log.delta(Some(since))))?;
+        if state.profile.is_some() {
+            for channel_id in &state.ws_private_channels {
+                send_ws_message(*channel_id, delta.clone());
             }
+        } else {
+            push_ws_message(server, PRIVATE_WS_PATH, delta);
         }
+        state.record_push_timing("structure-delta", started);
+    }
+    let started = std::time::Instant::now();
+    let pushes_content = !state.changed_notes.is_empty();
+    for note_id in std::mem::take(&mut state.changed_notes) {
+        let Some(metadata) = state.notes.get(&note_id) else {
+            continue;
+        };
+        // A note made private after a public channel subscribed is no longer sent to it
+        let channels: Vec<(u32, bool)> = state
+            .ws_subscriptions
+            .iter()
+            .filter(|(_, subscribed)| subscribed.contains(&note_id))
+            .map(|(channel_id, _)| (*channel_id, state.ws_private_channels.contains(channel_id)))
+            .filter(|(_, is_private)| *is_private || metadata.is_public)
+            .collect();
+        if channels.is_empty() {
+            continue;
+        }
+        let note = state.get_full_note(metadata)?;
+        let private_bytes: Vec<u8> = NoteResponse::GetNote(Ok(note.clone())).into();
+        let public_bytes: Vec<u8> = NoteResponse::GetNote(Ok(Note {
+            folder_id: note.folder_id.clone(),
+            ..state.public_note(metadata)?
+        }))
+        .into();
+        for (channel_id, is_private) in channels {
+            send_ws_message(
+                channel_id,
+                if is_private {
+                    private_bytes.clone()
+                } else {
+                    public_bytes.clone()
+                },
+            );
+        }
+    }
+    if pushes_content {
+        state.record_push_timing("note-content", started);
     }
-
-    server.ws_push_all_channels(
-        "/",
-        http::server::WsMessageType::Text,
-        LazyLoadBlob {
-            mime: None,
-            bytes: NoteResponse::GetStructure(Ok((
-                state.folders.values().cloned().collect(),
-                notes,
-            )))
-            .into(),
-        },
-    );
     Ok(())
 }
 
@@ -1082,7 +16302,11 @@ fn init(our: Address) {
     init_logging(Level::DEBUG, Level::INFO, None, None, None).unwrap();
     info!("{our}: begin");
 
-    let drive = vfs::create_drive(our.package_id(), "notes", None).unwrap();
+    let workspaces = load_workspaces().unwrap_or_else(|e| {
+        error!("Error loading workspaces: {e}, using the default workspace");
+        WorkspaceList::default()
+    });
+    let drive = workspace_drive(&workspaces.active).unwrap();
 
     let mut state = State::load_from_disk(drive.clone()).unwrap_or_else(|e| {
         error!("Error loading state: {e}, starting fresh");
@@ -1097,7 +16321,10 @@ fn init(our: Address) {
         .bind_http_path("/api", private_config.clone())
         .unwrap();
     server
-        .bind_ws_path("/", http::server::WsBindingConfig::default())
+        .bind_ws_path(
+            PRIVATE_WS_PATH,
+            http::server::WsBindingConfig::default().authenticated(true),
+        )
         .unwrap();
 
     // Public endpoints
@@ -1105,19 +16332,739 @@ fn init(our: Address) {
     server
         .bind_http_path("/public", public_config.clone())
         .unwrap();
+    // WebDAV clients authenticate with their own credentials, checked in handle_webdav_request
+    server
+        .bind_http_path("/dav", public_config.clone())
+        .unwrap();
+    // API token holders are checked in handle_token_api_request
+    server
+        .bind_http_path(TOKEN_API_PATH, public_config.clone())
+        .unwrap();
+    // Profile sessions are checked in handle_profile_api_request, and by WsRequest::LogIn
+    server
+        .bind_http_path(PROFILE_API_PATH, public_config.clone())
+        .unwrap();
     server
-        .serve_ui("ui", vec!["/"], public_config.clone())
+        .bind_ws_path(
+            PROFILE_WS_PATH,
+            http::server::WsBindingConfig::default().authenticated(false),
+        )
         .unwrap();
+    // UI files are served by handle_ui_request rather than as static files, so they can be cached
+    match ui_file_paths() {
+        Ok(paths) => {
+            for path in std::iter::once("/".to_string()).chain(paths) {
+                server.bind_http_path(&path, public_config.clone()).unwrap();
+            }
+        }
+        Err(e) => {
+            error!("Failed to list UI files, serving them uncached: {}", e);
+            server
+                .serve_ui("ui", vec!["/"], public_config.clone())
+                .unwrap();
+        }
+    }
 
     hyperware_process_lib::homepage::add_to_homepage("wifenote", Some(ICON), Some(""), None);
 
+    schedule_backup(&mut state);
+    schedule_s3_backup(&mut state);
+    schedule_git_sync(&mut state, false);
+    schedule_replica_sync(&mut state, false);
+    schedule_feed_poll(&mut state);
+    schedule_cold_storage(&mut state);
+    schedule_shared_edit_replay(&mut state);
+    if let Err(e) = resubscribe_mirrors(&state) {
+        error!("failed to resubscribe mirrored folders: {e:?}");
+    }
+    if let Err(e) = state.sync_by_name_tree(&HashSet::new()) {
+        error!("failed to update the by-name tree: {e:?}");
+    }
+    if state.prune_invites() {
+        if let Err(e) = state.save_to_disk() {
+            error!("failed to save pruned invites: {e:?}");
+        }
+    }
+
     loop {
         match await_message() {
-            Err(send_error) => error!("got SendError: {send_error}"),
+            Err(send_error) => {
+                error!("got SendError: {send_error}");
+                if let Err(e) = handle_send_error(&send_error, &mut state) {
+                    error!("got error while handling SendError: {e:?}");
+                }
+            }
             Ok(ref message) => match handle_message(message, &mut state, &mut server) {
                 Ok(_) => {}
-                Err(e) => error!("got error while handling message: {e:?}"),
+                Err(e) => error!(
+                    "[{}] got error while handling message: {e:?}",
+                    state.message_id
+                ),
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_requests_are_limited_to_node_protocols() {
+        assert!(is_remote_request_allowed(&NoteRequest::GetNote(
+            "note".to_string()
+        )));
+        assert!(is_remote_request_allowed(&NoteRequest::GetShareKey((
+            "note".to_string(),
+            Vec::new()
+        ))));
+        assert!(is_remote_request_allowed(&NoteRequest::FetchBackup));
+        assert!(!is_remote_request_allowed(&NoteRequest::DeleteNote(
+            "note".to_string()
+        )));
+        assert!(!is_remote_request_allowed(&NoteRequest::RestoreFromBackup));
+        assert!(!is_remote_request_allowed(&NoteRequest::Undo));
+    }
+
+    fn folder(id: &str, name: &str) -> Folder {
+        Folder {
+            id: id.to_string(),
+            name: name.to_string(),
+            parent_id: None,
+            mirror_of: None,
+        }
+    }
+
+    #[test]
+    fn structure_log_sends_only_what_changed() {
+        let mut log = StructureLog::new();
+        assert!(log.update(vec![folder("a", "A"), folder("b", "B")], Vec::new()));
+        let cursor = log.seq;
+
+        // The same structure again records nothing
+        assert!(!log.update(vec![folder("a", "A"), folder("b", "B")], Vec::new()));
+        assert_eq!(log.seq, cursor);
+
+        assert!(log.update(vec![folder("a", "Renamed")], Vec::new()));
+        let delta = log.delta(Some(cursor));
+        assert!(!delta.full);
+        assert_eq!(delta.folders.len(), 1);
+        assert_eq!(delta.folders[0].name, "Renamed");
+        assert_eq!(delta.removed, vec!["b".to_string()]);
+
+        // A cursor the log doesn't cover gets everything
+        let delta = log.delta(Some(cursor - 10));
+        assert!(delta.full);
+        assert_eq!(delta.folders.len(), 1);
+    }
+
+    fn note(id: &str, folder_id: Option<&str>, content: &str) -> Note {
+        Note {
+            id: id.to_string(),
+            name: id.to_string(),
+            folder_id: folder_id.map(str::to_string),
+            note_type: NoteType::Markdown,
+            content: content.as_bytes().to_vec(),
+            is_public: false,
+            collaborators: Vec::new(),
+            tags: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn append_only_tokens_cannot_read_content() {
+        let mut state = State::new("/test".to_string());
+        state
+            .folders
+            .insert("inbox".to_string(), folder("inbox", "Inbox"));
+        let scope = TokenScope {
+            access: TokenAccess::AppendOnly,
+            folders: vec!["inbox".to_string()],
+            operations: Vec::new(),
+        };
+        assert!(!is_token_request_allowed(
+            &state,
+            &scope,
+            &NoteRequest::GetNote("note".to_string())
+        ));
+        assert!(is_token_request_allowed(
+            &state,
+            &scope,
+            &NoteRequest::GetStructure
+        ));
+
+        let structure = (
+            vec![folder("inbox", "Inbox"), folder("other", "Other")],
+            vec![
+                note("in", Some("inbox"), "secret"),
+                note("out", Some("other"), "secret"),
+            ],
+        );
+        let NoteResponse::GetStructure(Ok((folders, notes))) = scoped_response(
+            &state,
+            &scope,
+            NoteResponse::GetStructure(Ok(structure.clone())),
+        ) else {
+            panic!("Expected a structure");
+        };
+        assert_eq!(folders.len(), 1);
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].id, "in");
+        assert!(notes[0].content.is_empty());
+
+        // Read-only tokens still get content
+        let scope = TokenScope {
+            access: TokenAccess::ReadOnly,
+            ..scope
+        };
+        let NoteResponse::GetStructure(Ok((_, notes))) =
+            scoped_response(&state, &scope, NoteResponse::GetStructure(Ok(structure)))
+        else {
+            panic!("Expected a structure");
+        };
+        assert_eq!(notes[0].content, b"secret");
+    }
+
+    #[test]
+    fn only_the_owner_changes_node_settings() {
+        let state = State::new("/test".to_string());
+        let scope = TokenScope {
+            access: TokenAccess::ReadWrite,
+            folders: Vec::new(),
+            operations: Vec::new(),
+        };
+        let update = |change: fn(&mut Settings)| {
+            let mut settings = state.settings.clone();
+            change(&mut settings);
+            NoteRequest::UpdateSettings(settings)
+        };
+        let allowed = |req: &NoteRequest| is_token_request_allowed(&state, &scope, req);
+
+        assert!(allowed(&update(|s| s.dark_mode = Some(true))));
+        assert!(!allowed(&update(|s| s.max_note_bytes = Some(1 << 30))));
+        assert!(!allowed(&update(|s| s.drive_quota_bytes = Some(1 << 40))));
+        assert!(!allowed(
+            &update(|s| s.cors_origins = vec!["*".to_string()])
+        ));
+        assert!(!allowed(&update(|s| s.cold_storage_months = Some(1))));
+    }
+
+    #[test]
+    fn wrong_passwords_are_limited_per_client() {
+        let mut state = State::new("/test".to_string());
+        for _ in 0..PASSWORD_ATTEMPT_LIMIT {
+            assert!(state.may_try_password("1.2.3.4"));
+            state.record_wrong_password("1.2.3.4".to_string());
+        }
+        assert!(!state.may_try_password("1.2.3.4"));
+        assert!(state.may_try_password("5.6.7.8"));
+
+        // Failures that have left the window are forgotten
+        let old = now_secs() - PASSWORD_ATTEMPT_WINDOW_SECS;
+        for failures in state.password_failures.values_mut() {
+            failures.iter_mut().for_each(|time| *time = old);
+        }
+        assert!(state.may_try_password("1.2.3.4"));
+        assert!(state.password_failures.is_empty());
+    }
+
+    fn exchange_keys() -> ExchangeKeys {
+        ExchangeKeys {
+            secret: new_exchange_secret(),
+            peers: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn share_keys_are_sealed_for_the_asking_node_only() {
+        let (owner, collaborator, outsider) = (exchange_keys(), exchange_keys(), exchange_keys());
+        let (owner_public, collaborator_public) = (
+            owner.public_key().unwrap(),
+            collaborator.public_key().unwrap(),
+        );
+
+        // Both ends agree on the wrapping key without it being sent
+        let wrapping = owner.wrapping_key(&collaborator_public, "note").unwrap();
+        assert_eq!(
+            collaborator.wrapping_key(&owner_public, "note").unwrap(),
+            wrapping
+        );
+        assert_ne!(
+            owner.wrapping_key(&collaborator_public, "other").unwrap(),
+            wrapping
+        );
+
+        let share_key = [7u8; 32];
+        let sealed = seal_share_key(&share_key, &wrapping).unwrap();
+        assert!(!sealed.windows(32).any(|window| window == share_key));
+        assert_eq!(open_share_key(&sealed, &wrapping).unwrap(), share_key);
+        let eavesdropped = outsider.wrapping_key(&owner_public, "note").unwrap();
+        assert!(open_share_key(&sealed, &eavesdropped).is_err());
+        assert!(owner.wrapping_key(b"not a key", "note").is_err());
+    }
+
+    #[test]
+    fn exchange_keys_are_pinned_on_first_use() {
+        let mut keys = exchange_keys();
+        let (first, second) = (
+            exchange_keys().public_key().unwrap(),
+            exchange_keys().public_key().unwrap(),
+        );
+        assert_eq!(keys.pin("peer.os", &first), Ok(true));
+        assert_eq!(keys.pin("peer.os", &first), Ok(false));
+        assert!(keys.pin("peer.os", &second).is_err());
+        keys.peers.remove("peer.os");
+        assert_eq!(keys.pin("peer.os", &second), Ok(true));
+    }
+
+    #[test]
+    fn feeds_parse_rss_items_and_atom_entries() {
+        let rss = r#"<rss><channel><title>Blog</title><link>https://b.example</link>
+            <item><title>First &amp; best</title><link>https://b.example/1</link>
+            <guid>post-1</guid><pubDate>Mon, 01 Jan 2024</pubDate>
+            <description><![CDATA[<p>Hi</p>]]></description></item>
+            <item><link>https://b.example/2</link></item>
+            </channel></rss>"#;
+        let items = parse_feed(rss);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].id, "post-1");
+        assert_eq!(items[0].title, "First & best");
+        assert_eq!(items[0].link.as_deref(), Some("https://b.example/1"));
+        assert_eq!(items[0].published.as_deref(), Some("Mon, 01 Jan 2024"));
+        assert_eq!(items[0].html, "<p>Hi</p>");
+        assert_eq!(items[1].id, "https://b.example/2");
+        assert_eq!(items[1].title, "Untitled");
+
+        let atom = r#"<feed><title>Log</title>
+            <entry><id>urn:1</id><title>Atom post</title>
+            <link rel="edit" href="https://a.example/edit"/>
+            <link href="https://a.example/1"/>
+            <updated>2024-01-02</updated><content>Body</content></entry>
+            </feed>"#;
+        let items = parse_feed(atom);
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].id, "urn:1");
+        assert_eq!(items[0].link.as_deref(), Some("https://a.example/1"));
+        assert_eq!(items[0].published.as_deref(), Some("2024-01-02"));
+        assert_eq!(items[0].html, "Body");
+
+        let url = "https://b.example/rss";
+        assert!(feed_items(url, Ok((http::StatusCode::OK, rss.as_bytes().to_vec()))).is_ok());
+        assert!(feed_items(url, Ok((http::StatusCode::NOT_FOUND, Vec::new()))).is_err());
+        assert!(feed_items(url, Ok((http::StatusCode::OK, b"<html></html>".to_vec()))).is_err());
+        assert!(feed_items(url, Err("timeout".to_string())).is_err());
+    }
+
+    fn metadata(id: &str) -> NoteMetadata {
+        NoteMetadata {
+            id: id.to_string(),
+            name: id.to_string(),
+            folder_id: None,
+            note_type: NoteType::Markdown,
+            is_public: false,
+            collaborators: Vec::new(),
+            tags: Vec::new(),
+            is_locked: false,
+            read_only: false,
+            noindex: false,
+            compressed: false,
+            archive: None,
+            published: None,
+            updated: 0,
+            created: 0,
+            due: None,
+            aliases: Vec::new(),
+            alias_folders: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn replica_syncs_pull_push_and_keep_both_sides_of_a_conflict() {
+        let mut state = State::new("/test".to_string());
+        state.dry_run = true;
+        state
+            .folders
+            .insert("kept".to_string(), folder("kept", "Kept"));
+        state
+            .folders
+            .insert("gone".to_string(), folder("gone", "Gone"));
+        state.notes.insert("n".to_string(), metadata("n"));
+        state
+            .note_hashes
+            .insert("n".to_string(), sha256_hex(b"ours"));
+        let hashes = state.replica_hashes();
+        for id in ["kept", "gone"] {
+            state
+                .replica
+                .synced
+                .insert(id.to_string(), hashes[id].clone());
+        }
+        state
+            .replica
+            .synced
+            .insert("n".to_string(), "before".to_string());
+        let mut job = ReplicaSyncJob {
+            id: 1,
+            node: "peer.os".to_string(),
+            expected: HashMap::new(),
+            agreed: Vec::new(),
+            pulls: Vec::new(),
+            conflicts: Vec::new(),
+            pushes: Vec::new(),
+            pulled: 0,
+            rerun: false,
+        };
+
+        let theirs = HashMap::from([
+            ("gone".to_string(), None),
+            ("new".to_string(), Some("h-new".to_string())),
+            ("raced".to_string(), Some("h-raced".to_string())),
+            ("n".to_string(), Some("theirs".to_string())),
+        ]);
+        state.plan_replica_sync(&mut job, theirs).unwrap();
+        assert!(!state.folders.contains_key("gone"));
+        job.pulls.sort();
+        assert_eq!(job.pulls, ["new", "raced"]);
+        assert_eq!(job.conflicts, ["n"]);
+        assert_eq!(job.pushes, [("n".to_string(), Some("theirs".to_string()))]);
+
+        // Created here while the replica's items were on their way
+        state
+            .folders
+            .insert("raced".to_string(), folder("raced", "Ours"));
+        let mut replica = note("n", None, "theirs");
+        replica.name = "Note".to_string();
+        let items = vec![
+            ReplicaItem::Folder(folder("new", "New")),
+            ReplicaItem::Folder(folder("raced", "Theirs")),
+            ReplicaItem::Note(ReplicaNote {
+                note: replica,
+                locked: false,
+                read_only: false,
+                noindex: false,
+                due: None,
+                aliases: Vec::new(),
+                alias_folders: Vec::new(),
+            }),
+        ];
+        state.apply_replica_pulls(&mut job, items).unwrap();
+        assert_eq!(state.folders["new"].name, "New");
+        assert_eq!(state.folders["raced"].name, "Ours");
+        assert!(!job.agreed.iter().any(|(id, _)| id == "raced"));
+        assert!(job.agreed.iter().any(|(id, _)| id == "new"));
+        assert_eq!(job.pulled, 2);
+        assert!(state
+            .notes
+            .values()
+            .any(|n| n.name == "Note (from peer.os)"));
+        assert_eq!(state.notes["n"].name, "n");
+    }
+
+    #[test]
+    fn csv_round_trips_quoted_fields() {
+        let text = "name,notes\r\n\"Smith, J\",\"said \"\"hi\"\"\nthen left\"\nshort\n";
+        let rows = parse_csv(text);
+        assert_eq!(
+            rows,
+            vec![
+                vec!["name".to_string(), "notes".to_string()],
+                vec!["Smith, J".to_string(), "said \"hi\"\nthen left".to_string()],
+                vec!["short".to_string()],
+            ]
+        );
+        // Rows are padded to the widest one
+        let written = write_csv(&rows);
+        assert_eq!(
+            written,
+            "name,notes\n\"Smith, J\",\"said \"\"hi\"\"\nthen left\"\nshort,\n"
+        );
+        assert_eq!(
+            parse_csv(&written)[2],
+            vec!["short".to_string(), String::new()]
+        );
+        assert_eq!(
+            parse_csv("\u{feff}a,b"),
+            vec![vec!["a".to_string(), "b".to_string()]]
+        );
+    }
+
+    fn table(rows: &[&[&str]]) -> Vec<Vec<String>> {
+        rows.iter()
+            .map(|row| row.iter().map(|cell| cell.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn table_edits_insert_delete_and_grow() {
+        let mut rows = table(&[&["a", "b"], &["c"]]);
+        apply_table_edit(&mut rows, TableEdit::InsertRow(1)).unwrap();
+        assert_eq!(rows, table(&[&["a", "b"], &["", ""], &["c", ""]]));
+        apply_table_edit(&mut rows, TableEdit::DeleteColumn(0)).unwrap();
+        assert_eq!(rows, table(&[&["b"], &[""], &[""]]));
+        apply_table_edit(&mut rows, TableEdit::InsertColumn(1)).unwrap();
+        apply_table_edit(&mut rows, TableEdit::DeleteRow(1)).unwrap();
+        assert_eq!(rows, table(&[&["b", ""], &["", ""]]));
+
+        // A cell one row and column past the edge grows the table by them
+        apply_table_edit(&mut rows, TableEdit::SetCell((2, 2, "x".to_string()))).unwrap();
+        assert_eq!(
+            rows,
+            table(&[&["b", "", ""], &["", "", ""], &["", "", "x"]])
+        );
+        assert!(apply_table_edit(&mut rows, TableEdit::SetCell((4, 0, "y".to_string()))).is_err());
+        assert!(apply_table_edit(&mut rows, TableEdit::SetCell((0, 4, "y".to_string()))).is_err());
+        assert!(apply_table_edit(&mut rows, TableEdit::DeleteRow(3)).is_err());
+        assert!(apply_table_edit(&mut rows, TableEdit::InsertColumn(4)).is_err());
+        assert_eq!(rows.len(), 3);
+    }
+
+    #[test]
+    fn table_edits_respect_the_size_limits() {
+        let mut rows = vec![vec![String::new(); MAX_TABLE_COLUMNS as usize]];
+        assert!(apply_table_edit(&mut rows, TableEdit::InsertColumn(0)).is_err());
+        assert_eq!(rows[0].len(), MAX_TABLE_COLUMNS as usize);
+    }
+
+    fn tar_entry(name: &str, type_flag: u8, content: &[u8]) -> Vec<u8> {
+        let mut header = vec![0u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..136].copy_from_slice(format!("{:011o}\0", content.len()).as_bytes());
+        header[156] = type_flag;
+        let mut entry = header;
+        entry.extend_from_slice(content);
+        entry.resize(512 + content.len().div_ceil(512) * 512, 0);
+        entry
+    }
+
+    #[test]
+    fn tar_entries_are_read_and_directories_skipped() {
+        let mut archive = tar_entry("notes/", b'5', b"");
+        archive.extend(tar_entry("notes/a.md", b'0', b"# A\n"));
+        archive.extend(tar_entry("b.md", 0, &[b'b'; 600]));
+        archive.extend(vec![0u8; 1024]);
+        let entries = read_tar_entries(&archive).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], ("notes/a.md".to_string(), b"# A\n".to_vec()));
+        assert_eq!(entries[1].0, "b.md");
+        assert_eq!(entries[1].1.len(), 600);
+    }
+
+    #[test]
+    fn tar_entries_past_the_end_are_rejected() {
+        let mut archive = tar_entry("a.md", b'0', &[b'a'; 600]);
+        archive.truncate(512 + 100);
+        assert!(read_tar_entries(&archive).is_err());
+
+        let mut archive = tar_entry("a.md", b'0', b"");
+        archive[124..136].copy_from_slice(b"zzzzzzzzzzz\0");
+        assert!(read_tar_entries(&archive).is_err());
+    }
+
+    #[test]
+    fn archive_sizes_count_the_files_imports_store() {
+        let zip = |files: &[(&str, &[u8])]| {
+            let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+            let options = zip::write::SimpleFileOptions::default();
+            for (name, content) in files {
+                writer.start_file(*name, options).unwrap();
+                writer.write_all(content).unwrap();
+            }
+            writer.finish().unwrap().into_inner()
+        };
+        let inner = zip(&[("Page.md", &[b'p'; 40])]);
+        let outer = zip(&[
+            ("Notes/a.md", &[b'a'; 100]),
+            ("Notes/photo.png", &[0; 500]),
+            ("Part-1.zip", &inner),
+        ]);
+        assert_eq!(archive_file_sizes(&outer, 0).unwrap(), vec![100, 40]);
+
+        let mut tar = tar_entry("a.md", b'0', &[b'a'; 600]);
+        tar[257..262].copy_from_slice(b"ustar");
+        tar.extend(tar_entry("resources/r.png", b'0', &[0; 10]));
+        assert_eq!(archive_file_sizes(&tar, 0).unwrap(), vec![600, 10]);
+    }
+
+    fn export_data(folders: Vec<Folder>, notes: Vec<Note>) -> ExportData {
+        let mut data: ExportData =
+            serde_json::from_str(r#"{"version": 1, "folders": []}"#).unwrap();
+        data.folders = folders;
+        data.notes = notes;
+        data
+    }
+
+    fn child_folder(id: &str, name: &str, parent_id: &str) -> Folder {
+        Folder {
+            parent_id: Some(parent_id.to_string()),
+            ..folder(id, name)
+        }
+    }
+
+    #[test]
+    fn imports_skip_what_we_have_and_remap_clashing_ids() {
+        let mut state = State::new("/test".to_string());
+        state
+            .folders
+            .insert("same".to_string(), folder("same", "Work"));
+        state
+            .folders
+            .insert("clash".to_string(), folder("clash", "Ours"));
+        let data = export_data(
+            vec![
+                folder("same", "Work"),
+                folder("clash", "Theirs"),
+                child_folder("child", "Child", "clash"),
+            ],
+            vec![Note {
+                id: "note".to_string(),
+                name: "Note".to_string(),
+                folder_id: Some("clash".to_string()),
+                note_type: NoteType::Markdown,
+                content: b"text".to_vec(),
+                is_public: false,
+                collaborators: Vec::new(),
+                tags: Vec::new(),
+            }],
+        );
+
+        let ids = plan_import_ids(&state, &data);
+        assert!(ids.skipped.contains("same"));
+        assert!(!state.folders.contains_key(&ids.get("clash")));
+        assert_eq!(ids.get("child"), "child");
+
+        // Fresh IDs are picked again here, so the clashing folder is found by name
+        let data = remap_import(&state, data);
+        assert_eq!(data.folders.len(), 2);
+        let new_id = data
+            .folders
+            .iter()
+            .find(|f| f.name == "Theirs")
+            .map(|f| f.id.clone())
+            .unwrap();
+        assert!(!state.folders.contains_key(&new_id));
+        let child = data.folders.iter().find(|f| f.id == "child").unwrap();
+        assert_eq!(child.parent_id.as_deref(), Some(new_id.as_str()));
+        assert_eq!(data.notes[0].id, "note");
+        assert_eq!(data.notes[0].folder_id.as_deref(), Some(new_id.as_str()));
+    }
+
+    #[test]
+    fn import_folder_cycles_are_settled() {
+        let mut state = State::new("/test".to_string());
+        state.folders.insert("a".to_string(), folder("a", "A"));
+        state.folders.insert("b".to_string(), folder("b", "B"));
+        let data = export_data(
+            vec![child_folder("a", "A", "b"), child_folder("b", "B", "a")],
+            Vec::new(),
+        );
+        let ids = plan_import_ids(&state, &data);
+        assert!(ids.remapped.contains_key("a"));
+        assert!(ids.remapped.contains_key("b"));
+        assert_ne!(ids.remapped["a"], ids.remapped["b"]);
+    }
+
+    #[test]
+    fn unfurl_prefers_open_graph_and_resolves_urls() {
+        let url = url::Url::parse("https://example.com/posts/1").unwrap();
+        let html = r#"<html><head>
+            <title> Fallback
+              title </title>
+            <meta name="description" content="Plain description">
+            <meta property="og:title" content="OG &amp; title">
+            <meta property="og:image" content="/cover.png">
+            <link rel="apple-touch-icon" href="/touch.png">
+            <link rel="shortcut icon" href="icon.ico">
+            </head><body><meta property="og:description" content="Too late"></body></html>"#;
+        let preview = unfurl_html(&url, html);
+        assert_eq!(preview.title.as_deref(), Some("OG & title"));
+        assert_eq!(preview.description.as_deref(), Some("Plain description"));
+        assert_eq!(
+            preview.image.as_deref(),
+            Some("https://example.com/cover.png")
+        );
+        assert_eq!(
+            preview.favicon.as_deref(),
+            Some("https://example.com/posts/icon.ico")
+        );
+    }
+
+    #[test]
+    fn unfurl_falls_back_to_the_title_and_favicon() {
+        let url = url::Url::parse("https://example.com/a").unwrap();
+        let preview = unfurl_html(&url, "<head><title>Tom &amp; Jerry</title></head>");
+        assert_eq!(preview.title.as_deref(), Some("Tom & Jerry"));
+        assert_eq!(preview.description, None);
+        assert_eq!(preview.image, None);
+        assert_eq!(
+            preview.favicon.as_deref(),
+            Some("https://example.com/favicon.ico")
+        );
+    }
+
+    #[test]
+    fn matched_lines_follow_the_longest_common_subsequence() {
+        let base = ["a\n", "b\n", "c\n", "d\n"];
+        let other = ["a\n", "x\n", "c\n", "d\n", "e\n"];
+        assert_eq!(
+            matched_lines(&base, &other),
+            Some(vec![(0, 0), (2, 2), (3, 3)])
+        );
+        assert_eq!(matched_lines(&[], &other), Some(Vec::new()));
+    }
+
+    #[test]
+    fn merges_combine_edits_to_different_lines() {
+        let base = "one\ntwo\nthree\nfour";
+        let mine = "ONE\ntwo\nthree\nfour";
+        let theirs = "one\ntwo\nthree\nFOUR\nfive";
+        assert_eq!(
+            merge_text(base, mine, theirs),
+            Some(("ONE\ntwo\nthree\nFOUR\nfive".to_string(), 0))
+        );
+        // The same edit on both sides is no conflict
+        assert_eq!(merge_text(base, mine, mine), Some((mine.to_string(), 0)));
+    }
+
+    #[test]
+    fn merges_mark_conflicting_edits() {
+        let (merged, conflicts) =
+            merge_text("a\nb\nc\n", "a\nmine\nc\n", "a\ntheirs\nc\n").unwrap();
+        assert_eq!(conflicts, 1);
+        assert_eq!(
+            merged,
+            "a\n<<<<<<< mine\nmine\n=======\ntheirs\n>>>>>>> theirs\nc\n"
+        );
+    }
+
+    fn wav_chunk(id: &[u8], size: u32, body: &[u8]) -> Vec<u8> {
+        [id, &size.to_le_bytes(), body].concat()
+    }
+
+    #[test]
+    fn wav_durations_come_from_the_byte_rate() {
+        // 8 kHz mono 16-bit is 16000 bytes a second
+        let mut fmt = vec![0u8; 16];
+        fmt[8..12].copy_from_slice(&16000u32.to_le_bytes());
+        let wav = [
+            b"RIFF\0\0\0\0WAVE".to_vec(),
+            wav_chunk(b"fmt ", 16, &fmt),
+            wav_chunk(b"data", 8000, &[0; 8000]),
+        ]
+        .concat();
+        assert_eq!(wav_duration_ms(&wav), Some(500));
+    }
+
+    #[test]
+    fn wav_chunks_sized_past_the_end_are_not_followed() {
+        let mut fmt = vec![0u8; 16];
+        fmt[8..12].copy_from_slice(&16000u32.to_le_bytes());
+        let wav = [
+            b"RIFF\0\0\0\0WAVE".to_vec(),
+            wav_chunk(b"LIST", u32::MAX, &[]),
+            wav_chunk(b"fmt ", 16, &fmt),
+            wav_chunk(b"data", 8000, &[0; 8000]),
+        ]
+        .concat();
+        assert_eq!(wav_duration_ms(&wav), None);
+    }
+}